@@ -4,10 +4,19 @@
 /// directories, and builds a cache in memory without touching anything.
 ///
 /// Usage: cargo run --example build_cache -- <root>
+#[cfg(unix)]
 use camino::{Utf8Path, Utf8PathBuf};
+#[cfg(unix)]
 use ldconfig::{Cache, Error, SearchPaths};
+#[cfg(unix)]
 use std::env;
 
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("Building a cache needs a Unix host");
+}
+
+#[cfg(unix)]
 fn main() -> Result<(), Error> {
     let args: Vec<String> = env::args().collect();
     let root = if args.len() > 1 {
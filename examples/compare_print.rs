@@ -0,0 +1,45 @@
+/// Example: Check our `ldconfig -p` output against glibc's.
+///
+/// Renders the cache the way `ldconfig -p` prints it and diffs that
+/// against a capture of the real tool, e.g. `ldconfig -p > ref.txt`.
+/// Exits with status 1 on anything but a generator mismatch.
+///
+/// Usage: cargo run --example compare_print <cache> <reference.txt> [--as PATH]
+use anyhow::Error;
+use bpaf::Bpaf;
+use camino::Utf8PathBuf;
+use ldconfig::Cache;
+
+#[derive(Debug, Clone, Bpaf)]
+#[bpaf(options)]
+struct Options {
+    #[bpaf(long("as"), argument("PATH"), fallback("/etc/ld.so.cache".into()))]
+    /// Cache path the reference was printed from
+    printed_as: Utf8PathBuf,
+
+    #[bpaf(positional("cache"))]
+    /// Cache file to render
+    cache: Utf8PathBuf,
+
+    #[bpaf(positional("reference"))]
+    /// Captured `ldconfig -p` output
+    reference: Utf8PathBuf,
+}
+
+fn main() -> Result<(), Error> {
+    let options = options().run();
+
+    let cache = Cache::from_file(&options.cache)?;
+    let reference = std::fs::read_to_string(&options.reference)?;
+    let diffs = cache.compare_print_output(&options.printed_as, &reference);
+
+    for diff in &diffs {
+        println!("{}", diff);
+    }
+    let content = diffs.iter().filter(|d| d.is_content()).count();
+    println!("{} divergence(s), {} in content", diffs.len(), content);
+    if content > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
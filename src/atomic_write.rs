@@ -1,15 +1,25 @@
 //! Atomic file write via tempfile + rename.
 
-use std::fs;
 use std::io::Write;
 use std::path::Path;
 use tempfile::NamedTempFile;
+use tracing::debug_span;
 
 /// Write data to a file atomically. The parent directory must exist,
 /// like glibc's ldconfig which errors out on a missing cache directory.
 pub(crate) fn atomic_write<P: AsRef<Path>>(path: P, data: &[u8]) -> std::io::Result<()> {
     let path = path.as_ref();
+    let _span = debug_span!("write", path = %path.display(), bytes = data.len()).entered();
 
+    // Atomically replace the target via rename(2).
+    stage(path, data)?.persist(path)?;
+
+    Ok(())
+}
+
+/// Write data to a synced temporary file next to `path`, to be renamed
+/// over it with `persist`; dropping it removes it instead.
+pub(crate) fn stage(path: &Path, data: &[u8]) -> std::io::Result<NamedTempFile> {
     // The temp file must live in the target's directory so the final
     // rename stays on one filesystem.
     let parent_dir = path.parent().unwrap_or_else(|| Path::new("."));
@@ -24,18 +34,16 @@ pub(crate) fn atomic_write<P: AsRef<Path>>(path: P, data: &[u8]) -> std::io::Res
         use std::os::unix::fs::PermissionsExt;
         temp_file
             .as_file()
-            .set_permissions(fs::Permissions::from_mode(0o644))?;
+            .set_permissions(std::fs::Permissions::from_mode(0o644))?;
     }
 
-    // Atomically replace the target via rename(2).
-    temp_file.persist(path)?;
-
-    Ok(())
+    Ok(temp_file)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
     use tempfile::tempdir;
 
     #[test]
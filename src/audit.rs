@@ -0,0 +1,196 @@
+//! A record of the filesystem changes a run made, and of what it warned
+//! about, as JSON lines, for audit trails on regulated systems.
+//!
+//! ```no_run
+//! use ldconfig::{write_audit, Cache, Change, SearchPaths};
+//! use std::fs::OpenOptions;
+//!
+//! # #[cfg(unix)] {
+//! let paths = SearchPaths::from_file("/etc/ld.so.conf", None)?;
+//! let cache = Cache::builder().build(&paths)?;
+//! cache.write_to_file("/etc/ld.so.cache")?;
+//!
+//! let mut changes: Vec<Change> = cache.report().links.iter().map(Change::Link).collect();
+//! changes.push(Change::CacheWritten {
+//!     path: "/etc/ld.so.cache".as_ref(),
+//!     bytes: cache.size(),
+//! });
+//! let log = OpenOptions::new().create(true).append(true).open("/var/log/ldconfig.jsonl")?;
+//! write_audit(log, &changes)?;
+//! # }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use crate::{error::Error, LinkAction, Warning};
+use std::fmt::Write as _;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A filesystem change made by a run, or a warning it logged.
+#[derive(Debug, Clone, Copy)]
+pub enum Change<'a> {
+    /// A warning, with its stable code.
+    Warned(&'a Warning),
+    /// A symlink change a build made.
+    Link(&'a LinkAction),
+    /// A symlink change undone by a rollback.
+    Undone(&'a LinkAction),
+    /// A cache file written.
+    CacheWritten { path: &'a Path, bytes: usize },
+    /// The replaced cache saved as `path`.
+    BackupWritten { path: &'a Path },
+    /// The cache at `path` put back from `backup`.
+    CacheRestored { path: &'a Path, backup: &'a Path },
+}
+
+impl Change<'_> {
+    /// The change as one JSON object, stamped with `time` in seconds
+    /// since the epoch: `{"time":…,"action":"create_link","path":…}`.
+    /// Link paths are inside the root the run used.
+    pub fn to_json(&self, time: u64) -> String {
+        let mut fields = vec![("action", self.action().to_owned())];
+        match *self {
+            Change::Warned(warning) => {
+                fields.push(("code", warning.kind.code().to_owned()));
+                fields.push(("kind", warning.kind.name().to_owned()));
+                fields.push(("message", warning.message.clone()));
+            }
+            Change::Link(action) | Change::Undone(action) => match action {
+                LinkAction::Create { dir, link, target } => {
+                    fields.push(("path", format!("{}/{}", dir, link)));
+                    fields.push(("target", target.clone()));
+                }
+                LinkAction::Replace {
+                    dir,
+                    link,
+                    target,
+                    previous,
+                } => {
+                    fields.push(("path", format!("{}/{}", dir, link)));
+                    fields.push(("target", target.clone()));
+                    fields.push(("previous", previous.clone()));
+                }
+                LinkAction::RemoveStale { dir, link, target } => {
+                    fields.push(("path", format!("{}/{}", dir, link)));
+                    fields.push(("target", target.clone()));
+                }
+            },
+            Change::CacheWritten { path, .. } | Change::BackupWritten { path } => {
+                fields.push(("path", path.display().to_string()));
+            }
+            Change::CacheRestored { path, backup } => {
+                fields.push(("path", path.display().to_string()));
+                fields.push(("backup", backup.display().to_string()));
+            }
+        }
+
+        let mut out = format!("{{\"time\":{}", time);
+        for (key, value) in fields {
+            let _ = write!(out, ",\"{}\":{}", key, json_string(&value));
+        }
+        if let Change::CacheWritten { bytes, .. } = self {
+            let _ = write!(out, ",\"bytes\":{}", bytes);
+        }
+        out.push('}');
+        out
+    }
+
+    fn action(&self) -> &'static str {
+        match self {
+            Change::Warned(_) => "warning",
+            Change::Link(LinkAction::Create { .. }) => "create_link",
+            Change::Link(LinkAction::Replace { .. }) => "replace_link",
+            Change::Link(LinkAction::RemoveStale { .. }) => "remove_link",
+            Change::Undone(LinkAction::Create { .. }) => "undo_create_link",
+            Change::Undone(LinkAction::Replace { .. }) => "undo_replace_link",
+            Change::Undone(LinkAction::RemoveStale { .. }) => "undo_remove_link",
+            Change::CacheWritten { .. } => "write_cache",
+            Change::BackupWritten { .. } => "write_backup",
+            Change::CacheRestored { .. } => "restore_cache",
+        }
+    }
+}
+
+/// `s` quoted and escaped for JSON, control characters included.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if u32::from(c) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", u32::from(c));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Write `changes` to `out` as JSON lines stamped with the current time,
+/// in one write so concurrent runs appending to the same log do not
+/// interleave.
+pub fn write_audit(mut out: impl Write, changes: &[Change<'_>]) -> Result<(), Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let text: String = changes
+        .iter()
+        .map(|change| change.to_json(now) + "\n")
+        .collect();
+    out.write_all(text.as_bytes())?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_json_lines() {
+        let replace = LinkAction::Replace {
+            dir: "/usr/lib".into(),
+            link: "libz.so.1".into(),
+            target: "libz.so.1.3".into(),
+            previous: "libz \"old\".so".into(),
+        };
+        let warning = Warning {
+            kind: crate::WarningKind::NotSymlink,
+            message: "/usr/lib/libz.so.1 is not a symbolic link".into(),
+        };
+        let changes = [
+            Change::Warned(&warning),
+            Change::Link(&replace),
+            Change::CacheWritten {
+                path: Path::new("/etc/ld.so.cache"),
+                bytes: 4096,
+            },
+        ];
+        assert_eq!(
+            changes[0].to_json(7),
+            r#"{"time":7,"action":"warning","code":"W0015","kind":"not_symlink","message":"/usr/lib/libz.so.1 is not a symbolic link"}"#
+        );
+        assert_eq!(
+            changes[1].to_json(7),
+            r#"{"time":7,"action":"replace_link","path":"/usr/lib/libz.so.1","target":"libz.so.1.3","previous":"libz \"old\".so"}"#
+        );
+        assert_eq!(
+            changes[2].to_json(7),
+            r#"{"time":7,"action":"write_cache","path":"/etc/ld.so.cache","bytes":4096}"#
+        );
+
+        let mut out = Vec::new();
+        write_audit(&mut out, &changes).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.lines().count(), 3);
+        assert!(text.ends_with("}\n"));
+        assert_eq!(json_string("a\u{1}b"), "\"a\\u0001b\"");
+        assert_eq!(json_string("a\nb\tc\r"), "\"a\\nb\\tc\\u000d\"");
+    }
+}
@@ -0,0 +1,286 @@
+//! glibc's auxiliary cache, `/var/cache/ldconfig/aux-cache`: what
+//! ldconfig learned about each library file, keyed by its device, inode,
+//! change time and size, so unchanged files are not parsed again.
+//!
+//! Entries outlive the files they describe; [`AuxCache::prune`] drops
+//! those no scanned file matches anymore.
+//!
+//! ```no_run
+//! use ldconfig::{AuxCache, SearchPaths, AUX_CACHE_PATH};
+//!
+//! let mut aux = AuxCache::from_file(AUX_CACHE_PATH)?;
+//! print!("{}", aux);
+//! # #[cfg(unix)] {
+//! let paths = SearchPaths::from_file("/etc/ld.so.conf", None)?;
+//! if aux.prune(&paths, "/") > 0 {
+//!     aux.write_to_file(AUX_CACHE_PATH)?;
+//! }
+//! # }
+//! # Ok::<(), ldconfig::Error>(())
+//! ```
+
+use crate::cache_format::{flags_string, read_u32, read_u64};
+#[cfg(unix)]
+use crate::chroot_canon;
+use crate::{atomic_write, error::Error};
+#[cfg(unix)]
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Where glibc's ldconfig keeps the auxiliary cache.
+pub const AUX_CACHE_PATH: &str = "/var/cache/ldconfig/aux-cache";
+
+const AUX_CACHE_MAGIC: &[u8] = b"glibc-ld.so.auxcache-1.0";
+/// Magic, then the entry count and string table length.
+const AUX_HEADER_SIZE: usize = AUX_CACHE_MAGIC.len() + 8;
+/// struct aux_cache_file_entry: the four id fields, flags, soname,
+/// isa_level and padding to 8 bytes.
+const AUX_ENTRY_SIZE: usize = 48;
+
+/// One library file glibc's ldconfig inspected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuxEntry {
+    /// Device of the file, `st_dev`.
+    pub dev: u64,
+    /// Inode of the file, `st_ino`.
+    pub ino: u64,
+    /// Last status change, `st_ctime`, in seconds.
+    pub ctime: u64,
+    /// File size in bytes.
+    pub size: u64,
+    /// The cache flags the file was classified with.
+    pub flags: u32,
+    /// DT_SONAME, or the file name without one.
+    pub soname: String,
+    /// x86 ISA level, 0 if unmarked.
+    pub isa_level: u32,
+}
+
+/// An auxiliary cache, as read from or written to disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuxCache {
+    entries: Vec<AuxEntry>,
+}
+
+impl AuxCache {
+    /// Read and parse the auxiliary cache at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Parse auxiliary cache bytes; like glibc, any size other than the
+    /// one the header announces is an invalid file.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        if !data.starts_with(AUX_CACHE_MAGIC) {
+            return Err(Error::InvalidAuxCache("wrong magic"));
+        }
+        let nlibs = read_u32(data, AUX_CACHE_MAGIC.len())
+            .ok_or(Error::InvalidAuxCache("file too small"))? as usize;
+        let len_strings = read_u32(data, AUX_CACHE_MAGIC.len() + 4)
+            .ok_or(Error::InvalidAuxCache("file too small"))? as usize;
+        let strings = nlibs
+            .checked_mul(AUX_ENTRY_SIZE)
+            .and_then(|n| n.checked_add(AUX_HEADER_SIZE))
+            .ok_or(Error::InvalidAuxCache("size mismatch"))?;
+        if strings.checked_add(len_strings) != Some(data.len()) {
+            return Err(Error::InvalidAuxCache("size mismatch"));
+        }
+
+        let mut entries = Vec::with_capacity(nlibs);
+        for i in 0..nlibs {
+            let off = AUX_HEADER_SIZE + i * AUX_ENTRY_SIZE;
+            let field = |n: usize| read_u64(data, off + 8 * n).unwrap();
+            let soname = strings + read_u32(data, off + 36).unwrap() as usize;
+            let bytes = data
+                .get(soname..)
+                .and_then(|b| Some(&b[..b.iter().position(|&c| c == 0)?]))
+                .ok_or(Error::InvalidCacheOffset(soname))?;
+            entries.push(AuxEntry {
+                ino: field(0),
+                ctime: field(1),
+                size: field(2),
+                dev: field(3),
+                flags: read_u32(data, off + 32).unwrap(),
+                soname: String::from_utf8(bytes.to_vec()).map_err(|_| Error::InvalidCacheUtf8)?,
+                isa_level: read_u32(data, off + 40).unwrap(),
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    /// The entries, in file order.
+    pub fn entries(&self) -> &[AuxEntry] {
+        &self.entries
+    }
+
+    /// Number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Keep only the entries `keep` accepts, returning how many were
+    /// dropped.
+    pub fn retain(&mut self, keep: impl FnMut(&AuxEntry) -> bool) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(keep);
+        before - self.entries.len()
+    }
+
+    /// The file glibc's ldconfig reads back.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut strings = Vec::new();
+        let mut offsets: HashMap<&str, u32> = HashMap::new();
+        let mut out = Vec::with_capacity(AUX_HEADER_SIZE + self.entries.len() * AUX_ENTRY_SIZE);
+        out.extend_from_slice(AUX_CACHE_MAGIC);
+        out.extend_from_slice(&(self.entries.len() as u32).to_ne_bytes());
+        out.extend_from_slice(&[0; 4]);
+        for entry in &self.entries {
+            let soname = *offsets.entry(&entry.soname).or_insert_with(|| {
+                let offset = strings.len() as u32;
+                strings.extend_from_slice(entry.soname.as_bytes());
+                strings.push(0);
+                offset
+            });
+            for field in [entry.ino, entry.ctime, entry.size, entry.dev] {
+                out.extend_from_slice(&field.to_ne_bytes());
+            }
+            out.extend_from_slice(&entry.flags.to_ne_bytes());
+            out.extend_from_slice(&soname.to_ne_bytes());
+            out.extend_from_slice(&entry.isa_level.to_ne_bytes());
+            out.extend_from_slice(&[0; 4]);
+        }
+        let len_strings = (strings.len() as u32).to_ne_bytes();
+        out[AUX_CACHE_MAGIC.len() + 4..AUX_HEADER_SIZE].copy_from_slice(&len_strings);
+        out.extend_from_slice(&strings);
+        out
+    }
+
+    /// Write the auxiliary cache to `path` atomically.
+    pub fn write_to_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        atomic_write::atomic_write(path, &self.to_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl AuxCache {
+    /// Drop the entries no file in `dirs` (and their glibc-hwcaps
+    /// subdirectories), inside `prefix`, still matches by device, inode,
+    /// change time and size: removed or replaced files that glibc would
+    /// never look up again. Returns how many were dropped.
+    pub fn prune(&mut self, dirs: &[Utf8PathBuf], prefix: impl AsRef<Utf8Path>) -> usize {
+        let prefix = prefix.as_ref();
+        let mut live = HashSet::new();
+        let mut pending = dirs.to_vec();
+        while let Some(dir) = pending.pop() {
+            let Some(rd) = chroot_canon(prefix, &dir).and_then(|real| fs::read_dir(real).ok())
+            else {
+                continue;
+            };
+            for name in rd
+                .flatten()
+                .filter_map(|e| e.file_name().into_string().ok())
+            {
+                if name == "glibc-hwcaps" && dir.file_name() != Some("glibc-hwcaps") {
+                    let hwcaps = dir.join(&name);
+                    let real = chroot_canon(prefix, &hwcaps).and_then(|r| fs::read_dir(r).ok());
+                    let subdirs = real.into_iter().flatten().flatten();
+                    pending
+                        .extend(subdirs.filter_map(|e| Some(hwcaps.join(e.file_name().to_str()?))));
+                    continue;
+                }
+                // Like glibc, a link is known by the file it leads to,
+                // resolved inside the root.
+                let md = chroot_canon(prefix, &dir.join(&name))
+                    .and_then(|real| fs::metadata(real).ok())
+                    .filter(|md| md.is_file());
+                if let Some(md) = md {
+                    live.insert((md.dev(), md.ino(), md.ctime() as u64, md.size()));
+                }
+            }
+        }
+        self.retain(|e| live.contains(&(e.dev, e.ino, e.ctime, e.size)))
+    }
+}
+
+impl fmt::Display for AuxCache {
+    /// A heading with the entry count, then one line per entry: soname,
+    /// flags and the file identity.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} entries in auxiliary cache", self.entries.len())?;
+        for e in &self.entries {
+            write!(f, "\t{} ({}", e.soname, flags_string(e.flags))?;
+            if e.isa_level != 0 {
+                write!(f, ", isa-level {}", e.isa_level)?;
+            }
+            writeln!(
+                f,
+                ") dev {:#x} ino {} size {} ctime {}",
+                e.dev, e.ino, e.size, e.ctime
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::cache_format::{FLAG_ELF_LIBC6, FLAG_X8664_LIB64};
+
+    #[test]
+    fn round_trips_and_prunes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let lib = root.join("lib");
+        fs::create_dir(&lib).unwrap();
+        fs::write(lib.join("libz.so.1.3"), b"library").unwrap();
+        let md = fs::metadata(lib.join("libz.so.1.3")).unwrap();
+        let entry = |soname: &str, ino: u64| AuxEntry {
+            dev: md.dev(),
+            ino,
+            ctime: md.ctime() as u64,
+            size: md.size(),
+            flags: FLAG_ELF_LIBC6 | FLAG_X8664_LIB64,
+            soname: soname.into(),
+            isa_level: 0,
+        };
+        let mut aux = AuxCache {
+            entries: vec![
+                entry("libz.so.1", md.ino()),
+                entry("libgone.so.2", md.ino() + 1),
+            ],
+        };
+
+        let bytes = aux.to_bytes();
+        assert_eq!(bytes.len(), AUX_HEADER_SIZE + 2 * AUX_ENTRY_SIZE + 23);
+        assert_eq!(AuxCache::from_bytes(&bytes).unwrap(), aux);
+        assert!(AuxCache::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        // The bad offset is reported whole, past the string table.
+        let mut bad = bytes.clone();
+        bad[AUX_HEADER_SIZE + 36..][..4].copy_from_slice(&u32::MAX.to_ne_bytes());
+        assert!(matches!(
+            AuxCache::from_bytes(&bad),
+            Err(Error::InvalidCacheOffset(offset))
+                if offset == AUX_HEADER_SIZE + 2 * AUX_ENTRY_SIZE + u32::MAX as usize
+        ));
+        assert!(aux
+            .to_string()
+            .contains("\tlibgone.so.2 (libc6,x86-64) dev "));
+
+        assert_eq!(aux.prune(&["/lib".into()], &root), 1);
+        assert_eq!(aux.entries()[0].soname, "libz.so.1");
+    }
+}
@@ -0,0 +1,362 @@
+//! Keeping the cache a write replaces, to undo a bad rebuild.
+//!
+//! ```no_run
+//! use ldconfig::{rollback, Backup, Cache, SearchPaths};
+//!
+//! # #[cfg(unix)] {
+//! let paths = SearchPaths::from_file("/etc/ld.so.conf", None)?;
+//! let cache = Cache::builder().build(&paths)?;
+//! if let Some(saved) = cache.write_to_file_with_backup("/etc/ld.so.cache", Backup::Bak)? {
+//!     println!("previous cache kept as {}", saved.display());
+//! }
+//!
+//! // Later, to undo it:
+//! let restored = rollback("/etc/ld.so.cache")?;
+//! ldconfig::undo_links(&restored, "/")?;
+//! # }
+//! # Ok::<(), ldconfig::Error>(())
+//! ```
+
+use crate::atomic_write::atomic_write;
+use crate::{error::Error, Cache, LinkAction};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+/// How the backup of a replaced cache is named.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backup {
+    /// `ld.so.cache.bak`, overwritten by every backup.
+    #[default]
+    Bak,
+    /// `ld.so.cache.bak.SECONDS`, one per write, in seconds since the
+    /// epoch so they sort by age.
+    Timestamped,
+}
+
+impl FromStr for Backup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bak" => Ok(Backup::Bak),
+            "timestamp" => Ok(Backup::Timestamped),
+            _ => Err(format!("expected bak or timestamp, got `{}'", s)),
+        }
+    }
+}
+
+impl Backup {
+    /// Where the backup of the cache at `path` goes.
+    pub fn path_for(self, path: impl AsRef<Path>) -> PathBuf {
+        let mut name = OsString::from(path.as_ref().as_os_str());
+        name.push(".bak");
+        if self == Backup::Timestamped {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            name.push(format!(".{}", now));
+        }
+        PathBuf::from(name)
+    }
+}
+
+/// Where the symlink changes of the build a backup was taken before are
+/// recorded: next to it, with `.links` appended.
+fn journal_path(backup: &Path) -> PathBuf {
+    let mut name = OsString::from(backup.as_os_str());
+    name.push(".links");
+    PathBuf::from(name)
+}
+
+/// One tab-separated line per action, with its kind first.
+fn journal_line(action: &LinkAction) -> String {
+    match action {
+        LinkAction::Create { dir, link, target } => {
+            format!("create\t{}\t{}\t{}\n", dir, link, target)
+        }
+        LinkAction::Replace {
+            dir,
+            link,
+            target,
+            previous,
+        } => format!("replace\t{}\t{}\t{}\t{}\n", dir, link, target, previous),
+        LinkAction::RemoveStale { dir, link, target } => {
+            format!("remove\t{}\t{}\t{}\n", dir, link, target)
+        }
+    }
+}
+
+#[cfg(unix)]
+fn parse_journal_line(line: &str) -> Option<LinkAction> {
+    let fields: Vec<String> = line.split('\t').map(str::to_owned).collect();
+    match <[String; 4]>::try_from(fields) {
+        Ok([kind, dir, link, target]) => match kind.as_str() {
+            "create" => Some(LinkAction::Create { dir, link, target }),
+            "remove" => Some(LinkAction::RemoveStale { dir, link, target }),
+            _ => None,
+        },
+        Err(fields) => match <[String; 5]>::try_from(fields) {
+            Ok([kind, dir, link, target, previous]) if kind == "replace" => {
+                Some(LinkAction::Replace {
+                    dir,
+                    link,
+                    target,
+                    previous,
+                })
+            }
+            _ => None,
+        },
+    }
+}
+
+/// The most recently written backup of the cache at `path`, of either
+/// naming.
+fn latest_backup(path: &Path) -> io::Result<Option<PathBuf>> {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return Ok(None);
+    };
+    let bak = format!("{}.bak", name);
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut latest = None;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        let stamp = match file_name.strip_prefix(bak.as_str()) {
+            Some("") => 0,
+            Some(rest) => match rest.strip_prefix('.').map(str::parse::<u64>) {
+                Some(Ok(stamp)) => stamp,
+                _ => continue,
+            },
+            None => continue,
+        };
+        let modified = entry.metadata()?.modified()?;
+        if latest
+            .as_ref()
+            .is_none_or(|(m, s, _)| (modified, stamp) > (*m, *s))
+        {
+            latest = Some((modified, stamp, entry.path()));
+        }
+    }
+    Ok(latest.map(|(_, _, path)| path))
+}
+
+/// Put the most recent backup of the cache at `path`, from
+/// [`Cache::write_to_file_with_backup`], back in place atomically.
+/// Returns the backup restored, which is kept, for [`undo_links`].
+pub fn rollback(path: impl AsRef<Path>) -> Result<PathBuf, Error> {
+    let path = path.as_ref();
+    let backup = latest_backup(path)?.ok_or_else(|| Error::NoBackup(path.to_path_buf()))?;
+    let data = fs::read(&backup)?;
+    Cache::from_bytes(&data)?;
+    atomic_write(path, &data)?;
+    debug!("Restored {} from {}", path.display(), backup.display());
+    Ok(backup)
+}
+
+/// Undo the symlink changes made by the build `backup` was taken
+/// before, newest first, inside `prefix`. Links changed since are left
+/// alone. Returns the changes undone; the record of them is removed, so
+/// they are undone only once.
+#[cfg(unix)]
+pub fn undo_links(
+    backup: impl AsRef<Path>,
+    prefix: impl AsRef<camino::Utf8Path>,
+) -> Result<Vec<LinkAction>, Error> {
+    use crate::symlinks::read_target;
+    use std::os::unix::fs::symlink;
+    use tracing::warn;
+
+    let journal = journal_path(backup.as_ref());
+    let text = match fs::read_to_string(&journal) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut undone = Vec::new();
+    for line in text.lines().rev() {
+        let Some(action) = parse_journal_line(line) else {
+            warn!("{}: unreadable line `{}'", journal.display(), line);
+            continue;
+        };
+        let (LinkAction::Create { dir, link, .. }
+        | LinkAction::Replace { dir, link, .. }
+        | LinkAction::RemoveStale { dir, link, .. }) = &action;
+        let Some(real_dir) = crate::chroot_canon(prefix.as_ref(), camino::Utf8Path::new(dir))
+        else {
+            warn!("{}/{}: directory gone, not restored", dir, link);
+            continue;
+        };
+        let real = real_dir.join(link);
+        let current = fs::symlink_metadata(&real).ok().map(|_| read_target(&real));
+        match &action {
+            LinkAction::Create { target, .. } if current.as_ref() == Some(target) => {
+                fs::remove_file(&real)?;
+            }
+            LinkAction::Replace {
+                target, previous, ..
+            } if current.as_ref() == Some(target) => {
+                fs::remove_file(&real)?;
+                symlink(previous, &real)?;
+            }
+            LinkAction::RemoveStale { target, .. } if current.is_none() => {
+                symlink(target, &real)?;
+            }
+            _ => {
+                warn!("{}/{} changed since, not restored", dir, link);
+                continue;
+            }
+        }
+        debug!("{}/{}: undone", dir, link);
+        undone.push(action);
+    }
+    fs::remove_file(&journal)?;
+    Ok(undone)
+}
+
+impl Cache {
+    /// [`Cache::write_to_file`], first saving the cache it replaces as
+    /// `backup` says, along with the symlink changes this build made, for
+    /// [`rollback`] and [`undo_links`]. Returns where the previous cache
+    /// was saved, None when there was none; if it cannot be saved nothing
+    /// is written.
+    pub fn write_to_file_with_backup(
+        &self,
+        path: impl AsRef<Path>,
+        backup: Backup,
+    ) -> Result<Option<PathBuf>, Error> {
+        let path = path.as_ref();
+        let saved = self.save_backup(path, backup)?;
+        self.write_to_file(path)?;
+        Ok(saved)
+    }
+
+    /// Save the cache at `path` and the symlink changes of this build
+    /// as `backup` says, before replacing it.
+    pub(crate) fn save_backup(
+        &self,
+        path: &Path,
+        backup: Backup,
+    ) -> Result<Option<PathBuf>, Error> {
+        let saved = match fs::read(path) {
+            Ok(previous) => {
+                let saved = backup.path_for(path);
+                atomic_write(&saved, &previous)?;
+                let journal = journal_path(&saved);
+                let links = &self.report().links;
+                if !links.is_empty() {
+                    let text: String = links.iter().map(journal_line).collect();
+                    atomic_write(&journal, text.as_bytes())?;
+                } else if let Err(e) = fs::remove_file(&journal) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e.into());
+                    }
+                }
+                debug!("Saved {} as {}", path.display(), saved.display());
+                Some(saved)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(saved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Arch, SyntheticEntry};
+
+    fn cache(soname: &str) -> Cache {
+        Cache::from_file_entries(&[], None, &[])
+            .unwrap()
+            .add_entries(&[SyntheticEntry::new(
+                soname,
+                format!("/usr/lib/{}", soname),
+                Arch::X86_64,
+            )])
+            .unwrap()
+    }
+
+    #[test]
+    fn keeps_the_replaced_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("ld.so.cache");
+        let (old, new) = (cache("libold.so.1"), cache("libnew.so.1"));
+
+        assert_eq!(
+            old.write_to_file_with_backup(&path, Backup::Bak).unwrap(),
+            None
+        );
+        let saved = new
+            .write_to_file_with_backup(&path, Backup::Bak)
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved, tmp.path().join("ld.so.cache.bak"));
+        assert_eq!(fs::read(&saved).unwrap(), old.as_bytes());
+        assert_eq!(fs::read(&path).unwrap(), new.as_bytes());
+
+        let stamped = old
+            .write_to_file_with_backup(&path, Backup::Timestamped)
+            .unwrap()
+            .unwrap();
+        let name = stamped.file_name().unwrap().to_str().unwrap();
+        let secs = name.strip_prefix("ld.so.cache.bak.").unwrap();
+        assert!(secs.parse::<u64>().is_ok());
+        assert_eq!(fs::read(&stamped).unwrap(), new.as_bytes());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rolls_back_cache_and_links() {
+        use crate::SearchPaths;
+        use camino::Utf8PathBuf;
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let lib = root.join("lib");
+        fs::create_dir(&lib).unwrap();
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        fs::write(lib.join("libz.so.1.3"), dso).unwrap();
+        symlink("libgone.so.1.0", lib.join("libgone.so.1")).unwrap();
+        let path = root.join("ld.so.cache");
+        let old = cache("libold.so.1");
+        old.write_to_file(&path).unwrap();
+
+        let built = Cache::builder()
+            .build(&SearchPaths::new(vec![lib.clone()]))
+            .unwrap();
+        assert_eq!(built.report().links.len(), 2);
+        built
+            .write_to_file_with_backup(&path, Backup::Timestamped)
+            .unwrap();
+        assert!(lib.join("libz.so.1").is_symlink());
+        assert!(!lib.join("libgone.so.1").is_symlink());
+
+        let restored = rollback(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), old.as_bytes());
+        assert_eq!(undo_links(&restored, "/").unwrap().len(), 2);
+        assert!(!lib.join("libz.so.1").is_symlink());
+        assert_eq!(
+            fs::read_link(lib.join("libgone.so.1")).unwrap(),
+            Path::new("libgone.so.1.0")
+        );
+        // Undone once only.
+        assert!(undo_links(&restored, "/").unwrap().is_empty());
+        assert!(matches!(
+            rollback(root.join("other.cache")),
+            Err(Error::NoBackup(_))
+        ));
+    }
+}
@@ -1,9 +1,53 @@
-use bpaf::Bpaf;
+use bpaf::{Bpaf, ShellComp};
 use camino::{Utf8Path, Utf8PathBuf};
-use ldconfig::{chroot_canon, Cache, Error, SearchPaths};
+use ldconfig::{
+    chroot_canon, enter_root, write_audit, AuxCache, Backup, Cache, CacheDiff, CacheEntry,
+    CacheFormat, Change, Error, RootMode, Target, AUX_CACHE_PATH,
+};
+#[cfg(unix)]
+use ldconfig::{BuildPlan, LinkAction, RunOptions, RunSummary, SearchPaths};
+use std::fs::{File, OpenOptions};
+use std::io::IsTerminal;
+#[cfg(unix)]
+use std::io::Write;
+use std::str::FromStr;
 use tracing::{debug, Level};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+/// When to color the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorWhen {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorWhen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorWhen::Auto),
+            "always" => Ok(ColorWhen::Always),
+            "never" => Ok(ColorWhen::Never),
+            _ => Err(format!("expected auto, always or never, got `{}'", s)),
+        }
+    }
+}
+
+impl ColorWhen {
+    /// `auto` colors only a terminal `stream`, and honours NO_COLOR.
+    fn enabled(self, stream: impl IsTerminal) -> bool {
+        match self {
+            ColorWhen::Always => true,
+            ColorWhen::Never => false,
+            ColorWhen::Auto => stream.is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Bpaf)]
 #[bpaf(options)]
 struct Options {
@@ -11,6 +55,19 @@ struct Options {
     /// Verbose output
     verbose: bool,
 
+    #[bpaf(short, long)]
+    /// Only report errors, on stderr; exit with status 2 instead of 0 if
+    /// the build warned about anything
+    quiet: bool,
+
+    #[bpaf(long, argument("WHEN"), fallback(ColorWhen::Auto))]
+    /// Color diagnostics and diffs: auto, always or never
+    color: ColorWhen,
+
+    #[bpaf(long("no-color"))]
+    /// Same as --color never
+    no_color: bool,
+
     #[bpaf(short('N'))]
     /// Don't rebuild the cache
     no_cache: bool,
@@ -27,127 +84,629 @@ struct Options {
     /// Print cache contents
     print_cache: bool,
 
-    #[bpaf(short('r'), long("root"), argument("ROOT"), fallback("/".into()))]
+    #[bpaf(long)]
+    /// With -p, list libraries alphabetically, grouped by architecture,
+    /// instead of in cache order
+    alphabetical: bool,
+
+    #[bpaf(long)]
+    /// Print cache statistics
+    stats: bool,
+
+    #[bpaf(long("aux-cache"))]
+    /// Print the auxiliary cache, /var/cache/ldconfig/aux-cache
+    aux_cache: bool,
+
+    #[bpaf(long("prune-aux-cache"))]
+    /// Remove auxiliary cache entries for files no longer in the
+    /// configured or given directories
+    prune_aux_cache: bool,
+
+    #[bpaf(
+        long,
+        argument("OLD"),
+        complete_shell(ShellComp::File { mask: None }),
+        optional
+    )]
+    /// Print how the cache differs from the cache file OLD, e.g. a backup;
+    /// exits with 1 if it does
+    diff: Option<Utf8PathBuf>,
+
+    #[bpaf(long)]
+    /// Print what this build supports, as key=value lines
+    capabilities: bool,
+
+    #[bpaf(
+        short('r'),
+        long("root"),
+        argument("ROOT"),
+        complete_shell(ShellComp::Dir { mask: None }),
+        fallback("/".into())
+    )]
     /// Change to and use ROOT as root directory
     root: Utf8PathBuf,
 
-    #[bpaf(short('C'), long, argument("CACHE"))]
-    /// Use CACHE as cache file
+    #[bpaf(
+        long("batch-root"),
+        argument("ROOT"),
+        complete_shell(ShellComp::Dir { mask: None }),
+        many
+    )]
+    /// Build the cache of each ROOT in turn, resolving paths inside it
+    /// without chrooting, and print one summary line per root; may be
+    /// repeated
+    batch_roots: Vec<Utf8PathBuf>,
+
+    #[bpaf(long("root-mode"), argument("MODE"), fallback(RootMode::Auto))]
+    /// How -r enters ROOT: chroot like glibc 2.35+, emulate by resolving
+    /// paths inside it without privileges, or auto (chroot when permitted)
+    root_mode: RootMode,
+
+    #[bpaf(
+        short('C'),
+        long,
+        argument("CACHE"),
+        complete_shell(ShellComp::File { mask: None }),
+        optional
+    )]
+    /// Use CACHE as cache file; `-' is standard output, or input with -p
     cache: Option<Utf8PathBuf>,
 
-    #[bpaf(short('f'), long("config"), argument("CONF"))]
+    #[bpaf(long, argument("STYLE"), optional)]
+    /// Keep the cache being replaced as CACHE.bak (bak), or as
+    /// CACHE.bak.SECONDS (timestamp)
+    backup: Option<Backup>,
+
+    #[bpaf(long)]
+    /// Restore the newest backup of the cache and, unless -X, undo the
+    /// symlink changes of the build that replaced it
+    rollback: bool,
+
+    #[bpaf(long)]
+    /// Show the symlink changes and how the cache would differ, then ask
+    /// before making them
+    confirm: bool,
+
+    #[bpaf(
+        long("audit-log"),
+        argument("FILE"),
+        complete_shell(ShellComp::File { mask: None }),
+        optional
+    )]
+    /// Append every warning and filesystem change to FILE, outside any -r
+    /// ROOT, as JSON lines
+    audit_log: Option<Utf8PathBuf>,
+
+    #[bpaf(
+        short('f'),
+        long("config"),
+        argument("CONF"),
+        complete_shell(ShellComp::File { mask: None }),
+        optional
+    )]
     /// Use CONF as configuration file
     config_file: Option<Utf8PathBuf>,
 
-    #[bpaf(positional("DIRS"))]
+    #[bpaf(
+        short('c'),
+        long("format"),
+        argument("FORMAT"),
+        fallback(CacheFormat::New)
+    )]
+    /// Format to use: new (default), old, or compat
+    format: CacheFormat,
+
+    #[bpaf(long("target-glibc"), argument("VERSION"), fallback("auto".into()))]
+    /// Only write what the ld.so of glibc VERSION (e.g. 2.28) reads; by
+    /// default (auto) the glibc installed in ROOT
+    target_glibc: String,
+
+    #[bpaf(long)]
+    /// Fail, without writing the cache, if anything was warned about
+    strict: bool,
+
+    #[bpaf(long("require-dirs"))]
+    /// Fail if a configured or given directory is missing or unreadable,
+    /// instead of skipping it
+    require_dirs: bool,
+
+    #[bpaf(long("max-file-size"), argument("BYTES"))]
+    /// Skip, with a warning, files larger than BYTES named like libraries
+    max_file_size: Option<u64>,
+
+    #[bpaf(
+        positional("DIRS"),
+        complete_shell(ShellComp::Dir { mask: None }),
+        many
+    )]
     /// Additional directories to process
     dirs: Vec<Utf8PathBuf>,
 }
 
-fn init_logging(verbose: bool) {
-    let filter_level = if verbose { Level::DEBUG } else { Level::INFO };
+fn init_logging(verbose: bool, quiet: bool, color: bool) {
+    let filter_level = if quiet {
+        Level::ERROR
+    } else if verbose {
+        Level::DEBUG
+    } else {
+        Level::INFO
+    };
     let env_filter = EnvFilter::builder()
         .with_default_directive(filter_level.into())
         .from_env_lossy();
+    // Phase timings (build, scan, serialize, write...) are only reported
+    // when asked for through RUST_LOG, keeping -v output glibc-like.
+    let span_events = if std::env::var_os("RUST_LOG").is_some() {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+    // Packaging hooks running -q may treat any stdout as a failure.
+    let writer = if quiet {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
     let fmt_layer = fmt::layer()
+        .with_writer(writer)
+        .with_span_events(span_events)
         .with_level(verbose)
         .with_target(verbose)
+        .with_ansi(color)
         .without_time()
         .compact();
-    tracing_subscriber::registry()
+    let registry = tracing_subscriber::registry()
         .with(env_filter)
-        .with(fmt_layer)
-        .init();
+        .with(fmt_layer);
+    // Absent journald (containers, chroots) only stderr is used.
+    #[cfg(feature = "journald")]
+    let registry = registry.with(tracing_journald::layer().ok());
+    registry.init();
 }
 
-/// Resolve the cache file's directory inside the -r root and append the
-/// file name, like glibc's main(); symlinks cannot escape the root and
-/// the temp-file rename stays confined.
-fn cache_file_under_root(root: &Utf8Path, path: &Utf8Path) -> Option<Utf8PathBuf> {
-    let parent = path.parent().unwrap_or(Utf8Path::new("/"));
-    let dir = chroot_canon(root, parent)?;
-    Some(dir.join(path.file_name()?))
+/// The cache at `path`, or read from stdin for `-`.
+fn read_cache(path: &Utf8Path) -> Result<Cache, Error> {
+    if path == "-" {
+        return Ok(Cache::from_reader(std::io::stdin().lock())?);
+    }
+    Ok(Cache::from_file(path)?)
 }
 
-fn print_cache(cache_path: &Utf8Path) -> Result<(), Error> {
-    let cache = Cache::from_file(cache_path)?;
-    let info = cache.info();
-    println!("{} libs found in cache `{}'", info.num_entries, cache_path);
-    for entry in cache.entries() {
-        println!("{}", entry);
+fn print_cache(cache_path: &Utf8Path, alphabetical: bool) -> Result<(), Error> {
+    let cache = read_cache(cache_path)?;
+    debug!("Cache format: {}", cache.format());
+    if alphabetical {
+        print!("{}", cache.alphabetical());
+    } else {
+        print!("{}", cache.print_output(cache_path));
     }
-    if let Some(generator) = info.generator {
-        println!("Cache generated by: {}", generator);
+    Ok(())
+}
+
+/// `+`, `-` and `~` lines for the entries added, removed and changed
+/// since `old`, aligned on the soname, then a count of each.
+fn print_diff(diff: &CacheDiff, color: bool) {
+    let (green, red, yellow, bold, reset) = if color {
+        ("\x1b[32m", "\x1b[31m", "\x1b[33m", "\x1b[1m", "\x1b[0m")
+    } else {
+        ("", "", "", "", "")
+    };
+    let width = diff
+        .added
+        .iter()
+        .chain(&diff.removed)
+        .chain(diff.changed.iter().map(|(_, new)| new))
+        .map(|e| e.soname.len())
+        .max()
+        .unwrap_or(0);
+    let line = |sign: &str, paint: &str, entry: &CacheEntry, what: &str| {
+        println!(
+            "{paint}{sign} {:width$} {what} => {}{reset}",
+            entry.soname, entry.path
+        );
+    };
+    for entry in &diff.removed {
+        line("-", red, entry, &format!("({})", entry.arch));
+    }
+    for entry in &diff.added {
+        line("+", green, entry, &format!("({})", entry.arch));
+    }
+    for (old, new) in &diff.changed {
+        let mut what = Vec::new();
+        if old.flags != new.flags {
+            what.push(format!("{} -> {}", old.arch, new.arch));
+        }
+        if old.hwcap != new.hwcap {
+            what.push(format!("hwcap {:#x} -> {:#x}", old.hwcap, new.hwcap));
+        }
+        if old.osversion != new.osversion {
+            let os = |v: Option<ldconfig::OsVersion>| v.map_or("none".into(), |v| v.to_string());
+            what.push(format!(
+                "OS ABI {} -> {}",
+                os(old.osversion),
+                os(new.osversion)
+            ));
+        }
+        line("~", yellow, new, &format!("({})", what.join(", ")));
     }
+    println!(
+        "{bold}{} added, {} removed, {} changed{reset}",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len()
+    );
+}
+
+fn print_stats(cache_path: &Utf8Path) -> Result<(), Error> {
+    let cache = read_cache(cache_path)?;
+    print!("{}", cache.stats());
     Ok(())
 }
 
-fn main() {
-    if let Err(e) = run() {
-        eprintln!("ldconfig: {}", e);
+fn roll_back(
+    root: &Utf8Path,
+    cache_path: &Utf8Path,
+    links: bool,
+    audit: Option<&File>,
+) -> Result<i32, Error> {
+    let Some(real) = chroot_canon(root, cache_path) else {
+        eprintln!("ldconfig: Can't open cache file {}", cache_path);
         std::process::exit(1);
+    };
+    let backup = ldconfig::rollback(&real)?;
+    debug!("Restored {} from {}", cache_path, backup.display());
+    #[cfg(unix)]
+    let undone = if links {
+        ldconfig::undo_links(&backup, root)?
+    } else {
+        Vec::new()
+    };
+    #[cfg(not(unix))]
+    let undone: Vec<ldconfig::LinkAction> = {
+        let _ = links;
+        Vec::new()
+    };
+    debug!("Undid {} symlink changes", undone.len());
+    if let Some(audit) = audit {
+        let mut changes = vec![Change::CacheRestored {
+            path: real.as_std_path(),
+            backup: &backup,
+        }];
+        changes.extend(undone.iter().map(Change::Undone));
+        write_audit(audit, &changes)?;
+    }
+    Ok(0)
+}
+
+/// Print or prune the auxiliary cache inside the root.
+fn aux_cache(options: &Options, root: &Utf8Path) -> Result<i32, Error> {
+    let Some(real) = chroot_canon(root, Utf8Path::new(AUX_CACHE_PATH)) else {
+        eprintln!("ldconfig: Can't open auxiliary cache {}", AUX_CACHE_PATH);
+        std::process::exit(1);
+    };
+    let mut aux = AuxCache::from_file(&real)?;
+    if options.prune_aux_cache {
+        let pruned = prune_aux_cache(options, root, &mut aux)?;
+        aux.write_to_file(&real)?;
+        debug!("Removed {} stale entries from {}", pruned, AUX_CACHE_PATH);
+    }
+    if options.aux_cache {
+        print!("{}", aux);
+    }
+    Ok(0)
+}
+
+/// Needs a Unix host, like a build.
+#[cfg(not(unix))]
+fn prune_aux_cache(
+    _options: &Options,
+    _root: &Utf8Path,
+    _aux: &mut AuxCache,
+) -> Result<usize, Error> {
+    eprintln!("ldconfig: pruning the auxiliary cache is only supported on Unix hosts");
+    std::process::exit(1);
+}
+
+/// Drop the entries no file in the directories a build would scan
+/// matches.
+#[cfg(unix)]
+fn prune_aux_cache(options: &Options, root: &Utf8Path, aux: &mut AuxCache) -> Result<usize, Error> {
+    let mut search_paths = SearchPaths::new(options.dirs.clone());
+    if !options.only_cline {
+        let config_path = options
+            .config_file
+            .clone()
+            .unwrap_or_else(|| Target::Glibc.config_path(None));
+        let prefix = (root != "/").then_some(root);
+        search_paths.append(SearchPaths::from_file(config_path, prefix)?);
     }
+    Ok(aux.prune(&search_paths, root))
 }
 
-fn run() -> Result<(), Error> {
-    let options = options().run();
-    init_logging(options.verbose);
+/// Exit status of a -q build that succeeded but warned, the warnings
+/// themselves being hidden.
+#[cfg(unix)]
+const EXIT_WARNINGS: i32 = 2;
+
+fn main() {
+    match run() {
+        Ok(status) => std::process::exit(status),
+        Err(e) => {
+            eprintln!("ldconfig: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run() -> Result<i32, Error> {
+    let mut options = options().run();
+    if options.no_color {
+        options.color = ColorWhen::Never;
+    }
+    init_logging(
+        options.verbose,
+        options.quiet,
+        options.color.enabled(std::io::stderr()),
+    );
+
+    if options.capabilities {
+        print!("{}", ldconfig::capabilities());
+        return Ok(0);
+    }
+
+    if !options.batch_roots.is_empty() {
+        return batch(options);
+    }
 
     let root = {
         let trimmed = options.root.as_str().trim_end_matches('/');
         Utf8PathBuf::from(if trimmed.is_empty() { "/" } else { trimmed })
     };
+    // Opened before entering the root, so it stays where it was named.
+    let audit = options.audit_log.as_ref().map(|path| {
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("ldconfig: Can't open audit log {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    });
+    let root = enter_root(&root, options.root_mode)?;
     let cache_path = options
         .cache
-        .unwrap_or_else(|| Utf8PathBuf::from("/etc/ld.so.cache"));
+        .clone()
+        .or_else(|| Target::Glibc.cache_path(None))
+        .unwrap();
 
-    if options.print_cache {
-        let Some(real) = chroot_canon(&root, &cache_path) else {
+    if options.rollback {
+        return roll_back(&root, &cache_path, !options.no_links, audit.as_ref());
+    }
+
+    if options.aux_cache || options.prune_aux_cache {
+        return aux_cache(&options, &root);
+    }
+
+    if let Some(old_path) = &options.diff {
+        let resolve = |path: &Utf8Path| {
+            let real = if path == "-" {
+                Some(path.to_owned())
+            } else {
+                chroot_canon(&root, path)
+            };
+            real.unwrap_or_else(|| {
+                eprintln!("ldconfig: Can't open cache file {}", path);
+                std::process::exit(1);
+            })
+        };
+        let old = read_cache(&resolve(old_path))?;
+        let new = read_cache(&resolve(&cache_path))?;
+        let diff = new.diff(&old);
+        print_diff(&diff, options.color.enabled(std::io::stdout()));
+        return Ok(if diff.is_empty() { 0 } else { 1 });
+    }
+
+    if options.print_cache || options.stats {
+        let real = if cache_path == "-" {
+            Some(cache_path.clone())
+        } else {
+            chroot_canon(&root, &cache_path)
+        };
+        let Some(real) = real else {
             eprintln!("ldconfig: Can't open cache file {}", cache_path);
             std::process::exit(1);
         };
-        return print_cache(&real);
+        if options.stats {
+            print_stats(&real)?;
+        } else {
+            print_cache(&real, options.alphabetical)?;
+        }
+        return Ok(0);
+    }
+
+    build(options, &root, &cache_path, audit.as_ref())
+}
+
+/// Scan, update links and write the cache; needs a Unix host.
+#[cfg(not(unix))]
+fn build(
+    _options: Options,
+    _root: &Utf8Path,
+    _cache_path: &Utf8Path,
+    _audit: Option<&File>,
+) -> Result<i32, Error> {
+    eprintln!("ldconfig: building a cache is only supported on Unix hosts; use -p");
+    std::process::exit(1);
+}
+
+/// Build every --batch-root; needs a Unix host.
+#[cfg(not(unix))]
+fn batch(_options: Options) -> Result<i32, Error> {
+    eprintln!("ldconfig: building a cache is only supported on Unix hosts; use -p");
+    std::process::exit(1);
+}
+
+/// Build every --batch-root, printing a summary line for each; fails if
+/// any root did.
+#[cfg(unix)]
+fn batch(options: Options) -> Result<i32, Error> {
+    if options.audit_log.is_some()
+        || options.confirm
+        || options.cache.as_deref() == Some(Utf8Path::new("-"))
+    {
+        eprintln!("ldconfig: --batch-root can't be used with --audit-log, --confirm or -C -");
+        std::process::exit(1);
+    }
+    let cache_path = options
+        .cache
+        .clone()
+        .or_else(|| Target::Glibc.cache_path(None))
+        .unwrap();
+    let run_options = run_options(&options, Utf8Path::new("/"), &cache_path);
+    let color = options.color.enabled(std::io::stdout());
+    let mut status = 0;
+    for run in ldconfig::run_batch(&run_options, &options.batch_roots) {
+        match &run.result {
+            Ok(summary) => println!("{}: {}", run.root, summary_line(&summary.cache, color)),
+            Err(e) => {
+                println!("{}: {}", run.root, e);
+                status = 1;
+            }
+        }
     }
+    Ok(status)
+}
+
+/// The library run the command line asks for, in `root`.
+#[cfg(unix)]
+fn run_options(options: &Options, root: &Utf8Path, cache_path: &Utf8Path) -> RunOptions {
+    RunOptions::builder()
+        .root(root)
+        .maybe_config_file(options.config_file.clone())
+        .cache_file(cache_path)
+        .dirs(options.dirs.clone())
+        .only_dirs(options.only_cline)
+        .no_cache(options.no_cache)
+        .no_links(options.no_links)
+        .format(options.format)
+        .target_glibc(options.target_glibc.clone())
+        .strict(options.strict)
+        .require_dirs(options.require_dirs)
+        .maybe_max_file_size(options.max_file_size)
+        .maybe_backup(options.backup)
+        .build()
+}
 
+/// Scan, update links and write the cache; needs a Unix host. Returns
+/// the exit status.
+#[cfg(unix)]
+fn build(
+    options: Options,
+    root: &Utf8Path,
+    cache_path: &Utf8Path,
+    audit: Option<&File>,
+) -> Result<i32, Error> {
     let build_cache = !(options.no_cache || options.only_cline);
-    if build_cache {
-        if let Some(dir) = options.dirs.iter().find(|d| !d.as_str().starts_with('/')) {
-            eprintln!("ldconfig: relative path `{}' used to build cache", dir);
+    let run_options = run_options(&options, root, cache_path);
+    let summary = if options.confirm {
+        if build_cache && cache_path == "-" {
+            eprintln!("ldconfig: --confirm can't be used with -C -");
             std::process::exit(1);
         }
+        // The plan and the prompt are output -q promises not to print.
+        if options.quiet {
+            eprintln!("ldconfig: --confirm can't be used with -q");
+            std::process::exit(1);
+        }
+        match confirm(&run_options, options.color.enabled(std::io::stdout()))? {
+            Some(summary) => summary,
+            None => return Ok(1),
+        }
+    } else {
+        ldconfig::run(&run_options)?
+    };
+    let cache = &summary.cache;
+
+    for origin in &cache.report().origins {
+        match &origin.config {
+            Some(line) => debug!(
+                "{}: {:?} in {} ({})",
+                origin.path, origin.kind, origin.dir, line
+            ),
+            None => debug!("{}: {:?} in {}", origin.path, origin.kind, origin.dir),
+        }
     }
 
-    let search_paths = if options.only_cline {
-        SearchPaths::new(options.dirs)
-    } else {
-        let config_path = options
-            .config_file
-            .unwrap_or_else(|| Utf8PathBuf::from("/etc/ld.so.conf"));
-        let prefix = (root != "/").then_some(root.as_path());
-        let mut dirs = options.dirs;
-        dirs.extend(
-            SearchPaths::from_file(&config_path, prefix)?
-                .iter()
-                .cloned(),
+    if build_cache && summary.cache_file.is_none() {
+        cache.write_to(std::io::stdout().lock())?;
+    }
+
+    if let Some(audit) = audit {
+        write_audit(audit, &summary.changes())?;
+    }
+
+    // Only for people watching: scripts and pipes see glibc's silence.
+    if !options.quiet && std::io::stderr().is_terminal() {
+        eprintln!(
+            "{}",
+            summary_line(cache, options.color.enabled(std::io::stderr()))
         );
-        SearchPaths::new(dirs)
-    };
+    }
 
-    debug!("Directories to scan: {:?}", &*search_paths);
+    if options.quiet && !cache.report().warnings.is_empty() {
+        return Ok(EXIT_WARNINGS);
+    }
+    Ok(0)
+}
 
-    let cache = Cache::builder()
-        .prefix(root.as_path())
-        .update_symlinks(!options.no_links)
-        .build(&search_paths)?;
+/// Print what the run would change and ask on the terminal whether to go
+/// ahead; None, with nothing changed, unless the answer is yes.
+#[cfg(unix)]
+fn confirm(run_options: &RunOptions, color: bool) -> Result<Option<RunSummary>, Error> {
+    let planned = ldconfig::plan_run(run_options)?;
+    print_plan(planned.plan(), color);
+    print!("Apply these changes? [y/N] ");
+    let _ = std::io::stdout().flush();
+    // Unreadable or closed input is a no.
+    let mut answer = String::new();
+    let _ = std::io::stdin().read_line(&mut answer);
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        eprintln!("ldconfig: nothing changed");
+        return Ok(None);
+    }
+    Ok(Some(planned.apply()?))
+}
 
-    if build_cache {
-        let Some(real) = cache_file_under_root(&root, &cache_path) else {
-            eprintln!("ldconfig: Can't open cache file directory {}", cache_path);
-            std::process::exit(1);
-        };
-        cache.write_to_file(&real)?;
-        debug!("Wrote {} bytes to {}", cache.size(), real);
+/// The symlink changes, one per line, then the cache diff.
+#[cfg(unix)]
+fn print_plan(plan: &BuildPlan, color: bool) {
+    for link in &plan.links {
+        println!("{}", link);
     }
+    print_diff(&plan.diff, color);
+}
 
-    Ok(())
+/// One line: entries, links created and warnings.
+#[cfg(unix)]
+fn summary_line(cache: &Cache, color: bool) -> String {
+    let report = cache.report();
+    let warnings = report.warnings.len();
+    // Dangling links removed are not links created.
+    let links = report
+        .links
+        .iter()
+        .filter(|l| !matches!(l, LinkAction::RemoveStale { .. }))
+        .count();
+    let (bold, yellow, reset) = if color {
+        ("\x1b[1m", "\x1b[33m", "\x1b[0m")
+    } else {
+        ("", "", "")
+    };
+    let warn_color = if warnings > 0 { yellow } else { "" };
+    format!(
+        "{bold}{} entries{reset}, {} links created, {warn_color}{} warnings{reset}",
+        cache.info().num_entries,
+        links,
+        warnings,
+    )
 }
@@ -0,0 +1,120 @@
+//! A non-executing ldd: prints the dependency tree of binaries inside a
+//! root using the cache and ld.so's search rules, without running them.
+
+use bpaf::Bpaf;
+use camino::Utf8PathBuf;
+use ldconfig::Error;
+#[cfg(unix)]
+use ldconfig::{chroot_canon, Cache, Dependency, Resolver, Target};
+use tracing::Level;
+
+#[derive(Debug, Clone, Bpaf)]
+#[bpaf(options)]
+struct Options {
+    #[bpaf(short, long)]
+    /// Verbose output
+    verbose: bool,
+
+    #[bpaf(short('r'), long("root"), argument("ROOT"), fallback("/".into()))]
+    /// Resolve inside ROOT
+    root: Utf8PathBuf,
+
+    #[bpaf(short('C'), long, argument("CACHE"))]
+    /// Use CACHE, inside ROOT, as cache file
+    cache: Option<Utf8PathBuf>,
+
+    #[bpaf(long)]
+    /// Print the trees as JSON
+    json: bool,
+
+    #[bpaf(long)]
+    /// Print the library graph as Graphviz DOT; without FILEs, of every
+    /// library in the cache
+    dot: bool,
+
+    #[bpaf(positional("FILE"), many)]
+    /// Executables or shared objects, as paths inside ROOT
+    files: Vec<Utf8PathBuf>,
+}
+
+fn main() {
+    let options = options().run();
+    tracing_subscriber::fmt()
+        .with_max_level(if options.verbose {
+            Level::DEBUG
+        } else {
+            Level::WARN
+        })
+        .without_time()
+        .with_writer(std::io::stderr)
+        .init();
+
+    match run(options) {
+        Ok(true) => {}
+        // Something was not found: listed already, but scripts want to know.
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("lddr: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn run(_options: Options) -> Result<bool, Error> {
+    eprintln!("lddr: resolving dependencies is only supported on Unix hosts");
+    std::process::exit(1);
+}
+
+/// Resolve and print every file; false if any dependency is missing.
+#[cfg(unix)]
+fn run(options: Options) -> Result<bool, Error> {
+    let cache_path = options.cache.or_else(|| Target::Glibc.cache_path(None));
+    // A root without a cache still resolves through the default paths.
+    let cache = cache_path
+        .and_then(|path| chroot_canon(&options.root, &path))
+        .and_then(|p| Cache::from_file(p).ok());
+    let resolver = Resolver::new(&options.root, cache.as_ref());
+
+    if options.dot {
+        print!("{}", resolver.dot(&options.files)?);
+        return Ok(true);
+    }
+    if options.files.is_empty() {
+        eprintln!("lddr: at least one file is required");
+        std::process::exit(1);
+    }
+
+    let mut complete = true;
+    let mut trees = Vec::new();
+    for file in &options.files {
+        let tree = resolver.resolve(file)?;
+        complete &= !tree.has_missing();
+        trees.push(tree);
+    }
+
+    if options.json {
+        let items: Vec<_> = trees.iter().map(Dependency::to_json).collect();
+        println!("[{}]", items.join(","));
+    } else {
+        for tree in &trees {
+            println!("{}:", tree.name);
+            for dep in &tree.needed {
+                print_tree(dep, 1);
+            }
+        }
+    }
+    Ok(complete)
+}
+
+#[cfg(unix)]
+fn print_tree(dep: &Dependency, depth: usize) {
+    let indent = "    ".repeat(depth - 1);
+    match &dep.path {
+        Some(path) => println!("\t{}{} => {}", indent, dep.name, path),
+        None => println!("\t{}{} => not found", indent, dep.name),
+    }
+    for child in &dep.needed {
+        print_tree(child, depth + 1);
+    }
+}
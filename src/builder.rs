@@ -0,0 +1,1312 @@
+//! Building a cache by scanning directories.
+//!
+//! Unix only: the scan relies on symlinks, device/inode identity and
+//! non-blocking opens, and maintains soname links in place.
+
+use crate::cache::{normalize_prefix, remap, SyntheticEntry};
+use crate::cache_format::{
+    self, Arch, CacheFormat, FileEntry, GlibcVersion, OsVersion, Provenance, RawExtension,
+};
+use crate::config::SYSTEM_DIRS;
+use crate::elf::ElfMemo;
+use crate::scanner::{
+    check_dir, collect_dirs, scan_dir, DsoNameFilter, ElfLibrary, LibraryFilter, PathPolicy,
+    ScanOptions, SymlinkPolicy, DEFAULT_DENIED_DIRS, DEFAULT_SKIP_SUFFIXES,
+};
+use crate::spill::{EntrySpill, Source};
+use crate::{
+    chroot_canon, error::Error, symlinks, BuildReport, Cache, EntryOrigin, OriginKind, SearchPaths,
+    Shadowed, WarningKind,
+};
+use bon::bon;
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use tracing::{debug, debug_span, info, info_span};
+
+#[bon]
+impl Cache {
+    /// The one way to build a cache: `Cache::builder()`, then any options,
+    /// then `.build(&search_paths)`. Every option has a glibc-like default.
+    #[builder]
+    pub fn new(
+        /// Directories to scan
+        #[builder(finish_fn)]
+        search_paths: &SearchPaths,
+        /// Update symlinks in directories
+        #[builder(default = true)]
+        update_symlinks: bool,
+        /// Point entries at the library file itself when no soname link
+        /// exists or is created for it, instead of at the missing link;
+        /// for builds with `update_symlinks(false)` on read-only trees
+        #[builder(default)]
+        unlinked_entries: bool,
+        /// Add an entry for every name of a library, its file name and the
+        /// symlinks to it as well as its soname, all pointing at the file
+        /// itself with symlinks resolved; glibc-hwcaps entries keep their
+        /// path
+        #[builder(default)]
+        alias_entries: bool,
+        #[builder(default)]
+        /// Dry run mode (don't make changes)
+        dry_run: bool,
+        /// Root prefix; every path, including absolute symlink targets, is
+        /// resolved inside it rather than on the host
+        #[builder(into, default = "/")]
+        prefix: &Utf8Path,
+        /// Drop libraries whose ABI tag requires a newer kernel than this
+        min_os_version: Option<OsVersion>,
+        /// File name suffixes of backup and temporary files to ignore
+        #[builder(default = DEFAULT_SKIP_SUFFIXES)]
+        skip_suffixes: &[&str],
+        /// Directories never scanned, even when configured, with a
+        /// warning; pass `&[]` to scan everything
+        #[builder(default = DEFAULT_DENIED_DIRS)]
+        denied_dirs: &[&str],
+        /// Fail when a configured or given directory is missing, not a
+        /// directory or unreadable, instead of skipping it; the built-in
+        /// system directories stay optional
+        #[builder(default)]
+        require_dirs: bool,
+        /// Warn about static archives (`libfoo.a`) and relocatable objects
+        /// named like libraries in the scanned directories: ld.so never
+        /// loads them, and they usually mean broken packaging
+        #[builder(default)]
+        misplaced_objects: bool,
+        /// ABIs expected in a directory, `("/usr/lib32", &[Arch::Libc6])`:
+        /// a library found there with another ABI is still cached, with a
+        /// warning, catching 64-bit libraries packaged into a 32-bit tree.
+        /// glibc-hwcaps subdirectories share their directory's
+        #[builder(default)]
+        dir_arches: &[(&str, &[Arch])],
+        /// Skip files larger than this many bytes, with a warning, instead
+        /// of mapping and inspecting them; a guard against huge corrupt
+        /// files named like libraries
+        max_file_size: Option<u64>,
+        /// Whether entries record directories as configured or with
+        /// symlinks resolved; either way a warning tells when the two lead
+        /// onto different mounts, one of them an overlay or bind mount.
+        /// glibc-hwcaps subdirectories are recorded below their directory,
+        /// never resolved
+        #[builder(default)]
+        path_policy: PathPolicy,
+        /// Store a SHA-256 of each library in a crate-specific extension
+        #[builder(default)]
+        content_hashes: bool,
+        /// Store each library's size and mtime in a crate-specific extension
+        #[builder(default)]
+        file_stats: bool,
+        /// Build metadata to embed, see [`Provenance::current`]
+        provenance: Option<Provenance>,
+        /// Fail with every collected warning instead of building a cache
+        /// when anything was warned about, duplicate sonames and dangling
+        /// symlinks included; symlinks are only changed once the build
+        /// succeeds
+        #[builder(default)]
+        strict: bool,
+        /// Sonames or glob patterns (`libvendor-stub*.so.*`) left out of the
+        /// cache; their symlinks are still maintained
+        #[builder(default)]
+        exclude_sonames: &[&str],
+        /// Entries to add on top of the scanned libraries
+        #[builder(default)]
+        synthetic_entries: &[SyntheticEntry],
+        /// Directory priorities overriding the configured order: higher
+        /// values are scanned first, and so win for duplicate sonames;
+        /// unlisted directories have priority 0
+        #[builder(default)]
+        dir_priorities: &[(&str, i32)],
+        /// Warn when the cache would hold more entries than this, or fail
+        /// under `max_entries_policy`
+        max_entries: Option<usize>,
+        /// Whether going over `max_entries` warns or fails the build
+        #[builder(default)]
+        max_entries_policy: BudgetPolicy,
+        /// Warn when the cache string table would exceed this many bytes,
+        /// or fail under `max_string_bytes_policy`
+        max_string_bytes: Option<usize>,
+        /// Whether going over `max_string_bytes` warns or fails the build
+        #[builder(default)]
+        max_string_bytes_policy: BudgetPolicy,
+        /// On-disk layout, like glibc's `ldconfig -c`
+        #[builder(default = CacheFormat::New)]
+        format: CacheFormat,
+        /// Which files in a directory are inspected as possible libraries,
+        /// from the directory and the file name, instead of glibc's
+        /// [`is_dso_name`](crate::is_dso_name); e.g. to also cache plugin
+        /// directories with unprefixed names. Files still have to be
+        /// shared objects
+        dso_names: Option<DsoNameFilter<'_>>,
+        /// Which symlinks not named as the soname of their library are
+        /// cached under their own name, from the link name and the
+        /// soname, instead of glibc's
+        /// [`is_dev_symlink`](crate::is_dev_symlink); e.g. to keep
+        /// dash-versioned plugin links (`libfoo-2.so` to `libfoo.so.2`).
+        /// Links it rejects are cached under the soname
+        symlink_policy: Option<SymlinkPolicy<'_>>,
+        /// Reuse what earlier builds sharing this memo found in files
+        /// that did not change since, by device, inode, size and times
+        elf_memo: Option<&ElfMemo>,
+        /// Cache every shared object (ET_DYN with a PT_DYNAMIC) in the
+        /// scanned directories, whatever its file name; executables built
+        /// as PIE are still left out
+        #[builder(default)]
+        all_shared_objects: bool,
+        /// Custom policy run on every library found, before duplicate
+        /// sonames are resolved: those it rejects are left out of the
+        /// cache, though their symlinks are still maintained
+        filter: Option<LibraryFilter<'_>>,
+        /// Directory prefixes rewritten in entry paths, from where the
+        /// libraries are scanned to where they will be at run time:
+        /// `("/build/stage/usr/lib", "/usr/lib")`. The longest matching
+        /// prefix wins; unlike `prefix`, each directory can be staged
+        /// somewhere else
+        #[builder(default)]
+        remap_paths: &[(&str, &str)],
+        /// Oldest glibc ("2.28") whose ld.so must read the cache: what it
+        /// does not know is left out. Before 2.33 that is glibc-hwcaps
+        /// entries and their ISA levels, and the extension section with
+        /// the generator, provenance, hashes and stats; before 2.2 the new
+        /// format itself, so the cache is written in the old one.
+        /// "auto" uses the glibc installed in `prefix`
+        /// ([`GlibcVersion::detect`]), or the latest if there is none
+        target_glibc: Option<&str>,
+        /// Keep at most this many entries in memory: the rest are spilled
+        /// to sorted runs in temporary files and merged while the cache is
+        /// serialized in place into a mapped temporary file, which the
+        /// cache then reads from. For very large roots on small builders;
+        /// shadowed libraries are then reported in cache order and entry
+        /// origins are not recorded
+        spill_entries: Option<usize>,
+        /// The file the cache is for, when spilling: the temporary file is
+        /// then made in its directory, and [`Cache::apply`] or
+        /// [`Cache::write_to_file`] to it renames the temporary file into
+        /// place instead of copying the cache
+        cache_file: Option<&Utf8Path>,
+    ) -> Result<Self, Error> {
+        let prefix = normalize_prefix(prefix);
+        let target_glibc = match target_glibc {
+            Some("auto") => {
+                let detected = GlibcVersion::detect(&prefix);
+                if detected.is_none() {
+                    debug!("No glibc found in {}, targeting the latest", prefix);
+                }
+                detected
+            }
+            other => other.map(str::parse::<GlibcVersion>).transpose()?,
+        };
+        let supports = |feature| target_glibc.is_none_or(|target| target >= feature);
+        let hwcaps_supported = supports(GlibcVersion::GLIBC_HWCAPS);
+        let extensions_supported = supports(GlibcVersion::EXTENSIONS);
+        let excluded = exclude_sonames
+            .iter()
+            .map(|p| glob::Pattern::new(p).map_err(|e| Error::Pattern(p.to_string(), e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let _build = info_span!("build", prefix = %prefix, dirs = search_paths.len()).entered();
+        let mut report = BuildReport::default();
+        let priority = |dir: &Utf8PathBuf| {
+            let dir = dir.as_str().trim_end_matches('/');
+            dir_priorities
+                .iter()
+                .find(|(d, _)| d.trim_end_matches('/') == dir)
+                .map_or(0, |&(_, p)| p)
+        };
+        let mut ordered = search_paths.to_vec();
+        ordered.sort_by_key(|d| std::cmp::Reverse(priority(d)));
+        if require_dirs {
+            let optional =
+                |d: &&Utf8PathBuf| SYSTEM_DIRS.contains(&d.as_str().trim_end_matches('/'));
+            for dir in ordered.iter().filter(|d| !optional(d)) {
+                check_dir(&prefix, dir).map_err(|e| Error::Directory(dir.clone(), e))?;
+            }
+        }
+        let dirs = collect_dirs(&ordered, &prefix, denied_dirs, path_policy, &mut report);
+        // Strict builds plan their link changes and only make them once
+        // nothing was warned about.
+        let plan_links = dry_run || strict;
+        let scan_options = ScanOptions {
+            prefix: prefix.clone(),
+            remove_stale_links: update_symlinks,
+            dry_run: plan_links,
+            max_osversion: min_os_version.map(OsVersion::to_raw),
+            skip_suffixes: skip_suffixes.iter().map(|s| s.to_string()).collect(),
+            hash_contents: content_hashes,
+            dso_names,
+            all_shared_objects,
+            collect_aliases: alias_entries,
+            misplaced_objects,
+            max_file_size,
+            symlink_policy,
+            elf_memo,
+        };
+
+        let scan = debug_span!("scan", dirs = dirs.len()).entered();
+        let mut entries = Vec::new();
+        let mut spill = spill_entries.map(EntrySpill::new);
+        let mut tally = DirectoryTally::default();
+        let mut left_out = 0;
+        let mut aliased = HashSet::new();
+        for dir in &dirs {
+            // glibc-hwcaps subdirectories come from the directory
+            // two levels up.
+            let configured = match dir.hwcaps {
+                None => Some(dir.path.as_path()),
+                Some(_) => dir.path.parent().and_then(Utf8Path::parent),
+            };
+            let expected = configured.and_then(|configured| {
+                dir_arches
+                    .iter()
+                    .find(|(d, _)| d.trim_end_matches('/') == configured)
+                    .copied()
+            });
+            for lib in scan_dir(dir, &scan_options, &mut report) {
+                if let Some((expecting, arches)) = expected {
+                    let arch = Arch::from_flags(lib.flags);
+                    if !arches.contains(&arch) {
+                        let names: Vec<String> = arches.iter().map(Arch::to_string).collect();
+                        report.warn(
+                            WarningKind::ArchMismatch,
+                            format!(
+                                "{}/{} is {}, but {} only expects {}",
+                                dir.path,
+                                lib.name,
+                                arch,
+                                expecting,
+                                names.join(" or ")
+                            ),
+                        );
+                    }
+                }
+                // The cached file name is the soname for regular
+                // directories (relying on the symlink), the actual file
+                // for glibc-hwcaps subdirectories (search_dir).
+                let mut linked = false;
+                let value_name = match &dir.hwcaps {
+                    None => {
+                        // Don't create links to links.
+                        if update_symlinks && !lib.is_link {
+                            linked = symlinks::create_link(
+                                &prefix,
+                                &dir.real,
+                                &dir.path,
+                                &lib.name,
+                                &lib.soname,
+                                plan_links,
+                                &mut report,
+                            );
+                        }
+                        let missing = || fs::symlink_metadata(dir.real.join(&lib.soname)).is_err();
+                        if unlinked_entries && !linked && missing() {
+                            &lib.name
+                        } else {
+                            &lib.soname
+                        }
+                    }
+                    Some(_) => &lib.name,
+                };
+                if excluded.iter().any(|p| p.matches(&lib.soname)) {
+                    debug!(
+                        directory = %dir.path,
+                        library = %lib.name,
+                        action = "exclude",
+                        "{}/{}: soname {} excluded",
+                        dir.path,
+                        lib.name,
+                        lib.soname
+                    );
+                    continue;
+                }
+                // glibc-hwcaps entries keep the path they were found at,
+                // through a symlinked subdirectory or file: variants are
+                // often symlink farms sharing their targets, which would
+                // otherwise all record the same path.
+                let canonical = match dir.hwcaps {
+                    None if alias_entries => canonical_path(&prefix, &dir.path.join(&lib.name)),
+                    _ => None,
+                };
+                let path = match &canonical {
+                    Some(file) => remap(file.as_str(), remap_paths),
+                    None => remap(&format!("{}/{}", dir.path, value_name), remap_paths),
+                };
+                if let Some(filter) = filter {
+                    let library = ElfLibrary {
+                        path: path.clone(),
+                        file: dir.real.join(&lib.name),
+                        soname: lib.soname.clone(),
+                        arch: Arch::from_flags(lib.flags),
+                        isa_level: lib.isa_level,
+                        hwcaps: dir.hwcaps.clone(),
+                        size: lib.stat.map_or(0, |s| s.size),
+                    };
+                    if !filter(&library) {
+                        debug!(
+                            directory = %dir.path,
+                            library = %lib.name,
+                            action = "filter",
+                            "{}/{}: rejected by the library filter",
+                            dir.path,
+                            lib.name
+                        );
+                        continue;
+                    }
+                }
+                let kind = if linked {
+                    OriginKind::CreatedLink
+                } else if lib.is_link || *value_name != lib.name {
+                    OriginKind::Symlink
+                } else {
+                    OriginKind::File
+                };
+                // Spilled builds keep nothing per entry.
+                if spill.is_none() {
+                    report.origins.push(EntryOrigin {
+                        path: path.clone(),
+                        file: lib.name.clone(),
+                        dir: dir.path.to_string(),
+                        config: configured.and_then(|d| search_paths.source(d)).cloned(),
+                        kind,
+                    });
+                }
+                let mut names = vec![lib.soname.clone()];
+                if canonical.is_some() {
+                    names.extend(lib.aliases.iter().cloned());
+                    names.sort();
+                    names.dedup();
+                    // Development links share their target's aliases;
+                    // spilled builds drop the repeats while merging.
+                    if spill.is_none() {
+                        names.retain(|name| aliased.insert((name.clone(), path.clone())));
+                    }
+                }
+                let entry = FileEntry {
+                    path,
+                    soname: lib.soname,
+                    flags: lib.flags,
+                    isa_level: lib.isa_level,
+                    hwcaps: dir.hwcaps.clone(),
+                    hwcap: 0,
+                    osversion: 0,
+                    sha256: lib.sha256,
+                    stat: lib.stat.filter(|_| file_stats),
+                };
+                let Some(entry) = for_target(entry, hwcaps_supported, extensions_supported) else {
+                    left_out += 1;
+                    continue;
+                };
+                let source = match canonical {
+                    Some(_) => Source::Canonical,
+                    None => Source::Scanned,
+                };
+                for soname in names {
+                    let entry = FileEntry {
+                        soname,
+                        ..entry.clone()
+                    };
+                    match &mut spill {
+                        Some(spill) => spill.push(entry, source)?,
+                        None => {
+                            tally.add(&entry);
+                            entries.push(entry);
+                        }
+                    }
+                }
+            }
+        }
+
+        drop(scan);
+
+        let mut first: HashMap<(&str, u32, Option<&str>), &str> = HashMap::new();
+        for e in &entries {
+            let key = (e.soname.as_str(), e.flags, e.hwcaps.as_deref());
+            match first.get(&key) {
+                None => {
+                    first.insert(key, &e.path);
+                }
+                Some(used) => {
+                    debug!(library = %e.path, action = "shadow", "{} shadowed by {}", e.path, used);
+                    report.shadowed.push(Shadowed {
+                        soname: e.soname.clone(),
+                        used: used.to_string(),
+                        shadowed: e.path.clone(),
+                    });
+                }
+            }
+        }
+
+        for synthetic in synthetic_entries {
+            let entry = synthetic.to_file_entry();
+            let Some(entry) = for_target(entry, hwcaps_supported, extensions_supported) else {
+                left_out += 1;
+                continue;
+            };
+            match &mut spill {
+                Some(spill) => spill.push(entry, Source::Synthetic)?,
+                None => {
+                    tally.add(&entry);
+                    entries.push(entry);
+                }
+            }
+        }
+
+        if !hwcaps_supported {
+            debug!(
+                "{} glibc-hwcaps entries left out for glibc {}",
+                left_out,
+                target_glibc.unwrap_or(GlibcVersion::GLIBC_HWCAPS)
+            );
+        }
+
+        let extensions: Vec<RawExtension> = provenance
+            .iter()
+            .filter(|_| extensions_supported)
+            .map(Provenance::to_extension)
+            .collect();
+        let generator = extensions_supported.then(cache_format::default_generator);
+        let mut cache = match spill {
+            Some(spill) => {
+                let _span =
+                    debug_span!("serialize", entries = spill.len(), spilled = true).entered();
+                let data = spill.write_cache(
+                    generator.as_deref(),
+                    &extensions,
+                    &mut report.shadowed,
+                    |e| tally.add(e),
+                    cache_file.map(|file| match file.parent() {
+                        Some(dir) if !dir.as_str().is_empty() => dir.as_std_path(),
+                        _ => Path::new("."),
+                    }),
+                )?;
+                Self::from_serialized(data)?
+            }
+            None => Self::from_file_entries(&entries, generator.as_deref(), &extensions)?,
+        };
+        let count = tally.0.values().map(|&(n, _)| n).sum::<usize>();
+        info!("Cache entries: {} libraries", count);
+
+        let duplicates: Vec<String> = report
+            .shadowed
+            .iter()
+            .map(|s| format!("{} shadowed by {} for {}", s.shadowed, s.used, s.soname))
+            .collect();
+        for message in duplicates {
+            report.warn(WarningKind::DuplicateSoname, message);
+        }
+
+        if let Some(max) = max_entries.filter(|&max| count > max) {
+            let message = format!(
+                "Cache has {} entries, over the budget of {}; most from {}",
+                count,
+                max,
+                tally.top(|(n, _)| n)
+            );
+            max_entries_policy.apply(&mut report, message)?;
+        }
+        let strings = cache_format::string_table_len(&cache.data);
+        if let Some(max) = max_string_bytes.filter(|&max| strings > max) {
+            let message = format!(
+                "Cache string table is {} bytes, over the budget of {}; most from {}",
+                strings,
+                max,
+                tally.top(|(_, bytes)| bytes)
+            );
+            max_string_bytes_policy.apply(&mut report, message)?;
+        }
+
+        if strict && !report.warnings.is_empty() {
+            return Err(Error::Strict(report));
+        }
+        let format = if supports(GlibcVersion::NEW_FORMAT) {
+            format
+        } else {
+            CacheFormat::Old
+        };
+        if format != CacheFormat::New {
+            cache = cache.with_format(format)?;
+        }
+        cache.report = report;
+        if strict && !dry_run {
+            cache.apply_links(&prefix)?;
+        }
+        Ok(cache)
+    }
+}
+
+/// Where `path`, inside `prefix`, really is with every symlink resolved,
+/// as seen from inside the root.
+fn canonical_path(prefix: &Utf8Path, path: &Utf8Path) -> Option<Utf8PathBuf> {
+    if prefix == "/" {
+        return Utf8PathBuf::from_path_buf(fs::canonicalize(path).ok()?).ok();
+    }
+    let real = chroot_canon(prefix, path)?;
+    Some(Utf8Path::new("/").join(real.strip_prefix(prefix).ok()?))
+}
+
+/// Entries drop what ld.so of the target glibc does not know: None for
+/// glibc-hwcaps entries, no hashes or stats without extensions.
+fn for_target(mut entry: FileEntry, hwcaps: bool, extensions: bool) -> Option<FileEntry> {
+    if !hwcaps && entry.hwcaps.is_some() {
+        return None;
+    }
+    if !extensions {
+        entry.sha256 = None;
+        entry.stat = None;
+    }
+    Some(entry)
+}
+
+/// What a build does when the cache goes over one of its budgets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BudgetPolicy {
+    /// Build the cache anyway, with a warning.
+    #[default]
+    Warn,
+    /// Fail the build, whatever else it found.
+    Fail,
+}
+
+impl BudgetPolicy {
+    fn apply(self, report: &mut BuildReport, message: String) -> Result<(), Error> {
+        match self {
+            BudgetPolicy::Warn => {
+                report.warn(WarningKind::OverBudget, message);
+                Ok(())
+            }
+            BudgetPolicy::Fail => Err(Error::OverBudget(message)),
+        }
+    }
+}
+
+/// Entry count and string table bytes per directory, for budget
+/// warnings without keeping the entries around.
+#[derive(Default)]
+struct DirectoryTally(HashMap<String, (usize, usize)>);
+
+impl DirectoryTally {
+    fn add(&mut self, e: &FileEntry) {
+        let dir = Utf8Path::new(&e.path).parent().map_or("", Utf8Path::as_str);
+        let (entries, string_bytes) = self.0.entry(dir.to_owned()).or_default();
+        *entries += 1;
+        *string_bytes += e.soname.len() + e.path.len() + 2;
+    }
+
+    /// The three directories contributing most by `measure`, e.g.
+    /// "/usr/lib (812), /usr/lib32 (301), /opt/lib (12)".
+    fn top(&self, measure: impl Fn((usize, usize)) -> usize) -> String {
+        let mut per_dir: Vec<(&str, usize)> = self
+            .0
+            .iter()
+            .map(|(d, &t)| (d.as_str(), measure(t)))
+            .collect();
+        per_dir.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        per_dir
+            .iter()
+            .take(3)
+            .map(|(dir, n)| format!("{} ({})", dir, n))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Arch, CacheEntry};
+    use std::fs;
+
+    #[test]
+    fn strict_builds_fail_on_warnings() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let fifo = std::ffi::CString::new(dir.join("libx.so.1").as_str()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        let paths = SearchPaths::new(vec![dir]);
+
+        let cache = Cache::builder().dry_run(true).build(&paths).unwrap();
+        assert_eq!(cache.report().warnings.len(), 1);
+
+        let err = Cache::builder()
+            .dry_run(true)
+            .strict(true)
+            .build(&paths)
+            .err()
+            .unwrap();
+        let msg = err.to_string();
+        assert!(msg.starts_with("1 warning(s) in strict mode:\n"), "{}", msg);
+        assert!(msg.contains("libx.so.1 is not a regular file"), "{}", msg);
+    }
+
+    #[test]
+    fn strict_builds_change_no_links_on_failure() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let (first, second) = (root.join("first"), root.join("second"));
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        for dir in [&first, &second] {
+            fs::create_dir(dir).unwrap();
+            fs::write(dir.join("libz.so.1.3"), &dso).unwrap();
+        }
+        std::os::unix::fs::symlink("libgone.so.1.0", first.join("libgone.so.1")).unwrap();
+        let paths = SearchPaths::new(vec![first.clone(), second.clone()]);
+
+        let err = Cache::builder().strict(true).build(&paths).err().unwrap();
+        let Error::Strict(report) = err else {
+            panic!("{}", err);
+        };
+        let kinds: Vec<WarningKind> = report.warnings.iter().map(|w| w.kind).collect();
+        assert_eq!(
+            kinds,
+            [WarningKind::DanglingSymlink, WarningKind::DuplicateSoname]
+        );
+        assert!(!first.join("libz.so.1").is_symlink());
+        assert!(!second.join("libz.so.1").is_symlink());
+        assert!(first.join("libgone.so.1").is_symlink());
+
+        // Without anything to warn about, the planned links are made.
+        fs::remove_file(first.join("libgone.so.1")).unwrap();
+        let paths = SearchPaths::new(vec![first.clone()]);
+        let cache = Cache::builder().strict(true).build(&paths).unwrap();
+        assert_eq!(cache.report().links.len(), 1);
+        assert!(first.join("libz.so.1").is_symlink());
+    }
+
+    #[test]
+    fn excluded_sonames_are_left_out() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        for (file, soname) in [
+            ("libz.so.1.3", "libz.so.1"),
+            ("libvendor-stub.so.1.0", "libvendor-stub.so.1"),
+            ("libvendor-stubgl.so.2.0", "libvendor-stubgl.so.2"),
+        ] {
+            let dso = crate::elf::dso_with(Some(soname), &[], None);
+            fs::write(dir.join(file), dso).unwrap();
+        }
+        let paths = SearchPaths::new(vec![dir]);
+        let all = Cache::builder().dry_run(true).build(&paths).unwrap();
+        assert_eq!(all.entries().count(), 3);
+        let cache = Cache::builder()
+            .dry_run(true)
+            .exclude_sonames(&["libvendor-stub*.so.*"])
+            .build(&paths)
+            .unwrap();
+        let sonames: Vec<String> = cache.entries().map(|e| e.soname).collect();
+        assert_eq!(sonames, ["libz.so.1"]);
+        // Their symlinks are still maintained.
+        assert_eq!(cache.report().links.len(), 3);
+
+        let err = Cache::builder()
+            .dry_run(true)
+            .exclude_sonames(&["lib[z"])
+            .build(&paths)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("lib[z"));
+    }
+
+    #[test]
+    fn synthetic_entries_need_no_scan() {
+        let built = Cache::builder()
+            .dry_run(true)
+            .synthetic_entries(&[SyntheticEntry::new(
+                "libgpu.so.1",
+                "/run/gpu/libgpu.so.1",
+                Arch::X86_64,
+            )])
+            .build(&SearchPaths::new(Vec::new()))
+            .unwrap();
+        assert_eq!(built.entries().count(), 1);
+    }
+
+    #[test]
+    fn target_glibc_limits_features() {
+        let mut hw = SyntheticEntry::new(
+            "libgpu.so.1",
+            "/run/gpu/glibc-hwcaps/x86-64-v3/libgpu.so.1",
+            Arch::X86_64,
+        );
+        hw.hwcaps = Some("x86-64-v3".into());
+        let synthetic = [
+            SyntheticEntry::new("libgpu.so.1", "/run/gpu/libgpu.so.1", Arch::X86_64),
+            hw,
+        ];
+        let build = |target| {
+            Cache::builder()
+                .dry_run(true)
+                .synthetic_entries(&synthetic)
+                .maybe_target_glibc(target)
+                .build(&SearchPaths::new(Vec::new()))
+        };
+
+        let latest = build(None).unwrap();
+        assert_eq!(latest.entries().count(), 2);
+        assert!(latest.info().generator.is_some());
+        // No glibc in the (host) root of this build: nothing is left out.
+        let auto = Cache::builder()
+            .prefix(Utf8Path::new("/nonexistent"))
+            .dry_run(true)
+            .synthetic_entries(&synthetic)
+            .target_glibc("auto")
+            .build(&SearchPaths::new(Vec::new()))
+            .unwrap();
+        assert_eq!(auto.entries().count(), 2);
+        let v2_33 = build(Some("2.33")).unwrap();
+        assert_eq!(v2_33.entries().count(), 2);
+        assert!(v2_33.info().generator.is_some());
+        let v2_32 = build(Some("2.32")).unwrap();
+        assert_eq!(v2_32.entries().count(), 1);
+        assert_eq!(v2_32.info().generator, None);
+        let v2_28 = build(Some("2.28")).unwrap();
+        assert_eq!(v2_28.entries().count(), 1);
+        assert_eq!(v2_28.info().generator, None);
+        assert_eq!(v2_28.format(), CacheFormat::New);
+        assert_eq!(build(Some("2.1.3")).unwrap().format(), CacheFormat::Old);
+        assert!(build(Some("two")).is_err());
+    }
+
+    fn lib_dir(root: &Utf8Path, name: &str, lib: &str) -> Utf8PathBuf {
+        let dir = root.join(name);
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join(lib), crate::elf::minimal_dso()).unwrap();
+        dir
+    }
+
+    #[test]
+    fn dso_name_filter_replaces_glibc_rule() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let plugins = lib_dir(&root, "plugins", "gstvideo.so");
+        let lib = lib_dir(&root, "lib", "libz.so.1");
+        fs::write(lib.join("helper.so"), crate::elf::minimal_dso()).unwrap();
+        let paths = SearchPaths::new(vec![plugins, lib]);
+
+        let sonames = |cache: Cache| -> Vec<String> { cache.entries().map(|e| e.soname).collect() };
+        let default = Cache::builder().dry_run(true).build(&paths).unwrap();
+        assert_eq!(sonames(default), ["libz.so.1"]);
+
+        let filter =
+            |dir: &Utf8Path, name: &str| dir.ends_with("plugins") || crate::is_dso_name(name);
+        let cache = Cache::builder()
+            .dry_run(true)
+            .dso_names(&filter)
+            .build(&paths)
+            .unwrap();
+        assert_eq!(sonames(cache), ["libz.so.1", "gstvideo.so"]);
+    }
+
+    #[test]
+    fn symlink_policy_replaces_glibc_rule() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let lib = root.join("lib");
+        fs::create_dir(&lib).unwrap();
+        let dso = crate::elf::dso_with(Some("libfoo.so.2"), &[], None);
+        fs::write(lib.join("libfoo.so.2.0"), dso).unwrap();
+        std::os::unix::fs::symlink("libfoo.so.2.0", lib.join("libfoo-2.so")).unwrap();
+        let paths = SearchPaths::new(vec![lib]);
+
+        let sonames = |cache: Cache| -> Vec<String> { cache.entries().map(|e| e.soname).collect() };
+        let default = Cache::builder().dry_run(true).build(&paths).unwrap();
+        assert_eq!(sonames(default), ["libfoo.so.2"]);
+
+        let policy =
+            |name: &str, soname: &str| name.contains('-') || crate::is_dev_symlink(name, soname);
+        let cache = Cache::builder()
+            .dry_run(true)
+            .symlink_policy(&policy)
+            .build(&paths)
+            .unwrap();
+        let mut cached = sonames(cache);
+        cached.sort();
+        assert_eq!(cached, ["libfoo-2.so", "libfoo.so.2"]);
+    }
+
+    #[test]
+    fn elf_memo_parses_shared_files_once() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let a = lib_dir(&root, "a", "libz.so.1");
+        fs::create_dir(root.join("b")).unwrap();
+        fs::hard_link(a.join("libz.so.1"), root.join("b/libz.so.1")).unwrap();
+
+        let memo = ElfMemo::new();
+        for dir in ["a", "b"] {
+            let paths = SearchPaths::new(vec![root.join(dir)]);
+            let cache = Cache::builder()
+                .dry_run(true)
+                .elf_memo(&memo)
+                .build(&paths)
+                .unwrap();
+            assert_eq!(cache.entries().count(), 1);
+        }
+        assert_eq!((memo.len(), memo.hits()), (1, 1));
+
+        // A hash wanted now was not computed then.
+        let paths = SearchPaths::new(vec![a]);
+        let hashed = Cache::builder()
+            .dry_run(true)
+            .content_hashes(true)
+            .elf_memo(&memo)
+            .build(&paths)
+            .unwrap();
+        assert!(hashed.entries().next().unwrap().sha256.is_some());
+        assert_eq!(memo.hits(), 1);
+    }
+
+    #[test]
+    fn dir_arches_flag_misplaced_libraries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let lib32 = lib_dir(&root, "lib32", "libz.so.1");
+        let lib64 = lib_dir(&root, "lib64", "libm.so.6");
+        let paths = SearchPaths::new(vec![lib32.clone(), lib64.clone()]);
+
+        let cache = Cache::builder()
+            .dry_run(true)
+            .dir_arches(&[
+                (lib32.as_str(), &[Arch::Libc6]),
+                (lib64.as_str(), &[Arch::X86_64]),
+            ])
+            .build(&paths)
+            .unwrap();
+        assert_eq!(cache.entries().count(), 2);
+        let warnings = &cache.report().warnings;
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].kind, WarningKind::ArchMismatch);
+        assert_eq!(warnings[0].kind.code(), "W0014");
+        assert_eq!(
+            warnings[0].message,
+            format!(
+                "{}/libz.so.1 is libc6,x86-64, but {} only expects libc6",
+                lib32, lib32
+            )
+        );
+    }
+
+    #[test]
+    fn all_shared_objects_ignores_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let lib = lib_dir(&root, "lib", "vendorgfx.so.3");
+        // A PIE program: PT_INTERP in place of the PT_LOAD header.
+        let mut pie = crate::elf::dso_needing(&[], None);
+        pie[64..68].copy_from_slice(&goblin::elf::program_header::PT_INTERP.to_ne_bytes());
+        fs::write(lib.join("tool"), pie).unwrap();
+        fs::write(lib.join("README"), "not ELF").unwrap();
+        let paths = SearchPaths::new(vec![lib]);
+
+        let default = Cache::builder().dry_run(true).build(&paths).unwrap();
+        assert_eq!(default.entries().count(), 0);
+
+        let cache = Cache::builder()
+            .dry_run(true)
+            .all_shared_objects(true)
+            .build(&paths)
+            .unwrap();
+        let sonames: Vec<String> = cache.entries().map(|e| e.soname).collect();
+        assert_eq!(sonames, ["vendorgfx.so.3"]);
+    }
+
+    #[test]
+    fn library_filter_runs_before_dedup() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let vendor = lib_dir(&root, "vendor", "libz.so.1");
+        let usr = lib_dir(&root, "usr", "libz.so.1");
+        let paths = SearchPaths::new(vec![vendor, usr.clone()]);
+
+        let seen = std::cell::RefCell::new(Vec::new());
+        let filter = |lib: &ElfLibrary| {
+            seen.borrow_mut().push(lib.clone());
+            !lib.path.contains("/vendor/")
+        };
+        let cache = Cache::builder()
+            .dry_run(true)
+            .filter(&filter)
+            .build(&paths)
+            .unwrap();
+        let entries: Vec<String> = cache.entries().map(|e| e.path).collect();
+        assert_eq!(entries, [format!("{}/libz.so.1", usr)]);
+        assert!(cache.report().shadowed.is_empty());
+
+        let seen = seen.into_inner();
+        assert_eq!(seen.len(), 2);
+        assert_eq!(seen[1].file, usr.join("libz.so.1"));
+        assert_eq!(seen[1].arch, Arch::X86_64);
+        assert_eq!(seen[1].size, 136);
+    }
+
+    #[test]
+    fn remap_paths_rewrites_staged_dirs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::create_dir(root.join("stage")).unwrap();
+        let usr = lib_dir(&root, "stage/usr", "libz.so.1");
+        let opt = lib_dir(&root, "opt", "libm.so.6");
+        let paths = SearchPaths::new(vec![usr, opt]);
+
+        let staged = format!("{}/stage", root);
+        let cache = Cache::builder()
+            .dry_run(true)
+            .remap_paths(&[(&staged, "/"), (&format!("{}/usr/", staged), "/usr/lib")])
+            .build(&paths)
+            .unwrap();
+        let mut entries: Vec<String> = cache.entries().map(|e| e.path).collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            [
+                format!("{}/opt/libm.so.6", root),
+                "/usr/lib/libz.so.1".into()
+            ]
+        );
+        assert_eq!(
+            remap("/stagex/lib/libz.so.1", &[("/stage", "/")]),
+            "/stagex/lib/libz.so.1"
+        );
+    }
+
+    #[test]
+    fn spilled_builds_match_in_memory_ones() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let usr = lib_dir(&root, "usr", "libz.so.1");
+        let vendor = lib_dir(&root, "vendor", "libz.so.1");
+        let opt = lib_dir(&root, "opt", "libm.so.6");
+        let paths = SearchPaths::new(vec![usr, vendor, opt]);
+
+        let in_memory = Cache::builder().dry_run(true).build(&paths).unwrap();
+        let spilled = Cache::builder()
+            .dry_run(true)
+            .spill_entries(1)
+            .build(&paths)
+            .unwrap();
+        assert_eq!(spilled.as_bytes(), in_memory.as_bytes());
+        assert_eq!(spilled.report().shadowed, in_memory.report().shadowed);
+    }
+
+    #[test]
+    fn spilled_alias_builds_match_in_memory_ones() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(fs::canonicalize(tmp.path()).unwrap()).unwrap();
+        let usr = root.join("usr/lib");
+        fs::create_dir_all(&usr).unwrap();
+        let z = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        fs::write(usr.join("libz.so.1.3"), z).unwrap();
+        std::os::unix::fs::symlink("libz.so.1.3", usr.join("libz.so")).unwrap();
+        let m = crate::elf::dso_with(Some("libm.so.6"), &[], None);
+        fs::write(usr.join("libm.so.6"), m).unwrap();
+        // A second directory whose link resolves to the same file.
+        let lib = root.join("lib");
+        fs::create_dir(&lib).unwrap();
+        std::os::unix::fs::symlink(usr.join("libz.so.1.3"), lib.join("libz.so.1")).unwrap();
+        let paths = SearchPaths::new(vec![usr, lib]);
+
+        let build = |spill: Option<usize>| {
+            Cache::builder()
+                .dry_run(true)
+                .alias_entries(true)
+                .maybe_spill_entries(spill)
+                .build(&paths)
+                .unwrap()
+        };
+        let in_memory = build(None);
+        let spilled = build(Some(1));
+        assert_eq!(in_memory.entries().count(), 4);
+        assert_eq!(spilled.as_bytes(), in_memory.as_bytes());
+        assert_eq!(spilled.report().shadowed, in_memory.report().shadowed);
+        assert!(spilled.report().origins.is_empty());
+    }
+
+    #[test]
+    fn physical_paths_resolve_symlinks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let usr = lib_dir(&root, "usr", "libz.so.1");
+        std::os::unix::fs::symlink(&usr, root.join("lib")).unwrap();
+        let paths = SearchPaths::new(vec![root.join("lib")]);
+
+        let build = |policy| {
+            let cache = Cache::builder()
+                .dry_run(true)
+                .path_policy(policy)
+                .build(&paths)
+                .unwrap();
+            cache.entries().map(|e| e.path).collect::<Vec<_>>()
+        };
+        assert_eq!(build(PathPolicy::Logical), [root.join("lib/libz.so.1")]);
+        assert_eq!(build(PathPolicy::Physical), [usr.join("libz.so.1")]);
+    }
+
+    #[test]
+    fn hwcaps_symlinks_keep_their_path() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(fs::canonicalize(tmp.path()).unwrap()).unwrap();
+        let dso = crate::elf::dso_with(Some("libfoo.so.1"), &[], None);
+        let opt = root.join("opt/v3");
+        fs::create_dir_all(&opt).unwrap();
+        fs::write(opt.join("libfoo.so.1.2"), dso).unwrap();
+        let farm = root.join("farm");
+        fs::create_dir(&farm).unwrap();
+        std::os::unix::fs::symlink(opt.join("libfoo.so.1.2"), farm.join("libfoo.so.1")).unwrap();
+        let lib = root.join("usr/lib");
+        fs::create_dir_all(lib.join("glibc-hwcaps")).unwrap();
+        std::os::unix::fs::symlink(&farm, lib.join("glibc-hwcaps/x86-64-v3")).unwrap();
+        let paths = SearchPaths::new(vec![lib.clone()]);
+
+        let expected = lib.join("glibc-hwcaps/x86-64-v3/libfoo.so.1");
+        for policy in [PathPolicy::Logical, PathPolicy::Physical] {
+            let cache = Cache::builder()
+                .dry_run(true)
+                .alias_entries(true)
+                .path_policy(policy)
+                .build(&paths)
+                .unwrap();
+            let entries: Vec<CacheEntry> = cache.entries().collect();
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].path, expected);
+            assert_eq!(entries[0].hwcaps.as_deref(), Some("x86-64-v3"));
+            let users = cache.entries_for_path(opt.join("libfoo.so.1.2"), "/");
+            assert_eq!(users.len(), 1);
+        }
+    }
+
+    #[test]
+    fn required_dirs_must_be_scannable() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let lib = lib_dir(&root, "lib", "libz.so.1");
+        let missing = root.join("not-mounted");
+        let file = root.join("file");
+        fs::write(&file, b"").unwrap();
+
+        let build = |dirs: Vec<Utf8PathBuf>, require| {
+            Cache::builder()
+                .dry_run(true)
+                .require_dirs(require)
+                .build(&SearchPaths::new(dirs))
+        };
+        let with_system = |dir: &Utf8Path| vec![lib.clone(), dir.to_path_buf(), "/lib64".into()];
+        assert!(build(with_system(&missing), false).is_ok());
+        assert!(build(vec![lib.clone(), "/lib64".into()], true).is_ok());
+        for dir in [&missing, &file] {
+            match build(with_system(dir), true) {
+                Err(Error::Directory(failed, _)) => assert_eq!(&failed, dir),
+                other => panic!("expected a directory error, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn unlinked_entries_point_at_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let lib = root.join("usr");
+        fs::create_dir(&lib).unwrap();
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        fs::write(lib.join("libz.so.1.3"), dso).unwrap();
+        let paths = SearchPaths::new(vec![lib.clone()]);
+
+        let build = |unlinked| {
+            let cache = Cache::builder()
+                .update_symlinks(false)
+                .unlinked_entries(unlinked)
+                .build(&paths)
+                .unwrap();
+            let entry = cache.entries().next().unwrap();
+            (entry.soname, entry.path)
+        };
+        assert_eq!(
+            build(false),
+            ("libz.so.1".into(), lib.join("libz.so.1").into())
+        );
+        assert_eq!(
+            build(true),
+            ("libz.so.1".into(), lib.join("libz.so.1.3").into())
+        );
+        assert!(!lib.join("libz.so.1").exists());
+    }
+
+    #[test]
+    fn alias_entries_cover_every_name() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(fs::canonicalize(tmp.path()).unwrap()).unwrap();
+        let lib = root.join("usr");
+        fs::create_dir(&lib).unwrap();
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        fs::write(lib.join("libz.so.1.3"), dso).unwrap();
+        std::os::unix::fs::symlink("libz.so.1.3", lib.join("libz.so")).unwrap();
+        let paths = SearchPaths::new(vec![lib.clone()]);
+
+        let cache = Cache::builder().alias_entries(true).build(&paths).unwrap();
+        let mut names: Vec<(String, String)> =
+            cache.entries().map(|e| (e.soname, e.path)).collect();
+        names.sort();
+        let file = lib.join("libz.so.1.3").to_string();
+        assert_eq!(
+            names,
+            [
+                ("libz.so".into(), file.clone()),
+                ("libz.so.1".into(), file.clone()),
+                ("libz.so.1.3".into(), file),
+            ]
+        );
+    }
+
+    #[test]
+    fn dir_priorities_reorder_duplicates() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let usr = lib_dir(&root, "usr", "libz.so.1");
+        let vendor = lib_dir(&root, "vendor", "libz.so.1");
+        let paths = SearchPaths::new(vec![usr.clone(), vendor.clone()]);
+
+        let cache = Cache::builder().dry_run(true).build(&paths).unwrap();
+        let order: Vec<String> = cache.entries().map(|e| e.path).collect();
+        assert_eq!(order, [usr.join("libz.so.1"), vendor.join("libz.so.1")]);
+        assert_eq!(cache.report().shadowed.len(), 1);
+        assert_eq!(cache.report().shadowed[0].used, usr.join("libz.so.1"));
+
+        let vendor_dir = format!("{}/", vendor);
+        let cache = Cache::builder()
+            .dry_run(true)
+            .dir_priorities(&[(vendor_dir.as_str(), 10)])
+            .build(&paths)
+            .unwrap();
+        let order: Vec<String> = cache.entries().map(|e| e.path).collect();
+        assert_eq!(order, [vendor.join("libz.so.1"), usr.join("libz.so.1")]);
+        assert_eq!(cache.report().shadowed[0].shadowed, usr.join("libz.so.1"));
+    }
+
+    #[test]
+    fn duplicates_follow_directory_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let names = ["c", "a", "d", "b"];
+        let dirs: Vec<Utf8PathBuf> = names
+            .iter()
+            .map(|n| lib_dir(&root, n, "libdup.so.1"))
+            .collect();
+
+        let build = |dirs: Vec<Utf8PathBuf>| {
+            Cache::builder()
+                .dry_run(true)
+                .build(&SearchPaths::new(dirs))
+                .unwrap()
+        };
+        let cache = build(dirs.clone());
+        let order: Vec<String> = cache
+            .entries()
+            .filter(|e| e.soname == "libdup.so.1")
+            .map(|e| e.path)
+            .collect();
+        let expected: Vec<String> = dirs.iter().map(|d| d.join("libdup.so.1").into()).collect();
+        assert_eq!(order, expected);
+        assert_eq!(cache.report().shadowed.len(), 3);
+        for _ in 0..4 {
+            assert_eq!(build(dirs.clone()).as_bytes(), cache.as_bytes());
+        }
+
+        let mut reversed = dirs.clone();
+        reversed.reverse();
+        let first = build(reversed).entries().next().unwrap().path;
+        assert_eq!(first, dirs[3].join("libdup.so.1"));
+    }
+
+    #[test]
+    fn dry_run_plans_link_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let dir = lib_dir(&root, "lib", "libz.so.1");
+        std::os::unix::fs::symlink("libgone.so.1.0", dir.join("libgone.so.1")).unwrap();
+        let paths = SearchPaths::new(vec![dir.clone()]);
+
+        let preview = Cache::builder().dry_run(true).build(&paths).unwrap();
+        let plan = preview.plan(None);
+        assert_eq!(
+            plan.links,
+            [crate::LinkAction::RemoveStale {
+                dir: dir.to_string(),
+                link: "libgone.so.1".into(),
+                target: "libgone.so.1.0".into(),
+            }]
+        );
+        assert!(fs::symlink_metadata(dir.join("libgone.so.1")).is_ok());
+        assert_eq!(plan.diff.added.len(), 1);
+
+        let built = Cache::builder().build(&paths).unwrap();
+        assert_eq!(built.report().links, plan.links);
+        assert!(fs::symlink_metadata(dir.join("libgone.so.1")).is_err());
+        assert!(preview.plan(Some(&built)).diff.is_empty());
+    }
+
+    #[test]
+    fn entries_record_their_origin() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let dir = lib_dir(&root, "lib", "libz.so.1");
+        fs::write(root.join("ld.so.conf"), format!("# libs\n{}\n", dir)).unwrap();
+        let paths = SearchPaths::from_file(root.join("ld.so.conf"), None).unwrap();
+
+        let cache = Cache::builder().dry_run(true).build(&paths).unwrap();
+        let path = dir.join("libz.so.1");
+        let origin = cache.report().origin(path.as_str()).unwrap();
+        assert_eq!(origin.kind, OriginKind::File);
+        assert_eq!(origin.dir, dir);
+        let config = origin.config.as_ref().unwrap();
+        assert_eq!(
+            (config.file.as_path(), config.line),
+            (root.join("ld.so.conf").as_path(), 2)
+        );
+    }
+
+    #[test]
+    fn budgets_warn_with_breakdown() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let big = lib_dir(&root, "big", "liba.so.1");
+        fs::write(big.join("libb.so.1"), crate::elf::minimal_dso()).unwrap();
+        let small = lib_dir(&root, "small", "libc.so.1");
+        let paths = SearchPaths::new(vec![small, big.clone()]);
+
+        let within = Cache::builder()
+            .dry_run(true)
+            .max_entries(3)
+            .max_string_bytes(4096)
+            .build(&paths)
+            .unwrap();
+        assert!(within.report().warnings.is_empty());
+
+        let over = Cache::builder()
+            .dry_run(true)
+            .max_entries(2)
+            .max_string_bytes(16)
+            .build(&paths)
+            .unwrap();
+        let warnings = &over.report().warnings;
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0]
+            .message
+            .starts_with("Cache has 3 entries, over the budget of 2; most from "));
+        assert!(warnings[0].message.contains(&format!("{} (2)", big)));
+        assert!(warnings[1].message.contains("over the budget of 16"));
+
+        // Only the budget set to fail does.
+        let failed = Cache::builder()
+            .dry_run(true)
+            .max_entries(2)
+            .max_string_bytes(16)
+            .max_string_bytes_policy(BudgetPolicy::Fail)
+            .build(&paths);
+        assert!(matches!(failed, Err(Error::OverBudget(m)) if m.contains("budget of 16")));
+        let warned = Cache::builder()
+            .dry_run(true)
+            .max_entries(2)
+            .max_entries_policy(BudgetPolicy::Warn)
+            .max_string_bytes(4096)
+            .max_string_bytes_policy(BudgetPolicy::Fail)
+            .build(&paths)
+            .unwrap();
+        assert_eq!(warned.report().warnings.len(), 1);
+    }
+}
@@ -17,49 +17,90 @@
 //!     println!("{} => {}", entry.soname, entry.path);
 //! }
 //!
-//! // Find specific libraries
-//! for entry in cache.find("libc") {
+//! // Find specific libraries, by substring or shell pattern
+//! for entry in cache.find("libssl.so.*") {
 //!     println!("Found: {}", entry.soname);
 //! }
 //! # Ok::<(), ldconfig::Error>(())
 //! ```
 
-use crate::cache_format::{self, flags_string, CacheInfo as InternalCacheInfo, FileEntry};
-use crate::scanner::{collect_dirs, scan_dir};
-use crate::{atomic_write, error::Error, symlinks, SearchPaths};
-use bon::bon;
+use crate::cache_format::{
+    self, flags_string, legacy_hwcap_names, Arch, CacheFormat, CacheInfo as InternalCacheInfo,
+    FileEntry, FileStat, OsVersion, Provenance, RawExtension,
+};
+use crate::{atomic_write, chroot_canon, error::Error, raw, BuildReport};
 use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, Range};
 use std::path::Path;
-use tracing::info;
 
 /// Information about the cache file
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct CacheInfo {
     pub num_entries: usize,
     pub generator: Option<String>,
+    /// Build provenance, if the builder was given any.
+    pub provenance: Option<Provenance>,
+}
+
+/// Orderings for [`Cache::entries_sorted_by`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Soname, byte-wise.
+    Name,
+    /// Library path, byte-wise.
+    Path,
+    /// ABI description, as in `arch` ("libc6,x86-64").
+    Arch,
+    /// Baseline entries first, then by glibc-hwcaps subdirectory and
+    /// legacy hwcap mask.
+    Hwcap,
 }
 
 /// A cache entry representing a library
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct CacheEntry {
     pub soname: String,
     pub path: String,
     /// Flag description as printed by ldconfig -p, e.g. "libc6,x86-64".
     pub arch: String,
+    /// The ABI `flags` decode to; prefer this over matching `arch`.
+    pub abi: Arch,
     pub hwcap: u64,
     /// glibc-hwcaps subdirectory name for extension entries.
     pub hwcaps: Option<String>,
     pub flags: u32,
+    /// Minimum kernel ABI, if the cache recorded one (glibc < 2.33).
+    pub osversion: Option<OsVersion>,
+    /// SHA-256 of the library when the cache was built, if recorded.
+    pub sha256: Option<[u8; 32]>,
+    /// Size and mtime of the library when the cache was built, if recorded.
+    pub stat: Option<FileStat>,
+}
+
+impl CacheEntry {
+    /// Capability subdirectory names encoded by a legacy (pre-glibc-hwcaps)
+    /// hwcap mask, e.g. `["i686", "tls"]`. Empty for glibc-hwcaps entries
+    /// and entries without a mask.
+    pub fn legacy_hwcaps(&self) -> Vec<String> {
+        legacy_hwcap_names(self.flags, self.hwcap)
+    }
 }
 
 impl fmt::Display for CacheEntry {
-    /// One `ldconfig -p` line, matching glibc's print_entry.
+    /// One `ldconfig -p` line, matching glibc's print_entry. The alternate
+    /// form (`{:#}`) names legacy hwcap bits instead of printing the mask.
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "\t{} ({}", self.soname, self.arch)?;
         if let Some(name) = &self.hwcaps {
             write!(f, ", hwcap: \"{}\"", name)?;
+        } else if self.hwcap != 0 && f.alternate() {
+            write!(f, ", hwcap: {}", self.legacy_hwcaps().join(", "))?;
         } else if self.hwcap != 0 {
             write!(f, ", hwcap: {:#018x}", self.hwcap)?;
         }
@@ -67,6 +108,47 @@ impl fmt::Display for CacheEntry {
     }
 }
 
+/// An entry added to a cache by hand rather than found by scanning, e.g.
+/// for a library a container runtime will bind-mount later. Nothing is
+/// checked against the filesystem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct SyntheticEntry {
+    pub soname: String,
+    pub path: String,
+    pub abi: Arch,
+    /// glibc-hwcaps subdirectory name, for an extension entry.
+    pub hwcaps: Option<String>,
+    /// Legacy hwcap mask; ignored when `hwcaps` is set.
+    pub hwcap: u64,
+}
+
+impl SyntheticEntry {
+    pub fn new(soname: impl Into<String>, path: impl Into<String>, abi: Arch) -> Self {
+        Self {
+            soname: soname.into(),
+            path: path.into(),
+            abi,
+            hwcaps: None,
+            hwcap: 0,
+        }
+    }
+
+    pub(crate) fn to_file_entry(&self) -> FileEntry {
+        FileEntry {
+            soname: self.soname.clone(),
+            path: self.path.clone(),
+            flags: self.abi.flags(),
+            isa_level: 0,
+            hwcaps: self.hwcaps.clone(),
+            hwcap: if self.hwcaps.is_some() { 0 } else { self.hwcap },
+            osversion: 0,
+            sha256: None,
+            stat: None,
+        }
+    }
+}
+
 /// Cache for dynamic linker library information
 ///
 /// This type can be used to:
@@ -75,11 +157,97 @@ impl fmt::Display for CacheEntry {
 /// - Write cache files to disk
 /// - Get cache metadata
 pub struct Cache {
-    data: Vec<u8>,
+    pub(crate) data: CacheBytes,
     info: InternalCacheInfo,
+    pub(crate) report: BuildReport,
+}
+
+/// The serialized cache: in memory, or mapped from the temporary file a
+/// spilled build wrote it into, kept until renamed into place.
+pub(crate) enum CacheBytes {
+    Owned(Vec<u8>),
+    #[cfg(unix)]
+    Mapped(
+        memmap2::Mmap,
+        std::sync::Mutex<Option<tempfile::NamedTempFile>>,
+    ),
+}
+
+impl CacheBytes {
+    /// Whether the bytes are in a file next to `path`, which
+    /// [`CacheBytes::persist`] can rename over it instead of copying.
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub(crate) fn staged_for(&self, path: &Path) -> bool {
+        match self {
+            CacheBytes::Owned(_) => false,
+            #[cfg(unix)]
+            CacheBytes::Mapped(_, file) => file
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|file| file.path().parent() == path.parent()),
+        }
+    }
+
+    /// Sync the file the bytes are mapped from and rename it to `path`,
+    /// as [`atomic_write`](crate::atomic_write::atomic_write) would have
+    /// written them; only once, and only if [`CacheBytes::staged_for`]
+    /// `path`.
+    #[cfg(unix)]
+    pub(crate) fn persist(&self, path: &Path) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let CacheBytes::Mapped(_, file) = self else {
+            return Err(io::ErrorKind::NotFound.into());
+        };
+        let mut file = file.lock().unwrap();
+        let staged = file.take().ok_or(io::ErrorKind::NotFound)?;
+        let result = staged.as_file().sync_all().and_then(|()| {
+            let mode = fs::Permissions::from_mode(0o644);
+            staged.as_file().set_permissions(mode)
+        });
+        if let Err(e) = result {
+            *file = Some(staged);
+            return Err(e);
+        }
+        staged.persist(path).map(drop).map_err(|e| {
+            *file = Some(e.file);
+            e.error
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn persist(&self, _path: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::NotFound.into())
+    }
+}
+
+impl Deref for CacheBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            CacheBytes::Owned(data) => data,
+            #[cfg(unix)]
+            CacheBytes::Mapped(map, _) => map,
+        }
+    }
+}
+
+impl From<Vec<u8>> for CacheBytes {
+    fn from(data: Vec<u8>) -> Self {
+        CacheBytes::Owned(data)
+    }
+}
+
+#[cfg(unix)]
+impl From<(memmap2::Mmap, tempfile::NamedTempFile)> for CacheBytes {
+    fn from((map, file): (memmap2::Mmap, tempfile::NamedTempFile)) -> Self {
+        CacheBytes::Mapped(map, std::sync::Mutex::new(Some(file)))
+    }
 }
 
 /// Iterator over cache entries
+#[derive(Clone)]
 pub struct CacheEntries<'a> {
     cache: &'a Cache,
     entries: std::slice::Iter<'a, cache_format::CacheEntry>,
@@ -97,75 +265,27 @@ impl<'a> Iterator for CacheEntries<'a> {
             soname,
             path,
             arch: flags_string(entry.flags),
+            abi: Arch::from_flags(entry.flags),
             hwcap: entry.hwcap,
             hwcaps: entry.hwcaps.clone(),
             flags: entry.flags,
+            osversion: OsVersion::from_raw(entry.osversion),
+            sha256: entry.sha256,
+            stat: entry.stat,
         })
     }
 }
 
-#[bon]
-impl Cache {
-    #[builder]
-    pub fn new(
-        /// Directories to scan
-        #[builder(finish_fn)]
-        search_paths: &SearchPaths,
-        /// Update symlinks in directories
-        #[builder(default = true)]
-        update_symlinks: bool,
-        #[builder(default)]
-        /// Dry run mode (don't make changes)
-        dry_run: bool,
-        /// Root prefix
-        #[builder(into, default = "/")]
-        prefix: &Utf8Path,
-    ) -> Result<Self, Error> {
-        let prefix = normalize_prefix(prefix);
-        let update_links = update_symlinks && !dry_run;
-        let dirs = collect_dirs(search_paths, &prefix);
-
-        let mut entries = Vec::new();
-        for dir in &dirs {
-            for lib in scan_dir(dir, &prefix, update_links) {
-                // The cached file name is the soname for regular
-                // directories (relying on the symlink), the actual file
-                // for glibc-hwcaps subdirectories (search_dir).
-                let value_name = match &dir.hwcaps {
-                    None => {
-                        // Don't create links to links.
-                        if update_links && !lib.is_link {
-                            symlinks::create_link(
-                                &prefix,
-                                &dir.real,
-                                &dir.path,
-                                &lib.name,
-                                &lib.soname,
-                            );
-                        }
-                        &lib.soname
-                    }
-                    Some(_) => &lib.name,
-                };
-                entries.push(FileEntry {
-                    path: format!("{}/{}", dir.path, value_name),
-                    soname: lib.soname,
-                    flags: lib.flags,
-                    isa_level: lib.isa_level,
-                    hwcaps: dir.hwcaps.clone(),
-                });
-            }
-        }
-
-        info!("Cache entries: {} libraries", entries.len());
+impl<'a> IntoIterator for &'a Cache {
+    type Item = CacheEntry;
+    type IntoIter = CacheEntries<'a>;
 
-        let data = cache_format::build_cache(&entries);
-        let info = cache_format::parse_cache(&data)?;
-        Ok(Self { data, info })
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries()
     }
 }
 
-fn normalize_prefix(prefix: &Utf8Path) -> Utf8PathBuf {
+pub(crate) fn normalize_prefix(prefix: &Utf8Path) -> Utf8PathBuf {
     let trimmed = prefix.as_str().trim_end_matches('/');
     if trimmed.is_empty() {
         Utf8PathBuf::from("/")
@@ -174,6 +294,35 @@ fn normalize_prefix(prefix: &Utf8Path) -> Utf8PathBuf {
     }
 }
 
+/// `path` with the longest prefix in `rules` replaced; prefixes only
+/// match whole components.
+pub(crate) fn remap(path: &str, rules: &[(&str, &str)]) -> String {
+    rules
+        .iter()
+        .filter_map(|&(from, to)| {
+            let from = from.trim_end_matches('/');
+            let rest = path.strip_prefix(from)?;
+            rest.starts_with('/').then_some((from.len(), to, rest))
+        })
+        .max_by_key(|&(len, _, _)| len)
+        .map_or_else(
+            || path.to_owned(),
+            |(_, to, rest)| format!("{}{}", to.trim_end_matches('/'), rest),
+        )
+}
+
+/// Whether `entry` was found by scanning `dir`: directly inside it, or in
+/// one of its glibc-hwcaps subdirectories.
+fn in_directory(entry: &FileEntry, dir: &Utf8Path) -> bool {
+    let Some(parent) = Utf8Path::new(&entry.path).parent() else {
+        return false;
+    };
+    match &entry.hwcaps {
+        None => parent == dir,
+        Some(name) => parent == dir.join("glibc-hwcaps").join(name),
+    }
+}
+
 impl Cache {
     /// Read and parse cache from file path
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
@@ -181,23 +330,321 @@ impl Cache {
         Self::from_bytes(&data)
     }
 
+    /// Read and parse a cache from any reader, e.g. stdin or a file
+    /// extracted from an image archive.
+    pub fn from_reader(mut reader: impl Read) -> Result<Self, Error> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Self::from_bytes(&data)
+    }
+
     /// Parse cache from bytes
+    ///
+    /// Only the structure ld.so relies on is checked up front; entry
+    /// strings are decoded lazily and [`entries`](Self::entries) stops at
+    /// the first one that does not. Never panics, whatever `data` holds.
     pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
         let info = cache_format::parse_cache(data)?;
         Ok(Self {
-            data: data.to_vec(),
+            data: data.to_vec().into(),
             info,
+            report: BuildReport::default(),
         })
     }
 
+    /// [`from_bytes`](Self::from_bytes) followed by [`validate`](Self::validate).
+    pub fn from_bytes_checked(data: &[u8]) -> Result<Self, Error> {
+        let cache = Self::from_bytes(data)?;
+        cache.validate()?;
+        Ok(cache)
+    }
+
+    /// Check what [`from_bytes`](Self::from_bytes) leaves for later: every
+    /// entry string is valid UTF-8, and [`rebuild`](Self::rebuild) gives
+    /// back the same entries in the same order, so lookups by binary
+    /// search work as ld.so expects.
+    pub fn validate(&self) -> Result<(), Error> {
+        for entry in &self.info.entries {
+            self.extract_string(entry.key_offset)?;
+            self.extract_string(entry.value_offset)?;
+        }
+        let rebuilt = self.rebuild()?;
+        let key = |cache: &Cache| {
+            cache
+                .entries()
+                .map(|e| {
+                    let hwcap = if e.hwcaps.is_some() { 0 } else { e.hwcap };
+                    (e.soname, e.path, e.flags, hwcap, e.hwcaps, e.osversion)
+                })
+                .collect::<Vec<_>>()
+        };
+        if key(self) != key(&rebuilt) {
+            return Err(Error::InvalidCache(
+                "entries out of order or lost on rebuild",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Serialize `entries` and parse the result back.
+    pub(crate) fn from_file_entries(
+        entries: &[FileEntry],
+        generator: Option<&str>,
+        extensions: &[RawExtension],
+    ) -> Result<Self, Error> {
+        let _span = tracing::debug_span!("serialize", entries = entries.len()).entered();
+        Self::from_serialized(cache_format::build_cache(entries, generator, extensions))
+    }
+
+    /// A cache over bytes this crate serialized.
+    pub(crate) fn from_serialized(data: impl Into<CacheBytes>) -> Result<Self, Error> {
+        let data = data.into();
+        let info = cache_format::parse_cache(&data)?;
+        Ok(Self {
+            data,
+            info,
+            report: BuildReport::default(),
+        })
+    }
+
+    /// Entries as they would be fed to the serializer.
+    fn file_entries(&self) -> Result<Vec<FileEntry>, Error> {
+        cache_format::file_entries(&self.data, &self.info)
+    }
+
+    /// Re-serialize the cache from its parsed entries and extensions.
+    ///
+    /// The result is always in the new format and carries the same
+    /// entries, strings, generator and extension sections; for caches
+    /// built by this crate, not edited since, it is byte-identical to the
+    /// input.
+    pub fn rebuild(&self) -> Result<Self, Error> {
+        Self::from_file_entries(
+            &self.file_entries()?,
+            self.info.generator.as_deref(),
+            &self.info.extensions,
+        )
+    }
+
+    /// A copy of the cache without the entries whose path is `path`, for
+    /// package-removal hooks that know what they deleted. Nothing is
+    /// rescanned; generator and extension sections are kept.
+    pub fn remove_path(&self, path: &str) -> Result<Self, Error> {
+        self.retain(|e| e.path != path)
+    }
+
+    /// A copy of the cache without any entry for `soname`, across all
+    /// ABIs and hwcaps subdirectories.
+    pub fn remove_soname(&self, soname: &str) -> Result<Self, Error> {
+        self.retain(|e| e.soname != soname)
+    }
+
+    /// Replace every entry from `dir` (including its glibc-hwcaps
+    /// subdirectories) with the entries of `scanned`, a cache built from
+    /// just that directory. Per-package install hooks use this to avoid a
+    /// full-system scan:
+    ///
+    /// ```no_run
+    /// use ldconfig::{Cache, SearchPaths};
+    ///
+    /// # #[cfg(unix)] {
+    /// let cache = Cache::from_file("/etc/ld.so.cache")?;
+    /// let dir = "/usr/lib/foo".into();
+    /// let scanned = Cache::builder().build(&SearchPaths::new(vec![dir]))?;
+    /// let cache = cache.replace_directory("/usr/lib/foo", &scanned)?;
+    /// cache.write_to_file("/etc/ld.so.cache")?;
+    /// # }
+    /// # Ok::<(), ldconfig::Error>(())
+    /// ```
+    ///
+    /// Entries of `scanned` outside `dir` are ignored. The build report of
+    /// `scanned` is carried over.
+    pub fn replace_directory(
+        &self,
+        dir: impl AsRef<Utf8Path>,
+        scanned: &Cache,
+    ) -> Result<Self, Error> {
+        let dir = normalize_prefix(dir.as_ref());
+        let mut entries = self.file_entries()?;
+        entries.retain(|e| !in_directory(e, &dir));
+        entries.extend(
+            scanned
+                .file_entries()?
+                .into_iter()
+                .filter(|e| in_directory(e, &dir)),
+        );
+        let mut cache = Self::from_file_entries(
+            &entries,
+            self.info.generator.as_deref(),
+            &self.info.extensions,
+        )?;
+        cache.report = scanned.report.clone();
+        Ok(cache)
+    }
+
+    /// A copy of the cache with the entry paths under `from` moved under
+    /// `to`, e.g. from the staging root it was built in to where the tree
+    /// is mounted at run time, without scanning again; other entries are
+    /// kept as they are. Prefixes only match whole components. The
+    /// layout, generator and extensions are kept.
+    pub fn rewrite_paths(
+        &self,
+        from: impl AsRef<Utf8Path>,
+        to: impl AsRef<Utf8Path>,
+    ) -> Result<Self, Error> {
+        let rule = [(from.as_ref().as_str(), to.as_ref().as_str())];
+        let mut entries = self.file_entries()?;
+        for entry in &mut entries {
+            entry.path = remap(&entry.path, &rule);
+        }
+        let cache = Self::from_file_entries(
+            &entries,
+            self.info.generator.as_deref(),
+            &self.info.extensions,
+        )?;
+        match self.format() {
+            CacheFormat::New => Ok(cache),
+            format => cache.with_format(format),
+        }
+    }
+
+    /// One cache per ABI found in this one, each holding that ABI's
+    /// entries in their order, in the same layout and with the same
+    /// generator and extensions: per-ABI sysroots from a single scan.
+    pub fn split_by_arch(&self) -> Result<BTreeMap<Arch, Cache>, Error> {
+        let mut arches: Vec<Arch> = self.entries().map(|e| e.abi).collect();
+        arches.sort();
+        arches.dedup();
+        arches
+            .into_iter()
+            .map(|arch| {
+                let mut part = self.retain(|e| Arch::from_flags(e.flags) == arch)?;
+                if self.format() != CacheFormat::New {
+                    part = part.with_format(self.format())?;
+                }
+                Ok((arch, part))
+            })
+            .collect()
+    }
+
+    /// A copy of the cache with `extra` added; existing entries are kept.
+    /// A new-format cache keeps its layout as far as it can: the string
+    /// table stays in place and only new strings are appended, so that
+    /// [`Cache::update_file`] rewrites few blocks.
+    pub fn add_entries(&self, extra: &[SyntheticEntry]) -> Result<Self, Error> {
+        let mut entries = self.file_entries()?;
+        entries.extend(extra.iter().map(SyntheticEntry::to_file_entry));
+        let generator = self.info.generator.as_deref();
+        if self.format() != CacheFormat::New {
+            return Self::from_file_entries(&entries, generator, &self.info.extensions);
+        }
+        let _span = tracing::debug_span!("serialize", entries = entries.len()).entered();
+        Self::from_serialized(cache_format::build_cache_over(
+            &self.data,
+            &entries,
+            generator,
+            &self.info.extensions,
+        ))
+    }
+
+    fn retain(&self, keep: impl FnMut(&FileEntry) -> bool) -> Result<Self, Error> {
+        let mut entries = self.file_entries()?;
+        entries.retain(keep);
+        Self::from_file_entries(
+            &entries,
+            self.info.generator.as_deref(),
+            &self.info.extensions,
+        )
+    }
+
+    /// Entries whose file no longer matches the size and mtime recorded
+    /// at build time, or is gone; paths are looked up inside `prefix`.
+    /// Only stats the files, so it is cheap enough for a periodic check.
+    /// Entries without recorded metadata are never reported.
+    pub fn stale_entries(&self, prefix: impl AsRef<Utf8Path>) -> Vec<CacheEntry> {
+        let prefix = normalize_prefix(prefix.as_ref());
+        self.entries()
+            .filter(|e| {
+                let Some(recorded) = e.stat else {
+                    return false;
+                };
+                let current = if prefix == "/" {
+                    Some(Utf8PathBuf::from(&e.path))
+                } else {
+                    chroot_canon(&prefix, Utf8Path::new(&e.path))
+                }
+                .and_then(|p| fs::metadata(p).ok())
+                .map(|md| FileStat::from_metadata(&md));
+                current != Some(recorded)
+            })
+            .collect()
+    }
+
+    /// Entries leading to the file at `path`, directly or through
+    /// symlinks (soname links, merged-usr aliases...): the cache keys that
+    /// break if it is deleted. Paths are resolved inside `prefix`; an
+    /// entry whose file is already gone only matches its exact path.
+    pub fn entries_for_path(
+        &self,
+        path: impl AsRef<Utf8Path>,
+        prefix: impl AsRef<Utf8Path>,
+    ) -> Vec<CacheEntry> {
+        let prefix = normalize_prefix(prefix.as_ref());
+        let resolve = |p: &Utf8Path| {
+            if prefix == "/" {
+                fs::canonicalize(p)
+                    .ok()
+                    .and_then(|p| Utf8PathBuf::try_from(p).ok())
+            } else {
+                chroot_canon(&prefix, p)
+            }
+        };
+        let path = path.as_ref();
+        let target = resolve(path);
+        self.entries()
+            .filter(|e| {
+                e.path == path || target.is_some() && resolve(Utf8Path::new(&e.path)) == target
+            })
+            .collect()
+    }
+
     /// Get cache metadata
     pub fn info(&self) -> CacheInfo {
         CacheInfo {
             num_entries: self.info.entries.len(),
             generator: self.info.generator.clone(),
+            provenance: Provenance::from_extensions(&self.info.extensions),
         }
     }
 
+    /// On-disk layout the cache was read from, or was built in.
+    pub fn format(&self) -> CacheFormat {
+        self.info.format
+    }
+
+    /// The same cache in another on-disk layout. An old-only cache drops
+    /// glibc-hwcaps entries, the generator and extension sections; modern
+    /// ld.so reads only the new layout, on its own or inside a compat one.
+    pub fn with_format(&self, format: CacheFormat) -> Result<Self, Error> {
+        let data = cache_format::build_cache_as(
+            format,
+            &self.file_entries()?,
+            self.info.generator.as_deref(),
+            &self.info.extensions,
+        )?;
+        let info = cache_format::parse_cache(&data)?;
+        Ok(Self {
+            data: data.into(),
+            info,
+            report: self.report.clone(),
+        })
+    }
+
+    /// Warnings and other details from building this cache.
+    pub fn report(&self) -> &BuildReport {
+        &self.report
+    }
+
     /// Get iterator over all entries
     pub fn entries(&self) -> CacheEntries<'_> {
         CacheEntries {
@@ -206,15 +653,116 @@ impl Cache {
         }
     }
 
+    /// Every entry as stored, in cache order, with the byte range of its
+    /// record in [`as_bytes`](Self::as_bytes): flags, absolute string
+    /// offsets, osversion and the raw hwcap field, for debugging and
+    /// repair tools. Zip with [`entries`](Self::entries) for the decoded
+    /// form.
+    pub fn raw_entries(
+        &self,
+    ) -> impl ExactSizeIterator<Item = (&raw::RawEntry, Range<usize>)> + '_ {
+        let (start, size) = match self.info.format {
+            CacheFormat::Old => (cache_format::OLD_HEADER_SIZE, cache_format::OLD_ENTRY_SIZE),
+            _ => (
+                cache_format::new_format_offset(&self.data).unwrap_or(0)
+                    + cache_format::HEADER_SIZE,
+                cache_format::ENTRY_SIZE,
+            ),
+        };
+        self.info
+            .entries
+            .iter()
+            .enumerate()
+            .map(move |(i, entry)| (entry, start + i * size..start + (i + 1) * size))
+    }
+
+    /// All entries ordered by `key`; ties keep cache order.
+    pub fn entries_sorted_by(&self, key: SortKey) -> Vec<CacheEntry> {
+        let mut entries: Vec<CacheEntry> = self.entries().collect();
+        match key {
+            SortKey::Name => entries.sort_by(|a, b| a.soname.cmp(&b.soname)),
+            SortKey::Path => entries.sort_by(|a, b| a.path.cmp(&b.path)),
+            SortKey::Arch => entries.sort_by(|a, b| a.arch.cmp(&b.arch)),
+            SortKey::Hwcap => entries.sort_by(|a, b| {
+                (a.hwcaps.is_some(), &a.hwcaps, a.hwcap).cmp(&(
+                    b.hwcaps.is_some(),
+                    &b.hwcaps,
+                    b.hwcap,
+                ))
+            }),
+        }
+        entries
+    }
+
     /// Find entries matching a library name (returns iterator)
+    ///
+    /// A name with shell wildcards (`*`, `?`, `[...]`) is a pattern for
+    /// the whole soname, like `libssl.so.*` or `lib*crypto*`; anything
+    /// else, malformed patterns included, matches as a substring.
     pub fn find<'a>(&'a self, name: &'a str) -> impl Iterator<Item = CacheEntry> + 'a {
+        let pattern = name
+            .contains(['*', '?', '['])
+            .then(|| glob::Pattern::new(name).ok())
+            .flatten();
+        self.entries().filter(move |entry| match &pattern {
+            Some(pattern) => pattern.matches(&entry.soname),
+            None => entry.soname.contains(name),
+        })
+    }
+
+    /// The entries ld.so running on `kernel` can load, in cache order:
+    /// like `_dl_load_cache_lookup`, it skips those whose recorded
+    /// osversion is newer. Entries without one are always kept.
+    pub fn entries_for_kernel(&self, kernel: OsVersion) -> impl Iterator<Item = CacheEntry> + '_ {
         self.entries()
-            .filter(move |entry| entry.soname.contains(name))
+            .filter(move |e| e.osversion.is_none_or(|v| v.to_raw() <= kernel.to_raw()))
+    }
+
+    /// Overwrite the cache file at `path` in place, writing only the
+    /// blocks that differ from what it holds, and return how many bytes
+    /// were written. Unlike [`Cache::write_to_file`] this is not atomic:
+    /// it is meant for A/B-updated systems on wear-sensitive flash, which
+    /// write the inactive slot and check it before switching to it.
+    pub fn update_file(&self, path: impl AsRef<Path>) -> Result<usize, Error> {
+        const BLOCK: usize = 4096;
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut current = Vec::new();
+        file.read_to_end(&mut current)?;
+        let mut written = 0;
+        for (i, block) in self.data.chunks(BLOCK).enumerate() {
+            let at = i * BLOCK;
+            if current.get(at..at + block.len()) != Some(block) {
+                file.seek(SeekFrom::Start(at as u64))?;
+                file.write_all(block)?;
+                written += block.len();
+            }
+        }
+        file.set_len(self.data.len() as u64)?;
+        file.sync_all()?;
+        Ok(written)
     }
 
     /// Write cache to file atomically
     pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
-        atomic_write::atomic_write(path, &self.data)?;
+        let path = path.as_ref();
+        if self.data.staged_for(path) {
+            self.data.persist(path)?;
+        } else {
+            atomic_write::atomic_write(path, &self.data)?;
+        }
+        Ok(())
+    }
+
+    /// Write the cache to any writer, e.g. stdout or a pipe into an image
+    /// builder; unlike [`Cache::write_to_file`] nothing is atomic.
+    pub fn write_to(&self, mut writer: impl Write) -> Result<(), Error> {
+        writer.write_all(&self.data)?;
+        writer.flush()?;
         Ok(())
     }
 
@@ -232,7 +780,7 @@ impl Cache {
     fn extract_string(&self, offset: u32) -> Result<String, Error> {
         let start = offset as usize;
         if start >= self.data.len() {
-            return Err(Error::InvalidCacheOffset(offset));
+            return Err(Error::InvalidCacheOffset(start));
         }
 
         let slice = &self.data[start..];
@@ -242,6 +790,83 @@ impl Cache {
     }
 }
 
+#[cfg(feature = "regex")]
+impl Cache {
+    /// Entries whose soname matches the regular expression `pattern`,
+    /// e.g. `^lib.*\.so\.[0-9]{2}$` for two-digit major versions.
+    /// Unanchored, like `grep -E`.
+    pub fn find_regex(
+        &self,
+        pattern: &str,
+    ) -> Result<impl Iterator<Item = CacheEntry> + '_, Error> {
+        let re = regex::Regex::new(pattern)?;
+        Ok(self
+            .entries()
+            .filter(move |entry| re.is_match(&entry.soname)))
+    }
+}
+
+#[cfg(feature = "signing")]
+impl Cache {
+    /// A copy of the cache carrying a detached ed25519 signature over its
+    /// canonical contents: the cache rebuilt without any signature. An
+    /// existing signature is replaced.
+    pub fn sign(&self, key: &ed25519_dalek::SigningKey) -> Result<Self, Error> {
+        use ed25519_dalek::Signer;
+
+        let (entries, mut extensions) = self.unsigned_parts()?;
+        let canonical =
+            Self::from_file_entries(&entries, self.info.generator.as_deref(), &extensions)?;
+        extensions.push(RawExtension {
+            tag: cache_format::TAG_SIGNATURE,
+            flags: 0,
+            data: key.sign(&canonical.data).to_bytes().to_vec(),
+        });
+        Self::from_file_entries(&entries, self.info.generator.as_deref(), &extensions)
+    }
+
+    /// Check the signature added by [`Cache::sign`] against `key`.
+    pub fn verify(&self, key: &ed25519_dalek::VerifyingKey) -> Result<(), Error> {
+        let signature = self
+            .info
+            .extensions
+            .iter()
+            .find(|e| e.tag == cache_format::TAG_SIGNATURE)
+            .ok_or(Error::Signature("cache is not signed"))?;
+        let signature = ed25519_dalek::Signature::from_slice(&signature.data)
+            .map_err(|_| Error::Signature("malformed signature"))?;
+        let (entries, extensions) = self.unsigned_parts()?;
+        let canonical =
+            Self::from_file_entries(&entries, self.info.generator.as_deref(), &extensions)?;
+        key.verify_strict(&canonical.data, &signature)
+            .map_err(|_| Error::Signature("signature does not match"))
+    }
+
+    fn unsigned_parts(&self) -> Result<(Vec<FileEntry>, Vec<RawExtension>), Error> {
+        let extensions = self
+            .info
+            .extensions
+            .iter()
+            .filter(|e| e.tag != cache_format::TAG_SIGNATURE)
+            .cloned()
+            .collect();
+        Ok((self.file_entries()?, extensions))
+    }
+}
+
+/// A well-formed cache built from arbitrary entries, for property tests
+/// of code consuming caches; fuzz the parser with raw bytes instead.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Cache {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let entries: Vec<SyntheticEntry> = u.arbitrary()?;
+        let generator: Option<String> = u.arbitrary()?;
+        let entries: Vec<FileEntry> = entries.iter().map(SyntheticEntry::to_file_entry).collect();
+        Self::from_file_entries(&entries, generator.as_deref(), &[])
+            .map_err(|_| arbitrary::Error::IncorrectFormat)
+    }
+}
+
 impl fmt::Display for Cache {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "{} libs found in cache", self.info.entries.len())?;
@@ -254,3 +879,628 @@ impl fmt::Display for Cache {
         Ok(())
     }
 }
+
+impl fmt::Debug for Cache {
+    /// A summary rather than the bytes: format, size, entry count,
+    /// generator, warnings and the first few entries.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const SHOWN: usize = 4;
+        let first: Vec<String> = self
+            .entries()
+            .take(SHOWN)
+            .map(|e| e.to_string().trim_start().to_owned())
+            .collect();
+        f.debug_struct("Cache")
+            .field("format", &self.info.format)
+            .field("size", &self.data.len())
+            .field("entries", &self.info.entries.len())
+            .field("generator", &self.info.generator)
+            .field("warnings", &self.report.warnings.len())
+            .field("first", &first)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A cache listed for people rather than in glibc's order, see
+/// [`Cache::alphabetical`].
+#[derive(Clone, Copy)]
+pub struct Alphabetical<'a>(&'a Cache);
+
+impl Cache {
+    /// Display the entries grouped by architecture, each group in
+    /// alphabetical order of soname, with versions compared numerically
+    /// (`libfoo.so.2` before `libfoo.so.10`); entries for the same soname
+    /// keep cache order, the one ld.so picks first. Legacy hwcap bits are
+    /// named. The plain [`Display`](fmt::Display) keeps cache order, as
+    /// `ldconfig -p` prints it.
+    pub fn alphabetical(&self) -> Alphabetical<'_> {
+        Alphabetical(self)
+    }
+}
+
+impl fmt::Display for Alphabetical<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cache = self.0;
+        let mut groups: BTreeMap<String, Vec<CacheEntry>> = BTreeMap::new();
+        for entry in cache.entries() {
+            groups.entry(entry.arch.clone()).or_default().push(entry);
+        }
+        writeln!(f, "{} libs found in cache", cache.info.entries.len())?;
+        for (arch, mut entries) in groups {
+            entries.sort_by(|a, b| cache_format::dl_cache_libcmp(&a.soname, &b.soname));
+            writeln!(f, "{} ({} libs):", arch, entries.len())?;
+            for entry in entries {
+                writeln!(f, "{:#}", entry)?;
+            }
+        }
+        if let Some(generator) = &cache.info.generator {
+            writeln!(f, "Cache generated by: {}", generator)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_format::FLAG_X8664_LIB64;
+
+    fn entry(soname: &str, path: &str) -> FileEntry {
+        FileEntry {
+            soname: soname.into(),
+            path: path.into(),
+            flags: FLAG_X8664_LIB64,
+            isa_level: 0,
+            hwcaps: None,
+            hwcap: 0,
+            osversion: 0,
+            sha256: None,
+            stat: None,
+        }
+    }
+
+    fn sample() -> Cache {
+        Cache::from_file_entries(
+            &[
+                entry("libz.so.1", "/usr/lib/libz.so.1"),
+                entry("libz.so.1", "/opt/lib/libz.so.1"),
+                entry("libm.so.6", "/usr/lib/libm.so.6"),
+            ],
+            Some("test"),
+            &[],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn raw_entries_locate_their_records() {
+        for format in [CacheFormat::New, CacheFormat::Compat, CacheFormat::Old] {
+            let cache = sample().with_format(format).unwrap();
+            let data = cache.as_bytes();
+            for ((raw, record), entry) in cache.raw_entries().zip(cache.entries()) {
+                assert_eq!(
+                    data[record.start..record.start + 4],
+                    raw.flags.to_ne_bytes()
+                );
+                assert_eq!(raw.flags, entry.flags);
+                let key = &data[raw.key_offset as usize..];
+                assert!(key.starts_with(entry.soname.as_bytes()));
+            }
+            assert_eq!(cache.raw_entries().len(), cache.info().num_entries);
+        }
+    }
+
+    #[test]
+    fn debug_summarizes() {
+        let cache = sample();
+        let debug = format!("{:?}", cache);
+        assert!(debug.starts_with(&format!(
+            "Cache {{ format: New, size: {}, entries: 3, generator: Some(\"test\"), \
+             warnings: 0, first: [\"libz.so.1 (",
+            cache.size()
+        )));
+        assert!(debug.ends_with(") => /usr/lib/libm.so.6\"], .. }"));
+    }
+
+    #[test]
+    fn alphabetical_listing_groups_by_arch() {
+        let libc6 = |soname, path, flags| FileEntry {
+            flags: cache_format::FLAG_ELF_LIBC6 | flags,
+            ..entry(soname, path)
+        };
+        let cache = Cache::from_file_entries(
+            &[
+                libc6("libfoo.so.10", "/usr/lib/libfoo.so.10", FLAG_X8664_LIB64),
+                libc6("libz.so.1", "/usr/lib/libz.so.1", FLAG_X8664_LIB64),
+                libc6("libfoo.so.2", "/usr/lib/libfoo.so.2", FLAG_X8664_LIB64),
+                libc6("libz.so.1", "/opt/lib/libz.so.1", FLAG_X8664_LIB64),
+                libc6("libz.so.1", "/usr/lib32/libz.so.1", 0),
+            ],
+            None,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            cache.alphabetical().to_string(),
+            "5 libs found in cache\n\
+             libc6 (1 libs):\n\
+             \tlibz.so.1 (libc6) => /usr/lib32/libz.so.1\n\
+             libc6,x86-64 (4 libs):\n\
+             \tlibfoo.so.2 (libc6,x86-64) => /usr/lib/libfoo.so.2\n\
+             \tlibfoo.so.10 (libc6,x86-64) => /usr/lib/libfoo.so.10\n\
+             \tlibz.so.1 (libc6,x86-64) => /usr/lib/libz.so.1\n\
+             \tlibz.so.1 (libc6,x86-64) => /opt/lib/libz.so.1\n"
+        );
+    }
+
+    #[test]
+    fn streams_through_readers_and_writers() {
+        let cache = sample();
+        let mut piped = Vec::new();
+        cache.write_to(&mut piped).unwrap();
+        assert_eq!(piped, cache.as_bytes());
+        let read = Cache::from_reader(piped.as_slice()).unwrap();
+        assert_eq!(read.as_bytes(), cache.as_bytes());
+        assert!(Cache::from_reader(&b"ld.so-1.7"[..]).is_err());
+    }
+
+    #[test]
+    fn checked_parse_rejects_what_lenient_parse_defers() {
+        let data = sample().as_bytes().to_vec();
+        assert!(Cache::from_bytes_checked(&data).is_ok());
+
+        let mut bad = data.clone();
+        let at = bad.windows(4).position(|w| w == b"libm").unwrap();
+        bad[at + 3] = 0xff;
+        assert!(Cache::from_bytes(&bad).is_ok());
+        assert!(Cache::from_bytes_checked(&bad).is_err());
+
+        // Swapped entries would defeat ld.so's binary search.
+        let unsorted = Cache::from_file_entries(
+            &[
+                entry("libz.so.1", "/usr/lib/libz.so.1"),
+                entry("liba.so.1", "/usr/lib/liba.so.1"),
+            ],
+            None,
+            &[],
+        )
+        .unwrap();
+        let mut swapped = unsorted.as_bytes().to_vec();
+        let (first, second) = (48, 48 + 24);
+        let a: Vec<u8> = swapped[first..first + 24].to_vec();
+        swapped.copy_within(second..second + 24, first);
+        swapped[second..second + 24].copy_from_slice(&a);
+        assert!(Cache::from_bytes(&swapped).is_ok());
+        assert!(Cache::from_bytes_checked(&swapped).is_err());
+    }
+
+    #[test]
+    fn splits_by_arch() {
+        let cache = Cache::from_file_entries(&[], Some("test"), &[])
+            .unwrap()
+            .add_entries(&[
+                SyntheticEntry::new("libz.so.1", "/usr/lib64/libz.so.1", Arch::X86_64),
+                SyntheticEntry::new("libz.so.1", "/usr/lib/libz.so.1", Arch::Libc6),
+                SyntheticEntry::new("libm.so.6", "/usr/lib64/libm.so.6", Arch::X86_64),
+            ])
+            .unwrap()
+            .with_format(CacheFormat::Compat)
+            .unwrap();
+        let split = cache.split_by_arch().unwrap();
+        assert_eq!(
+            split.keys().copied().collect::<Vec<_>>(),
+            [Arch::Libc6, Arch::X86_64]
+        );
+        let x86_64 = &split[&Arch::X86_64];
+        assert_eq!(x86_64.format(), CacheFormat::Compat);
+        assert_eq!(x86_64.info().generator.as_deref(), Some("test"));
+        assert!(x86_64.entries().all(|e| e.path.starts_with("/usr/lib64/")));
+        assert_eq!(x86_64.entries().count(), 2);
+        assert_eq!(split[&Arch::Libc6].entries().count(), 1);
+    }
+
+    #[test]
+    fn converts_between_formats() {
+        let mut hw = entry("libz.so.1", "/usr/lib/glibc-hwcaps/x86-64-v3/libz.so.1");
+        hw.hwcaps = Some("x86-64-v3".into());
+        let cache = Cache::from_file_entries(
+            &[hw, entry("libz.so.1", "/usr/lib/libz.so.1")],
+            Some("test"),
+            &[],
+        )
+        .unwrap();
+        let paths = |c: &Cache| c.entries().map(|e| e.path).collect::<Vec<_>>();
+
+        let compat = cache.with_format(CacheFormat::Compat).unwrap();
+        assert_eq!(compat.format(), CacheFormat::Compat);
+        assert_eq!(paths(&compat), paths(&cache));
+        assert_eq!(compat.info().generator.as_deref(), Some("test"));
+
+        let old = cache.with_format(CacheFormat::Old).unwrap();
+        assert_eq!(old.format(), CacheFormat::Old);
+        assert_eq!(paths(&old), ["/usr/lib/libz.so.1"]);
+        assert_eq!(old.entries().next().unwrap().flags, FLAG_X8664_LIB64);
+
+        // Going back yields the new layout again.
+        let new = compat.with_format(CacheFormat::New).unwrap();
+        assert_eq!(new.as_bytes(), cache.as_bytes());
+        assert_eq!(
+            "compat".parse::<CacheFormat>().unwrap(),
+            CacheFormat::Compat
+        );
+        assert!("libc5".parse::<CacheFormat>().is_err());
+    }
+
+    #[test]
+    fn iterates_by_reference() {
+        let cache = sample();
+        let mut sonames = Vec::new();
+        for e in &cache {
+            sonames.push(e.soname);
+        }
+        assert_eq!(sonames.len(), 3);
+
+        let mut entries = cache.entries();
+        entries.next();
+        let rest = entries.clone();
+        assert_eq!(entries.count(), 2);
+        assert_eq!(rest.count(), 2);
+    }
+
+    #[test]
+    fn remove_path_drops_only_that_file() {
+        let cache = sample().remove_path("/opt/lib/libz.so.1").unwrap();
+        let paths: Vec<String> = cache.entries().map(|e| e.path).collect();
+        assert_eq!(paths, ["/usr/lib/libz.so.1", "/usr/lib/libm.so.6"]);
+        assert_eq!(cache.info().generator.as_deref(), Some("test"));
+    }
+
+    #[test]
+    fn replace_directory_splices_entries() {
+        let mut hw = entry("libz.so.1", "/opt/lib/glibc-hwcaps/x86-64-v3/libz.so.1.3");
+        hw.hwcaps = Some("x86-64-v3".into());
+        let cache = Cache::from_file_entries(
+            &[
+                entry("libz.so.1", "/usr/lib/libz.so.1"),
+                entry("libz.so.1", "/opt/lib/libz.so.1"),
+                entry("libold.so.1", "/opt/lib/libold.so.1"),
+                hw,
+            ],
+            Some("test"),
+            &[],
+        )
+        .unwrap();
+        let scanned = Cache::from_file_entries(
+            &[
+                entry("libnew.so.2", "/opt/lib/libnew.so.2"),
+                entry("libz.so.1", "/opt/lib/libz.so.1"),
+                entry("libstray.so.1", "/elsewhere/libstray.so.1"),
+            ],
+            None,
+            &[],
+        )
+        .unwrap();
+
+        let spliced = cache.replace_directory("/opt/lib/", &scanned).unwrap();
+        let mut paths: Vec<String> = spliced.entries().map(|e| e.path).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "/opt/lib/libnew.so.2",
+                "/opt/lib/libz.so.1",
+                "/usr/lib/libz.so.1"
+            ]
+        );
+        assert_eq!(spliced.info().generator.as_deref(), Some("test"));
+    }
+
+    #[test]
+    fn stale_entries_compare_size_and_mtime() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::create_dir(root.join("lib")).unwrap();
+        fs::write(root.join("lib/liba.so.1"), b"same").unwrap();
+        fs::write(root.join("lib/libb.so.1"), b"old").unwrap();
+        let stat = |name: &str| {
+            Some(FileStat::from_metadata(
+                &fs::metadata(root.join(name)).unwrap(),
+            ))
+        };
+
+        let mut a = entry("liba.so.1", "/lib/liba.so.1");
+        a.stat = stat("lib/liba.so.1");
+        let mut b = entry("libb.so.1", "/lib/libb.so.1");
+        b.stat = stat("lib/libb.so.1");
+        let mut gone = entry("libc.so.1", "/lib/libc.so.1");
+        gone.stat = a.stat;
+        let unrecorded = entry("libd.so.1", "/lib/libd.so.1");
+        let cache = Cache::from_file_entries(&[a, b, gone, unrecorded], None, &[]).unwrap();
+        assert!(cache.entries().any(|e| e.stat.is_some()));
+
+        fs::write(root.join("lib/libb.so.1"), b"newer").unwrap();
+        let mut stale: Vec<String> = cache
+            .stale_entries(&root)
+            .into_iter()
+            .map(|e| e.soname)
+            .collect();
+        stale.sort();
+        assert_eq!(stale, ["libb.so.1", "libc.so.1"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn entries_for_path_follows_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::create_dir_all(root.join("usr")).unwrap();
+        fs::create_dir(root.join("lib")).unwrap();
+        symlink("../lib", root.join("usr/lib")).unwrap();
+        fs::write(root.join("lib/libz.so.1.3"), b"z").unwrap();
+        symlink("libz.so.1.3", root.join("lib/libz.so.1")).unwrap();
+        fs::write(root.join("lib/libm.so.6"), b"m").unwrap();
+
+        let cache = Cache::from_file_entries(
+            &[
+                entry("libz.so.1", "/lib/libz.so.1"),
+                entry("libz.so.1", "/usr/lib/libz.so.1"),
+                entry("libm.so.6", "/lib/libm.so.6"),
+                entry("libgone.so.1", "/lib/libgone.so.1"),
+            ],
+            None,
+            &[],
+        )
+        .unwrap();
+        let paths = |p: &str| -> Vec<String> {
+            let mut found: Vec<String> = cache
+                .entries_for_path(p, &root)
+                .into_iter()
+                .map(|e| e.path)
+                .collect();
+            found.sort();
+            found
+        };
+        assert_eq!(
+            paths("/lib/libz.so.1.3"),
+            ["/lib/libz.so.1", "/usr/lib/libz.so.1"]
+        );
+        assert_eq!(paths("/usr/lib/libm.so.6"), ["/lib/libm.so.6"]);
+        assert_eq!(paths("/lib/libgone.so.1"), ["/lib/libgone.so.1"]);
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn signatures_cover_the_entries() {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let signed = sample().sign(&key).unwrap();
+        signed.verify(&key.verifying_key()).unwrap();
+        // Re-signing replaces rather than stacks signatures.
+        assert_eq!(signed.sign(&key).unwrap().as_bytes(), signed.as_bytes());
+        // Parsing keeps the signature; rebuilding is byte-identical.
+        let reread = Cache::from_bytes(signed.as_bytes()).unwrap();
+        reread.verify(&key.verifying_key()).unwrap();
+
+        let other = ed25519_dalek::SigningKey::from_bytes(&[8; 32]);
+        assert!(signed.verify(&other.verifying_key()).is_err());
+        assert!(sample().verify(&key.verifying_key()).is_err());
+        let tampered = signed.remove_soname("libm.so.6").unwrap();
+        assert!(tampered.verify(&key.verifying_key()).is_err());
+    }
+
+    #[test]
+    fn synthetic_entries_are_added() {
+        let mut hw = SyntheticEntry::new(
+            "libgpu.so.1",
+            "/run/gpu/glibc-hwcaps/x86-64-v3/libgpu.so.1",
+            Arch::X86_64,
+        );
+        hw.hwcaps = Some("x86-64-v3".into());
+        let cache = sample()
+            .add_entries(&[
+                SyntheticEntry::new("libgpu.so.1", "/run/gpu/libgpu.so.1", Arch::X86_64),
+                hw,
+            ])
+            .unwrap();
+        let gpu: Vec<CacheEntry> = cache.find("libgpu").collect();
+        assert_eq!(gpu.len(), 2);
+        assert_eq!(gpu[0].hwcaps.as_deref(), Some("x86-64-v3"));
+        assert_eq!(gpu[1].path, "/run/gpu/libgpu.so.1");
+        assert!(gpu.iter().all(|e| e.abi == Arch::X86_64));
+        assert_eq!(cache.entries().count(), 5);
+    }
+
+    #[test]
+    fn added_entries_keep_the_layout() {
+        let entries: Vec<FileEntry> = (0..2000)
+            .map(|i| {
+                let soname = format!("libpkg{}.so.1", i);
+                entry(&soname, &format!("/usr/lib/{}", soname))
+            })
+            .collect();
+        let cache = Cache::from_file_entries(&entries, Some("test"), &[]).unwrap();
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("ld.so.cache");
+        cache.write_to_file(&path).unwrap();
+
+        let extra = SyntheticEntry::new("libnew.so.1", "/opt/lib/libnew.so.1", Arch::X86_64);
+        let added = cache.add_entries(std::slice::from_ref(&extra)).unwrap();
+        added.validate().unwrap();
+        let rebuilt = Cache::from_file_entries(&entries, Some("test"), &[])
+            .unwrap()
+            .add_entries(&[])
+            .unwrap();
+        assert_eq!(rebuilt.as_bytes(), cache.as_bytes());
+        let names = |c: &Cache| -> Vec<(String, String)> {
+            c.entries().map(|e| (e.soname, e.path)).collect()
+        };
+        let mut all = entries.clone();
+        all.push(extra.to_file_entry());
+        let full = Cache::from_file_entries(&all, Some("test"), &[]).unwrap();
+        assert_eq!(names(&added), names(&full));
+
+        let written = added.update_file(&path).unwrap();
+        assert!(
+            written * 2 < added.size(),
+            "{} of {}",
+            written,
+            added.size()
+        );
+        assert_eq!(fs::read(&path).unwrap(), added.as_bytes());
+        assert_eq!(added.update_file(&path).unwrap(), 0);
+    }
+
+    #[test]
+    fn paths_move_to_another_prefix() {
+        let staged = Cache::from_file_entries(
+            &[
+                entry("libz.so.1", "/stage/usr/lib/libz.so.1"),
+                entry("libm.so.6", "/stage-old/lib/libm.so.6"),
+                entry("libc.so.6", "/lib/libc.so.6"),
+            ],
+            Some("test"),
+            &[],
+        )
+        .unwrap();
+        let paths = |c: &Cache| {
+            let mut paths: Vec<String> = c.entries().map(|e| e.path).collect();
+            paths.sort();
+            paths
+        };
+
+        let moved = staged.rewrite_paths("/stage/", "/").unwrap();
+        assert_eq!(
+            paths(&moved),
+            [
+                "/lib/libc.so.6",
+                "/stage-old/lib/libm.so.6",
+                "/usr/lib/libz.so.1"
+            ]
+        );
+        assert_eq!(moved.info().generator.as_deref(), Some("test"));
+
+        let old = staged.with_format(CacheFormat::Old).unwrap();
+        let moved = old.rewrite_paths("/stage", "/srv/root").unwrap();
+        assert_eq!(moved.format(), CacheFormat::Old);
+        assert!(paths(&moved).contains(&"/srv/root/usr/lib/libz.so.1".to_string()));
+    }
+
+    #[test]
+    fn newer_kernel_entries_are_skipped() {
+        let with_os = |soname: &str, os: OsVersion| FileEntry {
+            osversion: os.to_raw(),
+            ..entry(soname, &format!("/lib/{}", soname))
+        };
+        let cache = Cache::from_file_entries(
+            &[
+                with_os("libold.so.1", OsVersion::linux(2, 6, 32)),
+                with_os("libnew.so.1", OsVersion::linux(5, 10, 0)),
+                entry("libany.so.1", "/lib/libany.so.1"),
+            ],
+            Some("test"),
+            &[],
+        )
+        .unwrap();
+
+        let sonames = |kernel| -> Vec<String> {
+            let mut names: Vec<String> =
+                cache.entries_for_kernel(kernel).map(|e| e.soname).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(
+            sonames(OsVersion::linux(3, 2, 0)),
+            ["libany.so.1", "libold.so.1"]
+        );
+        assert_eq!(sonames(OsVersion::linux(5, 10, 0)).len(), 3);
+    }
+
+    #[test]
+    fn find_accepts_shell_patterns() {
+        let sonames = |name| -> Vec<String> { sample().find(name).map(|e| e.soname).collect() };
+        assert_eq!(sonames("z.so"), ["libz.so.1", "libz.so.1"]);
+        assert_eq!(sonames("libm.so.*"), ["libm.so.6"]);
+        assert_eq!(sonames("lib?.so.1"), ["libz.so.1", "libz.so.1"]);
+        // Patterns match the whole soname.
+        assert!(sonames("libm*.so").is_empty());
+        // Malformed patterns fall back to substrings.
+        assert!(sonames("lib[z").is_empty());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn find_regex_matches_sonames() {
+        let cache = sample()
+            .add_entries(&[SyntheticEntry::new(
+                "libicuuc.so.72",
+                "/usr/lib/libicuuc.so.72",
+                Arch::X86_64,
+            )])
+            .unwrap();
+        let found: Vec<String> = cache
+            .find_regex(r"\.so\.[0-9]{2}$")
+            .unwrap()
+            .map(|e| e.soname)
+            .collect();
+        assert_eq!(found, ["libicuuc.so.72"]);
+        assert_eq!(cache.find_regex("^lib[mz]").unwrap().count(), 3);
+        assert!(cache.find_regex("(").is_err());
+    }
+
+    #[test]
+    fn sorts_entries_stably() {
+        let mut hw = SyntheticEntry::new(
+            "libm.so.6",
+            "/usr/lib/glibc-hwcaps/x86-64-v3/libm.so.6",
+            Arch::X86_64,
+        );
+        hw.hwcaps = Some("x86-64-v3".into());
+        let cache = sample().add_entries(&[hw]).unwrap();
+        let paths = |key| -> Vec<String> {
+            cache
+                .entries_sorted_by(key)
+                .into_iter()
+                .map(|e| e.path)
+                .collect()
+        };
+        assert_eq!(
+            paths(SortKey::Path),
+            [
+                "/opt/lib/libz.so.1",
+                "/usr/lib/glibc-hwcaps/x86-64-v3/libm.so.6",
+                "/usr/lib/libm.so.6",
+                "/usr/lib/libz.so.1",
+            ]
+        );
+        let names: Vec<String> = cache
+            .entries_sorted_by(SortKey::Name)
+            .into_iter()
+            .map(|e| e.soname)
+            .collect();
+        assert_eq!(names, ["libm.so.6", "libm.so.6", "libz.so.1", "libz.so.1"]);
+        assert_eq!(
+            paths(SortKey::Hwcap).last().unwrap(),
+            "/usr/lib/glibc-hwcaps/x86-64-v3/libm.so.6"
+        );
+        let mixed = cache
+            .add_entries(&[SyntheticEntry::new(
+                "libz.so.1",
+                "/usr/lib32/libz.so.1",
+                Arch::Libc6,
+            )])
+            .unwrap();
+        let first = mixed.entries_sorted_by(SortKey::Arch).remove(0);
+        assert_eq!(first.path, "/usr/lib32/libz.so.1");
+    }
+
+    #[test]
+    fn remove_soname_drops_every_entry() {
+        let cache = sample().remove_soname("libz.so.1").unwrap();
+        let sonames: Vec<String> = cache.entries().map(|e| e.soname).collect();
+        assert_eq!(sonames, ["libm.so.6"]);
+        // Unknown names leave the cache byte-identical.
+        let same = cache.remove_soname("libnope.so").unwrap();
+        assert_eq!(same.as_bytes(), cache.as_bytes());
+    }
+}
@@ -1,52 +1,86 @@
-//! ld.so.cache binary format (new format only, magic glibc-ld.so.cache1.1).
+//! ld.so.cache binary format. The new format (magic glibc-ld.so.cache1.1)
+//! is written; the old libc5-era format and the compat layout (old header
+//! followed by a new cache) are also read.
 //!
 //! Layout and constants follow glibc's elf/cache.c and
 //! sysdeps/generic/dl-cache.h; flag values sysdeps/generic/ldconfig.h.
 
+use crate::chroot::chroot_canon;
 use crate::error::Error;
+use camino::{Utf8Path, Utf8PathBuf};
 use std::cmp::Ordering;
 use std::collections::HashMap;
-use tracing::debug;
-
-pub(crate) const CACHE_MAGIC: [u8; 20] = *b"glibc-ld.so.cache1.1";
-
-pub(crate) const FLAG_TYPE_MASK: u32 = 0x00ff;
-pub(crate) const FLAG_REQUIRED_MASK: u32 = 0xff00;
-pub(crate) const FLAG_ELF_LIBC6: u32 = 0x0003;
-pub(crate) const FLAG_SPARC_LIB64: u32 = 0x0100;
-pub(crate) const FLAG_X8664_LIB64: u32 = 0x0300;
-pub(crate) const FLAG_S390_LIB64: u32 = 0x0400;
-pub(crate) const FLAG_POWERPC_LIB64: u32 = 0x0500;
-pub(crate) const FLAG_MIPS64_LIBN32: u32 = 0x0600;
-pub(crate) const FLAG_MIPS64_LIBN64: u32 = 0x0700;
-pub(crate) const FLAG_X8664_LIBX32: u32 = 0x0800;
-pub(crate) const FLAG_ARM_LIBHF: u32 = 0x0900;
-pub(crate) const FLAG_AARCH64_LIB64: u32 = 0x0a00;
-pub(crate) const FLAG_ARM_LIBSF: u32 = 0x0b00;
-pub(crate) const FLAG_MIPS_LIB32_NAN2008: u32 = 0x0c00;
-pub(crate) const FLAG_MIPS64_LIBN32_NAN2008: u32 = 0x0d00;
-pub(crate) const FLAG_MIPS64_LIBN64_NAN2008: u32 = 0x0e00;
-pub(crate) const FLAG_RISCV_FLOAT_ABI_SOFT: u32 = 0x0f00;
-pub(crate) const FLAG_RISCV_FLOAT_ABI_DOUBLE: u32 = 0x1000;
-pub(crate) const FLAG_LARCH_FLOAT_ABI_SOFT: u32 = 0x1100;
-pub(crate) const FLAG_LARCH_FLOAT_ABI_DOUBLE: u32 = 0x1200;
-
-const EXTENSION_MAGIC: u32 = 0xEAA4_2174;
-const TAG_GENERATOR: u32 = 0;
-const TAG_GLIBC_HWCAPS: u32 = 1;
+use std::fmt;
+use std::fs;
+use tracing::{debug, warn};
+
+pub const CACHE_MAGIC: [u8; 20] = *b"glibc-ld.so.cache1.1";
+pub const OLD_CACHE_MAGIC: [u8; 11] = *b"ld.so-1.7.0";
+
+pub const FLAG_TYPE_MASK: u32 = 0x00ff;
+pub const FLAG_REQUIRED_MASK: u32 = 0xff00;
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) const FLAG_ELF: u32 = 0x0001;
+pub const FLAG_ELF_LIBC6: u32 = 0x0003;
+pub const FLAG_SPARC_LIB64: u32 = 0x0100;
+pub const FLAG_X8664_LIB64: u32 = 0x0300;
+pub const FLAG_S390_LIB64: u32 = 0x0400;
+pub const FLAG_POWERPC_LIB64: u32 = 0x0500;
+pub const FLAG_MIPS64_LIBN32: u32 = 0x0600;
+pub const FLAG_MIPS64_LIBN64: u32 = 0x0700;
+pub const FLAG_X8664_LIBX32: u32 = 0x0800;
+pub const FLAG_ARM_LIBHF: u32 = 0x0900;
+pub const FLAG_AARCH64_LIB64: u32 = 0x0a00;
+pub const FLAG_ARM_LIBSF: u32 = 0x0b00;
+pub const FLAG_MIPS_LIB32_NAN2008: u32 = 0x0c00;
+pub const FLAG_MIPS64_LIBN32_NAN2008: u32 = 0x0d00;
+pub const FLAG_MIPS64_LIBN64_NAN2008: u32 = 0x0e00;
+pub const FLAG_RISCV_FLOAT_ABI_SOFT: u32 = 0x0f00;
+pub const FLAG_RISCV_FLOAT_ABI_DOUBLE: u32 = 0x1000;
+pub const FLAG_LARCH_FLOAT_ABI_SOFT: u32 = 0x1100;
+pub const FLAG_LARCH_FLOAT_ABI_DOUBLE: u32 = 0x1200;
+
+pub const EXTENSION_MAGIC: u32 = 0xEAA4_2174;
+pub const TAG_GENERATOR: u32 = 0;
+pub const TAG_GLIBC_HWCAPS: u32 = 1;
+// Crate-specific tags, far from glibc's small sequential ones; ld.so and
+// glibc ldconfig skip tags they do not know.
+/// SHA-256 per entry, in entry order; all zero for none.
+pub const TAG_CONTENT_SHA256: u32 = 0x6c64_7201;
+/// Size and mtime per entry ([`FILE_STAT_SIZE`] bytes), in entry order.
+pub const TAG_FILE_STAT: u32 = 0x6c64_7202;
+/// Detached ed25519 signature; kept as a raw extension so it survives
+/// rebuilds, and always written last.
+pub const TAG_SIGNATURE: u32 = 0x6c64_7203;
+/// Build provenance as `key=value` lines.
+pub const TAG_PROVENANCE: u32 = 0x6c64_7204;
+pub const FILE_STAT_SIZE: usize = 24;
 
 /// Marks the hwcap field as a glibc-hwcaps string index (dl-cache.h).
-const DL_CACHE_HWCAP_EXTENSION: u64 = 1 << 62;
-const DL_CACHE_HWCAP_ISA_LEVEL_MASK: u64 = (1 << 10) - 1;
+pub const DL_CACHE_HWCAP_EXTENSION: u64 = 1 << 62;
+pub const DL_CACHE_HWCAP_ISA_LEVEL_MASK: u64 = (1 << 10) - 1;
+
+/// Legacy (pre-2.33) hwcap masks: the "tls" subdirectory bit and, on x86,
+/// the platform bits from sysdeps/x86/dl-procinfo.h.
+const LEGACY_HWCAP_TLS: u64 = 1 << 63;
+const LEGACY_X86_FIRST_PLATFORM: u32 = 48;
+const LEGACY_X86_PLATFORMS: [&str; 4] = ["i586", "i686", "haswell", "xeon_phi"];
+const LEGACY_X86_HWCAPS: [&str; 3] = ["sse2", "x86_64", "avx512_1"];
 
-const HEADER_SIZE: usize = 48;
-const ENTRY_SIZE: usize = 24;
+/// struct cache_file_new, up to the first entry.
+pub const HEADER_SIZE: usize = 48;
+/// struct file_entry_new.
+pub const ENTRY_SIZE: usize = 24;
+/// struct cache_file: the 11-byte magic padded to 12, then nlibs.
+pub const OLD_HEADER_SIZE: usize = 16;
+/// struct file_entry.
+pub const OLD_ENTRY_SIZE: usize = 12;
 
-const ENDIAN_CURRENT: u8 = if cfg!(target_endian = "little") { 2 } else { 3 };
+pub(crate) const ENDIAN_CURRENT: u8 = if cfg!(target_endian = "little") { 2 } else { 3 };
 
 /// One library destined for the cache.
 #[derive(Debug, Clone)]
-pub(crate) struct FileEntry {
+pub struct FileEntry {
     /// Cache key: the soname.
     pub soname: String,
     /// Cache value: the full path text.
@@ -56,28 +90,336 @@ pub(crate) struct FileEntry {
     pub isa_level: u32,
     /// glibc-hwcaps subdirectory name, if any.
     pub hwcaps: Option<String>,
+    /// Legacy hwcap mask, written for entries without `hwcaps`.
+    pub hwcap: u64,
+    /// Raw osversion field; glibc stopped filling it in 2.33.
+    pub osversion: u32,
+    /// SHA-256 of the library contents, when hashing was requested.
+    pub sha256: Option<[u8; 32]>,
+    /// Size and mtime of the library, when requested.
+    pub stat: Option<FileStat>,
+}
+
+/// Size and modification time of a library file, as recorded in the
+/// file metadata extension. Comparing it against a fresh `stat` is a
+/// cheap staleness check that needs no ELF parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct FileStat {
+    pub size: u64,
+    /// Seconds since the epoch.
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+}
+
+impl FileStat {
+    #[cfg(unix)]
+    pub fn from_metadata(md: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self {
+            size: md.size(),
+            mtime: md.mtime(),
+            mtime_nsec: md.mtime_nsec() as u32,
+        }
+    }
+
+    /// Same encoding as `struct stat` on Unix: whole seconds rounded
+    /// down, then the nanosecond remainder.
+    #[cfg(not(unix))]
+    pub fn from_metadata(md: &fs::Metadata) -> Self {
+        use std::time::UNIX_EPOCH;
+        let (mtime, mtime_nsec) = match md.modified().map(|t| t.duration_since(UNIX_EPOCH)) {
+            Ok(Ok(d)) => (d.as_secs() as i64, d.subsec_nanos()),
+            Ok(Err(before)) => {
+                let d = before.duration();
+                match d.subsec_nanos() {
+                    0 => (-(d.as_secs() as i64), 0),
+                    n => (-(d.as_secs() as i64) - 1, 1_000_000_000 - n),
+                }
+            }
+            Err(_) => (0, 0),
+        };
+        Self {
+            size: md.len(),
+            mtime,
+            mtime_nsec,
+        }
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; FILE_STAT_SIZE] {
+        let mut b = [0u8; FILE_STAT_SIZE];
+        b[..8].copy_from_slice(&self.size.to_ne_bytes());
+        b[8..16].copy_from_slice(&self.mtime.to_ne_bytes());
+        b[16..20].copy_from_slice(&self.mtime_nsec.to_ne_bytes());
+        b
+    }
+
+    pub(crate) fn from_bytes(b: &[u8]) -> Option<Self> {
+        let stat = Self {
+            size: read_u64(b, 0)?,
+            mtime: read_u64(b, 8)? as i64,
+            mtime_nsec: read_u32(b, 16)?,
+        };
+        // All zero marks an entry without metadata; no library is empty.
+        (stat.size != 0).then_some(stat)
+    }
+}
+
+/// On-disk layout of a cache file, detected from its magic(s).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum CacheFormat {
+    /// Old libc5-era format only (`ld.so-1.7.0`).
+    Old,
+    /// New format only (`glibc-ld.so.cache1.1`), written since glibc 2.32.
+    New,
+    /// Old header and entries followed by an embedded new-format cache.
+    Compat,
+}
+
+impl std::str::FromStr for CacheFormat {
+    type Err = Error;
+
+    /// The names glibc's `ldconfig -c` takes: old, compat or new.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "old" => Ok(CacheFormat::Old),
+            "compat" => Ok(CacheFormat::Compat),
+            "new" => Ok(CacheFormat::New),
+            _ => Err(Error::UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for CacheFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CacheFormat::Old => "old (ld.so-1.7.0)",
+            CacheFormat::New => "new (glibc-ld.so.cache1.1)",
+            CacheFormat::Compat => "compat (ld.so-1.7.0 + glibc-ld.so.cache1.1)",
+        })
+    }
+}
+
+/// Kernel ABI version an entry requires, from the library's
+/// NT_GNU_ABI_TAG note (the entry osversion field).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct OsVersion {
+    /// ABI tag OS number: 0 Linux, 1 Hurd, 2 Solaris, 3 FreeBSD, ...
+    pub os: u8,
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+/// OS names for the ABI tag, in the order of glibc's abi_tag_os.
+const ABI_TAG_OS: [&str; 6] = ["Linux", "Hurd", "Solaris", "FreeBSD", "kNetBSD", "Syllable"];
+
+impl OsVersion {
+    /// A Linux kernel version.
+    pub fn linux(major: u8, minor: u8, patch: u8) -> Self {
+        Self {
+            os: 0,
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Decode the raw field, `None` when unset (0).
+    pub fn from_raw(raw: u32) -> Option<Self> {
+        let [os, major, minor, patch] = raw.to_be_bytes();
+        (raw != 0).then_some(Self {
+            os,
+            major,
+            minor,
+            patch,
+        })
+    }
+
+    /// Encode as stored in the cache: `os << 24 | major << 16 | minor << 8 | patch`.
+    pub fn to_raw(self) -> u32 {
+        u32::from_be_bytes([self.os, self.major, self.minor, self.patch])
+    }
+
+    /// The (major, minor, patch) version triple.
+    pub fn triple(self) -> (u8, u8, u8) {
+        (self.major, self.minor, self.patch)
+    }
+
+    /// OS name, "Unknown OS" for numbers glibc does not know either.
+    pub fn os_name(self) -> &'static str {
+        ABI_TAG_OS
+            .get(self.os as usize)
+            .copied()
+            .unwrap_or("Unknown OS")
+    }
 }
 
+impl fmt::Display for OsVersion {
+    /// e.g. "Linux 3.2.0", as older glibc printed it after "OS ABI:".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}.{}.{}",
+            self.os_name(),
+            self.major,
+            self.minor,
+            self.patch
+        )
+    }
+}
+
+/// A glibc release, as in "2.28"; orders like the releases do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct GlibcVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl GlibcVersion {
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+
+    /// Oldest ld.so reading the new format; older ones need the old one.
+    pub const NEW_FORMAT: Self = Self::new(2, 2);
+    /// Oldest ld.so aware of the extension section (generator, ...).
+    pub const EXTENSIONS: Self = Self::new(2, 33);
+    /// Oldest ld.so loading from glibc-hwcaps subdirectories, whose
+    /// entries also carry the ISA level.
+    pub const GLIBC_HWCAPS: Self = Self::new(2, 33);
+
+    /// The glibc installed in `prefix` ("/" for the host), read from its
+    /// libc.so.6 without running anything, so foreign roots work too.
+    /// The release banner is used when present, otherwise the newest
+    /// `GLIBC_2.N` symbol version, which may trail the release. None
+    /// when no glibc is found.
+    pub fn detect(prefix: impl AsRef<Utf8Path>) -> Option<Self> {
+        let prefix = prefix.as_ref();
+        let mut dirs: Vec<Utf8PathBuf> = Vec::new();
+        for dir in ["/lib", "/usr/lib"] {
+            // Debian-style multiarch directories (/lib/x86_64-linux-gnu).
+            let Some(real) = chroot_canon(prefix, Utf8Path::new(dir)) else {
+                continue;
+            };
+            let Ok(rd) = fs::read_dir(real) else {
+                continue;
+            };
+            let mut multiarch: Vec<Utf8PathBuf> = rd
+                .flatten()
+                .filter_map(|e| e.file_name().into_string().ok())
+                .filter(|name| name.contains("-linux-"))
+                .map(|name| Utf8Path::new(dir).join(name))
+                .collect();
+            multiarch.sort();
+            dirs.extend(multiarch);
+        }
+        dirs.extend(["/lib64", "/usr/lib64", "/lib", "/usr/lib"].map(Utf8PathBuf::from));
+        dirs.iter().find_map(|dir| {
+            let real = chroot_canon(prefix, &dir.join("libc.so.6"))?;
+            let version = Self::from_libc(&fs::read(&real).ok()?)?;
+            debug!("Detected glibc {} from {}", version, real);
+            Some(version)
+        })
+    }
+
+    fn from_libc(data: &[u8]) -> Option<Self> {
+        // "GNU C Library (...) stable release version 2.36."
+        let banner = b"release version ";
+        if let Some(at) = data.windows(banner.len()).position(|w| w == banner) {
+            let rest = &data[at + banner.len()..];
+            let end = rest
+                .iter()
+                .position(|&b| !(b.is_ascii_digit() || b == b'.'))
+                .unwrap_or(rest.len());
+            if let Some(version) = std::str::from_utf8(&rest[..end])
+                .ok()
+                .and_then(|v| v.trim_end_matches('.').parse().ok())
+            {
+                return Some(version);
+            }
+        }
+        let node = b"GLIBC_2.";
+        data.windows(node.len())
+            .enumerate()
+            .filter(|(_, w)| w == node)
+            .filter_map(|(at, _)| {
+                let rest = &data[at + node.len()..];
+                let end = rest.iter().position(|b| !b.is_ascii_digit())?;
+                let minor = std::str::from_utf8(&rest[..end]).ok()?.parse().ok()?;
+                Some(Self::new(2, minor))
+            })
+            .max()
+    }
+}
+
+impl std::str::FromStr for GlibcVersion {
+    type Err = Error;
+
+    /// "2.28"; a patch level ("2.31.9000") is ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('.');
+        let mut number = || parts.next().and_then(|n| n.parse().ok());
+        match (number(), number()) {
+            (Some(major), Some(minor)) => Ok(Self { major, minor }),
+            _ => Err(Error::UnknownGlibcVersion(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for GlibcVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// An entry as stored: string offsets instead of strings.
 #[derive(Debug, Clone)]
-pub(crate) struct CacheEntry {
+pub struct CacheEntry {
     pub flags: u32,
+    /// Absolute offset of the soname in the parsed buffer.
     pub key_offset: u32,
+    /// Absolute offset of the path in the parsed buffer.
     pub value_offset: u32,
+    pub osversion: u32,
+    /// Legacy mask, or [`DL_CACHE_HWCAP_EXTENSION`] with the ISA level
+    /// and glibc-hwcaps index.
     pub hwcap: u64,
     /// Resolved glibc-hwcaps subdirectory name for extension entries.
     pub hwcaps: Option<String>,
+    /// From the content hash extension, if present.
+    pub sha256: Option<[u8; 32]>,
+    /// From the file metadata extension, if present.
+    pub stat: Option<FileStat>,
+}
+
+/// An extension section this crate does not interpret, kept verbatim so
+/// that rebuilding a parsed cache preserves it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawExtension {
+    pub tag: u32,
+    pub flags: u32,
+    pub data: Vec<u8>,
 }
 
+/// A parsed cache file: its layout, entries and extension sections.
 #[derive(Debug, Clone)]
-pub(crate) struct CacheInfo {
+pub struct CacheInfo {
+    pub format: CacheFormat,
     pub entries: Vec<CacheEntry>,
     pub generator: Option<String>,
+    pub extensions: Vec<RawExtension>,
 }
 
 /// Entry order written by glibc (elf/cache.c compare()): reversed
 /// _dl_cache_libcmp on the soname, then flags descending, then
-/// glibc-hwcaps entries before plain ones, ordered by subdirectory name.
-fn compare(a: &FileEntry, b: &FileEntry) -> Ordering {
+/// glibc-hwcaps entries before plain ones, ordered by subdirectory name,
+/// then the most specific legacy hwcap mask and the highest osversion
+/// first.
+pub(crate) fn compare(a: &FileEntry, b: &FileEntry) -> Ordering {
     dl_cache_libcmp(&b.soname, &a.soname)
         .then_with(|| b.flags.cmp(&a.flags))
         .then_with(|| match (&a.hwcaps, &b.hwcaps) {
@@ -86,12 +428,391 @@ fn compare(a: &FileEntry, b: &FileEntry) -> Ordering {
             (None, Some(_)) => Ordering::Greater,
             (None, None) => Ordering::Equal,
         })
+        .then_with(|| b.hwcap.count_ones().cmp(&a.hwcap.count_ones()))
+        .then_with(|| b.hwcap.cmp(&a.hwcap))
+        .then_with(|| b.osversion.cmp(&a.osversion))
+}
+
+/// SOURCE_DATE_EPOCH if given, else the current time. A malformed value
+/// is not silently replaced: the time is then left out.
+fn build_time(source_date_epoch: Option<&str>) -> Option<u64> {
+    match source_date_epoch {
+        Some(epoch) => match epoch.trim().parse() {
+            Ok(epoch) => Some(epoch),
+            Err(_) => {
+                warn!("Ignoring malformed SOURCE_DATE_EPOCH `{}'", epoch);
+                None
+            }
+        },
+        None => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs()),
+    }
+}
+
+/// How and when a cache was produced, stored as `key=value` lines in a
+/// crate-specific extension section. Every field is optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Provenance {
+    /// Build time, in seconds since the epoch; SOURCE_DATE_EPOCH when
+    /// set, for reproducible builds.
+    pub timestamp: Option<u64>,
+    pub host: Option<String>,
+    /// Caller-defined digest of the configuration the cache was built from.
+    pub config_hash: Option<String>,
+    pub tool_version: Option<String>,
+}
+
+impl Provenance {
+    /// The current time, host name and this crate's version. When
+    /// SOURCE_DATE_EPOCH is set, as reproducible builds do, it is the
+    /// time recorded instead.
+    pub fn current() -> Self {
+        let timestamp = build_time(std::env::var("SOURCE_DATE_EPOCH").ok().as_deref());
+        let host = fs::read_to_string("/proc/sys/kernel/hostname")
+            .ok()
+            .map(|h| h.trim_end().to_owned())
+            .filter(|h| !h.is_empty());
+        Self {
+            timestamp,
+            host,
+            config_hash: None,
+            tool_version: Some(default_generator()),
+        }
+    }
+
+    // Only the (Unix-only) builder embeds provenance.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn to_extension(&self) -> RawExtension {
+        let mut data = String::new();
+        let mut field = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                // Values are single lines; anything after a newline would
+                // read back as a bogus key.
+                let value = value.lines().next().unwrap_or_default();
+                data.push_str(&format!("{}={}\n", key, value));
+            }
+        };
+        field("timestamp", self.timestamp.map(|t| t.to_string()));
+        field("host", self.host.clone());
+        field("config_hash", self.config_hash.clone());
+        field("tool_version", self.tool_version.clone());
+        RawExtension {
+            tag: TAG_PROVENANCE,
+            flags: 0,
+            data: data.into_bytes(),
+        }
+    }
+
+    /// Decode the provenance section among `extensions`, if any. Unknown
+    /// keys are ignored.
+    pub(crate) fn from_extensions(extensions: &[RawExtension]) -> Option<Self> {
+        let ext = extensions.iter().find(|e| e.tag == TAG_PROVENANCE)?;
+        let mut p = Self::default();
+        for line in String::from_utf8_lossy(&ext.data).lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match key {
+                "timestamp" => p.timestamp = value.parse().ok(),
+                "host" => p.host = Some(value.to_owned()),
+                "config_hash" => p.config_hash = Some(value.to_owned()),
+                "tool_version" => p.tool_version = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+        Some(p)
+    }
+}
+
+/// Generator string recorded in caches built by this crate.
+pub(crate) fn default_generator() -> String {
+    format!("ldconfig-rs {}", env!("CARGO_PKG_VERSION"))
 }
 
-/// Serialize entries into cache bytes.
-pub(crate) fn build_cache(entries: &[FileEntry]) -> Vec<u8> {
+/// Serialize entries like [`build_cache`] in any layout. The old layout
+/// only holds soname, path and flags: glibc-hwcaps entries, the generator
+/// and extensions only make it into the new part, as with glibc.
+pub(crate) fn build_cache_as(
+    format: CacheFormat,
+    entries: &[FileEntry],
+    generator: Option<&str>,
+    extensions: &[RawExtension],
+) -> Result<Vec<u8>, Error> {
+    let new = build_cache(entries, generator, extensions);
+    if format == CacheFormat::New {
+        return Ok(new);
+    }
+    let parsed = parse_new(&new, 0, CacheFormat::New)?;
+    let plain: Vec<&CacheEntry> = parsed
+        .entries
+        .iter()
+        .filter(|e| e.hwcaps.is_none())
+        .collect();
+
+    let entries_end = OLD_HEADER_SIZE + plain.len() * OLD_ENTRY_SIZE;
+    let mut out = Vec::new();
+    out.extend_from_slice(&OLD_CACHE_MAGIC);
+    out.resize(12, 0);
+    out.extend_from_slice(&(plain.len() as u32).to_ne_bytes());
+    // Old string offsets count from the end of the old entries.
+    let push_entry = |out: &mut Vec<u8>, flags: u32, key: usize, value: usize| {
+        out.extend_from_slice(&flags.to_ne_bytes());
+        out.extend_from_slice(&(key as u32).to_ne_bytes());
+        out.extend_from_slice(&(value as u32).to_ne_bytes());
+    };
+    if format == CacheFormat::Compat {
+        // Point into the new part's string table, at ALIGN_CACHE.
+        let new_start = entries_end.next_multiple_of(std::mem::align_of::<u64>());
+        let pad = new_start - entries_end;
+        for e in &plain {
+            let (key, value) = (e.key_offset as usize, e.value_offset as usize);
+            push_entry(&mut out, e.flags, pad + key, pad + value);
+        }
+        out.resize(new_start, 0);
+        out.extend_from_slice(&new);
+        // Extension offsets are file offsets: shift them past the old part.
+        let shift = |out: &mut Vec<u8>, at: usize| {
+            let value = read_u32(out, at).unwrap() + new_start as u32;
+            out[at..at + 4].copy_from_slice(&value.to_ne_bytes());
+        };
+        if let Some(ext) = Header::parse(&new).map(|h| h.extension_offset as usize) {
+            if ext != 0 {
+                shift(&mut out, new_start + 32);
+                let count = read_u32(&new, ext + 4).unwrap() as usize;
+                for i in 0..count {
+                    shift(&mut out, new_start + ext + 8 + i * 16 + 8);
+                }
+            }
+        }
+    } else {
+        let mut strings = Vec::new();
+        let mut intern = |offset: u32| {
+            let at = strings.len();
+            strings.extend_from_slice(read_string(&new, offset as usize).unwrap().as_bytes());
+            strings.push(0);
+            at
+        };
+        for e in &plain {
+            let (key, value) = (intern(e.key_offset), intern(e.value_offset));
+            push_entry(&mut out, e.flags, key, value);
+        }
+        out.extend_from_slice(&strings);
+    }
+    Ok(out)
+}
+
+/// Entries from which sorting and string interning are split across
+/// threads; below that, threads cost more than they save.
+const PARALLEL_ENTRIES: usize = 1 << 15;
+
+/// How many threads to split `len` entries across.
+fn shards_for(len: usize) -> usize {
+    if len < PARALLEL_ENTRIES {
+        return 1;
+    }
+    std::thread::available_parallelism()
+        .map_or(1, usize::from)
+        .min(len / (PARALLEL_ENTRIES / 4))
+}
+
+/// Entries in cache order. Stable: entries comparing equal (the same
+/// library in several directories) keep their scan order, like glibc's
+/// insertion into its sorted list, so the loader's first hit is the first
+/// directory. With several `shards`, chunks are sorted on their own
+/// threads and merged pairwise, the earlier chunk first on ties, so the
+/// order is the same.
+fn sort_entries(entries: &[FileEntry], shards: usize) -> Vec<&FileEntry> {
     let mut sorted: Vec<&FileEntry> = entries.iter().collect();
-    sorted.sort_by(|a, b| compare(a, b));
+    if shards <= 1 {
+        sorted.sort_by(|a, b| compare(a, b));
+        return sorted;
+    }
+    let mut runs: Vec<Vec<&FileEntry>> = sorted
+        .chunks(sorted.len().div_ceil(shards))
+        .map(<[_]>::to_vec)
+        .collect();
+    std::thread::scope(|scope| {
+        for run in &mut runs {
+            scope.spawn(move || run.sort_by(|a, b| compare(a, b)));
+        }
+    });
+    while runs.len() > 1 {
+        runs = std::thread::scope(|scope| {
+            let merges: Vec<_> = runs
+                .chunks(2)
+                .map(|pair| scope.spawn(move || merge_runs(pair)))
+                .collect();
+            merges.into_iter().map(|m| m.join().unwrap()).collect()
+        });
+    }
+    runs.pop().unwrap_or_default()
+}
+
+/// Merge one or two sorted runs, the first winning ties.
+fn merge_runs<'a>(pair: &[Vec<&'a FileEntry>]) -> Vec<&'a FileEntry> {
+    let [a, b] = pair else {
+        return pair[0].clone();
+    };
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if compare(b[j], a[i]) == Ordering::Less {
+            merged.push(b[j]);
+            j += 1;
+        } else {
+            merged.push(a[i]);
+            i += 1;
+        }
+    }
+    merged.extend_from_slice(&a[i..]);
+    merged.extend_from_slice(&b[j..]);
+    merged
+}
+
+/// A string table under construction, interned like glibc's stringtable
+/// (without its suffix merging); offsets are absolute file offsets.
+struct StringTable<'a> {
+    base: usize,
+    table: Vec<u8>,
+    offsets: HashMap<&'a str, u32>,
+}
+
+impl<'a> StringTable<'a> {
+    fn add(&mut self, s: &'a str) -> u32 {
+        if let Some(&off) = self.offsets.get(s) {
+            return off;
+        }
+        let off = (self.base + self.table.len()) as u32;
+        self.offsets.insert(s, off);
+        self.table.extend_from_slice(s.as_bytes());
+        self.table.push(0);
+        off
+    }
+
+    /// Add the soname and path of every entry, in order, giving their
+    /// offsets. Shards of entries are interned on their own threads first,
+    /// then merged in order, so the table is the same either way.
+    fn add_entries(&mut self, sorted: &[&'a FileEntry], shards: usize) -> Vec<(u32, u32)> {
+        if shards <= 1 {
+            return sorted
+                .iter()
+                .map(|e| (self.add(&e.soname), self.add(&e.path)))
+                .collect();
+        }
+        let interned: Vec<Shard> = std::thread::scope(|scope| {
+            let shards: Vec<_> = sorted
+                .chunks(sorted.len().div_ceil(shards))
+                .map(|chunk| scope.spawn(move || intern_shard(chunk)))
+                .collect();
+            shards.into_iter().map(|s| s.join().unwrap()).collect()
+        });
+        let mut offsets = Vec::with_capacity(sorted.len());
+        for (unique, ids) in interned {
+            let global: Vec<u32> = unique.into_iter().map(|s| self.add(s)).collect();
+            offsets.extend(
+                ids.into_iter()
+                    .map(|(key, value)| (global[key as usize], global[value as usize])),
+            );
+        }
+        offsets
+    }
+}
+
+/// Distinct strings in first-use order, and each entry's (soname, path)
+/// indices into them.
+type Shard<'a> = (Vec<&'a str>, Vec<(u32, u32)>);
+
+/// The sonames and paths of `entries`, interned on their own.
+fn intern_shard<'a>(entries: &[&'a FileEntry]) -> Shard<'a> {
+    let mut ids: HashMap<&str, u32> = HashMap::new();
+    let mut unique = Vec::new();
+    let mut id = |s: &'a str| {
+        *ids.entry(s).or_insert_with(|| {
+            unique.push(s);
+            unique.len() as u32 - 1
+        })
+    };
+    let pairs = entries
+        .iter()
+        .map(|e| (id(&e.soname), id(&e.path)))
+        .collect();
+    (unique, pairs)
+}
+
+/// Serialize entries into cache bytes. The generator section is only
+/// written when `generator` is given; `extensions` are appended verbatim.
+/// Huge entry lists are sorted and interned on several threads.
+pub fn build_cache(
+    entries: &[FileEntry],
+    generator: Option<&str>,
+    extensions: &[RawExtension],
+) -> Vec<u8> {
+    build_cache_sharded(
+        entries,
+        generator,
+        extensions,
+        shards_for(entries.len()),
+        None,
+    )
+}
+
+/// Serialize entries like [`build_cache`], keeping as much as possible of
+/// `old`, a new-format cache, at the same offsets, so that rewriting it in
+/// place touches few blocks: its string table stays where it is, except
+/// for the start the larger entry array now covers, and strings still
+/// there are shared instead of appended again.
+pub(crate) fn build_cache_over(
+    old: &[u8],
+    entries: &[FileEntry],
+    generator: Option<&str>,
+    extensions: &[RawExtension],
+) -> Vec<u8> {
+    let shards = shards_for(entries.len());
+    build_cache_sharded(entries, generator, extensions, shards, Some(old))
+}
+
+/// The part of `old`'s string table from `start` on, a file offset, and
+/// the offsets of the entry strings found there.
+fn kept_strings(old: &[u8], start: usize) -> (&[u8], HashMap<&str, u32>) {
+    let mut offsets = HashMap::new();
+    let info = old
+        .starts_with(&CACHE_MAGIC)
+        .then(|| parse_new(old, 0, CacheFormat::New).ok())
+        .flatten();
+    let Some(info) = info else {
+        return (&[], offsets);
+    };
+    let end = HEADER_SIZE + info.entries.len() * ENTRY_SIZE + string_table_len(old);
+    if start >= end {
+        return (&[], offsets);
+    }
+    for e in &info.entries {
+        for offset in [e.key_offset as usize, e.value_offset as usize] {
+            let Some(bytes) = old.get(offset..end).filter(|_| offset >= start) else {
+                continue;
+            };
+            let nul = bytes.iter().position(|&b| b == 0);
+            if let Some(s) = nul.and_then(|nul| std::str::from_utf8(&bytes[..nul]).ok()) {
+                offsets.insert(s, offset as u32);
+            }
+        }
+    }
+    (&old[start..end], offsets)
+}
+
+fn build_cache_sharded<'a>(
+    entries: &'a [FileEntry],
+    generator: Option<&str>,
+    extensions: &[RawExtension],
+    shards: usize,
+    old: Option<&'a [u8]>,
+) -> Vec<u8> {
+    // Existing entries come in cache order, one run the stable sort only
+    // merges the added ones into.
+    let sorted = sort_entries(entries, shards);
 
     // glibc-hwcaps subdirectory names, indexed in name order like
     // assign_glibc_hwcaps_indices.
@@ -105,102 +826,180 @@ pub(crate) fn build_cache(entries: &[FileEntry]) -> Vec<u8> {
     }
     hwcaps_names.sort_unstable();
 
-    let string_table_offset = HEADER_SIZE + sorted.len() * ENTRY_SIZE;
-    let mut table: Vec<u8> = Vec::new();
-    let mut offsets: HashMap<String, u32> = HashMap::new();
-    // Strings are interned like glibc's stringtable (without its suffix
-    // merging); stored offsets are absolute file offsets.
-    let mut add_string = |table: &mut Vec<u8>, s: &str| -> u32 {
-        if let Some(&off) = offsets.get(s) {
-            return off;
-        }
-        let off = (string_table_offset + table.len()) as u32;
-        offsets.insert(s.to_owned(), off);
-        table.extend_from_slice(s.as_bytes());
-        table.push(0);
-        off
+    let entries_end = HEADER_SIZE + sorted.len() * ENTRY_SIZE;
+    let (kept, offsets) = old.map_or_else(Default::default, |old| kept_strings(old, entries_end));
+    let mut strings = StringTable {
+        base: entries_end + kept.len(),
+        table: Vec::new(),
+        offsets,
+    };
+    let string_offsets = strings.add_entries(&sorted, shards);
+    let hwcaps_offsets: Vec<u32> = hwcaps_names.iter().map(|n| strings.add(n)).collect();
+    let len_strings = kept.len() + strings.table.len();
+
+    let hwcaps_data: Vec<u8> = hwcaps_offsets
+        .iter()
+        .flat_map(|o| o.to_ne_bytes())
+        .collect();
+    // (tag, flags, data) in directory order.
+    let mut sections: Vec<(u32, u32, &[u8])> = Vec::new();
+    if let Some(generator) = generator {
+        sections.push((TAG_GENERATOR, 0, generator.as_bytes()));
+    }
+    if !hwcaps_data.is_empty() {
+        sections.push((TAG_GLIBC_HWCAPS, 0, &hwcaps_data));
+    }
+    // One digest per entry in cache order, all zero for unhashed entries.
+    let sha256_data: Vec<u8> = if sorted.iter().any(|e| e.sha256.is_some()) {
+        sorted
+            .iter()
+            .flat_map(|e| e.sha256.unwrap_or([0; 32]))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    if !sha256_data.is_empty() {
+        sections.push((TAG_CONTENT_SHA256, 0, &sha256_data));
+    }
+    let stat_data: Vec<u8> = if sorted.iter().any(|e| e.stat.is_some()) {
+        sorted
+            .iter()
+            .flat_map(|e| e.stat.map_or([0; FILE_STAT_SIZE], FileStat::to_bytes))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    if !stat_data.is_empty() {
+        sections.push((TAG_FILE_STAT, 0, &stat_data));
+    }
+    for ext in extensions {
+        sections.push((ext.tag, ext.flags, &ext.data));
+    }
+    let strings_end = (entries_end + len_strings).next_multiple_of(4);
+    let extension_offset = if sections.is_empty() {
+        0
+    } else {
+        strings_end as u32
     };
 
     let mut cache = Vec::new();
-    cache.extend_from_slice(&CACHE_MAGIC);
-    cache.extend_from_slice(&(sorted.len() as u32).to_ne_bytes());
-    let len_strings_pos = cache.len();
-    cache.extend_from_slice(&0u32.to_ne_bytes());
-    cache.push(ENDIAN_CURRENT);
-    cache.extend_from_slice(&[0u8; 3]);
-    let extension_offset_pos = cache.len();
-    cache.extend_from_slice(&0u32.to_ne_bytes());
-    cache.extend_from_slice(&[0u8; 12]); // unused[3]
-
-    for e in &sorted {
-        let key = add_string(&mut table, &e.soname);
-        let value = add_string(&mut table, &e.path);
-        let hwcap = match &e.hwcaps {
-            Some(n) => {
-                let index = hwcaps_names.iter().position(|x| x == n).unwrap() as u64;
-                DL_CACHE_HWCAP_EXTENSION | (u64::from(e.isa_level) << 32) | index
-            }
-            None => 0,
-        };
-        cache.extend_from_slice(&e.flags.to_ne_bytes());
-        cache.extend_from_slice(&key.to_ne_bytes());
-        cache.extend_from_slice(&value.to_ne_bytes());
-        cache.extend_from_slice(&0u32.to_ne_bytes()); // osversion_unused
-        cache.extend_from_slice(&hwcap.to_ne_bytes());
+    cache.extend_from_slice(&header_bytes(
+        sorted.len() as u32,
+        len_strings as u32,
+        extension_offset,
+    ));
+    for (e, &(key, value)) in sorted.iter().zip(&string_offsets) {
+        cache.extend_from_slice(&entry_bytes(e, key, value, &hwcaps_names));
+    }
+    cache.extend_from_slice(kept);
+    cache.extend_from_slice(&strings.table);
+    cache.resize(strings_end, 0);
+    if sections.is_empty() {
+        return cache;
     }
 
-    let hwcaps_offsets: Vec<u32> = hwcaps_names
+    let lens: Vec<(u32, u32, u32)> = sections
         .iter()
-        .map(|n| add_string(&mut table, n))
+        .map(|&(tag, flags, data)| (tag, flags, data.len() as u32))
         .collect();
-
-    cache[len_strings_pos..len_strings_pos + 4]
-        .copy_from_slice(&(table.len() as u32).to_ne_bytes());
-    cache.extend_from_slice(&table);
-
-    while cache.len() % 4 != 0 {
-        cache.push(0);
+    let (directory, placement) = extension_directory(extension_offset, &lens);
+    cache.extend_from_slice(&directory);
+    for (i, offset) in placement {
+        cache.resize(offset as usize, 0);
+        cache.extend_from_slice(sections[i].2);
     }
 
-    // Extension directory, then the hwcaps index array, then the
-    // generator string (write_extensions in elf/cache.c).
-    let extension_offset = cache.len() as u32;
-    cache[extension_offset_pos..extension_offset_pos + 4]
-        .copy_from_slice(&extension_offset.to_ne_bytes());
+    cache
+}
 
-    let generator = format!("ldconfig-rs {}", env!("CARGO_PKG_VERSION"));
-    let section_count: u32 = if hwcaps_offsets.is_empty() { 1 } else { 2 };
-    let data_start = extension_offset + 8 + 16 * section_count;
-    let hwcaps_size = (hwcaps_offsets.len() * 4) as u32;
+/// The fixed part of a new-format cache, struct cache_file_new, in
+/// native byte order.
+pub(crate) fn header_bytes(
+    nlibs: u32,
+    len_strings: u32,
+    extension_offset: u32,
+) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..CACHE_MAGIC.len()].copy_from_slice(&CACHE_MAGIC);
+    header[20..24].copy_from_slice(&nlibs.to_ne_bytes());
+    header[24..28].copy_from_slice(&len_strings.to_ne_bytes());
+    header[28] = ENDIAN_CURRENT;
+    header[32..36].copy_from_slice(&extension_offset.to_ne_bytes());
+    header
+}
 
-    cache.extend_from_slice(&EXTENSION_MAGIC.to_ne_bytes());
-    cache.extend_from_slice(&section_count.to_ne_bytes());
+/// The new-format entry for `e`, struct file_entry_new, its strings at
+/// file offsets `key` and `value`. glibc-hwcaps entries encode their
+/// subdirectory as an index into `hwcaps_names` and their ISA level.
+pub(crate) fn entry_bytes(
+    e: &FileEntry,
+    key: u32,
+    value: u32,
+    hwcaps_names: &[impl AsRef<str>],
+) -> [u8; ENTRY_SIZE] {
+    let hwcap = match &e.hwcaps {
+        Some(n) => {
+            let index = hwcaps_names.iter().position(|x| x.as_ref() == n).unwrap() as u64;
+            DL_CACHE_HWCAP_EXTENSION | (u64::from(e.isa_level) << 32) | index
+        }
+        None => e.hwcap,
+    };
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[..4].copy_from_slice(&e.flags.to_ne_bytes());
+    entry[4..8].copy_from_slice(&key.to_ne_bytes());
+    entry[8..12].copy_from_slice(&value.to_ne_bytes());
+    entry[12..16].copy_from_slice(&e.osversion.to_ne_bytes());
+    entry[16..24].copy_from_slice(&hwcap.to_ne_bytes());
+    entry
+}
 
-    cache.extend_from_slice(&TAG_GENERATOR.to_ne_bytes());
-    cache.extend_from_slice(&0u32.to_ne_bytes()); // flags
-    cache.extend_from_slice(&(data_start + hwcaps_size).to_ne_bytes());
-    cache.extend_from_slice(&(generator.len() as u32).to_ne_bytes());
+/// The extension directory at file offset `extension_offset` for
+/// sections of the given (tag, flags, length), and where their data goes:
+/// the hwcaps index array first, then the others in directory order
+/// (write_extensions in elf/cache.c), each 4-byte aligned. The data
+/// placement is (section index, file offset) in file order.
+pub(crate) fn extension_directory(
+    extension_offset: u32,
+    sections: &[(u32, u32, u32)],
+) -> (Vec<u8>, Vec<(usize, u32)>) {
+    let mut data_order: Vec<usize> = (0..sections.len()).collect();
+    data_order.sort_by_key(|&i| sections[i].0 != TAG_GLIBC_HWCAPS);
+    let mut data_offsets = vec![0u32; sections.len()];
+    let mut pos = extension_offset + 8 + 16 * sections.len() as u32;
+    for &i in &data_order {
+        pos = pos.next_multiple_of(4);
+        data_offsets[i] = pos;
+        pos += sections[i].2;
+    }
 
-    if !hwcaps_offsets.is_empty() {
-        cache.extend_from_slice(&TAG_GLIBC_HWCAPS.to_ne_bytes());
-        cache.extend_from_slice(&0u32.to_ne_bytes()); // flags
-        cache.extend_from_slice(&data_start.to_ne_bytes());
-        cache.extend_from_slice(&hwcaps_size.to_ne_bytes());
-        for off in &hwcaps_offsets {
-            cache.extend_from_slice(&off.to_ne_bytes());
-        }
+    let mut directory = Vec::with_capacity(8 + 16 * sections.len());
+    directory.extend_from_slice(&EXTENSION_MAGIC.to_ne_bytes());
+    directory.extend_from_slice(&(sections.len() as u32).to_ne_bytes());
+    for (&(tag, flags, len), offset) in sections.iter().zip(&data_offsets) {
+        directory.extend_from_slice(&tag.to_ne_bytes());
+        directory.extend_from_slice(&flags.to_ne_bytes());
+        directory.extend_from_slice(&offset.to_ne_bytes());
+        directory.extend_from_slice(&len.to_ne_bytes());
     }
-    cache.extend_from_slice(generator.as_bytes());
+    let placement = data_order
+        .into_iter()
+        .map(|i| (i, data_offsets[i]))
+        .collect();
+    (directory, placement)
+}
 
-    cache
+/// String table size of a new-format cache produced by [`build_cache`].
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) fn string_table_len(data: &[u8]) -> usize {
+    read_u32(data, 24).unwrap_or(0) as usize
 }
 
-fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
     data.get(offset..offset + 4)
         .map(|b| u32::from_ne_bytes(b.try_into().unwrap()))
 }
 
-fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+pub(crate) fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
     data.get(offset..offset + 8)
         .map(|b| u64::from_ne_bytes(b.try_into().unwrap()))
 }
@@ -211,22 +1010,130 @@ fn read_string(data: &[u8], offset: usize) -> Option<String> {
     Some(String::from_utf8_lossy(&bytes[..nul]).into_owned())
 }
 
-/// Parse cache bytes. Rejects anything that is not a well-formed
-/// native-endian new-format cache.
-pub(crate) fn parse_cache(data: &[u8]) -> Result<CacheInfo, Error> {
-    if data.len() < HEADER_SIZE {
-        return Err(Error::InvalidCache("file too small"));
+/// The fixed part of a new-format cache, struct cache_file_new.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub nlibs: u32,
+    /// Size of the string table following the entries.
+    pub len_strings: u32,
+    /// Byte order in the low two bits; 0 when unset.
+    pub flags: u8,
+    /// File offset of the extension directory, 0 if none.
+    pub extension_offset: u32,
+}
+
+impl Header {
+    /// Read the header at the start of `data`, None if it is too short
+    /// or lacks [`CACHE_MAGIC`].
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || !data.starts_with(&CACHE_MAGIC) {
+            return None;
+        }
+        Some(Self {
+            nlibs: read_u32(data, 20)?,
+            len_strings: read_u32(data, 24)?,
+            flags: data[28],
+            extension_offset: read_u32(data, 32)?,
+        })
+    }
+
+    /// Offset of the string table from the header; string offsets in
+    /// entries are relative to the header too.
+    pub fn string_table_offset(&self) -> usize {
+        HEADER_SIZE + self.nlibs as usize * ENTRY_SIZE
     }
-    if data[..20] != CACHE_MAGIC {
-        return Err(Error::InvalidCache(
-            "wrong magic (only the new format is supported)",
-        ));
+}
+
+/// Offset of the new-format header in `data`: 0 for a new cache, past
+/// the old entries in a compat one, None for an old-only cache.
+pub fn new_format_offset(data: &[u8]) -> Option<usize> {
+    if data.starts_with(&CACHE_MAGIC) {
+        return Some(0);
     }
-    let nlibs = read_u32(data, 20).unwrap() as usize;
-    let len_strings = read_u32(data, 24).unwrap() as usize;
+    if !data.starts_with(&OLD_CACHE_MAGIC) {
+        return None;
+    }
+    let nlibs = read_u32(data, 12)? as usize;
+    let entries_end = nlibs
+        .checked_mul(OLD_ENTRY_SIZE)?
+        .checked_add(OLD_HEADER_SIZE)?;
+    // The new cache follows at ALIGN_CACHE, the alignment of
+    // struct cache_file_new (that of its uint64_t hwcap).
+    let start = entries_end.next_multiple_of(std::mem::align_of::<u64>());
+    data.get(start..)?
+        .starts_with(&CACHE_MAGIC)
+        .then_some(start)
+}
+
+/// Parse cache bytes in any of the three layouts. Entry string offsets
+/// in the result are absolute offsets into `data`. A compat file is read
+/// through its new-format part, like ld.so does.
+pub fn parse_cache(data: &[u8]) -> Result<CacheInfo, Error> {
+    if data.starts_with(&CACHE_MAGIC) {
+        return parse_new(data, 0, CacheFormat::New);
+    }
+    if !data.starts_with(&OLD_CACHE_MAGIC) {
+        return Err(Error::InvalidCache("wrong magic"));
+    }
+
+    let nlibs = read_u32(data, 12).ok_or(Error::InvalidCache("file too small"))? as usize;
+    let entries_end = nlibs
+        .checked_mul(OLD_ENTRY_SIZE)
+        .and_then(|n| n.checked_add(OLD_HEADER_SIZE))
+        .filter(|&end| end <= data.len())
+        .ok_or(Error::InvalidCache("truncated entries"))?;
+    if let Some(new_start) = new_format_offset(data) {
+        return parse_new(data, new_start, CacheFormat::Compat);
+    }
+    parse_old(data, nlibs, entries_end)
+}
+
+/// Old-format entries: string offsets are relative to the string table
+/// that directly follows the entry array.
+fn parse_old(data: &[u8], nlibs: usize, strtab: usize) -> Result<CacheInfo, Error> {
+    let mut entries = Vec::with_capacity(nlibs);
+    for i in 0..nlibs {
+        let off = OLD_HEADER_SIZE + i * OLD_ENTRY_SIZE;
+        let flags = read_u32(data, off).unwrap();
+        let absolute = |rel: u32| {
+            strtab
+                .checked_add(rel as usize)
+                .filter(|&o| o < data.len())
+                .and_then(|o| u32::try_from(o).ok())
+                .ok_or(Error::InvalidCache("entry string offset out of range"))
+        };
+        let key_offset = absolute(read_u32(data, off + 4).unwrap())?;
+        let value_offset = absolute(read_u32(data, off + 8).unwrap())?;
+        entries.push(CacheEntry {
+            flags,
+            key_offset,
+            value_offset,
+            osversion: 0,
+            hwcap: 0,
+            hwcaps: None,
+            sha256: None,
+            stat: None,
+        });
+    }
+    Ok(CacheInfo {
+        format: CacheFormat::Old,
+        entries,
+        generator: None,
+        extensions: Vec::new(),
+    })
+}
+
+/// Parse the new-format cache starting at `base`. Its string offsets are
+/// relative to `base` (dl-cache.c uses the new header as the string table
+/// origin); extension offsets are relative to the start of the file.
+fn parse_new(file: &[u8], base: usize, format: CacheFormat) -> Result<CacheInfo, Error> {
+    let data = &file[base..];
+    let header = Header::parse(data).ok_or(Error::InvalidCache("file too small"))?;
+    let nlibs = header.nlibs as usize;
+    let len_strings = header.len_strings as usize;
     // 0 = unset (written by older ldconfig); only the low two bits carry
     // the byte order, the rest is ignored by readers (dl-cache.h).
-    if data[28] != 0 && (data[28] & 3) != ENDIAN_CURRENT {
+    if header.flags != 0 && (header.flags & 3) != ENDIAN_CURRENT {
         return Err(Error::InvalidCache("wrong endianness"));
     }
 
@@ -244,38 +1151,63 @@ pub(crate) fn parse_cache(data: &[u8]) -> Result<CacheInfo, Error> {
     }
 
     // Extensions are optional; a malformed section is ignored, like ld.so.
+    // Their offsets count from the start of the file, not from the new
+    // header: cache_extension_load gets the mapping as its base.
     let mut generator = None;
+    let mut extensions = Vec::new();
     let mut hwcaps_array: Vec<u32> = Vec::new();
-    let ext_offset = read_u32(data, 32).unwrap() as usize;
+    let mut sha256_array: Vec<[u8; 32]> = Vec::new();
+    let mut stat_array: Vec<Option<FileStat>> = Vec::new();
+    let ext_offset = header.extension_offset as usize;
     if ext_offset != 0 && ext_offset.is_multiple_of(4) {
-        if let Some(magic) = read_u32(data, ext_offset) {
+        if let Some(magic) = read_u32(file, ext_offset) {
             if magic == EXTENSION_MAGIC {
-                let count = read_u32(data, ext_offset + 4).unwrap_or(0) as usize;
+                let count = read_u32(file, ext_offset + 4).unwrap_or(0) as usize;
                 for i in 0..count {
                     let sec = ext_offset + 8 + i * 16;
-                    let (Some(tag), Some(off), Some(size)) = (
-                        read_u32(data, sec),
-                        read_u32(data, sec + 8),
-                        read_u32(data, sec + 12),
+                    let (Some(tag), Some(flags), Some(off), Some(size)) = (
+                        read_u32(file, sec),
+                        read_u32(file, sec + 4),
+                        read_u32(file, sec + 8),
+                        read_u32(file, sec + 12),
                     ) else {
                         break;
                     };
                     let (off, size) = (off as usize, size as usize);
-                    if off.checked_add(size).filter(|&e| e <= data.len()).is_none() {
+                    if off.checked_add(size).filter(|&e| e <= file.len()).is_none() {
                         continue;
                     }
                     match tag {
                         TAG_GENERATOR => {
                             generator =
-                                Some(String::from_utf8_lossy(&data[off..off + size]).into_owned());
+                                Some(String::from_utf8_lossy(&file[off..off + size]).into_owned());
                         }
                         TAG_GLIBC_HWCAPS => {
-                            hwcaps_array = data[off..off + size]
+                            hwcaps_array = file[off..off + size]
                                 .chunks_exact(4)
                                 .map(|b| u32::from_ne_bytes(b.try_into().unwrap()))
                                 .collect();
                         }
-                        _ => debug!("ignoring unknown cache extension tag {}", tag),
+                        TAG_CONTENT_SHA256 if size == nlibs * 32 => {
+                            sha256_array = file[off..off + size]
+                                .chunks_exact(32)
+                                .map(|b| b.try_into().unwrap())
+                                .collect();
+                        }
+                        TAG_FILE_STAT if size == nlibs * FILE_STAT_SIZE => {
+                            stat_array = file[off..off + size]
+                                .chunks_exact(FILE_STAT_SIZE)
+                                .map(FileStat::from_bytes)
+                                .collect();
+                        }
+                        _ => {
+                            debug!("preserving unknown cache extension tag {}", tag);
+                            extensions.push(RawExtension {
+                                tag,
+                                flags,
+                                data: file[off..off + size].to_vec(),
+                            });
+                        }
                     }
                 }
             }
@@ -289,31 +1221,226 @@ pub(crate) fn parse_cache(data: &[u8]) -> Result<CacheInfo, Error> {
         let flags = read_u32(data, off).unwrap();
         let key_offset = read_u32(data, off + 4).unwrap();
         let value_offset = read_u32(data, off + 8).unwrap();
+        let osversion = read_u32(data, off + 12).unwrap();
         let hwcap = read_u64(data, off + 16).unwrap();
 
         if !strtab.contains(&(key_offset as usize)) || !strtab.contains(&(value_offset as usize)) {
             return Err(Error::InvalidCache("entry string offset out of range"));
         }
 
-        let hwcaps =
-            if (hwcap >> 32) & !DL_CACHE_HWCAP_ISA_LEVEL_MASK == DL_CACHE_HWCAP_EXTENSION >> 32 {
-                hwcaps_array
-                    .get(hwcap as u32 as usize)
-                    .and_then(|&str_off| read_string(data, str_off as usize))
-            } else {
-                None
-            };
+        let hwcaps = if is_hwcap_extension(hwcap) {
+            hwcaps_array
+                .get(hwcap as u32 as usize)
+                .and_then(|&str_off| read_string(data, str_off as usize))
+        } else {
+            None
+        };
 
+        let rebase = |off: u32| {
+            u32::try_from(base + off as usize)
+                .map_err(|_| Error::InvalidCache("entry string offset out of range"))
+        };
         entries.push(CacheEntry {
             flags,
-            key_offset,
-            value_offset,
+            key_offset: rebase(key_offset)?,
+            value_offset: rebase(value_offset)?,
+            osversion,
             hwcap,
             hwcaps,
+            sha256: sha256_array.get(i).copied().filter(|h| *h != [0; 32]),
+            stat: stat_array.get(i).copied().flatten(),
         });
     }
 
-    Ok(CacheInfo { entries, generator })
+    Ok(CacheInfo {
+        format,
+        entries,
+        generator,
+        extensions,
+    })
+}
+
+/// Recover the entries a parsed cache was built from, so it can be
+/// re-serialized with [`build_cache`]. `data` is the buffer `info` was
+/// parsed from.
+pub fn file_entries(data: &[u8], info: &CacheInfo) -> Result<Vec<FileEntry>, Error> {
+    let string = |offset: u32| {
+        read_string(data, offset as usize).ok_or(Error::InvalidCacheOffset(offset as usize))
+    };
+    info.entries
+        .iter()
+        .map(|e| {
+            let extension = e.hwcaps.is_some();
+            Ok(FileEntry {
+                soname: string(e.key_offset)?,
+                path: string(e.value_offset)?,
+                flags: e.flags,
+                isa_level: if extension {
+                    ((e.hwcap >> 32) & DL_CACHE_HWCAP_ISA_LEVEL_MASK) as u32
+                } else {
+                    0
+                },
+                hwcaps: e.hwcaps.clone(),
+                hwcap: if extension { 0 } else { e.hwcap },
+                osversion: e.osversion,
+                sha256: e.sha256,
+                stat: e.stat,
+            })
+        })
+        .collect()
+}
+
+/// Library ABI decoded from entry flags; the typed form of
+/// [`flags_string`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Arch {
+    /// ELF libc6 without an ABI qualifier: i386, 32-bit PowerPC and SPARC,
+    /// unmarked ARM, ...
+    Libc6,
+    Sparc64,
+    X86_64,
+    S390_64,
+    PowerPc64,
+    Mips64N32,
+    Mips64N64,
+    X32,
+    ArmHardFloat,
+    AArch64,
+    ArmSoftFloat,
+    Mips32Nan2008,
+    Mips64N32Nan2008,
+    Mips64N64Nan2008,
+    RiscvSoftFloat,
+    RiscvDoubleFloat,
+    LoongArchSoftFloat,
+    LoongArchDoubleFloat,
+    /// Flags this crate cannot classify, kept as-is.
+    Unknown(u16),
+}
+
+impl Arch {
+    /// Every classified ABI paired with its required-flag bits.
+    pub(crate) const TABLE: [(Arch, u32); 18] = [
+        (Arch::Libc6, 0),
+        (Arch::Sparc64, FLAG_SPARC_LIB64),
+        (Arch::X86_64, FLAG_X8664_LIB64),
+        (Arch::S390_64, FLAG_S390_LIB64),
+        (Arch::PowerPc64, FLAG_POWERPC_LIB64),
+        (Arch::Mips64N32, FLAG_MIPS64_LIBN32),
+        (Arch::Mips64N64, FLAG_MIPS64_LIBN64),
+        (Arch::X32, FLAG_X8664_LIBX32),
+        (Arch::ArmHardFloat, FLAG_ARM_LIBHF),
+        (Arch::AArch64, FLAG_AARCH64_LIB64),
+        (Arch::ArmSoftFloat, FLAG_ARM_LIBSF),
+        (Arch::Mips32Nan2008, FLAG_MIPS_LIB32_NAN2008),
+        (Arch::Mips64N32Nan2008, FLAG_MIPS64_LIBN32_NAN2008),
+        (Arch::Mips64N64Nan2008, FLAG_MIPS64_LIBN64_NAN2008),
+        (Arch::RiscvSoftFloat, FLAG_RISCV_FLOAT_ABI_SOFT),
+        (Arch::RiscvDoubleFloat, FLAG_RISCV_FLOAT_ABI_DOUBLE),
+        (Arch::LoongArchSoftFloat, FLAG_LARCH_FLOAT_ABI_SOFT),
+        (Arch::LoongArchDoubleFloat, FLAG_LARCH_FLOAT_ABI_DOUBLE),
+    ];
+
+    /// Decode entry flags.
+    pub fn from_flags(flags: u32) -> Self {
+        if flags & FLAG_TYPE_MASK == FLAG_ELF_LIBC6 && flags & !0xffff == 0 {
+            let required = flags & FLAG_REQUIRED_MASK;
+            if let Some(&(arch, _)) = Self::TABLE.iter().find(|(_, f)| *f == required) {
+                return arch;
+            }
+        }
+        Arch::Unknown(flags as u16)
+    }
+
+    /// Entry flags for this ABI.
+    pub fn flags(self) -> u32 {
+        match self {
+            Arch::Unknown(flags) => u32::from(flags),
+            _ => {
+                let (_, required) = Self::TABLE.iter().find(|(a, _)| *a == self).unwrap();
+                required | FLAG_ELF_LIBC6
+            }
+        }
+    }
+}
+
+impl fmt::Display for Arch {
+    /// The `ldconfig -p` rendering, e.g. "libc6,x86-64".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&flags_string(self.flags()))
+    }
+}
+
+/// Whether an entry hwcap value is a glibc-hwcaps reference rather than
+/// a legacy mask.
+pub(crate) fn is_hwcap_extension(hwcap: u64) -> bool {
+    (hwcap >> 32) & !DL_CACHE_HWCAP_ISA_LEVEL_MASK == DL_CACHE_HWCAP_EXTENSION >> 32
+}
+
+/// Names of the capability subdirectories a legacy hwcap mask stands for,
+/// as the pre-2.33 loader would have searched them. Bits without a known
+/// name for the entry's architecture are rendered as `hwcap_<bit>`.
+pub(crate) fn legacy_hwcap_names(flags: u32, hwcap: u64) -> Vec<String> {
+    if hwcap == 0 || is_hwcap_extension(hwcap) {
+        return Vec::new();
+    }
+    let x86 = flags & FLAG_TYPE_MASK == FLAG_ELF_LIBC6
+        && matches!(
+            flags & FLAG_REQUIRED_MASK,
+            0 | FLAG_X8664_LIB64 | FLAG_X8664_LIBX32
+        );
+    let mut names = Vec::new();
+    for bit in 0..64 {
+        if hwcap & (1 << bit) == 0 {
+            continue;
+        }
+        let known = if 1 << bit == LEGACY_HWCAP_TLS {
+            Some("tls")
+        } else if x86 && bit >= LEGACY_X86_FIRST_PLATFORM {
+            LEGACY_X86_PLATFORMS
+                .get((bit - LEGACY_X86_FIRST_PLATFORM) as usize)
+                .copied()
+        } else if x86 {
+            LEGACY_X86_HWCAPS.get(bit as usize).copied()
+        } else {
+            None
+        };
+        names.push(known.map_or_else(|| format!("hwcap_{}", bit), str::to_owned));
+    }
+    names
+}
+
+/// Whether ld.so before 2.37 uses an entry with the legacy mask `hwcap`
+/// on a CPU with capability bits `mask` and platform `platform` ("i686",
+/// "haswell"...), as dl-cache.c's HWCAP_CHECK decides: every bit must be
+/// in the mask, except tls and platform bits, and the platform bits must
+/// name that platform. Not for glibc-hwcaps entries.
+pub(crate) fn legacy_hwcap_usable(hwcap: u64, mask: u64, platform: Option<&str>) -> bool {
+    let platform_mask = ((1 << LEGACY_X86_PLATFORMS.len()) - 1) << LEGACY_X86_FIRST_PLATFORM;
+    if hwcap & !(mask | platform_mask | LEGACY_HWCAP_TLS) != 0 {
+        return false;
+    }
+    let platform_bit = platform
+        .and_then(|p| LEGACY_X86_PLATFORMS.iter().position(|&n| n == p))
+        .map(|i| 1 << (LEGACY_X86_FIRST_PLATFORM + i as u32));
+    let required = hwcap & platform_mask;
+    required == 0 || Some(required) == platform_bit
+}
+
+/// The legacy hwcap bit a capability subdirectory name stands for, the
+/// inverse of [`legacy_hwcap_names`] for the names it knows.
+pub(crate) fn legacy_hwcap_bit(name: &str) -> Option<u64> {
+    if name == "tls" {
+        return Some(LEGACY_HWCAP_TLS);
+    }
+    if let Some(i) = LEGACY_X86_PLATFORMS.iter().position(|&n| n == name) {
+        return Some(1 << (LEGACY_X86_FIRST_PLATFORM + i as u32));
+    }
+    LEGACY_X86_HWCAPS
+        .iter()
+        .position(|&n| n == name)
+        .map(|bit| 1 << bit)
 }
 
 /// Flag rendering matching glibc's print_entry.
@@ -348,9 +1475,36 @@ pub(crate) fn flags_string(flags: u32) -> String {
     s
 }
 
+/// Whether the ld.so loading objects with cache flags `object` accepts an
+/// entry with `entry` flags, per the sysdeps dl-cache.h variants of
+/// `_dl_cache_check_flags`. Biarch caches rely on this: an i686 and an
+/// x86-64 entry for one soname never stand in for each other, while ARM
+/// float-ABI loaders also take unmarked libc6 entries and only the
+/// generic check (i386) also takes plain ELF ones; AArch64 wants an exact
+/// match.
+#[cfg(unix)]
+pub(crate) fn dl_cache_check_flags(entry: u32, object: u32) -> bool {
+    entry == object
+        || match Arch::from_flags(object) {
+            Arch::ArmHardFloat | Arch::ArmSoftFloat => entry == FLAG_ELF_LIBC6,
+            Arch::Libc6 => entry == FLAG_ELF,
+            _ => false,
+        }
+}
+
 /// Numeric-aware string comparison matching glibc's `_dl_cache_libcmp`.
 /// Digits sort after non-digits; runs of digits compare numerically.
-pub(crate) fn dl_cache_libcmp(p1: &str, p2: &str) -> Ordering {
+/// This is the order ld.so and ldconfig pick the highest version of a
+/// library by.
+///
+/// ```
+/// use ldconfig::libcmp;
+///
+/// let mut names = ["libfoo.so.1.10", "libfoo.so.1.9", "libfoo.so.1"];
+/// names.sort_by(|a, b| libcmp(a, b));
+/// assert_eq!(names.last(), Some(&"libfoo.so.1.10"));
+/// ```
+pub fn dl_cache_libcmp(p1: &str, p2: &str) -> Ordering {
     let b1 = p1.as_bytes();
     let b2 = p2.as_bytes();
     let mut i = 0;
@@ -405,6 +1559,38 @@ mod tests {
             flags,
             isa_level: 0,
             hwcaps: hwcaps.map(str::to_owned),
+            hwcap: 0,
+            osversion: 0,
+            sha256: None,
+            stat: None,
+        }
+    }
+
+    fn build(entries: &[FileEntry]) -> Vec<u8> {
+        build_cache(entries, Some(&default_generator()), &[])
+    }
+
+    #[test]
+    fn sharded_builds_are_identical() {
+        let entries: Vec<FileEntry> = (0..500)
+            .map(|i| {
+                let hwcaps = (i % 9 == 0).then_some("x86-64-v3");
+                entry(
+                    &format!("lib{}.so.{}", i % 37, i % 4),
+                    &format!("/lib{}/lib{}.so.{}", i % 5, i % 37, i % 4),
+                    if i % 3 == 0 { 0x0303 } else { 0x0003 },
+                    hwcaps,
+                )
+            })
+            .collect();
+        let expected = build_cache_sharded(&entries, None, &[], 1, None);
+        assert!(build_cache(&entries, None, &[]) == expected);
+        for shards in [2, 3, 7, 64] {
+            assert!(
+                build_cache_sharded(&entries, None, &[], shards, None) == expected,
+                "{} shards",
+                shards
+            );
         }
     }
 
@@ -490,6 +1676,29 @@ mod tests {
         );
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn biarch_flags_stay_apart() {
+        let x86_64 = Arch::X86_64.flags();
+        let i686 = Arch::Libc6.flags();
+        assert!(dl_cache_check_flags(x86_64, x86_64));
+        assert!(!dl_cache_check_flags(i686, x86_64));
+        assert!(!dl_cache_check_flags(x86_64, i686));
+        assert!(!dl_cache_check_flags(Arch::X32.flags(), x86_64));
+        assert!(dl_cache_check_flags(FLAG_ELF, i686));
+
+        let armhf = Arch::ArmHardFloat.flags();
+        assert!(dl_cache_check_flags(i686, armhf));
+        assert!(!dl_cache_check_flags(Arch::ArmSoftFloat.flags(), armhf));
+        assert!(!dl_cache_check_flags(armhf, Arch::AArch64.flags()));
+        assert!(!dl_cache_check_flags(Arch::AArch64.flags(), armhf));
+
+        // sysdeps/aarch64/dl-cache.h: flags == _DL_CACHE_DEFAULT_ID only.
+        let aarch64 = Arch::AArch64.flags();
+        assert!(dl_cache_check_flags(aarch64, aarch64));
+        assert!(!dl_cache_check_flags(FLAG_ELF, aarch64));
+    }
+
     #[test]
     fn sort_hwcaps_entries_first_by_name() {
         let mut entries = [
@@ -518,7 +1727,7 @@ mod tests {
             entry("libtest.so.1", "/usr/lib/libtest.so.1", 0x0303, None),
             entry("libother.so.2", "/usr/lib/libother.so.2", 0x0303, None),
         ];
-        let data = build_cache(&entries);
+        let data = build(&entries);
         let info = parse_cache(&data).unwrap();
 
         assert_eq!(info.entries.len(), 2);
@@ -546,7 +1755,7 @@ mod tests {
                 Some("x86-64-v3"),
             ),
         ];
-        let data = build_cache(&entries);
+        let data = build(&entries);
         let info = parse_cache(&data).unwrap();
 
         assert_eq!(info.entries.len(), 2);
@@ -567,11 +1776,145 @@ mod tests {
             Some("x86-64-v3"),
         );
         e.isa_level = 2;
-        let data = build_cache(&[e]);
+        let data = build(&[e]);
         let info = parse_cache(&data).unwrap();
         assert_eq!((info.entries[0].hwcap >> 32) & 0x3ff, 2);
     }
 
+    #[test]
+    fn rebuild_from_parsed_entries_is_identical() {
+        let mut legacy = entry("libc.so.6", "/lib/i686/libc.so.6", 0x0003, None);
+        legacy.hwcap = 1 << 49;
+        let mut hw = entry(
+            "liba.so.1",
+            "/usr/lib/glibc-hwcaps/x86-64-v3/liba.so.1",
+            0x0303,
+            Some("x86-64-v3"),
+        );
+        hw.isa_level = 3;
+        hw.osversion = OsVersion::linux(3, 2, 0).to_raw();
+        let extra = RawExtension {
+            tag: 0x1234,
+            flags: 7,
+            data: b"opaque".to_vec(),
+        };
+        let data = build_cache(
+            &[
+                entry("liba.so.1", "/usr/lib/liba.so.1", 0x0303, None),
+                hw,
+                legacy,
+            ],
+            Some("someone else"),
+            std::slice::from_ref(&extra),
+        );
+        let info = parse_cache(&data).unwrap();
+        assert_eq!(info.generator.as_deref(), Some("someone else"));
+        assert_eq!(info.extensions, [extra]);
+
+        let entries = file_entries(&data, &info).unwrap();
+        let rebuilt = build_cache(&entries, info.generator.as_deref(), &info.extensions);
+        assert_eq!(rebuilt, data);
+    }
+
+    #[test]
+    fn content_hashes_round_trip() {
+        let mut a = entry("liba.so.1", "/lib/liba.so.1", 0x0303, None);
+        a.sha256 = Some([0xaa; 32]);
+        let b = entry("libb.so.1", "/lib/libb.so.1", 0x0303, None);
+        let data = build(&[a, b]);
+        let info = parse_cache(&data).unwrap();
+        // Sorted: libb before liba.
+        assert_eq!(info.entries[0].sha256, None);
+        assert_eq!(info.entries[1].sha256, Some([0xaa; 32]));
+        assert!(info.extensions.is_empty());
+
+        let entries = file_entries(&data, &info).unwrap();
+        let rebuilt = build_cache(&entries, info.generator.as_deref(), &info.extensions);
+        assert_eq!(rebuilt, data);
+    }
+
+    #[test]
+    fn file_stats_round_trip() {
+        let mut a = entry("liba.so.1", "/lib/liba.so.1", 0x0303, None);
+        a.stat = Some(FileStat {
+            size: 4096,
+            mtime: -1,
+            mtime_nsec: 999_999_999,
+        });
+        let b = entry("libb.so.1", "/lib/libb.so.1", 0x0303, None);
+        let data = build(&[a.clone(), b]);
+        let info = parse_cache(&data).unwrap();
+        assert_eq!(info.entries[0].stat, None);
+        assert_eq!(info.entries[1].stat, a.stat);
+
+        let entries = file_entries(&data, &info).unwrap();
+        let rebuilt = build_cache(&entries, info.generator.as_deref(), &info.extensions);
+        assert_eq!(rebuilt, data);
+    }
+
+    #[test]
+    fn provenance_round_trip() {
+        let p = Provenance {
+            timestamp: Some(1_700_000_000),
+            host: Some("builder\ninjected=1".into()),
+            config_hash: None,
+            tool_version: Some("ldconfig-rs 0.1.1".into()),
+        };
+        let data = build_cache(
+            &[entry("liba.so.1", "/lib/liba.so.1", 3, None)],
+            None,
+            &[p.to_extension()],
+        );
+        let info = parse_cache(&data).unwrap();
+        let read = Provenance::from_extensions(&info.extensions).unwrap();
+        assert_eq!(read.timestamp, p.timestamp);
+        assert_eq!(read.host.as_deref(), Some("builder"));
+        assert_eq!(read.config_hash, None);
+        assert_eq!(read.tool_version, p.tool_version);
+        assert_eq!(Provenance::from_extensions(&[]), None);
+    }
+
+    #[test]
+    fn glibc_version_from_libc_bytes() {
+        let banner = b"\0GNU C Library (Debian GLIBC 2.36-9) stable release version 2.36.\n\0";
+        assert_eq!(
+            GlibcVersion::from_libc(banner),
+            Some(GlibcVersion::new(2, 36))
+        );
+        let stripped = b"\0GLIBC_2.2.5\0GLIBC_2.34\0GLIBC_PRIVATE\0GLIBC_2.17\0";
+        assert_eq!(
+            GlibcVersion::from_libc(stripped),
+            Some(GlibcVersion::new(2, 34))
+        );
+        assert_eq!(GlibcVersion::from_libc(b"musl"), None);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::create_dir_all(root.join("usr/lib/aarch64-linux-gnu")).unwrap();
+        fs::write(root.join("usr/lib/aarch64-linux-gnu/libc.so.6"), banner).unwrap();
+        assert_eq!(GlibcVersion::detect(&root), Some(GlibcVersion::new(2, 36)));
+        assert_eq!(
+            GlibcVersion::detect(root.join("usr/lib/aarch64-linux-gnu")),
+            None
+        );
+    }
+
+    #[test]
+    fn source_date_epoch_fixes_the_build_time() {
+        assert_eq!(build_time(Some("1700000000")), Some(1_700_000_000));
+        assert_eq!(build_time(Some("yesterday")), None);
+        assert!(build_time(None).is_some_and(|now| now > 1_700_000_000));
+    }
+
+    #[test]
+    fn rebuild_without_extensions_has_no_directory() {
+        let data = build_cache(&[entry("liba.so.1", "/lib/liba.so.1", 3, None)], None, &[]);
+        let info = parse_cache(&data).unwrap();
+        assert_eq!(read_u32(&data, 32), Some(0));
+        assert_eq!(info.generator, None);
+        assert_eq!(file_entries(&data, &info).unwrap().len(), 1);
+    }
+
     #[test]
     fn parse_rejects_garbage_without_panicking() {
         assert!(parse_cache(b"").is_err());
@@ -579,7 +1922,7 @@ mod tests {
         assert!(parse_cache(&[0u8; 48]).is_err());
 
         // Truncations of a valid cache must never panic.
-        let data = build_cache(&[entry("liba.so.1", "/usr/lib/liba.so.1", 0x0303, None)]);
+        let data = build(&[entry("liba.so.1", "/usr/lib/liba.so.1", 0x0303, None)]);
         for len in 0..data.len() {
             let _ = parse_cache(&data[..len]);
         }
@@ -590,6 +1933,140 @@ mod tests {
         assert!(parse_cache(&bad).is_err());
     }
 
+    fn old_cache(libs: &[(u32, &str, &str)]) -> Vec<u8> {
+        let mut data = OLD_CACHE_MAGIC.to_vec();
+        data.push(0);
+        data.extend_from_slice(&(libs.len() as u32).to_ne_bytes());
+        let mut strings = Vec::new();
+        for (flags, key, value) in libs {
+            data.extend_from_slice(&flags.to_ne_bytes());
+            data.extend_from_slice(&(strings.len() as u32).to_ne_bytes());
+            strings.extend_from_slice(key.as_bytes());
+            strings.push(0);
+            data.extend_from_slice(&(strings.len() as u32).to_ne_bytes());
+            strings.extend_from_slice(value.as_bytes());
+            strings.push(0);
+        }
+        data.extend_from_slice(&strings);
+        data
+    }
+
+    #[test]
+    fn detects_formats() {
+        let new = build(&[entry("liba.so.1", "/usr/lib/liba.so.1", 0x0303, None)]);
+        assert_eq!(parse_cache(&new).unwrap().format, CacheFormat::New);
+
+        let old = old_cache(&[(0x0303, "libold.so.1", "/lib/libold.so.1")]);
+        assert_eq!(new_format_offset(&old), None);
+        let info = parse_cache(&old).unwrap();
+        assert_eq!(info.format, CacheFormat::Old);
+        assert_eq!(info.entries.len(), 1);
+        let e = &info.entries[0];
+        assert_eq!(e.flags, 0x0303);
+        assert_eq!(
+            read_string(&old, e.key_offset as usize).unwrap(),
+            "libold.so.1"
+        );
+        assert_eq!(
+            read_string(&old, e.value_offset as usize).unwrap(),
+            "/lib/libold.so.1"
+        );
+    }
+
+    #[test]
+    fn compat_reads_embedded_new_cache() {
+        let data = build_cache_as(
+            CacheFormat::Compat,
+            &[entry("liba.so.1", "/usr/lib/liba.so.1", 0x0303, None)],
+            Some(&default_generator()),
+            &[],
+        )
+        .unwrap();
+        let base = (OLD_HEADER_SIZE + OLD_ENTRY_SIZE).next_multiple_of(8);
+
+        let info = parse_cache(&data).unwrap();
+        assert_eq!(info.format, CacheFormat::Compat);
+        // Extension offsets are file offsets, as glibc writes them.
+        assert_eq!(info.generator, Some(default_generator()));
+        assert_eq!(new_format_offset(&data), Some(base));
+        let header = Header::parse(&data[base..]).unwrap();
+        assert_eq!(header.nlibs, 1);
+        let e = &info.entries[0];
+        assert!(e.key_offset as usize > base);
+        assert!(e.key_offset as usize >= base + header.string_table_offset());
+        assert_eq!(
+            read_string(&data, e.key_offset as usize).unwrap(),
+            "liba.so.1"
+        );
+    }
+
+    #[test]
+    fn old_format_rejects_out_of_range_strings() {
+        let mut data = old_cache(&[(0x0303, "liba.so.1", "/lib/liba.so.1")]);
+        data[20..24].copy_from_slice(&u32::MAX.to_ne_bytes());
+        assert!(parse_cache(&data).is_err());
+        for len in 0..data.len() {
+            let _ = parse_cache(&data[..len]);
+        }
+    }
+
+    #[test]
+    fn legacy_hwcap_mask_round_trips_and_sorts_specific_first() {
+        let mut tls = entry("libc.so.6", "/lib/tls/i686/libc.so.6", 0x0003, None);
+        tls.hwcap = LEGACY_HWCAP_TLS | 1 << (LEGACY_X86_FIRST_PLATFORM + 1);
+        let plain = entry("libc.so.6", "/lib/libc.so.6", 0x0003, None);
+        let data = build(&[plain, tls]);
+        let info = parse_cache(&data).unwrap();
+
+        assert_eq!(info.entries[0].hwcap, LEGACY_HWCAP_TLS | 1 << 49);
+        assert_eq!(info.entries[0].hwcaps, None);
+        assert_eq!(info.entries[1].hwcap, 0);
+    }
+
+    #[test]
+    fn osversion_decodes() {
+        let v = OsVersion::from_raw(0x0003_0200).unwrap();
+        assert_eq!(v.triple(), (3, 2, 0));
+        assert_eq!(v.to_string(), "Linux 3.2.0");
+        assert_eq!(v.to_raw(), 0x0003_0200);
+        assert_eq!(OsVersion::from_raw(0), None);
+        assert_eq!(
+            OsVersion::from_raw(0x0300_0a01).unwrap().to_string(),
+            "FreeBSD 0.10.1"
+        );
+        assert_eq!(
+            OsVersion::from_raw(0x4000_0000).unwrap().os_name(),
+            "Unknown OS"
+        );
+    }
+
+    #[test]
+    fn legacy_hwcap_names_decode() {
+        assert_eq!(
+            legacy_hwcap_names(0x0003, LEGACY_HWCAP_TLS | 1 << 49 | 1),
+            ["sse2", "i686", "tls"]
+        );
+        assert_eq!(legacy_hwcap_names(0x0a03, 1 << 5), ["hwcap_5"]);
+        assert!(legacy_hwcap_names(0x0303, 0).is_empty());
+        // glibc-hwcaps references are not legacy masks.
+        assert!(legacy_hwcap_names(0x0303, DL_CACHE_HWCAP_EXTENSION).is_empty());
+    }
+
+    #[test]
+    fn arch_round_trips_flags() {
+        assert_eq!(Arch::from_flags(0x0303), Arch::X86_64);
+        assert_eq!(Arch::from_flags(0x0003), Arch::Libc6);
+        assert_eq!(Arch::from_flags(0x0a03), Arch::AArch64);
+        assert_eq!(Arch::from_flags(0x1003), Arch::RiscvDoubleFloat);
+        assert_eq!(Arch::from_flags(0x0002), Arch::Unknown(0x0002));
+        assert_eq!(Arch::from_flags(0x7f03), Arch::Unknown(0x7f03));
+        for (arch, _) in Arch::TABLE {
+            assert_eq!(Arch::from_flags(arch.flags()), arch);
+        }
+        assert_eq!(Arch::Unknown(0x7f03).flags(), 0x7f03);
+        assert_eq!(Arch::X86_64.to_string(), "libc6,x86-64");
+    }
+
     #[test]
     fn flags_strings_match_glibc() {
         assert_eq!(flags_string(0x0303), "libc6,x86-64");
@@ -0,0 +1,115 @@
+//! What this build of the crate supports, for tools deciding at run time
+//! whether to rely on it, e.g. for glibc-hwcaps entries.
+
+use crate::cache_format::Arch;
+use crate::CacheFormat;
+use std::fmt;
+
+/// The architectures, cache formats, extension sections and Cargo
+/// features this build knows about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// This crate's version.
+    pub version: &'static str,
+    /// Every ABI the crate classifies, in flag order.
+    pub architectures: Vec<Arch>,
+    /// Layouts read and written.
+    pub formats: Vec<CacheFormat>,
+    /// Extension sections read and written, by name.
+    pub extensions: Vec<&'static str>,
+    /// Optional Cargo features compiled in.
+    pub features: Vec<&'static str>,
+    /// Whether caches can be built (scanning directories, maintaining
+    /// symlinks), which needs a Unix host; reading works everywhere.
+    pub build: bool,
+}
+
+/// What this build supports.
+pub fn capabilities() -> Capabilities {
+    let mut extensions = vec![
+        "generator",
+        "glibc-hwcaps",
+        "sha256",
+        "file-stat",
+        "provenance",
+    ];
+    if cfg!(feature = "signing") {
+        extensions.push("signature");
+    }
+    let features = [
+        ("signing", cfg!(feature = "signing")),
+        ("journald", cfg!(feature = "journald")),
+        ("arbitrary", cfg!(feature = "arbitrary")),
+        ("regex", cfg!(feature = "regex")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        architectures: Arch::TABLE.iter().map(|&(arch, _)| arch).collect(),
+        formats: vec![CacheFormat::Old, CacheFormat::Compat, CacheFormat::New],
+        extensions,
+        features,
+        build: cfg!(unix),
+    }
+}
+
+impl fmt::Display for Capabilities {
+    /// `key=value` lines, lists separated by spaces, as
+    /// `ldconfig --capabilities` prints them. Architectures are their
+    /// entry flags in hex, which tell apart ABIs sharing a description,
+    /// and that description:
+    ///
+    /// ```text
+    /// version=0.1.1
+    /// architectures=0003:libc6 0103:libc6,64bit ... 0303:libc6,x86-64 ...
+    /// formats=old compat new
+    /// extensions=generator glibc-hwcaps sha256 file-stat provenance
+    /// features=regex
+    /// build=yes
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |items: Vec<String>| items.join(" ");
+        writeln!(f, "version={}", self.version)?;
+        writeln!(
+            f,
+            "architectures={}",
+            join(
+                self.architectures
+                    .iter()
+                    .map(|arch| format!("{:04x}:{}", arch.flags(), arch))
+                    .collect()
+            )
+        )?;
+        let formats = self.formats.iter().map(|format| match format {
+            CacheFormat::Old => "old".to_owned(),
+            CacheFormat::Compat => "compat".to_owned(),
+            CacheFormat::New => "new".to_owned(),
+        });
+        writeln!(f, "formats={}", join(formats.collect()))?;
+        writeln!(f, "extensions={}", self.extensions.join(" "))?;
+        writeln!(f, "features={}", self.features.join(" "))?;
+        writeln!(f, "build={}", if self.build { "yes" } else { "no" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_machine_readable_lines() {
+        let caps = capabilities();
+        assert!(caps.architectures.contains(&Arch::X86_64));
+        assert!(!caps
+            .architectures
+            .iter()
+            .any(|a| matches!(a, Arch::Unknown(_))));
+        let text = caps.to_string();
+        assert!(text.contains("\nformats=old compat new\n"));
+        assert!(text.contains(" 0303:libc6,x86-64 "));
+        assert_eq!(text.lines().count(), 6);
+        assert!(text.lines().all(|line| line.contains('=')));
+    }
+}
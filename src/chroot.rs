@@ -1,8 +1,12 @@
 //! Path canonicalization inside an alternate root, port of glibc's
-//! elf/chroot_canon.c.
+//! elf/chroot_canon.c, and entering that root for real.
 
+use crate::error::Error;
 use camino::{Utf8Path, Utf8PathBuf};
 use std::fs;
+use std::io;
+use std::str::FromStr;
+use tracing::debug;
 
 const ELOOP_MAX: u32 = 40;
 
@@ -79,7 +83,78 @@ pub fn chroot_canon(root: &Utf8Path, name: &Utf8Path) -> Option<Utf8PathBuf> {
     Some(Utf8PathBuf::from(out))
 }
 
-#[cfg(test)]
+/// How an alternate root (-r) is entered.
+///
+/// glibc 2.35 and later chroot into it, so configuration includes,
+/// symlinks and the cache file all resolve as they would on the booted
+/// system. Emulation gets the same answers without privileges, by running
+/// every path through [`chroot_canon`]; what it cannot cover are programs
+/// and files outside the crate's own lookups, such as `TMPDIR`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RootMode {
+    /// chroot(2) when permitted, emulate otherwise.
+    #[default]
+    Auto,
+    /// chroot(2), failing without the privilege to.
+    Chroot,
+    /// Resolve paths inside the root, never leaving the host's.
+    Emulate,
+}
+
+impl FromStr for RootMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(RootMode::Auto),
+            "chroot" => Ok(RootMode::Chroot),
+            "emulate" => Ok(RootMode::Emulate),
+            _ => Err(format!("expected auto, chroot or emulate, got `{}'", s)),
+        }
+    }
+}
+
+/// Enter `root` the way `mode` says, returning the prefix to build and
+/// read caches with from then on: `/` once chrooted, `root` itself when
+/// emulating. A real chroot applies to the whole process and cannot be
+/// undone.
+pub fn enter_root(root: &Utf8Path, mode: RootMode) -> Result<Utf8PathBuf, Error> {
+    if root == "/" || mode == RootMode::Emulate {
+        return Ok(root.to_path_buf());
+    }
+    match chroot(root) {
+        Ok(()) => {
+            debug!("Changed root to {}", root);
+            Ok(Utf8PathBuf::from("/"))
+        }
+        Err(e) if mode == RootMode::Auto && e.kind() == io::ErrorKind::PermissionDenied => {
+            debug!(
+                "Cannot chroot to {} ({}), resolving paths inside it",
+                root, e
+            );
+            Ok(root.to_path_buf())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(unix)]
+fn chroot(root: &Utf8Path) -> io::Result<()> {
+    let path = std::ffi::CString::new(root.as_str())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `path` is a valid NUL-terminated string.
+    if unsafe { libc::chroot(path.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    std::env::set_current_dir("/")
+}
+
+#[cfg(not(unix))]
+fn chroot(_root: &Utf8Path) -> io::Result<()> {
+    Err(io::ErrorKind::PermissionDenied.into())
+}
+
+#[cfg(all(test, unix))]
 mod tests {
     use super::*;
     use std::os::unix::fs::symlink;
@@ -126,4 +201,24 @@ mod tests {
         let canon = chroot_canon(&root, Utf8Path::new("/../../etc")).unwrap();
         assert_eq!(canon, root.join("etc"));
     }
+
+    #[test]
+    fn unprivileged_roots_are_emulated() {
+        assert_eq!("emulate".parse(), Ok(RootMode::Emulate));
+        assert!("fake".parse::<RootMode>().is_err());
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        assert_eq!(enter_root(&root, RootMode::Emulate).unwrap(), root);
+        assert_eq!(
+            enter_root(Utf8Path::new("/"), RootMode::Chroot).unwrap(),
+            "/"
+        );
+        // Trying either of the others as root would chroot the test runner.
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        assert_eq!(enter_root(&root, RootMode::Auto).unwrap(), root);
+        assert!(enter_root(&root, RootMode::Chroot).is_err());
+    }
 }
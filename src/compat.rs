@@ -0,0 +1,221 @@
+//! Comparing our `ldconfig -p` rendering against a capture of glibc's.
+
+use crate::Cache;
+use camino::Utf8Path;
+use std::collections::HashMap;
+use std::fmt;
+
+const GENERATOR_PREFIX: &str = "Cache generated by: ";
+
+/// One way our printed cache differs from a reference `ldconfig -p`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// The "N libs found in cache" line differs.
+    Header { ours: String, reference: String },
+    /// The "Cache generated by" line differs; expected unless the
+    /// reference was taken from a cache we wrote.
+    Generator {
+        ours: Option<String>,
+        reference: Option<String>,
+    },
+    /// Same soname and path, rendered differently (flags, hwcap...).
+    Changed { ours: String, reference: String },
+    /// Printed by the reference only.
+    Missing(String),
+    /// Printed by us only.
+    Extra(String),
+    /// Entries both print, but in another order: the first entry we
+    /// print ahead of where the reference has it.
+    Order { ours: String, reference: String },
+}
+
+impl Divergence {
+    /// Whether this is about content rather than the generator line.
+    pub fn is_content(&self) -> bool {
+        !matches!(self, Divergence::Generator { .. })
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |o: &Option<String>| o.clone().unwrap_or_else(|| "(none)".into());
+        match self {
+            Divergence::Header { ours, reference } => {
+                write!(f, "header:\n  ours: {}\n  ref:  {}", ours, reference)
+            }
+            Divergence::Generator { ours, reference } => write!(
+                f,
+                "generator:\n  ours: {}\n  ref:  {}",
+                show(ours),
+                show(reference)
+            ),
+            Divergence::Changed { ours, reference } => {
+                write!(f, "changed:\n  ours:{}\n  ref: {}", ours, reference)
+            }
+            Divergence::Missing(line) => write!(f, "missing:{}", line),
+            Divergence::Extra(line) => write!(f, "extra:  {}", line),
+            Divergence::Order { ours, reference } => {
+                write!(f, "order:\n  ours:{}\n  ref: {}", ours, reference)
+            }
+        }
+    }
+}
+
+/// (soname, path) of an entry line, `\tSONAME (...) => PATH`.
+fn entry_key(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix('\t')?;
+    let (soname, _) = rest.split_once(" (")?;
+    let (_, path) = rest.rsplit_once(" => ")?;
+    Some((soname, path))
+}
+
+/// Split `ldconfig -p` output into header, entry lines and generator.
+fn split_output(text: &str) -> (String, Vec<&str>, Option<String>) {
+    let mut lines = text.lines();
+    let header = lines.next().unwrap_or_default().to_owned();
+    let mut entries = Vec::new();
+    let mut generator = None;
+    for line in lines {
+        if let Some(g) = line.strip_prefix(GENERATOR_PREFIX) {
+            generator = Some(g.to_owned());
+        } else if !line.is_empty() {
+            entries.push(line);
+        }
+    }
+    (header, entries, generator)
+}
+
+/// Compare two `ldconfig -p` outputs, `ours` against `reference`.
+pub(crate) fn compare(ours: &str, reference: &str) -> Vec<Divergence> {
+    let (our_header, our_entries, our_generator) = split_output(ours);
+    let (ref_header, ref_entries, ref_generator) = split_output(reference);
+    let mut diffs = Vec::new();
+
+    if our_header != ref_header {
+        diffs.push(Divergence::Header {
+            ours: our_header,
+            reference: ref_header,
+        });
+    }
+
+    // Pair lines by (soname, path), in order for repeated keys.
+    let mut pool: HashMap<(&str, &str), Vec<usize>> = HashMap::new();
+    for (i, line) in our_entries.iter().enumerate().rev() {
+        if let Some(key) = entry_key(line) {
+            pool.entry(key).or_default().push(i);
+        }
+    }
+    let mut matched = vec![false; our_entries.len()];
+    let mut order = Vec::new();
+    for line in &ref_entries {
+        let found = entry_key(line).and_then(|key| pool.get_mut(&key)?.pop());
+        match found {
+            Some(i) => {
+                matched[i] = true;
+                order.push((i, *line));
+                if our_entries[i] != *line {
+                    diffs.push(Divergence::Changed {
+                        ours: our_entries[i].to_owned(),
+                        reference: (*line).to_owned(),
+                    });
+                }
+            }
+            None => diffs.push(Divergence::Missing((*line).to_owned())),
+        }
+    }
+    for (i, line) in our_entries.iter().enumerate() {
+        if !matched[i] {
+            diffs.push(Divergence::Extra((*line).to_owned()));
+        }
+    }
+    if let Some(w) = order.windows(2).find(|w| w[0].0 > w[1].0) {
+        diffs.push(Divergence::Order {
+            ours: our_entries[w[1].0].to_owned(),
+            reference: w[1].1.to_owned(),
+        });
+    }
+
+    if our_generator != ref_generator {
+        diffs.push(Divergence::Generator {
+            ours: our_generator,
+            reference: ref_generator,
+        });
+    }
+    diffs
+}
+
+impl Cache {
+    /// The exact `ldconfig -p` output for this cache as if read from
+    /// `path`, which only appears in the header line.
+    pub fn print_output(&self, path: impl AsRef<Utf8Path>) -> String {
+        let info = self.info();
+        let mut out = format!(
+            "{} libs found in cache `{}'\n",
+            info.num_entries,
+            path.as_ref()
+        );
+        for entry in self.entries() {
+            out.push_str(&format!("{}\n", entry));
+        }
+        if let Some(generator) = info.generator {
+            out.push_str(&format!("{}{}\n", GENERATOR_PREFIX, generator));
+        }
+        out
+    }
+
+    /// Compare [`print_output`](Self::print_output) against a captured
+    /// `ldconfig -p` of the same cache path; empty when they match.
+    pub fn compare_print_output(
+        &self,
+        path: impl AsRef<Utf8Path>,
+        reference: &str,
+    ) -> Vec<Divergence> {
+        compare(&self.print_output(path), reference)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFERENCE: &str = "\
+3 libs found in cache `/etc/ld.so.cache'
+\tlibz.so.1 (libc6,x86-64) => /usr/lib/libz.so.1
+\tlibm.so.6 (libc6,x86-64) => /usr/lib/libm.so.6
+\tlibc.so.6 (libc6,x86-64) => /usr/lib/libc.so.6
+Cache generated by: ldconfig (GNU libc) stable release version 2.40
+";
+
+    #[test]
+    fn identical_output_has_no_divergence() {
+        assert!(compare(REFERENCE, REFERENCE).is_empty());
+    }
+
+    #[test]
+    fn classifies_divergences() {
+        let ours = "\
+3 libs found in cache `/etc/ld.so.cache'
+\tlibc.so.6 (libc6,x86-64) => /usr/lib/libc.so.6
+\tlibz.so.1 (libc6,x86-64, hwcap: \"x86-64-v3\") => /usr/lib/libz.so.1
+\tlibnew.so.1 (libc6,x86-64) => /usr/lib/libnew.so.1
+Cache generated by: ldconfig-rs 0.1.1
+";
+        let diffs = compare(ours, REFERENCE);
+        assert!(diffs.contains(&Divergence::Changed {
+            ours: "\tlibz.so.1 (libc6,x86-64, hwcap: \"x86-64-v3\") => /usr/lib/libz.so.1".into(),
+            reference: "\tlibz.so.1 (libc6,x86-64) => /usr/lib/libz.so.1".into(),
+        }));
+        assert!(diffs.contains(&Divergence::Missing(
+            "\tlibm.so.6 (libc6,x86-64) => /usr/lib/libm.so.6".into()
+        )));
+        assert!(diffs.contains(&Divergence::Extra(
+            "\tlibnew.so.1 (libc6,x86-64) => /usr/lib/libnew.so.1".into()
+        )));
+        assert!(diffs.iter().any(|d| matches!(d, Divergence::Order { .. })));
+        let generator = diffs.iter().find(|d| !d.is_content()).unwrap();
+        assert!(
+            matches!(generator, Divergence::Generator { ours: Some(g), .. } if g.starts_with("ldconfig-rs"))
+        );
+        assert!(!diffs.iter().any(|d| matches!(d, Divergence::Header { .. })));
+    }
+}
@@ -2,18 +2,36 @@
 
 use crate::chroot::chroot_canon;
 use crate::error::Error;
+use crate::report::{Warning, WarningKind};
 use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
 use std::io::ErrorKind;
 use std::ops::Deref;
-use tracing::warn;
 
 /// Built-in system directories, appended after the configured ones like
 /// glibc's add_system_dir calls. /usr precedes the top-level aliases so
 /// that merged-usr systems cache the /usr path text, as their glibc does.
-const SYSTEM_DIRS: [&str; 4] = ["/usr/lib", "/usr/lib64", "/lib", "/lib64"];
+pub(crate) const SYSTEM_DIRS: [&str; 4] = ["/usr/lib", "/usr/lib64", "/lib", "/lib64"];
 
-const MAX_INCLUDE_DEPTH: u32 = 32;
+/// How deep includes nest by default, before a warning stops them.
+pub const DEFAULT_INCLUDE_DEPTH: u32 = 32;
+
+/// The configuration file line a directory was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigLine {
+    /// The file, as named inside the -r root.
+    pub file: Utf8PathBuf,
+    /// 1-based line number.
+    pub line: usize,
+}
+
+impl fmt::Display for ConfigLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
 
 /// List of directories to scan for libraries
 ///
@@ -21,7 +39,13 @@ const MAX_INCLUDE_DEPTH: u32 = 32;
 /// convenient constructors for creating directory lists from config files
 /// or defaults. Paths are as configured, without the -r prefix applied.
 #[derive(Debug, Clone)]
-pub struct SearchPaths(Vec<Utf8PathBuf>);
+pub struct SearchPaths {
+    dirs: Vec<Utf8PathBuf>,
+    /// Where each directory read from a configuration file first appeared.
+    sources: HashMap<Utf8PathBuf, ConfigLine>,
+    /// Problems reading the configuration.
+    warnings: Vec<Warning>,
+}
 
 impl SearchPaths {
     /// Parse a configuration file. `path` names the file inside `prefix`
@@ -30,27 +54,108 @@ impl SearchPaths {
     /// Missing or unreadable files yield only the built-in directories,
     /// with a warning, like glibc.
     pub fn from_file(path: impl AsRef<Utf8Path>, prefix: Option<&Utf8Path>) -> Result<Self, Error> {
+        Self::from_file_with_depth(path, prefix, DEFAULT_INCLUDE_DEPTH)
+    }
+
+    /// [`SearchPaths::from_file`], following includes at most
+    /// `max_depth` files deep: 0 ignores every `include`, 1 reads the
+    /// files the configuration includes but not what they include. Files
+    /// deeper are skipped with a warning.
+    pub fn from_file_with_depth(
+        path: impl AsRef<Utf8Path>,
+        prefix: Option<&Utf8Path>,
+        max_depth: u32,
+    ) -> Result<Self, Error> {
         let prefix = prefix
             .map(|p| p.as_str().trim_end_matches('/'))
             .filter(|p| !p.is_empty())
             .map(Utf8Path::new);
 
-        let mut dirs = Vec::new();
-        parse_conf(path.as_ref(), prefix, &mut dirs, 0);
-        dirs.extend(SYSTEM_DIRS.map(Utf8PathBuf::from));
-        Ok(Self(dirs))
+        let mut paths = Self::new(Vec::new());
+        parse_conf(
+            path.as_ref(),
+            prefix,
+            &mut paths,
+            &mut Vec::new(),
+            max_depth,
+        );
+        paths.extend(SYSTEM_DIRS.map(Utf8PathBuf::from));
+        Ok(paths)
     }
 
     /// Create config from explicit directory list
     pub fn new(directories: Vec<Utf8PathBuf>) -> Self {
-        Self(directories)
+        Self {
+            dirs: directories,
+            sources: HashMap::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Problems met reading the configuration, such as includes that
+    /// cannot be opened, which were skipped with a warning.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    /// Log a warning and record it.
+    fn warn(&mut self, kind: WarningKind, message: String) {
+        self.warnings.push(Warning::logged(kind, message));
+    }
+
+    /// The configuration file line `dir` was read from; None for
+    /// built-in and explicitly given directories.
+    pub fn source(&self, dir: impl AsRef<Utf8Path>) -> Option<&ConfigLine> {
+        let dir = dir.as_ref().as_str().trim_end_matches('/');
+        self.sources.get(Utf8Path::new(dir))
+    }
+
+    /// The directories in scan order, each with the configuration line it
+    /// was read from; conf.d includes appear in byte order of their file
+    /// names.
+    pub fn with_sources(&self) -> impl Iterator<Item = (&Utf8Path, Option<&ConfigLine>)> {
+        self.dirs
+            .iter()
+            .map(|dir| (dir.as_path(), self.source(dir)))
+    }
+
+    /// Move every directory of `other` to the end, keeping their sources
+    /// and warnings.
+    pub fn append(&mut self, other: SearchPaths) {
+        for (dir, line) in other.sources {
+            self.sources.entry(dir).or_insert(line);
+        }
+        self.dirs.extend(other.dirs);
+        self.warnings.extend(other.warnings);
+    }
+
+    /// Append a directory, scanned after the existing ones.
+    pub fn push(&mut self, dir: impl Into<Utf8PathBuf>) {
+        self.dirs.push(dir.into());
+    }
+
+    /// Insert a directory at `index`, shifting later ones back.
+    ///
+    /// # Panics
+    ///
+    /// If `index` is greater than the number of directories.
+    pub fn insert(&mut self, index: usize, dir: impl Into<Utf8PathBuf>) {
+        self.dirs.insert(index, dir.into());
+    }
+
+    /// Remove every occurrence of `dir`, returning whether any was there.
+    pub fn remove(&mut self, dir: impl AsRef<Utf8Path>) -> bool {
+        let before = self.dirs.len();
+        self.dirs.retain(|d| d != dir.as_ref());
+        self.sources.remove(dir.as_ref());
+        self.dirs.len() != before
     }
 }
 
 impl Default for SearchPaths {
     /// Create default config (standard system directories)
     fn default() -> Self {
-        Self(SYSTEM_DIRS.map(Utf8PathBuf::from).to_vec())
+        Self::new(SYSTEM_DIRS.map(Utf8PathBuf::from).to_vec())
     }
 }
 
@@ -58,19 +163,49 @@ impl Deref for SearchPaths {
     type Target = [Utf8PathBuf];
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.dirs
     }
 }
 
 impl AsRef<[Utf8PathBuf]> for SearchPaths {
     fn as_ref(&self) -> &[Utf8PathBuf] {
-        &self.0
+        &self.dirs
     }
 }
 
 impl From<Vec<Utf8PathBuf>> for SearchPaths {
     fn from(directories: Vec<Utf8PathBuf>) -> Self {
-        Self(directories)
+        Self::new(directories)
+    }
+}
+
+impl FromIterator<Utf8PathBuf> for SearchPaths {
+    fn from_iter<I: IntoIterator<Item = Utf8PathBuf>>(iter: I) -> Self {
+        Self::new(iter.into_iter().collect())
+    }
+}
+
+impl Extend<Utf8PathBuf> for SearchPaths {
+    fn extend<I: IntoIterator<Item = Utf8PathBuf>>(&mut self, iter: I) {
+        self.dirs.extend(iter);
+    }
+}
+
+impl IntoIterator for SearchPaths {
+    type Item = Utf8PathBuf;
+    type IntoIter = std::vec::IntoIter<Utf8PathBuf>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.dirs.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a SearchPaths {
+    type Item = &'a Utf8PathBuf;
+    type IntoIter = std::slice::Iter<'a, Utf8PathBuf>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.dirs.iter()
     }
 }
 
@@ -88,9 +223,20 @@ fn directive<'a>(line: &'a str, keyword: &str, ignore_case: bool) -> Option<&'a
     (matches && rest.starts_with([' ', '\t'])).then_some(rest)
 }
 
-fn parse_conf(file: &Utf8Path, prefix: Option<&Utf8Path>, dirs: &mut Vec<Utf8PathBuf>, depth: u32) {
-    if depth > MAX_INCLUDE_DEPTH {
-        warn!("{}: include nesting too deep", file);
+/// Parse `file` into `dirs`; `including` holds the files whose includes
+/// led to it, outermost first, by their resolved paths.
+fn parse_conf(
+    file: &Utf8Path,
+    prefix: Option<&Utf8Path>,
+    dirs: &mut SearchPaths,
+    including: &mut Vec<Utf8PathBuf>,
+    max_depth: u32,
+) {
+    if including.len() as u32 > max_depth {
+        dirs.warn(
+            WarningKind::IncludeTooDeep,
+            format!("{}: include nesting too deep", file),
+        );
         return;
     }
     let real = match prefix {
@@ -100,19 +246,36 @@ fn parse_conf(file: &Utf8Path, prefix: Option<&Utf8Path>, dirs: &mut Vec<Utf8Pat
         },
         None => file.to_path_buf(),
     };
+    // Through symlinks or relative patterns a file can be named many ways.
+    let resolved = fs::canonicalize(&real)
+        .ok()
+        .and_then(|p| Utf8PathBuf::try_from(p).ok())
+        .unwrap_or_else(|| real.clone());
+    if including.contains(&resolved) {
+        let from = including.last().unwrap_or(&resolved);
+        dirs.warn(
+            WarningKind::IncludeCycle,
+            format!("{}: include cycle back to {}, skipped", from, file),
+        );
+        return;
+    }
     let content = match fs::read_to_string(&real) {
         Ok(c) => c,
         Err(e) if e.kind() == ErrorKind::NotFound => return,
         Err(e) => {
-            warn!(
-                "Warning: ignoring configuration file that cannot be opened: {}: {}",
-                file, e
+            dirs.warn(
+                WarningKind::ConfigUnreadable,
+                format!(
+                    "Warning: ignoring configuration file that cannot be opened: {}: {}",
+                    file, e
+                ),
             );
             return;
         }
     };
 
-    for line in content.lines() {
+    including.push(resolved);
+    for (number, line) in content.lines().enumerate() {
         // '#' anywhere terminates the line; no quoting exists.
         let line = line.split('#').next().unwrap_or("").trim();
         if line.is_empty() {
@@ -120,30 +283,48 @@ fn parse_conf(file: &Utf8Path, prefix: Option<&Utf8Path>, dirs: &mut Vec<Utf8Pat
         }
         if let Some(rest) = directive(line, "include", false) {
             for pattern in rest.split_whitespace() {
-                expand_include(file, prefix, pattern, dirs, depth);
+                expand_include(file, prefix, pattern, dirs, including, max_depth);
             }
         } else if directive(line, "hwcap", true).is_some() {
-            warn!("{}: hwcap directive ignored", file);
+            dirs.warn(
+                WarningKind::HwcapDirective,
+                format!("{}: hwcap directive ignored", file),
+            );
         } else {
-            let dir = line.trim_end_matches('/');
-            if !dir.is_empty() {
-                dirs.push(Utf8PathBuf::from(dir));
+            // A line can list several directories, separated by blanks
+            // or colons, as glibc tokenizes them.
+            let listed = line.split(|c: char| c.is_ascii_whitespace() || c == ':');
+            for dir in listed.map(|d| d.trim_end_matches('/')) {
+                if dir.is_empty() {
+                    continue;
+                }
+                let dir = Utf8PathBuf::from(dir);
+                dirs.sources.entry(dir.clone()).or_insert(ConfigLine {
+                    file: file.to_path_buf(),
+                    line: number + 1,
+                });
+                dirs.dirs.push(dir);
             }
         }
     }
+    including.pop();
 }
 
 fn expand_include(
     from: &Utf8Path,
     prefix: Option<&Utf8Path>,
     pattern: &str,
-    dirs: &mut Vec<Utf8PathBuf>,
-    depth: u32,
+    dirs: &mut SearchPaths,
+    including: &mut Vec<Utf8PathBuf>,
+    max_depth: u32,
 ) {
     if prefix.is_some() && !pattern.starts_with('/') {
-        warn!(
-            "{}: need absolute file name for configuration file when using -r",
-            from
+        dirs.warn(
+            WarningKind::BadInclude,
+            format!(
+                "{}: need absolute file name for configuration file when using -r",
+                from
+            ),
         );
         return;
     }
@@ -167,21 +348,27 @@ fn expand_include(
     let paths = match glob::glob(glob_pattern.as_str()) {
         Ok(paths) => paths,
         Err(e) => {
-            warn!("{}: bad include pattern {}: {}", from, pattern, e);
+            dirs.warn(
+                WarningKind::BadInclude,
+                format!("{}: bad include pattern {}: {}", from, pattern, e),
+            );
             return;
         }
     };
+    let mut matches = Vec::new();
     for entry in paths {
-        let real = match entry {
-            Ok(p) => match Utf8PathBuf::try_from(p) {
-                Ok(p) => p,
-                Err(_) => continue,
-            },
-            Err(e) => {
-                warn!("{}: cannot read {}: {}", from, pattern, e);
-                continue;
-            }
-        };
+        match entry {
+            Ok(p) => matches.extend(Utf8PathBuf::try_from(p)),
+            Err(e) => dirs.warn(
+                WarningKind::ConfigUnreadable,
+                format!("{}: cannot read {}: {}", from, pattern, e),
+            ),
+        }
+    }
+    // Byte order, like glibc's glob in the C locale: distributions rely
+    // on 00- to 99- prefixes ranking conf.d files.
+    matches.sort_by(|a, b| a.as_str().as_bytes().cmp(b.as_str().as_bytes()));
+    for real in matches {
         // Recurse with the path inside the prefix so nested includes
         // resolve there too.
         let logical = match prefix {
@@ -191,7 +378,7 @@ fn expand_include(
             },
             None => real,
         };
-        parse_conf(&logical, prefix, dirs, depth + 1);
+        parse_conf(&logical, prefix, dirs, including, max_depth);
     }
 }
 
@@ -199,6 +386,20 @@ fn expand_include(
 mod tests {
     use super::*;
 
+    #[test]
+    fn behaves_like_a_collection() {
+        let mut paths: SearchPaths = ["/a", "/b"].map(Utf8PathBuf::from).into_iter().collect();
+        paths.extend(SearchPaths::new(vec!["/c".into(), "/a".into()]));
+        paths.insert(0, "/first");
+        paths.push("/last");
+        assert_eq!(&paths[1..3], ["/a", "/b"]);
+        assert!(paths.remove("/a"));
+        assert!(!paths.remove("/a"));
+        let dirs: Vec<&str> = (&paths).into_iter().map(|d| d.as_str()).collect();
+        assert_eq!(dirs, ["/first", "/b", "/c", "/last"]);
+        assert_eq!(paths.into_iter().count(), 4);
+    }
+
     fn write(path: &Utf8Path, content: &str) {
         fs::create_dir_all(path.parent().unwrap()).unwrap();
         fs::write(path, content).unwrap();
@@ -241,6 +442,15 @@ mod tests {
                 "/lib64",
             ]
         );
+        assert_eq!(
+            paths.source("/b/lib/"),
+            Some(&ConfigLine {
+                file: root.join("ld.so.conf.d/b.conf"),
+                line: 1,
+            })
+        );
+        assert_eq!(paths.source("/opt/lib").unwrap().line, 2);
+        assert_eq!(paths.source("/usr/lib"), None);
     }
 
     #[test]
@@ -252,7 +462,7 @@ mod tests {
         );
         let paths = SearchPaths::from_file(root.join("ld.so.conf"), None).unwrap();
         let dirs: Vec<&str> = paths.iter().map(|d| d.as_str()).collect();
-        assert_eq!(dirs[..3], ["/lib/\u{65e5}", "includ\u{e9} x", "/ok/lib"]);
+        assert_eq!(dirs[..4], ["/lib/\u{65e5}", "includ\u{e9}", "x", "/ok/lib"]);
     }
 
     #[test]
@@ -267,6 +477,108 @@ mod tests {
         assert_eq!(dirs[..2], ["/spaced/lib", "/slash/lib"]);
     }
 
+    #[test]
+    fn trailing_comments_end_every_line() {
+        let (_tmp, root) = tempdir();
+        write(
+            &root.join("ld.so.conf"),
+            "/opt/foo/lib # vendor libs\ninclude d/*.conf # more\n/bar/lib#tight\n",
+        );
+        write(&root.join("d/x.conf"), "/x/lib\t# from d\n");
+        let paths = SearchPaths::from_file(root.join("ld.so.conf"), None).unwrap();
+        let dirs: Vec<&str> = paths.iter().map(|d| d.as_str()).collect();
+        assert_eq!(dirs[..3], ["/opt/foo/lib", "/x/lib", "/bar/lib"]);
+    }
+
+    #[test]
+    fn lines_list_several_directories() {
+        let (_tmp, root) = tempdir();
+        write(
+            &root.join("ld.so.conf"),
+            "\t/usr/local/lib /opt/lib\n/a/lib:/b/lib/ \t:/c/lib\n",
+        );
+        let paths = SearchPaths::from_file(root.join("ld.so.conf"), None).unwrap();
+        let dirs: Vec<&str> = paths.iter().map(|d| d.as_str()).collect();
+        assert_eq!(
+            dirs[..5],
+            ["/usr/local/lib", "/opt/lib", "/a/lib", "/b/lib", "/c/lib"]
+        );
+        assert_eq!(paths.source("/opt/lib").unwrap().line, 1);
+        assert_eq!(paths.source("/c/lib").unwrap().line, 2);
+    }
+
+    #[test]
+    fn includes_follow_byte_order() {
+        let (_tmp, root) = tempdir();
+        write(&root.join("ld.so.conf"), "include conf.d/*.conf\n");
+        for name in ["99-last", "00-first", "50-Mid", "50-mid", "10-x"] {
+            write(
+                &root.join(format!("conf.d/{}.conf", name)),
+                &format!("/{}\n", name),
+            );
+        }
+        let paths = SearchPaths::from_file(root.join("ld.so.conf"), None).unwrap();
+        let listed: Vec<(&str, usize)> = paths
+            .with_sources()
+            .filter_map(|(dir, line)| Some((dir.as_str(), line?.line)))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("/00-first", 1),
+                ("/10-x", 1),
+                ("/50-Mid", 1),
+                ("/50-mid", 1),
+                ("/99-last", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn include_depth_is_configurable() {
+        let (_tmp, root) = tempdir();
+        write(&root.join("ld.so.conf"), "include 1.conf\n/0/lib\n");
+        write(&root.join("1.conf"), "include 2.conf\n/1/lib\n");
+        write(&root.join("2.conf"), "/2/lib\n");
+        let conf = root.join("ld.so.conf");
+
+        let at_most = |depth| {
+            let paths = SearchPaths::from_file_with_depth(&conf, None, depth).unwrap();
+            paths[..paths.len() - 4].to_vec()
+        };
+        assert_eq!(at_most(0), ["/0/lib"]);
+        assert_eq!(at_most(1), ["/1/lib", "/0/lib"]);
+        assert_eq!(at_most(2), ["/2/lib", "/1/lib", "/0/lib"]);
+        assert_eq!(
+            SearchPaths::from_file(&conf, None).unwrap()[..3],
+            at_most(2)[..]
+        );
+    }
+
+    #[test]
+    fn cyclic_includes_are_skipped() {
+        let (_tmp, root) = tempdir();
+        write(
+            &root.join("ld.so.conf"),
+            "include conf.d/*.conf\n/top/lib\n",
+        );
+        // Includes its parent, and itself through the glob.
+        write(
+            &root.join("conf.d/a.conf"),
+            &format!(
+                "/a/lib\ninclude {}\ninclude *.conf\n",
+                root.join("ld.so.conf")
+            ),
+        );
+        write(&root.join("conf.d/b.conf"), "/b/lib\n");
+
+        let paths = SearchPaths::from_file(root.join("ld.so.conf"), None).unwrap();
+        let dirs: Vec<&str> = paths.iter().map(|d| d.as_str()).collect();
+        // b.conf is reached twice, through a.conf's glob too: no cycle.
+        assert_eq!(dirs[..4], ["/a/lib", "/b/lib", "/b/lib", "/top/lib"]);
+        assert_eq!(dirs.len(), 8);
+    }
+
     #[test]
     fn prefix_confines_includes_to_root() {
         let (_tmp, root) = tempdir();
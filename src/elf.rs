@@ -4,23 +4,42 @@
 //! section headers may be stripped or damaged without affecting the scan.
 
 use goblin::container::{Container, Ctx};
-use goblin::elf::dynamic::{Dynamic, DT_SONAME};
+use goblin::elf::dynamic::{
+    Dynamic, DF_1_NODEFLIB, DT_FLAGS_1, DT_NEEDED, DT_RPATH, DT_RUNPATH, DT_SONAME,
+};
 use goblin::elf::header::{
     Header, EI_DATA, ELFDATA2LSB, ELFDATA2MSB, EM_386, EM_AARCH64, EM_ARM, EM_PPC, EM_PPC64,
-    EM_RISCV, EM_X86_64, ET_DYN,
+    EM_RISCV, EM_X86_64, ET_DYN, ET_EXEC,
+};
+use goblin::elf::program_header::{ProgramHeader, PT_DYNAMIC, PT_INTERP, PT_LOAD, PT_NOTE};
+use goblin::elf::section_header::{SHN_ABS, SHN_UNDEF};
+use goblin::elf::sym::{
+    STB_GLOBAL, STB_GNU_UNIQUE, STB_WEAK, STT_COMMON, STT_FUNC, STT_GNU_IFUNC, STT_OBJECT, STT_TLS,
+    STV_DEFAULT, STV_PROTECTED,
 };
-use goblin::elf::program_header::{ProgramHeader, PT_DYNAMIC, PT_LOAD};
+use goblin::elf::symver::VER_FLG_BASE;
 use memmap2::Mmap;
-use std::fs::File;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::path::Path;
-use tracing::debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use tracing::{debug, instrument};
 
 use crate::cache_format::{
-    FLAG_AARCH64_LIB64, FLAG_ARM_LIBHF, FLAG_ARM_LIBSF, FLAG_ELF_LIBC6, FLAG_POWERPC_LIB64,
-    FLAG_RISCV_FLOAT_ABI_DOUBLE, FLAG_RISCV_FLOAT_ABI_SOFT, FLAG_X8664_LIB64, FLAG_X8664_LIBX32,
+    Arch, FileStat, OsVersion, FLAG_AARCH64_LIB64, FLAG_ARM_LIBHF, FLAG_ARM_LIBSF, FLAG_ELF_LIBC6,
+    FLAG_POWERPC_LIB64, FLAG_RISCV_FLOAT_ABI_DOUBLE, FLAG_RISCV_FLOAT_ABI_SOFT, FLAG_X8664_LIB64,
+    FLAG_X8664_LIBX32,
 };
+use crate::error::Error;
+use crate::symbols::{ExportedSymbol, SymbolKind};
 
 const PT_GNU_PROPERTY: u32 = 0x6474_e553;
+const NT_GNU_ABI_TAG: u32 = 1;
 const NT_GNU_PROPERTY_TYPE_0: u32 = 5;
 const GNU_PROPERTY_X86_ISA_1_NEEDED: u32 = 0xc000_8002;
 
@@ -42,20 +61,285 @@ pub(crate) struct ElfInfo {
     pub flags: u32,
     /// x86 ISA level from GNU_PROPERTY_X86_ISA_1_NEEDED, 0 if unmarked.
     pub isa_level: u32,
+    /// Kernel ABI from NT_GNU_ABI_TAG in osversion encoding, 0 if absent.
+    pub osversion: u32,
+    /// SHA-256 of the whole file, only computed on request.
+    pub sha256: Option<[u8; 32]>,
+    /// Size and mtime of the file inspected.
+    pub stat: Option<FileStat>,
+    /// Has a PT_INTERP: a PIE program, unless it also has a soname.
+    pub has_interp: bool,
 }
 
-/// Inspect a shared object like glibc's process_elf_file.
-/// Returns None for anything that must not be cached.
-pub(crate) fn inspect(path: &Path) -> Option<ElfInfo> {
-    let file = File::open(path).ok()?;
+/// What the resolver needs from an executable or shared object.
+#[derive(Debug, Clone)]
+pub(crate) struct DynamicInfo {
+    /// Cache flags for the object's ABI, as in [`ElfInfo::flags`].
+    pub flags: u32,
+    pub machine: u16,
+    pub is_64: bool,
+    /// DT_NEEDED entries in order.
+    pub needed: Vec<String>,
+    /// DT_RPATH, split on `:`, unexpanded.
+    pub rpath: Vec<String>,
+    /// DT_RUNPATH, split on `:`, unexpanded; None when absent.
+    pub runpath: Option<Vec<String>>,
+    /// DF_1_NODEFLIB: its dependencies are not looked up in the cache or
+    /// the default directories.
+    pub nodeflib: bool,
+}
+
+/// Open a regular file for reading, None for anything else. Files over
+/// `max_size` bytes fail with [`io::ErrorKind::FileTooLarge`].
+fn open_file(path: &Path, max_size: Option<u64>) -> io::Result<Option<(File, Metadata)>> {
+    // Non-blocking, and re-checked on the descriptor: the scanner's type
+    // check races with the file being replaced by a fifo or device.
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    let md = file.metadata()?;
+    if !md.is_file() {
+        debug!("{}: not a regular file", path.display());
+        return Ok(None);
+    }
+    if let Some(max) = max_size.filter(|&max| md.len() > max) {
+        return Err(io::Error::new(
+            io::ErrorKind::FileTooLarge,
+            format!("larger than {} bytes", max),
+        ));
+    }
+    Ok(Some((file, md)))
+}
+
+/// Map a regular file read-only, None for anything else. Files over
+/// `max_size` bytes fail with [`io::ErrorKind::FileTooLarge`] unmapped.
+fn map_file(path: &Path, max_size: Option<u64>) -> io::Result<Option<(Mmap, Metadata)>> {
+    let Some((file, md)) = open_file(path, max_size)? else {
+        return Ok(None);
+    };
     // Safety: read-only shared mapping; a concurrent truncation can raise
     // SIGBUS, the same exposure glibc's ldconfig has when mmapping.
-    let map = unsafe { Mmap::map(&file).ok()? };
-    inspect_bytes(&map, path)
+    let map = unsafe { Mmap::map(&file)? };
+    Ok(Some((map, md)))
 }
 
-fn inspect_bytes(data: &[u8], path: &Path) -> Option<ElfInfo> {
-    let header = goblin::elf::Elf::parse_header(data).ok()?;
+/// Device, inode, size, and modification and status change times.
+type FileKey = (u64, u64, u64, i64, i64, i64, i64);
+
+fn file_key(md: &Metadata) -> FileKey {
+    (
+        md.dev(),
+        md.ino(),
+        md.len(),
+        md.mtime(),
+        md.mtime_nsec(),
+        md.ctime(),
+        md.ctime_nsec(),
+    )
+}
+
+/// Files already inspected, by device, inode, size and times, shared by
+/// builds that scan the same files, such as chroots whose library trees
+/// are hardlinked or bind-mounted: each file is parsed once.
+///
+/// ```no_run
+/// use ldconfig::{Cache, ElfMemo, SearchPaths};
+///
+/// let memo = ElfMemo::new();
+/// for root in ["/srv/chroot/a", "/srv/chroot/b"] {
+///     let paths = SearchPaths::from_file("/etc/ld.so.conf", Some(root.into()))?;
+///     Cache::builder().prefix(root).elf_memo(&memo).build(&paths)?;
+/// }
+/// println!("{} files parsed, {} reused", memo.len(), memo.hits());
+/// # Ok::<(), ldconfig::Error>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct ElfMemo {
+    seen: Mutex<HashMap<FileKey, Option<ElfInfo>>>,
+    hits: AtomicUsize,
+}
+
+impl ElfMemo {
+    /// An empty memo.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of files inspected.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Whether no file was inspected yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many inspections were answered from the memo.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<FileKey, Option<ElfInfo>>> {
+        // Entries are inserted whole, so a panicking builder leaves none
+        // half-written.
+        self.seen.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// What was found for `key`, unless a hash is wanted and was not
+    /// computed then.
+    fn get(&self, key: &FileKey, hash: bool) -> Option<Option<ElfInfo>> {
+        let found = self
+            .lock()
+            .get(key)
+            .filter(|info| !hash || info.as_ref().is_none_or(|i| i.sha256.is_some()))
+            .cloned()?;
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(found)
+    }
+}
+
+/// Inspect a shared object like glibc's process_elf_file, hashing its
+/// contents too when `hash` is set. Returns None for anything that must
+/// not be cached, an error when the file cannot be read at all or is
+/// over `max_size` bytes. With a `memo`, files inspected before are not
+/// read again.
+#[instrument(level = "trace", skip_all, fields(file = %path.display()))]
+pub(crate) fn inspect(
+    path: &Path,
+    hash: bool,
+    max_size: Option<u64>,
+    memo: Option<&ElfMemo>,
+) -> io::Result<Option<ElfInfo>> {
+    let Some((file, md)) = open_file(path, max_size)? else {
+        return Ok(None);
+    };
+    let key = file_key(&md);
+    if let Some(found) = memo.and_then(|memo| memo.get(&key, hash)) {
+        return Ok(found);
+    }
+    // Safety: as in map_file.
+    let map = unsafe { Mmap::map(&file)? };
+    let mut info = inspect_source(&mut &map[..], path);
+    if let Some(info) = info.as_mut() {
+        info.stat = Some(FileStat::from_metadata(&md));
+        if hash {
+            info.sha256 = Some(Sha256::digest(&map[..]).into());
+        }
+    }
+    if let Some(memo) = memo {
+        memo.lock().insert(key, info.clone());
+    }
+    Ok(info)
+}
+
+/// What a scan takes from a shared object, read without mapping it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibraryInfo {
+    /// DT_SONAME, if any; a scan falls back to the file name.
+    pub soname: Option<String>,
+    pub arch: Arch,
+    /// The cache entry flags.
+    pub flags: u32,
+    /// x86 ISA level, 0 if unmarked.
+    pub isa_level: u32,
+    /// Kernel ABI from the NT_GNU_ABI_TAG note, if any.
+    pub osversion: Option<OsVersion>,
+}
+
+/// Classify the shared object read from `reader` as a scan would, for
+/// libraries inside tar or zip archives, or where mapping files is
+/// undesirable. Only the ELF header, the program headers and the
+/// segments they point at are read. Returns None for anything a scan
+/// would not cache, program executables included.
+///
+/// ```no_run
+/// use std::fs::File;
+///
+/// let file = File::open("/usr/lib/libz.so.1")?;
+/// if let Some(info) = ldconfig::inspect_library(file)? {
+///     println!("{:?} {}", info.soname, info.arch);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn inspect_library<R: Read + Seek>(mut reader: R) -> Result<Option<LibraryInfo>, Error> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let mut source = ReaderSource {
+        reader,
+        len,
+        error: None,
+    };
+    let info = inspect_source(&mut source, Path::new("(reader)"));
+    if let Some(e) = source.error {
+        return Err(e.into());
+    }
+    Ok(info
+        .filter(|info| !info.has_interp || info.soname.is_some())
+        .map(|info| LibraryInfo {
+            soname: info.soname,
+            arch: Arch::from_flags(info.flags),
+            flags: info.flags,
+            isa_level: info.isa_level,
+            osversion: OsVersion::from_raw(info.osversion),
+        }))
+}
+
+/// Where the parts of an ELF file that get inspected are read from: a
+/// mapped file, or a stream.
+trait Source {
+    fn len(&self) -> u64;
+    /// The `len` bytes at `offset`, None when past the end.
+    fn bytes(&mut self, offset: u64, len: u64) -> Option<Cow<'_, [u8]>>;
+}
+
+impl Source for &[u8] {
+    fn len(&self) -> u64 {
+        <[u8]>::len(self) as u64
+    }
+
+    fn bytes(&mut self, offset: u64, len: u64) -> Option<Cow<'_, [u8]>> {
+        let start = usize::try_from(offset).ok()?;
+        let end = start.checked_add(usize::try_from(len).ok()?)?;
+        self.get(start..end).map(Cow::Borrowed)
+    }
+}
+
+/// A stream read part by part; the first read error is kept for the
+/// caller, the part reading as missing.
+struct ReaderSource<R> {
+    reader: R,
+    len: u64,
+    error: Option<io::Error>,
+}
+
+impl<R: Read + Seek> Source for ReaderSource<R> {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn bytes(&mut self, offset: u64, len: u64) -> Option<Cow<'_, [u8]>> {
+        if offset.checked_add(len)? > self.len || self.error.is_some() {
+            return None;
+        }
+        let mut buf = vec![0; usize::try_from(len).ok()?];
+        let read = self
+            .reader
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.reader.read_exact(&mut buf));
+        match read {
+            Ok(()) => Some(Cow::Owned(buf)),
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+}
+
+fn inspect_source(src: &mut impl Source, path: &Path) -> Option<ElfInfo> {
+    let head = src.bytes(0, src.len().min(64))?;
+    let header = goblin::elf::Elf::parse_header(&head).ok()?;
 
     let native = if cfg!(target_endian = "little") {
         ELFDATA2LSB
@@ -83,8 +367,7 @@ fn inspect_bytes(data: &[u8], path: &Path) -> Option<ElfInfo> {
     };
 
     let ctx = Ctx::new(header.container().ok()?, header.endianness().ok()?);
-    let phdrs =
-        ProgramHeader::parse(data, header.e_phoff as usize, header.e_phnum as usize, ctx).ok()?;
+    let phdrs = program_headers(src, &header, ctx)?;
     // Non-empty PT_DYNAMIC required; the last one wins like glibc.
     if !phdrs
         .iter()
@@ -95,20 +378,71 @@ fn inspect_bytes(data: &[u8], path: &Path) -> Option<ElfInfo> {
         return None;
     }
 
-    let soname = read_soname(data, &phdrs, ctx);
+    let soname = read_soname(src, &phdrs, ctx);
     let isa_level = if matches!(header.e_machine, EM_386 | EM_X86_64) {
-        read_isa_level(data, &phdrs, is_64)
+        read_isa_level(src, &phdrs, is_64)
     } else {
         0
     };
 
+    let osversion = read_abi_tag(src, &phdrs);
+
     Some(ElfInfo {
         soname,
         flags,
         isa_level,
+        osversion,
+        sha256: None,
+        stat: None,
+        has_interp: phdrs.iter().any(|ph| ph.p_type == PT_INTERP),
     })
 }
 
+/// An ABI the scanner files libraries under, and the ELF headers it
+/// recognizes them by; their entries get `arch.flags()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ElfArch {
+    pub arch: Arch,
+    /// The `e_machine` value, e.g. 62 for x86-64.
+    pub machine: u16,
+    /// The usual name for that machine: "x86_64", "i386", "aarch64"...
+    pub machine_name: &'static str,
+    /// Whether objects are ELFCLASS64.
+    pub class64: bool,
+}
+
+const fn elf_arch(arch: Arch, machine: u16, machine_name: &'static str, class64: bool) -> ElfArch {
+    ElfArch {
+        arch,
+        machine,
+        machine_name,
+        class64,
+    }
+}
+
+const SUPPORTED: [ElfArch; 13] = [
+    elf_arch(Arch::Libc6, EM_386, "i386", false),
+    elf_arch(Arch::X86_64, EM_X86_64, "x86_64", true),
+    elf_arch(Arch::X32, EM_X86_64, "x86_64", false),
+    elf_arch(Arch::Libc6, EM_ARM, "arm", false),
+    elf_arch(Arch::ArmHardFloat, EM_ARM, "arm", false),
+    elf_arch(Arch::ArmSoftFloat, EM_ARM, "arm", false),
+    elf_arch(Arch::AArch64, EM_AARCH64, "aarch64", true),
+    elf_arch(Arch::Libc6, EM_PPC, "ppc", false),
+    elf_arch(Arch::PowerPc64, EM_PPC64, "ppc64", true),
+    elf_arch(Arch::RiscvSoftFloat, EM_RISCV, "riscv", false),
+    elf_arch(Arch::RiscvDoubleFloat, EM_RISCV, "riscv", false),
+    elf_arch(Arch::RiscvSoftFloat, EM_RISCV, "riscv", true),
+    elf_arch(Arch::RiscvDoubleFloat, EM_RISCV, "riscv", true),
+];
+
+/// The ABIs the scanner can classify libraries into, by ELF machine and
+/// class; libraries of any other kind are skipped by a build. Caches
+/// holding other ABIs ([`Arch`]) can still be read and edited.
+pub fn supported_architectures() -> &'static [ElfArch] {
+    &SUPPORTED
+}
+
 /// Per-machine cache flags, following the sysdeps readelflib.c variants.
 fn machine_flags(h: &Header, is_64: bool) -> Option<u32> {
     match (h.e_machine, is_64) {
@@ -148,31 +482,229 @@ fn machine_flags(h: &Header, is_64: bool) -> Option<u32> {
     }
 }
 
-fn read_soname(data: &[u8], phdrs: &[ProgramHeader], ctx: Ctx) -> Option<String> {
-    let dynamic = Dynamic::parse(data, phdrs, ctx).ok()??;
+/// Read the dynamic section of an executable or shared object of the
+/// host's byte order, the way ld.so sees it before loading. Returns None
+/// for static or non-ELF files.
+#[instrument(level = "trace", skip_all, fields(file = %path.display()))]
+pub(crate) fn read_dynamic(path: &Path) -> io::Result<Option<DynamicInfo>> {
+    let Some((map, _)) = map_file(path, None)? else {
+        return Ok(None);
+    };
+    Ok(dynamic_bytes(&map))
+}
+
+fn dynamic_bytes(data: &[u8]) -> Option<DynamicInfo> {
+    let header = goblin::elf::Elf::parse_header(data).ok()?;
+    let native = if cfg!(target_endian = "little") {
+        ELFDATA2LSB
+    } else {
+        ELFDATA2MSB
+    };
+    if header.e_ident[EI_DATA] != native || !matches!(header.e_type, ET_DYN | ET_EXEC) {
+        return None;
+    }
+    let is_64 = header.container().ok()? == Container::Big;
+    let flags = machine_flags(&header, is_64)?;
+    let ctx = Ctx::new(header.container().ok()?, header.endianness().ok()?);
+    let mut src = data;
+    let phdrs = program_headers(&mut src, &header, ctx)?;
+    let dynamic = Dynamic::parse(data, &phdrs, ctx).ok()??;
+    let table = string_table(&mut src, &dynamic)?;
+
+    let strings = |tag| {
+        dynamic
+            .dyns
+            .iter()
+            .filter(move |d| d.d_tag == tag)
+            .filter_map(|d| dyn_str(&table, d.d_val as usize))
+    };
+    let split = |s: String| -> Vec<String> {
+        s.split(':')
+            .filter(|p| !p.is_empty())
+            .map(str::to_owned)
+            .collect()
+    };
+    Some(DynamicInfo {
+        flags,
+        machine: header.e_machine,
+        is_64,
+        needed: strings(DT_NEEDED).collect(),
+        rpath: strings(DT_RPATH).flat_map(split).collect(),
+        runpath: strings(DT_RUNPATH).map(split).reduce(|mut a, b| {
+            a.extend(b);
+            a
+        }),
+        nodeflib: dynamic
+            .dyns
+            .iter()
+            .any(|d| d.d_tag == DT_FLAGS_1 && d.d_val & DF_1_NODEFLIB != 0),
+    })
+}
+
+/// Read the symbols a shared object exports from its dynamic symbol
+/// table, with versions when the version sections are present. Returns
+/// None for static or non-ELF files.
+#[instrument(level = "trace", skip_all, fields(file = %path.display()))]
+pub(crate) fn read_exports(path: &Path) -> io::Result<Option<Vec<ExportedSymbol>>> {
+    let Some((map, _)) = map_file(path, None)? else {
+        return Ok(None);
+    };
+    Ok(exports_bytes(&map))
+}
+
+fn exports_bytes(data: &[u8]) -> Option<Vec<ExportedSymbol>> {
+    let elf = goblin::elf::Elf::parse(data).ok()?;
+    elf.dynamic.as_ref()?;
+    let mut versions = HashMap::new();
+    if let Some(verdef) = &elf.verdef {
+        for def in verdef.iter() {
+            // The base definition names the object itself.
+            if def.vd_flags & VER_FLG_BASE != 0 {
+                continue;
+            }
+            if let Some(name) = def
+                .iter()
+                .next()
+                .and_then(|aux| elf.dynstrtab.get_at(aux.vda_name))
+            {
+                versions.insert(def.vd_ndx, name);
+            }
+        }
+    }
+    let symbols = elf
+        .dynsyms
+        .iter()
+        .enumerate()
+        .filter(|(_, sym)| {
+            sym.st_shndx != SHN_UNDEF as usize
+                && matches!(sym.st_bind(), STB_GLOBAL | STB_WEAK | STB_GNU_UNIQUE)
+                && matches!(sym.st_visibility(), STV_DEFAULT | STV_PROTECTED)
+        })
+        .filter_map(|(i, sym)| {
+            let name = elf
+                .dynstrtab
+                .get_at(sym.st_name)
+                .filter(|n| !n.is_empty())?;
+            let versym = elf.versym.as_ref().and_then(|v| v.get_at(i));
+            let version = versym.as_ref().and_then(|v| versions.get(&v.version()));
+            // Version definitions show up as absolute symbols of their own.
+            if sym.st_shndx == SHN_ABS as usize && version.is_some_and(|v| *v == name) {
+                return None;
+            }
+            Some(ExportedSymbol {
+                name: name.to_owned(),
+                version: version.map(|v| v.to_string()),
+                default_version: !versym.is_some_and(|v| v.is_hidden()),
+                kind: match sym.st_type() {
+                    STT_FUNC => SymbolKind::Function,
+                    STT_GNU_IFUNC => SymbolKind::IndirectFunction,
+                    STT_OBJECT | STT_COMMON => SymbolKind::Object,
+                    STT_TLS => SymbolKind::Tls,
+                    _ => SymbolKind::Other,
+                },
+            })
+        })
+        .collect();
+    Some(symbols)
+}
+
+/// The program header table, None unless it has entries of the size of
+/// the class (as glibc's readelflib.c checks) and lies within the file.
+fn program_headers(src: &mut impl Source, header: &Header, ctx: Ctx) -> Option<Vec<ProgramHeader>> {
+    let entsize = ProgramHeader::size(ctx);
+    if usize::from(header.e_phentsize) != entsize {
+        return None;
+    }
+    let table = src.bytes(
+        header.e_phoff,
+        (entsize * usize::from(header.e_phnum)) as u64,
+    )?;
+    ProgramHeader::parse(&table, 0, usize::from(header.e_phnum), ctx).ok()
+}
+
+/// The segment `ph` describes, as stored in the file.
+fn segment<'a>(src: &'a mut impl Source, ph: &ProgramHeader) -> Option<Cow<'a, [u8]>> {
+    src.bytes(ph.p_offset, ph.p_filesz)
+}
+
+fn read_soname(src: &mut impl Source, phdrs: &[ProgramHeader], ctx: Ctx) -> Option<String> {
+    let ph = phdrs.iter().rfind(|ph| ph.p_type == PT_DYNAMIC)?;
+    let seg = segment(src, ph)?;
+    // Parsed on its own, with the load segments to map addresses.
+    let located: Vec<ProgramHeader> = std::iter::once(ProgramHeader {
+        p_offset: 0,
+        ..ph.clone()
+    })
+    .chain(phdrs.iter().filter(|ph| ph.p_type == PT_LOAD).cloned())
+    .collect();
+    let dynamic = Dynamic::parse(&seg, &located, ctx).ok()??;
+    drop(seg);
     // First DT_SONAME wins, as in glibc.
     let idx = dynamic.dyns.iter().find(|d| d.d_tag == DT_SONAME)?.d_val as usize;
+    let table = string_table(src, &dynamic)?;
+    dyn_str(&table, idx).filter(|s| !s.is_empty())
+}
 
-    let off = vaddr_to_offset(phdrs, dynamic.info.strtab as u64)? as usize;
-    let end = off.checked_add(dynamic.info.strsz)?.min(data.len());
-    let table = data.get(off..end)?;
+/// The dynamic string table, cut short at the end of the file.
+fn string_table<'a>(src: &'a mut impl Source, dynamic: &Dynamic) -> Option<Cow<'a, [u8]>> {
+    let off = dynamic.info.strtab as u64;
+    let len = (dynamic.info.strsz as u64).min(src.len().checked_sub(off)?);
+    src.bytes(off, len)
+}
+
+/// String at `idx` in the dynamic string table.
+fn dyn_str(table: &[u8], idx: usize) -> Option<String> {
     let bytes = table.get(idx..)?;
     let nul = bytes.iter().position(|&b| b == 0)?;
-    let soname = std::str::from_utf8(&bytes[..nul]).ok()?;
-    (!soname.is_empty()).then(|| soname.to_owned())
+    std::str::from_utf8(&bytes[..nul]).ok().map(str::to_owned)
 }
 
-fn vaddr_to_offset(phdrs: &[ProgramHeader], vaddr: u64) -> Option<u64> {
-    phdrs
-        .iter()
-        .find(|ph| ph.p_type == PT_LOAD && vaddr >= ph.p_vaddr && vaddr - ph.p_vaddr < ph.p_filesz)
-        .map(|ph| vaddr - ph.p_vaddr + ph.p_offset)
+/// Kernel ABI from the NT_GNU_ABI_TAG note, packed like glibc's
+/// readelflib.c: `os << 24 | major << 16 | minor << 8 | patch`.
+fn read_abi_tag(src: &mut impl Source, phdrs: &[ProgramHeader]) -> u32 {
+    let u32_at = |seg: &[u8], pos: usize| {
+        seg.get(pos..pos.checked_add(4)?)
+            .map(|b| u32::from_ne_bytes(b.try_into().unwrap()))
+    };
+
+    for ph in phdrs {
+        if ph.p_type != PT_NOTE {
+            continue;
+        }
+        let align = if ph.p_align == 8 { 8 } else { 4 };
+        let Some(seg) = segment(src, ph) else {
+            continue;
+        };
+        let seg = &seg[..];
+
+        let mut pos = 0usize;
+        while let (Some(namesz), Some(descsz), Some(n_type)) =
+            (u32_at(seg, pos), u32_at(seg, pos + 4), u32_at(seg, pos + 8))
+        {
+            let name_off = pos.saturating_add(12);
+            let padded = |n: u32| (n as usize).checked_next_multiple_of(align);
+            let desc_off = name_off.saturating_add(padded(namesz).unwrap_or(usize::MAX));
+            if n_type == NT_GNU_ABI_TAG
+                && namesz == 4
+                && descsz >= 16
+                && seg.get(name_off..name_off.saturating_add(4)) == Some(b"GNU\0")
+            {
+                let word = |i: usize| u32_at(seg, desc_off.saturating_add(4 * i)).unwrap_or(0);
+                return (word(0) << 24)
+                    | ((word(1) & 0xff) << 16)
+                    | ((word(2) & 0xff) << 8)
+                    | (word(3) & 0xff);
+            }
+            pos = desc_off.saturating_add(padded(descsz).unwrap_or(usize::MAX));
+        }
+    }
+    0
 }
 
 /// x86 ISA level from the NT_GNU_PROPERTY_TYPE_0 note
 /// (GNU_PROPERTY_X86_ISA_1_NEEDED), following elf/readelflib.c and
 /// sysdeps/unix/sysv/linux/x86/elf-read-prop.h.
-fn read_isa_level(data: &[u8], phdrs: &[ProgramHeader], is_64: bool) -> u32 {
+fn read_isa_level(src: &mut impl Source, phdrs: &[ProgramHeader], is_64: bool) -> u32 {
     let align = if is_64 { 8usize } else { 4 };
     let u32_at = |seg: &[u8], pos: usize| u32::from_ne_bytes(seg[pos..pos + 4].try_into().unwrap());
     let align_up = |v: usize, a: usize| v.div_ceil(a) * a;
@@ -181,12 +713,10 @@ fn read_isa_level(data: &[u8], phdrs: &[ProgramHeader], is_64: bool) -> u32 {
         if ph.p_type != PT_GNU_PROPERTY || ph.p_align as usize != align {
             continue;
         }
-        let Some(seg) = (ph.p_offset as usize)
-            .checked_add(ph.p_filesz as usize)
-            .and_then(|end| data.get(ph.p_offset as usize..end))
-        else {
+        let Some(seg) = segment(src, ph) else {
             continue;
         };
+        let seg = &seg[..];
 
         let mut pos = 0usize;
         while pos + 12 <= seg.len() {
@@ -242,6 +772,127 @@ fn read_isa_level(data: &[u8], phdrs: &[ProgramHeader], is_64: bool) -> u32 {
     0
 }
 
+/// Smallest x86-64 shared object the scanner accepts: an ELF header, one
+/// PT_DYNAMIC header and an empty dynamic section, so no DT_SONAME.
+#[cfg(test)]
+pub(crate) fn minimal_dso() -> Vec<u8> {
+    let mut d = Vec::new();
+    d.extend_from_slice(b"\x7fELF");
+    d.extend_from_slice(&[2, if cfg!(target_endian = "little") { 1 } else { 2 }, 1]);
+    d.resize(16, 0);
+    d.extend_from_slice(&ET_DYN.to_ne_bytes());
+    d.extend_from_slice(&EM_X86_64.to_ne_bytes());
+    d.extend_from_slice(&1u32.to_ne_bytes()); // e_version
+    d.extend_from_slice(&0u64.to_ne_bytes()); // e_entry
+    d.extend_from_slice(&64u64.to_ne_bytes()); // e_phoff
+    d.extend_from_slice(&0u64.to_ne_bytes()); // e_shoff
+    d.extend_from_slice(&0u32.to_ne_bytes()); // e_flags
+    for half in [64u16, 56, 1, 64, 0, 0] {
+        d.extend_from_slice(&half.to_ne_bytes());
+    }
+    d.extend_from_slice(&PT_DYNAMIC.to_ne_bytes());
+    d.extend_from_slice(&6u32.to_ne_bytes()); // p_flags
+    for word in [120u64, 120, 120, 16, 16, 8] {
+        d.extend_from_slice(&word.to_ne_bytes());
+    }
+    d.resize(136, 0);
+    d
+}
+
+/// x86-64 shared object with the given DT_NEEDED entries and DT_RUNPATH:
+/// one PT_LOAD mapping the whole file at its offset, then PT_DYNAMIC.
+#[cfg(test)]
+pub(crate) fn dso_needing(needed: &[&str], runpath: Option<&str>) -> Vec<u8> {
+    dso_with(None, needed, runpath)
+}
+
+/// [`dso_needing`] with a DT_SONAME as well.
+#[cfg(test)]
+pub(crate) fn dso_with(soname: Option<&str>, needed: &[&str], runpath: Option<&str>) -> Vec<u8> {
+    dso_tagged(soname, needed, runpath, &[])
+}
+
+/// [`dso_needing`] with a DT_RPATH as well.
+#[cfg(test)]
+pub(crate) fn dso_rpath(needed: &[&str], rpath: &str, runpath: Option<&str>) -> Vec<u8> {
+    // Built with the DT_RPATH string as DT_SONAME, then retagged.
+    let mut d = dso_with(Some(rpath), needed, runpath);
+    let at = (64 + 2 * 56..d.len())
+        .step_by(16)
+        .find(|&at| d[at..at + 8] == DT_SONAME.to_ne_bytes())
+        .unwrap();
+    d[at..at + 8].copy_from_slice(&DT_RPATH.to_ne_bytes());
+    d
+}
+
+/// [`dso_with`] with extra non-string dynamic entries, such as DT_FLAGS_1.
+#[cfg(test)]
+pub(crate) fn dso_tagged(
+    soname: Option<&str>,
+    needed: &[&str],
+    runpath: Option<&str>,
+    tags: &[(u64, u64)],
+) -> Vec<u8> {
+    use goblin::elf::dynamic::{DT_NULL, DT_STRSZ, DT_STRTAB};
+
+    let mut strtab = vec![0u8];
+    let mut dyns = tags.to_vec();
+    let strings = soname
+        .map(|name| (DT_SONAME, name))
+        .into_iter()
+        .chain(needed.iter().map(|name| (DT_NEEDED, *name)))
+        .chain(runpath.map(|path| (DT_RUNPATH, path)));
+    for (tag, s) in strings {
+        dyns.push((tag, strtab.len() as u64));
+        strtab.extend_from_slice(s.as_bytes());
+        strtab.push(0);
+    }
+    let dyn_off = 64 + 2 * 56;
+    let str_off = dyn_off + (dyns.len() + 3) * 16;
+    dyns.extend([(DT_STRTAB, str_off as u64), (DT_STRSZ, strtab.len() as u64)]);
+    dyns.push((DT_NULL, 0));
+    let total = (str_off + strtab.len()) as u64;
+
+    let mut d = Vec::new();
+    d.extend_from_slice(b"\x7fELF");
+    d.extend_from_slice(&[2, if cfg!(target_endian = "little") { 1 } else { 2 }, 1]);
+    d.resize(16, 0);
+    d.extend_from_slice(&ET_DYN.to_ne_bytes());
+    d.extend_from_slice(&EM_X86_64.to_ne_bytes());
+    d.extend_from_slice(&1u32.to_ne_bytes()); // e_version
+    d.extend_from_slice(&0u64.to_ne_bytes()); // e_entry
+    d.extend_from_slice(&64u64.to_ne_bytes()); // e_phoff
+    d.extend_from_slice(&0u64.to_ne_bytes()); // e_shoff
+    d.extend_from_slice(&0u32.to_ne_bytes()); // e_flags
+    for half in [64u16, 56, 2, 64, 0, 0] {
+        d.extend_from_slice(&half.to_ne_bytes());
+    }
+    d.extend_from_slice(&PT_LOAD.to_ne_bytes());
+    d.extend_from_slice(&5u32.to_ne_bytes());
+    for word in [0u64, 0, 0, total, total, 0x1000] {
+        d.extend_from_slice(&word.to_ne_bytes());
+    }
+    let dyn_len = (dyns.len() * 16) as u64;
+    d.extend_from_slice(&PT_DYNAMIC.to_ne_bytes());
+    d.extend_from_slice(&6u32.to_ne_bytes());
+    for word in [
+        dyn_off as u64,
+        dyn_off as u64,
+        dyn_off as u64,
+        dyn_len,
+        dyn_len,
+        8,
+    ] {
+        d.extend_from_slice(&word.to_ne_bytes());
+    }
+    for (tag, val) in dyns {
+        d.extend_from_slice(&tag.to_ne_bytes());
+        d.extend_from_slice(&val.to_ne_bytes());
+    }
+    d.extend_from_slice(&strtab);
+    d
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -258,13 +909,191 @@ mod tests {
         if !path.exists() {
             return;
         }
-        let info = inspect(path).unwrap();
+        let info = inspect(path, false, None, None).unwrap().unwrap();
+        assert_eq!(info.soname.as_deref(), Some("libz.so.1"));
+        assert_eq!(info.flags, FLAG_X8664_LIB64 | FLAG_ELF_LIBC6);
+        assert_eq!(info.sha256, None);
+
+        let hashed = inspect(path, true, None, None).unwrap().unwrap();
+        let expected: [u8; 32] = Sha256::digest(std::fs::read(path).unwrap()).into();
+        assert_eq!(hashed.sha256, Some(expected));
+    }
+
+    fn note(name: &[u8], n_type: u32, desc: &[u32]) -> Vec<u8> {
+        let mut n = Vec::new();
+        n.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+        n.extend_from_slice(&((desc.len() * 4) as u32).to_ne_bytes());
+        n.extend_from_slice(&n_type.to_ne_bytes());
+        n.extend_from_slice(name);
+        n.resize(n.len().next_multiple_of(4), 0);
+        for w in desc {
+            n.extend_from_slice(&w.to_ne_bytes());
+        }
+        n
+    }
+
+    fn note_phdr(len: usize) -> ProgramHeader {
+        ProgramHeader {
+            p_type: PT_NOTE,
+            p_offset: 0,
+            p_filesz: len as u64,
+            p_align: 4,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn abi_tag_packed_like_glibc() {
+        let mut data = note(b"GNU\0", 3, &[0xdead]); // build-id-like, skipped
+        data.extend(note(b"GNU\0", NT_GNU_ABI_TAG, &[0, 3, 2, 0]));
+        let phdrs = [note_phdr(data.len())];
+        assert_eq!(read_abi_tag(&mut &data[..], &phdrs), 0x0003_0200);
+
+        let other = note(b"XYZ\0", NT_GNU_ABI_TAG, &[0, 3, 2, 0]);
+        assert_eq!(read_abi_tag(&mut &other[..], &[note_phdr(other.len())]), 0);
+        // Truncated notes never panic.
+        for len in 0..data.len() {
+            let _ = read_abi_tag(&mut &data[..len], &[note_phdr(len)]);
+        }
+    }
+
+    #[test]
+    fn dynamic_section_is_read() {
+        let data = dso_needing(&["libb.so.2", "libc.so.6"], Some("$ORIGIN/../lib:/opt"));
+        let info = dynamic_bytes(&data).unwrap();
+        assert_eq!(info.needed, ["libb.so.2", "libc.so.6"]);
+        assert_eq!(info.runpath.unwrap(), ["$ORIGIN/../lib", "/opt"]);
+        assert!(info.rpath.is_empty());
+        assert!(info.is_64);
+        assert_eq!(info.flags, FLAG_X8664_LIB64 | FLAG_ELF_LIBC6);
+
+        assert!(dynamic_bytes(&data[..40]).is_none());
+    }
+
+    #[test]
+    fn biarch_machines_get_distinct_flags() {
+        let flags = |machine, is_64, e_flags| {
+            let h = Header {
+                e_machine: machine,
+                e_flags,
+                ..Header::new(Ctx::default())
+            };
+            machine_flags(&h, is_64)
+        };
+        use crate::cache_format::{FLAG_AARCH64_LIB64, FLAG_X8664_LIBX32};
+        // i686 next to x86-64 and x32 on one multilib host.
+        assert_eq!(flags(EM_386, false, 0), Some(FLAG_ELF_LIBC6));
+        assert_eq!(
+            flags(EM_X86_64, true, 0),
+            Some(FLAG_X8664_LIB64 | FLAG_ELF_LIBC6)
+        );
+        assert_eq!(
+            flags(EM_X86_64, false, 0),
+            Some(FLAG_X8664_LIBX32 | FLAG_ELF_LIBC6)
+        );
+        // armhf next to AArch64.
+        let hf = EF_ARM_EABI_VER5 | EF_ARM_ABI_FLOAT_HARD;
+        assert_eq!(
+            flags(EM_ARM, false, hf),
+            Some(FLAG_ARM_LIBHF | FLAG_ELF_LIBC6)
+        );
+        assert_eq!(
+            flags(EM_AARCH64, true, 0),
+            Some(FLAG_AARCH64_LIB64 | FLAG_ELF_LIBC6)
+        );
+        assert_eq!(flags(EM_386, true, 0), None);
+    }
+
+    #[test]
+    fn supported_architectures_match_classification() {
+        let machines = [
+            EM_386, EM_X86_64, EM_ARM, EM_AARCH64, EM_PPC, EM_PPC64, EM_RISCV,
+        ];
+        let e_flags = [
+            0,
+            EF_ARM_EABI_VER5 | EF_ARM_ABI_FLOAT_HARD,
+            EF_ARM_EABI_VER5 | EF_ARM_ABI_FLOAT_SOFT,
+            EF_RISCV_FLOAT_ABI_DOUBLE,
+        ];
+        let mut seen = Vec::new();
+        for machine in machines {
+            for class64 in [false, true] {
+                for e_flags in e_flags {
+                    let h = Header {
+                        e_machine: machine,
+                        e_flags,
+                        ..Header::new(Ctx::default())
+                    };
+                    let Some(flags) = machine_flags(&h, class64) else {
+                        continue;
+                    };
+                    let known = supported_architectures().iter().position(|a| {
+                        (a.machine, a.class64, a.arch.flags()) == (machine, class64, flags)
+                    });
+                    assert!(known.is_some(), "{} {} {:#x}", machine, class64, flags);
+                    seen.extend(known);
+                }
+            }
+        }
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), supported_architectures().len());
+    }
+
+    #[test]
+    fn minimal_dso_is_accepted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("libmin.so.1");
+        std::fs::write(&path, minimal_dso()).unwrap();
+        let info = inspect(&path, false, None, None).unwrap().unwrap();
+        assert_eq!(info.soname, None);
+        assert_eq!(info.flags, FLAG_X8664_LIB64 | FLAG_ELF_LIBC6);
+        assert_eq!(info.stat.map(|s| s.size), Some(136));
+    }
+
+    #[test]
+    fn oversized_and_truncated_files_are_not_parsed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("libmin.so.1");
+        std::fs::write(&path, minimal_dso()).unwrap();
+        let err = inspect(&path, false, Some(100), None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::FileTooLarge);
+        assert!(inspect(&path, false, Some(136), None).unwrap().is_some());
+
+        // Program headers of the wrong size, or past the end of the file.
+        let mut dso = minimal_dso();
+        dso[54] += 1;
+        assert!(inspect_source(&mut &dso[..], &path).is_none());
+        let dso = minimal_dso();
+        assert!(inspect_source(&mut &dso[..100], &path).is_none());
+    }
+
+    #[test]
+    fn libraries_are_classified_from_readers() {
+        let dso = dso_with(Some("libz.so.1"), &["libc.so.6"], None);
+        let info = inspect_library(io::Cursor::new(&dso)).unwrap().unwrap();
         assert_eq!(info.soname.as_deref(), Some("libz.so.1"));
+        assert_eq!(info.arch, Arch::X86_64);
         assert_eq!(info.flags, FLAG_X8664_LIB64 | FLAG_ELF_LIBC6);
+        assert_eq!(info.osversion, None);
+        assert_eq!(
+            inspect_source(&mut &dso[..], Path::new("libz.so.1")).map(|i| i.soname),
+            Some(info.soname)
+        );
+
+        // Cut short in the dynamic segment: still a library, no soname.
+        let truncated = io::Cursor::new(&dso[..200]);
+        assert_eq!(inspect_library(truncated).unwrap().unwrap().soname, None);
+        assert!(inspect_library(io::Cursor::new(b"!<arch>\n"))
+            .unwrap()
+            .is_none());
     }
 
     #[test]
     fn inspect_rejects_non_elf() {
-        assert!(inspect(Path::new("/etc/ld.so.conf")).is_none());
+        assert!(inspect(Path::new("/etc/ld.so.conf"), false, None, None)
+            .ok()
+            .flatten()
+            .is_none());
     }
 }
@@ -1,4 +1,5 @@
 // Error types for ldconfig
+use crate::report::BuildReport;
 use std::io;
 
 #[derive(Debug, thiserror::Error)]
@@ -9,9 +10,52 @@ pub enum Error {
     #[error("Invalid cache file: {0}")]
     InvalidCache(&'static str),
 
+    #[error("Invalid pattern `{0}': {1}")]
+    Pattern(String, glob::PatternError),
+
+    #[cfg(feature = "regex")]
+    #[error("Invalid regex: {0}")]
+    Regex(#[from] regex::Error),
+
+    #[error("Invalid auxiliary cache file: {0}")]
+    InvalidAuxCache(&'static str),
+
     #[error("Invalid cache offset: {0}")]
-    InvalidCacheOffset(u32),
+    InvalidCacheOffset(usize),
 
     #[error("Invalid UTF-8 in cache string")]
     InvalidCacheUtf8,
+
+    #[error("Unknown cache format `{0}'")]
+    UnknownFormat(String),
+
+    #[error("Unknown glibc version `{0}'")]
+    UnknownGlibcVersion(String),
+
+    #[error("Cache signature: {0}")]
+    Signature(&'static str),
+
+    #[error("Can't scan directory {0}: {1}")]
+    Directory(camino::Utf8PathBuf, io::Error),
+
+    #[error("relative path `{0}' used to build cache")]
+    RelativeDir(camino::Utf8PathBuf),
+
+    #[error("Can't open cache file directory {0}")]
+    CacheDirectory(camino::Utf8PathBuf),
+
+    #[error("{0} changed since the build was planned")]
+    ChangedSincePlan(String),
+
+    #[error("No backup of {} to roll back to", .0.display())]
+    NoBackup(std::path::PathBuf),
+
+    #[error("{0}: not a dynamic executable")]
+    NotDynamic(camino::Utf8PathBuf),
+
+    #[error("{0}")]
+    OverBudget(String),
+
+    #[error("{} warning(s) in strict mode:\n{}", .0.warnings.len(), .0)]
+    Strict(BuildReport),
 }
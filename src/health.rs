@@ -0,0 +1,230 @@
+//! A health check of a system's loader setup, for monitoring agents:
+//! everything a build would change or warn about, without changing it.
+//!
+//! ```no_run
+//! for issue in ldconfig::check_system("/")? {
+//!     println!("{}: {}", issue.kind(), issue);
+//! }
+//! # Ok::<(), ldconfig::Error>(())
+//! ```
+
+use crate::cache::normalize_prefix;
+use crate::{
+    error::Error, plan_run, Cache, LinkAction, RunOptions, Shadowed, Warning, WarningKind,
+};
+use camino::Utf8Path;
+use std::fmt;
+
+/// A problem [`check_system`] found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Issue {
+    /// The configuration could not be read entirely, e.g. an include
+    /// that cannot be opened.
+    Config(Warning),
+    /// The cache file is missing or cannot be parsed.
+    CacheUnreadable { path: String, error: String },
+    /// The cache holds other entries than a build would write now.
+    CacheOutdated {
+        added: usize,
+        removed: usize,
+        changed: usize,
+    },
+    /// A cache entry whose file changed or is gone since the cache was
+    /// built, by the size and mtime it recorded.
+    StaleEntry { path: String },
+    /// A `*.so.*` symlink leading nowhere, which a build removes.
+    DanglingSymlink { path: String, target: String },
+    /// A soname symlink missing or pointing at another file than a build
+    /// would make it.
+    SymlinkOutdated(LinkAction),
+    /// A library hidden by one with the same soname in an earlier
+    /// directory.
+    DuplicateSoname(Shadowed),
+    /// Anything else a build would warn about.
+    Scan(Warning),
+}
+
+impl Issue {
+    /// A stable snake_case name for the kind of issue, for metrics and
+    /// structured logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Issue::Config(_) => "config",
+            Issue::CacheUnreadable { .. } => "cache_unreadable",
+            Issue::CacheOutdated { .. } => "cache_outdated",
+            Issue::StaleEntry { .. } => "stale_entry",
+            Issue::DanglingSymlink { .. } => "dangling_symlink",
+            Issue::SymlinkOutdated(_) => "symlink_outdated",
+            Issue::DuplicateSoname(_) => "duplicate_soname",
+            Issue::Scan(_) => "scan",
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::Config(w) | Issue::Scan(w) => write!(f, "{}", w),
+            Issue::CacheUnreadable { path, error } => write!(f, "Can't read {}: {}", path, error),
+            Issue::CacheOutdated {
+                added,
+                removed,
+                changed,
+            } => write!(
+                f,
+                "cache out of date: {} added, {} removed, {} changed",
+                added, removed, changed
+            ),
+            Issue::StaleEntry { path } => write!(f, "{} changed since the cache was built", path),
+            Issue::DanglingSymlink { path, target } => {
+                write!(f, "{} -> {} is dangling", path, target)
+            }
+            Issue::SymlinkOutdated(action) => write!(f, "{}", action),
+            Issue::DuplicateSoname(s) => {
+                write!(f, "{} hides {} ({})", s.used, s.shadowed, s.soname)
+            }
+        }
+    }
+}
+
+/// Check the system installed in `prefix` ("/" for the host) against
+/// what [`run`](crate::run) would build there with its defaults:
+/// configuration problems, a cache missing or out of date with the
+/// filesystem, entries whose files changed, dangling or outdated soname
+/// symlinks and sonames found in several directories. Nothing is
+/// written; an empty list means a run would change nothing.
+pub fn check_system(prefix: impl AsRef<Utf8Path>) -> Result<Vec<Issue>, Error> {
+    let prefix = normalize_prefix(prefix.as_ref());
+    let planned = plan_run(&RunOptions::builder().root(prefix.as_path()).build())?;
+    let mut issues: Vec<Issue> = planned
+        .config_warnings()
+        .iter()
+        .cloned()
+        .map(Issue::Config)
+        .collect();
+
+    let built = planned.cache();
+    let report = built.report();
+
+    if let Some(cache_file) = planned.cache_file() {
+        match Cache::from_file(cache_file) {
+            Ok(existing) => {
+                let diff = built.diff(&existing);
+                if !diff.is_empty() {
+                    issues.push(Issue::CacheOutdated {
+                        added: diff.added.len(),
+                        removed: diff.removed.len(),
+                        changed: diff.changed.len(),
+                    });
+                }
+                issues.extend(
+                    existing
+                        .stale_entries(&prefix)
+                        .into_iter()
+                        .map(|e| Issue::StaleEntry { path: e.path }),
+                );
+            }
+            Err(error) => issues.push(Issue::CacheUnreadable {
+                path: cache_file.to_string(),
+                error: error.to_string(),
+            }),
+        }
+    }
+
+    for action in &report.links {
+        issues.push(match action {
+            LinkAction::RemoveStale { dir, link, target } => Issue::DanglingSymlink {
+                path: format!("{}/{}", dir, link),
+                target: target.clone(),
+            },
+            _ => Issue::SymlinkOutdated(action.clone()),
+        });
+    }
+    issues.extend(report.shadowed.iter().cloned().map(Issue::DuplicateSoname));
+    // Dangling symlinks and duplicate sonames are warnings too, already
+    // listed above with their details.
+    issues.extend(
+        report
+            .warnings
+            .iter()
+            .filter(|w| {
+                !matches!(
+                    w.kind,
+                    WarningKind::DanglingSymlink | WarningKind::DuplicateSoname
+                )
+            })
+            .cloned()
+            .map(Issue::Scan),
+    );
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{run, RunOptions};
+    use camino::Utf8PathBuf;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn finds_what_a_build_would_fix() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/ld.so.conf"), "/a\n/b\ninclude b.conf\n").unwrap();
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        for dir in ["a", "b"] {
+            fs::create_dir(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("libz.so.1.3"), &dso).unwrap();
+        }
+        symlink("libgone.so.1.0", root.join("a/libgone.so.1")).unwrap();
+
+        let issues = check_system(&root).unwrap();
+        let mut kinds: Vec<&str> = issues.iter().map(Issue::kind).collect();
+        kinds.sort();
+        assert_eq!(
+            kinds,
+            [
+                "cache_unreadable",
+                "config",
+                "dangling_symlink",
+                "duplicate_soname",
+                "symlink_outdated",
+                "symlink_outdated"
+            ]
+        );
+        assert!(issues.contains(&Issue::DanglingSymlink {
+            path: "/a/libgone.so.1".into(),
+            target: "libgone.so.1.0".into(),
+        }));
+
+        run(&RunOptions::builder().root(&root).build()).unwrap();
+        let kinds: Vec<&str> = check_system(&root)
+            .unwrap()
+            .iter()
+            .map(Issue::kind)
+            .collect();
+        assert_eq!(kinds, ["config", "duplicate_soname"]);
+    }
+
+    #[test]
+    fn checks_against_the_glibc_installed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/ld.so.conf"), "/opt/lib\n").unwrap();
+        fs::create_dir_all(root.join("lib")).unwrap();
+        let banner = b"\0GNU C Library (GNU libc) stable release version 2.31.\n\0";
+        fs::write(root.join("lib/libc.so.6"), banner).unwrap();
+        // A variant glibc 2.31 does not know to look for.
+        let v3 = root.join("opt/lib/glibc-hwcaps/x86-64-v3");
+        fs::create_dir_all(&v3).unwrap();
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        fs::write(root.join("opt/lib/libz.so.1.3"), &dso).unwrap();
+        fs::write(v3.join("libz.so.1"), &dso).unwrap();
+
+        run(&RunOptions::builder().root(&root).build()).unwrap();
+        assert_eq!(check_system(&root).unwrap(), []);
+    }
+}
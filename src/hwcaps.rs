@@ -0,0 +1,390 @@
+//! Discovering the capability subdirectories of a library directory, for
+//! tools deciding where optimized builds go, and which cached variants a
+//! CPU can use.
+//!
+//! ```no_run
+//! use ldconfig::{detect_hwcap_dirs, HwcapLayer};
+//!
+//! for dir in detect_hwcap_dirs("/usr/lib64", "/") {
+//!     if dir.layer == HwcapLayer::GlibcHwcaps {
+//!         println!("{} (priority {:?})", dir.path, dir.priority);
+//!     }
+//! }
+//! ```
+
+use crate::cache_format::{legacy_hwcap_bit, legacy_hwcap_usable, Arch};
+use crate::chroot::chroot_canon;
+use crate::{Cache, CacheEntry};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::fs;
+
+/// Legacy subdirectories nest at most this deep (`tls/i686/sse2`).
+const MAX_LEGACY_DEPTH: usize = 3;
+
+/// The loader mechanism a capability directory belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwcapLayer {
+    /// `<dir>/glibc-hwcaps/<name>`, searched by glibc 2.33 and later.
+    GlibcHwcaps,
+    /// Pre-2.33 platform and hwcap subdirectories (`tls`, `i686`,
+    /// `sse2`...), nested in any combination.
+    Legacy,
+}
+
+/// A capability subdirectory of a library directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HwcapDir {
+    /// The subdirectory, as seen inside the root.
+    pub path: Utf8PathBuf,
+    /// Its name below the library directory's capability layer:
+    /// "x86-64-v3", or "tls/i686" for nested legacy directories.
+    pub name: String,
+    pub layer: HwcapLayer,
+    /// Where ld.so searches a glibc-hwcaps subdirectory among those it
+    /// knows, 0 first (see [`glibc_hwcaps_names`]); None for names it
+    /// never searches and for legacy directories.
+    pub priority: Option<usize>,
+    /// The legacy hwcap mask the directory stands for; 0 for
+    /// glibc-hwcaps subdirectories.
+    pub hwcap: u64,
+}
+
+/// The glibc-hwcaps subdirectory names ld.so knows for `arch`, in search
+/// order: the best the CPU supports wins.
+pub fn glibc_hwcaps_names(arch: Arch) -> &'static [&'static str] {
+    match arch {
+        Arch::X86_64 => &["x86-64-v4", "x86-64-v3", "x86-64-v2"],
+        Arch::PowerPc64 => &["power10", "power9"],
+        Arch::S390_64 => &["z16", "z15", "z14", "z13"],
+        _ => &[],
+    }
+}
+
+/// What ld.so learns about the CPU it runs on, to pick among
+/// glibc-hwcaps variants.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuCaps {
+    /// AT_HWCAP.
+    pub hwcap: u64,
+    /// AT_HWCAP2.
+    pub hwcap2: u64,
+    /// x86-64 micro-architecture level, 1 to 4; x86 loaders get it from
+    /// cpuid rather than from the auxiliary vector.
+    pub x86_isa_level: u32,
+}
+
+// PowerPC AT_HWCAP2 bits (asm/cputable.h).
+const PPC_FEATURE2_ARCH_3_00: u64 = 0x0080_0000;
+const PPC_FEATURE2_HAS_IEEE128: u64 = 0x0040_0000;
+const PPC_FEATURE2_ARCH_3_1: u64 = 0x0004_0000;
+const PPC_FEATURE2_MMA: u64 = 0x0002_0000;
+// s390 AT_HWCAP bits (asm/elf.h).
+const HWCAP_S390_VX: u64 = 1 << 11;
+const HWCAP_S390_VXE: u64 = 1 << 13;
+const HWCAP_S390_VXRS_EXT2: u64 = 1 << 15;
+const HWCAP_S390_VXRS_PDE2: u64 = 1 << 19;
+
+impl CpuCaps {
+    /// The glibc-hwcaps subdirectories ld.so searches on this CPU for
+    /// `arch`, best first, as the dl-hwcaps-subdirs.c of each port
+    /// decides; every level needs the ones below it.
+    pub fn active_hwcaps(&self, arch: Arch) -> Vec<&'static str> {
+        let levels: Vec<bool> = match arch {
+            Arch::X86_64 => (2..=4).map(|level| self.x86_isa_level >= level).collect(),
+            Arch::PowerPc64 => vec![
+                self.hwcap2 & PPC_FEATURE2_ARCH_3_00 != 0
+                    && self.hwcap2 & PPC_FEATURE2_HAS_IEEE128 != 0,
+                self.hwcap2 & PPC_FEATURE2_ARCH_3_1 != 0 && self.hwcap2 & PPC_FEATURE2_MMA != 0,
+            ],
+            Arch::S390_64 => [
+                HWCAP_S390_VX,
+                HWCAP_S390_VXE,
+                HWCAP_S390_VXRS_EXT2,
+                HWCAP_S390_VXRS_PDE2,
+            ]
+            .map(|bit| self.hwcap & bit != 0)
+            .to_vec(),
+            _ => Vec::new(),
+        };
+        let active = levels.iter().take_while(|&&supported| supported).count();
+        // glibc_hwcaps_names lists the best level first.
+        let names = glibc_hwcaps_names(arch);
+        names[names.len() - active..].to_vec()
+    }
+}
+
+#[cfg(unix)]
+fn priority(name: &str) -> Option<usize> {
+    [Arch::X86_64, Arch::PowerPc64, Arch::S390_64]
+        .into_iter()
+        .find_map(|arch| glibc_hwcaps_names(arch).iter().position(|&n| n == name))
+}
+
+/// Subdirectory names of `dir` (inside `prefix`), sorted.
+fn subdirectories(prefix: &Utf8Path, dir: &Utf8Path) -> Vec<String> {
+    let Some(real) = chroot_canon(prefix, dir) else {
+        return Vec::new();
+    };
+    let Ok(rd) = fs::read_dir(real) else {
+        return Vec::new();
+    };
+    // Symlinked subdirectories resolve inside the root too.
+    let mut names: Vec<String> = rd
+        .flatten()
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter(|name| {
+            chroot_canon(prefix, &dir.join(name))
+                .and_then(|real| fs::metadata(real).ok())
+                .is_some_and(|md| md.is_dir())
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+fn legacy_dirs(
+    prefix: &Utf8Path,
+    dir: &Utf8Path,
+    name: &str,
+    hwcap: u64,
+    depth: usize,
+    out: &mut Vec<HwcapDir>,
+) {
+    if depth == MAX_LEGACY_DEPTH {
+        return;
+    }
+    for sub in subdirectories(prefix, dir) {
+        let Some(bit) = legacy_hwcap_bit(&sub) else {
+            continue;
+        };
+        let path = dir.join(&sub);
+        let name = if name.is_empty() {
+            sub
+        } else {
+            format!("{}/{}", name, sub)
+        };
+        out.push(HwcapDir {
+            path: path.clone(),
+            name: name.clone(),
+            layer: HwcapLayer::Legacy,
+            priority: None,
+            hwcap: hwcap | bit,
+        });
+        legacy_dirs(prefix, &path, &name, hwcap | bit, depth + 1, out);
+    }
+}
+
+/// The capability subdirectories of the library directory `dir`, a path
+/// inside `prefix` (the -r root): every glibc-hwcaps subdirectory, which
+/// the cache records whatever its name, then the legacy ones. A
+/// glibc-hwcaps subdirectory that is the directory itself, or another
+/// one under a second name, is listed once, as in a cache build. Only
+/// Unix hosts list glibc-hwcaps subdirectories.
+pub fn detect_hwcap_dirs(dir: impl AsRef<Utf8Path>, prefix: impl AsRef<Utf8Path>) -> Vec<HwcapDir> {
+    let dir = dir.as_ref();
+    let prefix = prefix.as_ref();
+    let mut out = Vec::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let mut seen = std::collections::HashSet::new();
+        if let Some(md) = chroot_canon(prefix, dir).and_then(|real| fs::metadata(real).ok()) {
+            seen.insert((md.dev(), md.ino()));
+        }
+        out.extend(
+            crate::scanner::glibc_hwcaps_dirs(prefix, dir, &mut seen)
+                .into_iter()
+                .filter_map(|d| {
+                    let name = d.hwcaps?;
+                    Some(HwcapDir {
+                        path: d.path,
+                        priority: priority(&name),
+                        name,
+                        layer: HwcapLayer::GlibcHwcaps,
+                        hwcap: 0,
+                    })
+                }),
+        );
+    }
+    legacy_dirs(prefix, dir, "", 0, 0, &mut out);
+    out
+}
+
+impl Cache {
+    /// The entries a CPU with legacy capability bits `hwcap_mask`
+    /// (AT_HWCAP as masked by LD_HWCAP_MASK) and platform `platform`
+    /// (AT_PLATFORM, e.g. "haswell") can load, in cache order: legacy
+    /// hwcap entries needing other bits or another platform are dropped.
+    /// glibc-hwcaps entries are kept, the loader picking among them by
+    /// CPU features rather than by this mask.
+    pub fn usable_entries(&self, hwcap_mask: u64, platform: Option<&str>) -> Vec<CacheEntry> {
+        self.entries()
+            .filter(|e| e.hwcaps.is_some() || legacy_hwcap_usable(e.hwcap, hwcap_mask, platform))
+            .collect()
+    }
+
+    /// The entries ld.so tries for `soname` of ABI `abi` on `cpu`, in
+    /// order: the first is the one it loads, the rest the fallbacks should
+    /// that file be missing. Variants in glibc-hwcaps subdirectories the
+    /// CPU supports come first, best first, then the baseline entries;
+    /// legacy hwcap entries, which ld.so no longer searches since 2.37,
+    /// are left out.
+    pub fn variant_chain(&self, soname: &str, abi: Arch, cpu: &CpuCaps) -> Vec<CacheEntry> {
+        let active = cpu.active_hwcaps(abi);
+        let mut chain: Vec<(usize, CacheEntry)> = self
+            .entries()
+            .filter(|e| e.soname == soname && e.abi == abi)
+            .filter_map(|e| match &e.hwcaps {
+                Some(name) => active.iter().position(|a| a == name).map(|rank| (rank, e)),
+                None => (e.hwcap == 0).then_some((active.len(), e)),
+            })
+            .collect();
+        // Stable: equal ranks keep cache order, as ld.so's search does.
+        chain.sort_by_key(|(rank, _)| *rank);
+        chain.into_iter().map(|(_, e)| e).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SyntheticEntry;
+
+    #[cfg(unix)]
+    #[test]
+    fn finds_both_layers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        for sub in [
+            "usr/lib/glibc-hwcaps/x86-64-v3",
+            "usr/lib/glibc-hwcaps/vendor-opt",
+            "usr/lib/tls/i686",
+            "usr/lib/plugins",
+        ] {
+            fs::create_dir_all(root.join(sub)).unwrap();
+        }
+        // The library directory itself, which a cache build scans once.
+        std::os::unix::fs::symlink("..", root.join("usr/lib/glibc-hwcaps/self")).unwrap();
+
+        let dirs = detect_hwcap_dirs("/usr/lib", &root);
+        let summary: Vec<(&str, HwcapLayer, Option<usize>)> = dirs
+            .iter()
+            .map(|d| (d.name.as_str(), d.layer, d.priority))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("vendor-opt", HwcapLayer::GlibcHwcaps, None),
+                ("x86-64-v3", HwcapLayer::GlibcHwcaps, Some(1)),
+                ("tls", HwcapLayer::Legacy, None),
+                ("tls/i686", HwcapLayer::Legacy, None),
+            ]
+        );
+        assert_eq!(dirs[1].path, "/usr/lib/glibc-hwcaps/x86-64-v3");
+        assert_eq!(dirs[3].path, "/usr/lib/tls/i686");
+        assert_eq!(
+            crate::cache_format::legacy_hwcap_names(Arch::Libc6.flags(), dirs[3].hwcap),
+            ["i686", "tls"]
+        );
+        assert!(detect_hwcap_dirs("/nowhere", &root).is_empty());
+    }
+
+    #[test]
+    fn usable_entries_follow_the_cpu() {
+        let legacy = |path: &str, hwcap: u64| {
+            let mut e = SyntheticEntry::new("libm.so.6", path, Arch::Libc6);
+            e.hwcap = hwcap;
+            e
+        };
+        let sse2 = legacy_hwcap_bit("sse2").unwrap();
+        let i686 = legacy_hwcap_bit("i686").unwrap();
+        let tls = legacy_hwcap_bit("tls").unwrap();
+        let mut hw = SyntheticEntry::new(
+            "libm.so.6",
+            "/lib/glibc-hwcaps/x86-64-v3/libm.so.6",
+            Arch::X86_64,
+        );
+        hw.hwcaps = Some("x86-64-v3".into());
+        let cache = Cache::from_file_entries(&[], None, &[])
+            .unwrap()
+            .add_entries(&[
+                legacy("/lib/libm.so.6", 0),
+                legacy("/lib/sse2/libm.so.6", sse2),
+                legacy("/lib/tls/i686/libm.so.6", tls | i686),
+                hw,
+            ])
+            .unwrap();
+        let usable = |mask, platform| -> Vec<String> {
+            let mut paths: Vec<String> = cache
+                .usable_entries(mask, platform)
+                .into_iter()
+                .map(|e| e.path)
+                .collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(
+            usable(0, Some("i586")),
+            ["/lib/glibc-hwcaps/x86-64-v3/libm.so.6", "/lib/libm.so.6"]
+        );
+        assert_eq!(usable(sse2, Some("i686")).len(), 4);
+        assert_eq!(usable(sse2, None).len(), 3);
+    }
+
+    #[test]
+    fn variant_chain_prefers_the_best_supported_level() {
+        let variant = |dir: Option<&str>, abi| {
+            let path = match dir {
+                Some(name) => format!("/usr/lib/glibc-hwcaps/{}/libblas.so.3", name),
+                None => "/usr/lib/libblas.so.3".to_owned(),
+            };
+            let mut e = SyntheticEntry::new("libblas.so.3", path, abi);
+            e.hwcaps = dir.map(str::to_owned);
+            e
+        };
+        let cache = Cache::from_file_entries(&[], None, &[])
+            .unwrap()
+            .add_entries(&[
+                variant(None, Arch::X86_64),
+                variant(Some("x86-64-v2"), Arch::X86_64),
+                variant(Some("x86-64-v4"), Arch::X86_64),
+                variant(Some("vendor-opt"), Arch::X86_64),
+                variant(Some("power9"), Arch::PowerPc64),
+                variant(None, Arch::PowerPc64),
+            ])
+            .unwrap();
+        let chain = |abi, cpu: CpuCaps| -> Vec<Option<String>> {
+            cache
+                .variant_chain("libblas.so.3", abi, &cpu)
+                .into_iter()
+                .map(|e| e.hwcaps)
+                .collect()
+        };
+
+        let v3 = CpuCaps {
+            x86_isa_level: 3,
+            ..CpuCaps::default()
+        };
+        assert_eq!(v3.active_hwcaps(Arch::X86_64), ["x86-64-v3", "x86-64-v2"]);
+        assert_eq!(
+            chain(Arch::X86_64, v3),
+            [Some("x86-64-v2".to_owned()), None]
+        );
+        assert_eq!(chain(Arch::X86_64, CpuCaps::default()), [None]);
+
+        let power9 = CpuCaps {
+            hwcap2: PPC_FEATURE2_ARCH_3_00 | PPC_FEATURE2_HAS_IEEE128 | PPC_FEATURE2_MMA,
+            ..CpuCaps::default()
+        };
+        assert_eq!(power9.active_hwcaps(Arch::PowerPc64), ["power9"]);
+        assert_eq!(
+            chain(Arch::PowerPc64, power9),
+            [Some("power9".to_owned()), None]
+        );
+        let z14 = CpuCaps {
+            hwcap: HWCAP_S390_VX | HWCAP_S390_VXE | HWCAP_S390_VXRS_PDE2,
+            ..CpuCaps::default()
+        };
+        assert_eq!(z14.active_hwcaps(Arch::S390_64), ["z14", "z13"]);
+    }
+}
@@ -21,33 +21,107 @@
 //!
 //! # Example: Build and write a cache
 //!
+//! Building scans the filesystem and maintains symlinks, so it is only
+//! available on Unix hosts; reading, querying and editing caches works
+//! everywhere.
+//!
 //! ```no_run
 //! use ldconfig::{SearchPaths, Cache};
 //!
+//! # #[cfg(unix)] {
 //! let search_paths = SearchPaths::from_file("/etc/ld.so.conf", None)?;
 //! let cache = Cache::builder()
 //!     .build(&search_paths)?;
 //! cache.write_to_file("/etc/ld.so.cache")?;
+//! # }
 //! # Ok::<(), ldconfig::Error>(())
 //! ```
 
 // Internal implementation modules
 pub(crate) mod cache_format;
 pub(crate) mod chroot;
+#[cfg(unix)]
 pub(crate) mod elf;
+#[cfg(unix)]
 pub(crate) mod scanner;
+#[cfg(unix)]
 pub(crate) mod symlinks;
 
 pub(crate) mod atomic_write;
 
+mod audit;
+mod aux_cache;
+mod backup;
+#[cfg(unix)]
+mod builder;
 mod cache;
+mod capabilities;
+mod compat;
 mod config;
 mod error;
+#[cfg(unix)]
+mod health;
+mod hwcaps;
+#[cfg(unix)]
+mod mounts;
+mod paths;
+mod plan;
+pub mod raw;
+mod report;
+#[cfg(unix)]
+mod resolver;
+#[cfg(unix)]
+mod run;
+#[cfg(unix)]
+mod spill;
+mod stats;
+#[cfg(unix)]
+mod symbols;
+#[cfg(unix)]
+mod transaction;
 
 // Main public API exports
-pub use cache::{Cache, CacheBuilder, CacheEntry, CacheInfo};
-pub use chroot::chroot_canon;
-pub use config::SearchPaths;
+pub use audit::{write_audit, Change};
+pub use aux_cache::{AuxCache, AuxEntry, AUX_CACHE_PATH};
+#[cfg(unix)]
+pub use backup::undo_links;
+pub use backup::{rollback, Backup};
+#[cfg(unix)]
+pub use builder::{BudgetPolicy, CacheBuilder};
+pub use cache::{
+    Alphabetical, Cache, CacheEntries, CacheEntry, CacheInfo, SortKey, SyntheticEntry,
+};
+pub use cache_format::dl_cache_libcmp as libcmp;
+pub use cache_format::{Arch, CacheFormat, FileStat, GlibcVersion, OsVersion, Provenance};
+pub use capabilities::{capabilities, Capabilities};
+pub use chroot::{chroot_canon, enter_root, RootMode};
+pub use compat::Divergence;
+pub use config::{ConfigLine, SearchPaths, DEFAULT_INCLUDE_DEPTH};
+#[cfg(unix)]
+pub use elf::{inspect_library, supported_architectures, ElfArch, ElfMemo, LibraryInfo};
+#[cfg(unix)]
+pub use health::{check_system, Issue};
+pub use hwcaps::{detect_hwcap_dirs, glibc_hwcaps_names, CpuCaps, HwcapDir, HwcapLayer};
+pub use paths::Target;
+pub use plan::{BuildPlan, CacheDiff};
+pub use report::{
+    BuildReport, EntryOrigin, LinkAction, OriginKind, Shadowed, Warning, WarningKind,
+};
+#[cfg(unix)]
+pub use resolver::{Dependency, Resolver};
+#[cfg(unix)]
+pub use run::{plan_run, run, run_batch, BatchRun, PlannedRun, RunOptions, RunSummary};
+#[cfg(unix)]
+pub use scanner::{
+    is_dev_symlink, is_dso_name, DsoNameFilter, ElfLibrary, LibraryFilter, PathPolicy,
+    SymlinkPolicy, DEFAULT_DENIED_DIRS, DEFAULT_SKIP_SUFFIXES,
+};
+pub use stats::CacheStats;
+#[cfg(unix)]
+pub use symbols::{exported_symbols, ExportedSymbol, SymbolKind};
+
+#[cfg(feature = "signing")]
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
 
 /// Errors encountered while reading or writing the cache
 ///
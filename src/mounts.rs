@@ -0,0 +1,114 @@
+//! The mount table, to tell when a directory's path only holds inside
+//! the build's mount namespace (overlay layers, bind mounts).
+
+use camino::{Utf8Path, Utf8PathBuf};
+use std::fs;
+
+/// A line of /proc/self/mountinfo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Mount {
+    /// Where it is mounted.
+    pub point: Utf8PathBuf,
+    /// The directory of the source filesystem mounted there: `/` unless
+    /// it is a bind mount of a subdirectory.
+    pub root: String,
+    pub fstype: String,
+}
+
+impl Mount {
+    /// What about the mount makes paths under it differ from the ones
+    /// the loader sees at run time, if anything.
+    pub fn layering(&self) -> Option<&'static str> {
+        if self.fstype == "overlay" {
+            Some("an overlay")
+        } else if self.root != "/" {
+            Some("a bind")
+        } else {
+            None
+        }
+    }
+}
+
+/// Undo the octal escapes (`\040` for a space) of mountinfo fields.
+fn unescape(field: &str) -> String {
+    let b = field.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        let octal = b
+            .get(i + 1..i + 4)
+            .filter(|d| d.iter().all(|c| (b'0'..=b'7').contains(c)));
+        match octal {
+            Some(d) if b[i] == b'\\' => {
+                out.push((d[0] - b'0') * 64 + (d[1] - b'0') * 8 + (d[2] - b'0'));
+                i += 4;
+            }
+            _ => {
+                out.push(b[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub(crate) fn parse_mountinfo(text: &str) -> Vec<Mount> {
+    text.lines()
+        .filter_map(|line| {
+            // id parent major:minor root point options [optional...] - fstype source super
+            let (mount, fs) = line.split_once(" - ")?;
+            let mut fields = mount.split(' ');
+            let root = unescape(fields.nth(3)?);
+            let point = Utf8PathBuf::from(unescape(fields.next()?));
+            let fstype = fs.split(' ').next()?.to_owned();
+            Some(Mount {
+                point,
+                root,
+                fstype,
+            })
+        })
+        .collect()
+}
+
+/// The current mount table; empty where there is no /proc.
+pub(crate) fn read_mountinfo() -> Vec<Mount> {
+    fs::read_to_string("/proc/self/mountinfo")
+        .map(|text| parse_mountinfo(&text))
+        .unwrap_or_default()
+}
+
+/// The mount `path` (absolute, canonical) lives on: the last mounted of
+/// those with the longest mount point containing it.
+pub(crate) fn mount_of<'a>(mounts: &'a [Mount], path: &Utf8Path) -> Option<&'a Mount> {
+    mounts
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| path.starts_with(&m.point))
+        .max_by_key(|(i, m)| (m.point.components().count(), *i))
+        .map(|(_, m)| m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_layered_mounts() {
+        let mounts = parse_mountinfo(
+            "\
+21 1 0:20 / / rw,relatime - overlay overlay rw,lowerdir=/l,upperdir=/u,workdir=/w
+22 21 8:1 /srv/libs /opt/vendor\\040libs rw,relatime shared:1 - ext4 /dev/sda1 rw
+23 21 0:21 / /proc rw,nosuid - proc proc rw
+24 21 8:2 / /usr/local rw - ext4 /dev/sda2 rw
+",
+        );
+        assert_eq!(mounts.len(), 4);
+        assert_eq!(mounts[1].point, "/opt/vendor libs");
+
+        let layering = |path| mount_of(&mounts, Utf8Path::new(path)).and_then(Mount::layering);
+        assert_eq!(layering("/usr/lib"), Some("an overlay"));
+        assert_eq!(layering("/opt/vendor libs/x86_64"), Some("a bind"));
+        assert_eq!(layering("/usr/local/lib"), None);
+        assert_eq!(layering("/opt/vendor"), Some("an overlay"));
+    }
+}
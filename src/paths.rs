@@ -0,0 +1,112 @@
+//! Where a system keeps its loader cache, configuration and trusted
+//! directories, by the layout of its C library.
+//!
+//! ```
+//! use ldconfig::Target;
+//! use camino::Utf8Path;
+//!
+//! let target = Target::Multiarch("aarch64-linux-gnu".into());
+//! let sysroot = Some(Utf8Path::new("/srv/arm64"));
+//! assert_eq!(target.cache_path(sysroot).unwrap(), "/srv/arm64/etc/ld.so.cache");
+//! assert_eq!(target.trusted_dirs(None)[0], "/lib/aarch64-linux-gnu");
+//! ```
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+/// A C library layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// glibc keeping 64-bit libraries in lib64, as x86_64 Fedora,
+    /// openSUSE and Arch do.
+    Glibc,
+    /// glibc on Debian-style multiarch, named by its triplet such as
+    /// `x86_64-linux-gnu`.
+    Multiarch(String),
+    /// musl for an architecture such as `x86_64`; its loader reads a
+    /// path file instead of a cache.
+    Musl(String),
+}
+
+impl Target {
+    /// The loader cache, `/etc/ld.so.cache`; None for musl, which has
+    /// none.
+    pub fn cache_path(&self, prefix: Option<&Utf8Path>) -> Option<Utf8PathBuf> {
+        match self {
+            Target::Glibc | Target::Multiarch(_) => Some(under(prefix, "/etc/ld.so.cache")),
+            Target::Musl(_) => None,
+        }
+    }
+
+    /// The configuration naming extra directories: `/etc/ld.so.conf`, or
+    /// musl's `/etc/ld-musl-ARCH.path`.
+    pub fn config_path(&self, prefix: Option<&Utf8Path>) -> Utf8PathBuf {
+        match self {
+            Target::Glibc | Target::Multiarch(_) => under(prefix, "/etc/ld.so.conf"),
+            Target::Musl(arch) => under(prefix, &format!("/etc/ld-musl-{}.path", arch)),
+        }
+    }
+
+    /// The directories the loader searches after the cache, or without
+    /// configuration for musl, in its order.
+    pub fn trusted_dirs(&self, prefix: Option<&Utf8Path>) -> Vec<Utf8PathBuf> {
+        let dirs: Vec<String> = match self {
+            Target::Glibc => vec!["/lib64".into(), "/usr/lib64".into()],
+            Target::Multiarch(triplet) => vec![
+                format!("/lib/{}", triplet),
+                format!("/usr/lib/{}", triplet),
+                "/lib".into(),
+                "/usr/lib".into(),
+            ],
+            Target::Musl(_) => vec!["/lib".into(), "/usr/local/lib".into(), "/usr/lib".into()],
+        };
+        dirs.iter().map(|dir| under(prefix, dir)).collect()
+    }
+
+    /// Where ld.so looks after the cache for a 64-bit or 32-bit object:
+    /// the trusted directories, but `/lib` and `/usr/lib` for 32-bit
+    /// objects on a glibc system, which its 32-bit loader searches.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn default_dirs(&self, is_64: bool) -> Vec<Utf8PathBuf> {
+        match self {
+            Target::Glibc | Target::Multiarch(_) if !is_64 => {
+                vec!["/lib".into(), "/usr/lib".into()]
+            }
+            _ => self.trusted_dirs(None),
+        }
+    }
+}
+
+/// `path` inside `prefix`, textually: the files need not exist yet.
+fn under(prefix: Option<&Utf8Path>, path: &str) -> Utf8PathBuf {
+    match prefix {
+        Some(prefix) => prefix.join(path.trim_start_matches('/')),
+        None => Utf8PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_per_target() {
+        let root = Some(Utf8Path::new("/sysroot"));
+        assert_eq!(Target::Glibc.cache_path(None).unwrap(), "/etc/ld.so.cache");
+        assert_eq!(
+            Target::Glibc.trusted_dirs(root),
+            ["/sysroot/lib64", "/sysroot/usr/lib64"]
+        );
+
+        let debian = Target::Multiarch("x86_64-linux-gnu".into());
+        assert_eq!(debian.config_path(root), "/sysroot/etc/ld.so.conf");
+        assert_eq!(debian.trusted_dirs(None)[1], "/usr/lib/x86_64-linux-gnu");
+
+        let musl = Target::Musl("x86_64".into());
+        assert_eq!(musl.cache_path(root), None);
+        assert_eq!(musl.config_path(root), "/sysroot/etc/ld-musl-x86_64.path");
+        assert_eq!(
+            musl.trusted_dirs(None),
+            ["/lib", "/usr/local/lib", "/usr/lib"]
+        );
+    }
+}
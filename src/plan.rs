@@ -0,0 +1,149 @@
+//! Previewing a build: what would be written and changed on disk.
+//!
+//! ```no_run
+//! use ldconfig::{Cache, SearchPaths};
+//!
+//! # #[cfg(unix)] {
+//! let paths = SearchPaths::from_file("/etc/ld.so.conf", None)?;
+//! let existing = Cache::from_file("/etc/ld.so.cache").ok();
+//! let preview = Cache::builder().dry_run(true).build(&paths)?;
+//! let plan = preview.plan(existing.as_ref());
+//! for link in &plan.links {
+//!     println!("{}", link);
+//! }
+//! for entry in &plan.diff.added {
+//!     println!("+{}", entry);
+//! }
+//! # }
+//! # Ok::<(), ldconfig::Error>(())
+//! ```
+
+use crate::{Cache, CacheEntry, LinkAction};
+use std::collections::HashMap;
+
+/// How the entries of two caches differ, matching entries by soname,
+/// path and glibc-hwcaps subdirectory.
+#[derive(Debug, Clone, Default)]
+pub struct CacheDiff {
+    /// Only in the new cache, in its order.
+    pub added: Vec<CacheEntry>,
+    /// Only in the old cache, in its order.
+    pub removed: Vec<CacheEntry>,
+    /// In both with other flags, hwcap mask or osversion, as (old, new).
+    pub changed: Vec<(CacheEntry, CacheEntry)>,
+}
+
+impl CacheDiff {
+    /// Whether both caches hold the same entries.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Everything a build would do, for review before applying it.
+#[derive(Debug, Clone)]
+pub struct BuildPlan {
+    /// The entries the cache would hold, in cache order.
+    pub entries: Vec<CacheEntry>,
+    /// Symlink changes, in the order they would be made.
+    pub links: Vec<LinkAction>,
+    /// The entries against the cache currently on disk.
+    pub diff: CacheDiff,
+}
+
+type Key = (String, String, Option<String>);
+
+fn key(e: &CacheEntry) -> Key {
+    (e.soname.clone(), e.path.clone(), e.hwcaps.clone())
+}
+
+impl Cache {
+    /// Entries added, removed or changed going from `old` to this cache.
+    pub fn diff(&self, old: &Cache) -> CacheDiff {
+        let old_entries: Vec<CacheEntry> = old.entries().collect();
+        // Pair repeated keys in order.
+        let mut pool: HashMap<Key, Vec<usize>> = HashMap::new();
+        for (i, e) in old_entries.iter().enumerate().rev() {
+            pool.entry(key(e)).or_default().push(i);
+        }
+        let mut matched = vec![false; old_entries.len()];
+        let mut diff = CacheDiff::default();
+        for entry in self.entries() {
+            match pool.get_mut(&key(&entry)).and_then(Vec::pop) {
+                Some(i) => {
+                    matched[i] = true;
+                    let was = &old_entries[i];
+                    if (was.flags, was.hwcap, was.osversion)
+                        != (entry.flags, entry.hwcap, entry.osversion)
+                    {
+                        diff.changed.push((was.clone(), entry));
+                    }
+                }
+                None => diff.added.push(entry),
+            }
+        }
+        diff.removed = old_entries
+            .into_iter()
+            .zip(matched)
+            .filter(|(_, m)| !m)
+            .map(|(e, _)| e)
+            .collect();
+        diff
+    }
+
+    /// The plan for a cache built with `dry_run(true)`: its entries, the
+    /// symlink changes the build skipped, and the diff against
+    /// `existing`, the cache on disk if there is one.
+    pub fn plan(&self, existing: Option<&Cache>) -> BuildPlan {
+        let diff = match existing {
+            Some(old) => self.diff(old),
+            None => CacheDiff {
+                added: self.entries().collect(),
+                ..CacheDiff::default()
+            },
+        };
+        BuildPlan {
+            entries: self.entries().collect(),
+            links: self.report().links.clone(),
+            diff,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Arch, SyntheticEntry};
+
+    fn cache(entries: &[SyntheticEntry]) -> Cache {
+        Cache::from_file_entries(&[], None, &[])
+            .unwrap()
+            .add_entries(entries)
+            .unwrap()
+    }
+
+    #[test]
+    fn diff_pairs_entries() {
+        let old = cache(&[
+            SyntheticEntry::new("libz.so.1", "/usr/lib/libz.so.1", Arch::X86_64),
+            SyntheticEntry::new("libm.so.6", "/usr/lib/libm.so.6", Arch::X86_64),
+        ]);
+        let new = cache(&[
+            SyntheticEntry::new("libz.so.1", "/usr/lib/libz.so.1", Arch::Libc6),
+            SyntheticEntry::new("libc.so.6", "/usr/lib/libc.so.6", Arch::X86_64),
+        ]);
+        let diff = new.diff(&old);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].soname, "libc.so.6");
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].soname, "libm.so.6");
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].1.abi, Arch::Libc6);
+        assert!(new.diff(&new).is_empty());
+
+        let plan = new.plan(None);
+        assert_eq!(plan.entries.len(), 2);
+        assert_eq!(plan.diff.added.len(), 2);
+        assert!(plan.links.is_empty());
+    }
+}
@@ -0,0 +1,38 @@
+//! The on-disk cache format, for tools that need more than [`Cache`]:
+//! hex inspectors, repair utilities, format fuzzers.
+//!
+//! [`parse_cache`] turns bytes into a [`RawCacheInfo`] whose [`RawEntry`]
+//! values keep their string offsets, [`build_cache`] serializes
+//! [`FileEntry`] values, and [`Header`] with the size and tag constants
+//! locates everything else. The entry and info types are renamed so they
+//! do not clash with [`CacheEntry`](crate::CacheEntry).
+//! Integers are in host byte order, as glibc writes them.
+//!
+//! ```no_run
+//! use ldconfig::raw;
+//!
+//! let data = std::fs::read("/etc/ld.so.cache")?;
+//! let base = raw::new_format_offset(&data).expect("no new-format cache");
+//! let header = raw::Header::parse(&data[base..]).unwrap();
+//! println!("{} entries, strings at {:#x}", header.nlibs, base + header.string_table_offset());
+//! for e in raw::parse_cache(&data)?.entries {
+//!     println!("flags {:#06x} key @{:#x} value @{:#x}", e.flags, e.key_offset, e.value_offset);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! [`Cache`]: crate::Cache
+
+pub use crate::cache_format::{
+    build_cache, file_entries, new_format_offset, parse_cache, CacheEntry as RawEntry,
+    CacheInfo as RawCacheInfo, FileEntry, Header, RawExtension, CACHE_MAGIC,
+    DL_CACHE_HWCAP_EXTENSION, DL_CACHE_HWCAP_ISA_LEVEL_MASK, ENTRY_SIZE, EXTENSION_MAGIC,
+    FILE_STAT_SIZE, FLAG_AARCH64_LIB64, FLAG_ARM_LIBHF, FLAG_ARM_LIBSF, FLAG_ELF_LIBC6,
+    FLAG_LARCH_FLOAT_ABI_DOUBLE, FLAG_LARCH_FLOAT_ABI_SOFT, FLAG_MIPS64_LIBN32,
+    FLAG_MIPS64_LIBN32_NAN2008, FLAG_MIPS64_LIBN64, FLAG_MIPS64_LIBN64_NAN2008,
+    FLAG_MIPS_LIB32_NAN2008, FLAG_POWERPC_LIB64, FLAG_REQUIRED_MASK, FLAG_RISCV_FLOAT_ABI_DOUBLE,
+    FLAG_RISCV_FLOAT_ABI_SOFT, FLAG_S390_LIB64, FLAG_SPARC_LIB64, FLAG_TYPE_MASK, FLAG_X8664_LIB64,
+    FLAG_X8664_LIBX32, HEADER_SIZE, OLD_CACHE_MAGIC, OLD_ENTRY_SIZE, OLD_HEADER_SIZE,
+    TAG_CONTENT_SHA256, TAG_FILE_STAT, TAG_GENERATOR, TAG_GLIBC_HWCAPS, TAG_PROVENANCE,
+    TAG_SIGNATURE,
+};
@@ -0,0 +1,252 @@
+//! Diagnostics collected while building a cache.
+
+use crate::config::ConfigLine;
+use std::fmt;
+use tracing::warn;
+
+/// A problem encountered during a build that did not stop it, like the
+/// warnings glibc's ldconfig prints before carrying on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub message: String,
+}
+
+impl Warning {
+    /// Log a warning, with its code as a structured field.
+    pub(crate) fn logged(kind: WarningKind, message: String) -> Self {
+        warn!(code = kind.code(), "{}", message);
+        Self { kind, message }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// What a [`Warning`] is about. Each kind has a stable code, for
+/// automation to suppress or escalate a class of warnings by; messages
+/// may change wording, codes do not. New kinds get new codes, so match
+/// with a fallback arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum WarningKind {
+    /// A configuration file or include that exists but cannot be read.
+    ConfigUnreadable,
+    /// Includes nested deeper than the limit.
+    IncludeTooDeep,
+    /// An include leading back to a file that included it.
+    IncludeCycle,
+    /// An include pattern that is malformed, or relative under a root.
+    BadInclude,
+    /// A `hwcap` line, which glibc no longer supports.
+    HwcapDirective,
+    /// A directory that cannot be stat'ed, opened or read.
+    DirectoryUnreadable,
+    /// A directory skipped for resolving into a pseudo filesystem.
+    PseudoFilesystem,
+    /// A directory whose configured and resolved paths lie on different
+    /// mounts, one of them an overlay or bind mount.
+    MountMismatch,
+    /// A file that cannot be stat'ed or opened.
+    FileUnreadable,
+    /// A file, or a symlink's target, that is not a regular file.
+    NotRegularFile,
+    /// A file over the size limit.
+    FileTooLarge,
+    /// A static archive or relocatable object named like a library.
+    MisplacedObject,
+    /// Libraries sharing a soname in a directory with different ABIs.
+    SonameTypeMismatch,
+    /// A library of an ABI its directory does not expect.
+    ArchMismatch,
+    /// A file in the way of a soname symlink.
+    NotSymlink,
+    /// A soname symlink that could not be replaced or created.
+    LinkFailed,
+    /// A cache over its entry or string table budget.
+    OverBudget,
+    /// A dangling soname symlink, removed when updating links.
+    DanglingSymlink,
+    /// A library shadowed by an earlier one with the same soname.
+    DuplicateSoname,
+}
+
+impl WarningKind {
+    /// The stable code, e.g. "W0007".
+    pub fn code(self) -> &'static str {
+        match self {
+            WarningKind::ConfigUnreadable => "W0001",
+            WarningKind::IncludeTooDeep => "W0002",
+            WarningKind::IncludeCycle => "W0003",
+            WarningKind::BadInclude => "W0004",
+            WarningKind::HwcapDirective => "W0005",
+            WarningKind::DirectoryUnreadable => "W0006",
+            WarningKind::PseudoFilesystem => "W0007",
+            WarningKind::MountMismatch => "W0008",
+            WarningKind::FileUnreadable => "W0009",
+            WarningKind::NotRegularFile => "W0010",
+            WarningKind::FileTooLarge => "W0011",
+            WarningKind::MisplacedObject => "W0012",
+            WarningKind::SonameTypeMismatch => "W0013",
+            WarningKind::ArchMismatch => "W0014",
+            WarningKind::NotSymlink => "W0015",
+            WarningKind::LinkFailed => "W0016",
+            WarningKind::OverBudget => "W0017",
+            WarningKind::DanglingSymlink => "W0018",
+            WarningKind::DuplicateSoname => "W0019",
+        }
+    }
+
+    /// A stable snake_case name, for structured logs.
+    pub fn name(self) -> &'static str {
+        match self {
+            WarningKind::ConfigUnreadable => "config_unreadable",
+            WarningKind::IncludeTooDeep => "include_too_deep",
+            WarningKind::IncludeCycle => "include_cycle",
+            WarningKind::BadInclude => "bad_include",
+            WarningKind::HwcapDirective => "hwcap_directive",
+            WarningKind::DirectoryUnreadable => "directory_unreadable",
+            WarningKind::PseudoFilesystem => "pseudo_filesystem",
+            WarningKind::MountMismatch => "mount_mismatch",
+            WarningKind::FileUnreadable => "file_unreadable",
+            WarningKind::NotRegularFile => "not_regular_file",
+            WarningKind::FileTooLarge => "file_too_large",
+            WarningKind::MisplacedObject => "misplaced_object",
+            WarningKind::SonameTypeMismatch => "soname_type_mismatch",
+            WarningKind::ArchMismatch => "arch_mismatch",
+            WarningKind::NotSymlink => "not_symlink",
+            WarningKind::LinkFailed => "link_failed",
+            WarningKind::OverBudget => "over_budget",
+            WarningKind::DanglingSymlink => "dangling_symlink",
+            WarningKind::DuplicateSoname => "duplicate_soname",
+        }
+    }
+}
+
+/// A library hidden by one with the same soname, ABI and hwcaps
+/// subdirectory found earlier in the scan order; the loader only ever
+/// uses the first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shadowed {
+    pub soname: String,
+    /// Path of the entry the loader picks.
+    pub used: String,
+    /// Path of the entry it never reaches.
+    pub shadowed: String,
+}
+
+/// A change to a soname symlink: made by a build, or only planned when
+/// building with `dry_run`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkAction {
+    /// `dir/link` did not exist and points to `target` afterwards.
+    Create {
+        dir: String,
+        link: String,
+        target: String,
+    },
+    /// The `dir/link` symlink is re-pointed from `previous` to `target`.
+    Replace {
+        dir: String,
+        link: String,
+        target: String,
+        previous: String,
+    },
+    /// The dangling `dir/link` symlink to `target` is removed.
+    RemoveStale {
+        dir: String,
+        link: String,
+        target: String,
+    },
+}
+
+impl fmt::Display for LinkAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkAction::Create { dir, link, target } => {
+                write!(f, "{}/{} -> {} (new)", dir, link, target)
+            }
+            LinkAction::Replace {
+                dir, link, target, ..
+            } => {
+                write!(f, "{}/{} -> {} (changed)", dir, link, target)
+            }
+            LinkAction::RemoveStale { dir, link, .. } => {
+                write!(f, "{}/{} (dangling, removed)", dir, link)
+            }
+        }
+    }
+}
+
+/// How a built cache entry's path leads to the library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OriginKind {
+    /// The path is the library file itself.
+    File,
+    /// The path goes through a symlink that was already there.
+    Symlink,
+    /// The path is a soname symlink this build created or replaced (or,
+    /// in a dry run, would).
+    CreatedLink,
+}
+
+/// Where a scanned cache entry came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryOrigin {
+    /// The entry's path in the cache.
+    pub path: String,
+    /// The file found by the scan, in `dir`.
+    pub file: String,
+    /// The directory scanned, as seen inside the -r root.
+    pub dir: String,
+    /// The configuration line naming the directory, or the directory
+    /// above a glibc-hwcaps subdirectory.
+    pub config: Option<ConfigLine>,
+    pub kind: OriginKind,
+}
+
+/// What happened while building a cache
+///
+/// Returned by [`Cache::report`](crate::Cache::report); empty for caches
+/// read from disk.
+#[derive(Debug, Clone, Default)]
+pub struct BuildReport {
+    pub warnings: Vec<Warning>,
+    pub shadowed: Vec<Shadowed>,
+    /// Every symlink change, in scan order; planned but not made in a
+    /// dry run.
+    pub links: Vec<LinkAction>,
+    /// Where every scanned entry came from, in scan order; empty for
+    /// spilled builds, which keep nothing per entry.
+    pub origins: Vec<EntryOrigin>,
+}
+
+impl fmt::Display for BuildReport {
+    /// One indented warning per line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, w) in self.warnings.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "  {}", w)?;
+        }
+        Ok(())
+    }
+}
+
+impl BuildReport {
+    /// Where the scanned entry with this cache path came from; None for
+    /// synthetic entries and caches read from disk.
+    pub fn origin(&self, path: &str) -> Option<&EntryOrigin> {
+        self.origins.iter().find(|o| o.path == path)
+    }
+
+    /// Log a warning and record it.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub(crate) fn warn(&mut self, kind: WarningKind, message: String) {
+        self.warnings.push(Warning::logged(kind, message));
+    }
+}
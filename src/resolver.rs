@@ -0,0 +1,570 @@
+//! Dependency resolution without running anything, following the search
+//! order of glibc's elf/dl-load.c inside an alternate root.
+//!
+//! ```no_run
+//! use ldconfig::{Cache, Resolver};
+//!
+//! let cache = Cache::from_file("/srv/image/etc/ld.so.cache")?;
+//! let tree = Resolver::new("/srv/image", Some(&cache)).resolve("/usr/bin/ls")?;
+//! for dep in &tree.needed {
+//!     println!("{} => {:?}", dep.name, dep.path);
+//! }
+//! # Ok::<(), ldconfig::Error>(())
+//! ```
+
+use crate::audit::json_string;
+use crate::cache_format::dl_cache_check_flags;
+use crate::elf::{self, DynamicInfo};
+use crate::{chroot_canon, error::Error, Cache, Target};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::io;
+use tracing::debug;
+
+/// What was read from each object, by its path on the host; None for
+/// files that are not dynamic objects or cannot be read.
+type Parsed = HashMap<Utf8PathBuf, Option<DynamicInfo>>;
+
+/// An object and what it pulls in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    /// The DT_NEEDED name, or the path asked for at the top of the tree.
+    pub name: String,
+    /// Where it was found, as seen from inside the root; None if not found.
+    pub path: Option<Utf8PathBuf>,
+    /// Its own dependencies; empty when listed earlier in the tree.
+    pub needed: Vec<Dependency>,
+}
+
+impl Dependency {
+    /// Whether this or anything below it was not found.
+    pub fn has_missing(&self) -> bool {
+        self.path.is_none() || self.needed.iter().any(Dependency::has_missing)
+    }
+
+    /// The tree as one JSON object:
+    /// `{"name":…,"path":… or null,"needed":[…]}`.
+    pub fn to_json(&self) -> String {
+        let path = self
+            .path
+            .as_ref()
+            .map_or_else(|| "null".to_owned(), |p| json_string(p.as_str()));
+        let needed: Vec<_> = self.needed.iter().map(Dependency::to_json).collect();
+        format!(
+            "{{\"name\":{},\"path\":{},\"needed\":[{}]}}",
+            json_string(&self.name),
+            path,
+            needed.join(",")
+        )
+    }
+}
+
+/// Resolves DT_NEEDED entries the way ld.so would for objects inside
+/// `root`: DT_RPATH (of the object and its loaders, unless it has a
+/// DT_RUNPATH; loaders with one of their own contribute none),
+/// DT_RUNPATH, the cache, then the default directories of the target
+/// ([`Target::Glibc`] unless [`Resolver::with_target`] says otherwise).
+/// Objects flagged DF_1_NODEFLIB have their dependencies looked up in
+/// their DT_RPATH and DT_RUNPATH only, skipping the cache and the default
+/// directories. The other DT_FLAGS and DT_FLAGS_1 bits (DF_SYMBOLIC,
+/// DF_BIND_NOW...) change symbol binding, not where libraries are found.
+///
+/// The environment (`LD_LIBRARY_PATH`, `LD_PRELOAD`) is ignored, as are
+/// `$PLATFORM` expansions, and glibc-hwcaps variants are passed over in
+/// favour of the baseline library since the target CPU is unknown.
+#[derive(Clone)]
+pub struct Resolver<'a> {
+    root: Utf8PathBuf,
+    cache: Option<&'a Cache>,
+    target: Target,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(root: impl Into<Utf8PathBuf>, cache: Option<&'a Cache>) -> Self {
+        Self {
+            root: root.into(),
+            cache,
+            target: Target::Glibc,
+        }
+    }
+
+    /// Search the default directories of `target` rather than those of
+    /// [`Target::Glibc`].
+    pub fn with_target(self, target: Target) -> Self {
+        Self { target, ..self }
+    }
+
+    /// Dependency tree of the executable or shared object at `object`,
+    /// a path inside the root.
+    pub fn resolve(&self, object: impl AsRef<Utf8Path>) -> Result<Dependency, Error> {
+        self.resolve_with(object.as_ref(), &mut Parsed::new())
+    }
+
+    /// [`Resolver::resolve`] reusing the objects already parsed.
+    fn resolve_with(&self, object: &Utf8Path, parsed: &mut Parsed) -> Result<Dependency, Error> {
+        let real = chroot_canon(&self.root, object)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let info = match parsed.get(&real) {
+            Some(info) => info.clone(),
+            None => {
+                let info = elf::read_dynamic(real.as_std_path())?;
+                parsed.insert(real, info.clone());
+                info
+            }
+        }
+        .ok_or_else(|| Error::NotDynamic(object.into()))?;
+        let mut loaded = HashMap::new();
+        Ok(Dependency {
+            name: object.to_string(),
+            path: Some(object.to_path_buf()),
+            needed: self.needed(object, &info, &[], &mut loaded, parsed),
+        })
+    }
+
+    /// Graphviz DOT source of the library-to-library edges below
+    /// `objects`, or below every baseline library in the cache when
+    /// `objects` is empty: the linkage graph of the whole image. Nodes
+    /// are paths labelled with their file name; missing libraries are
+    /// dashed and known by their DT_NEEDED name.
+    pub fn dot<P: AsRef<Utf8Path>>(&self, objects: &[P]) -> Result<String, Error> {
+        // Each object is parsed once, however many trees it is in.
+        let mut parsed = Parsed::new();
+        let mut trees = Vec::new();
+        if objects.is_empty() {
+            let entries = self.cache.into_iter().flat_map(Cache::entries);
+            for entry in entries.filter(|e| e.hwcaps.is_none() && e.hwcap == 0) {
+                match self.resolve_with(Utf8Path::new(&entry.path), &mut parsed) {
+                    Ok(tree) => trees.push(tree),
+                    Err(e) => debug!("{}: {}", entry.path, e),
+                }
+            }
+        } else {
+            for object in objects {
+                trees.push(self.resolve_with(object.as_ref(), &mut parsed)?);
+            }
+        }
+
+        let mut nodes = Vec::new();
+        let mut edges = Vec::new();
+        let mut seen_nodes = HashSet::new();
+        let mut seen_edges = HashSet::new();
+        let mut pending: Vec<&Dependency> = trees.iter().collect();
+        while let Some(dep) = pending.pop() {
+            let from = dot_node(dep);
+            if seen_nodes.insert(from.clone()) {
+                nodes.push((from.clone(), dep));
+            }
+            for child in &dep.needed {
+                let edge = (from.clone(), dot_node(child));
+                if seen_edges.insert(edge.clone()) {
+                    edges.push(edge);
+                }
+                pending.push(child);
+            }
+        }
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        edges.sort();
+
+        let mut out = String::from("digraph libraries {\n");
+        for (id, dep) in &nodes {
+            let (label, style) = match &dep.path {
+                Some(path) => (path.file_name().unwrap_or(path.as_str()), ""),
+                None => (dep.name.as_str(), ", style=dashed"),
+            };
+            let _ = writeln!(out, "  {} [label={}{}];", dot_id(id), dot_id(label), style);
+        }
+        for (from, to) in &edges {
+            let _ = writeln!(out, "  {} -> {};", dot_id(from), dot_id(to));
+        }
+        out.push_str("}\n");
+        Ok(out)
+    }
+
+    /// Resolve `info`'s DT_NEEDED entries; `loaders` holds the expanded
+    /// DT_RPATH of every object above it, nearest last, empty for those
+    /// with a DT_RUNPATH.
+    fn needed(
+        &self,
+        origin: &Utf8Path,
+        info: &DynamicInfo,
+        loaders: &[Vec<Utf8PathBuf>],
+        loaded: &mut HashMap<String, Option<Utf8PathBuf>>,
+        parsed: &mut Parsed,
+    ) -> Vec<Dependency> {
+        // ld.so ignores the DT_RPATH of an object with a DT_RUNPATH,
+        // for its own dependencies and for those it loads in turn.
+        let rpath = match info.runpath {
+            Some(_) => Vec::new(),
+            None => self.expand(&info.rpath, origin, info),
+        };
+        let mut chain = loaders.to_vec();
+        chain.push(rpath);
+
+        let mut deps = Vec::new();
+        for name in &info.needed {
+            if let Some(path) = loaded.get(name) {
+                deps.push(Dependency {
+                    name: name.clone(),
+                    path: path.clone(),
+                    needed: Vec::new(),
+                });
+                continue;
+            }
+            let found = self.search(name, origin, info, &chain, parsed);
+            loaded.insert(name.clone(), found.as_ref().map(|(path, _)| path.clone()));
+            let dep = match found {
+                Some((path, lib)) => Dependency {
+                    name: name.clone(),
+                    needed: self.needed(&path, &lib, &chain, loaded, parsed),
+                    path: Some(path),
+                },
+                None => Dependency {
+                    name: name.clone(),
+                    path: None,
+                    needed: Vec::new(),
+                },
+            };
+            deps.push(dep);
+        }
+        deps
+    }
+
+    fn search(
+        &self,
+        name: &str,
+        origin: &Utf8Path,
+        info: &DynamicInfo,
+        chain: &[Vec<Utf8PathBuf>],
+        parsed: &mut Parsed,
+    ) -> Option<(Utf8PathBuf, DynamicInfo)> {
+        if name.contains('/') {
+            return self.usable(Utf8Path::new(name), info, parsed);
+        }
+        let mut in_dirs = |dirs: &[Utf8PathBuf]| {
+            dirs.iter()
+                .find_map(|dir| self.usable(&dir.join(name), info, parsed))
+        };
+
+        let runpath = info
+            .runpath
+            .as_ref()
+            .map(|runpath| self.expand(runpath, origin, info));
+        // A DT_RUNPATH disables every DT_RPATH, the loaders' included.
+        if runpath.is_none() {
+            if let Some(found) = chain.iter().rev().find_map(|dirs| in_dirs(dirs)) {
+                return Some(found);
+            }
+        }
+        if let Some(found) = runpath.as_deref().and_then(in_dirs) {
+            return Some(found);
+        }
+        if info.nodeflib {
+            debug!(
+                "{}: not found, default paths disabled by DF_1_NODEFLIB",
+                name
+            );
+            return None;
+        }
+        if let Some(found) = self.in_cache(name, info, parsed) {
+            return Some(found);
+        }
+        let found = self
+            .target
+            .default_dirs(info.is_64)
+            .iter()
+            .find_map(|dir| self.usable(&dir.join(name), info, parsed));
+        if found.is_none() {
+            debug!("{}: not found", name);
+        }
+        found
+    }
+
+    fn in_cache(
+        &self,
+        name: &str,
+        info: &DynamicInfo,
+        parsed: &mut Parsed,
+    ) -> Option<(Utf8PathBuf, DynamicInfo)> {
+        let mut candidates: Vec<_> = self
+            .cache?
+            .entries()
+            .filter(|e| e.soname == name && dl_cache_check_flags(e.flags, info.flags))
+            .collect();
+        // Stable: baseline entries first, cache order otherwise.
+        candidates.sort_by_key(|e| e.hwcaps.is_some() || e.hwcap != 0);
+        candidates
+            .iter()
+            .find_map(|e| self.usable(Utf8Path::new(&e.path), info, parsed))
+    }
+
+    /// `path` if it exists inside the root and is a dynamic object for
+    /// the same machine and class as `info`.
+    fn usable(
+        &self,
+        path: &Utf8Path,
+        info: &DynamicInfo,
+        parsed: &mut Parsed,
+    ) -> Option<(Utf8PathBuf, DynamicInfo)> {
+        let real = chroot_canon(&self.root, path)?;
+        let lib = parsed
+            .entry(real)
+            .or_insert_with_key(|real| elf::read_dynamic(real.as_std_path()).ok().flatten())
+            .clone()?;
+        (lib.machine == info.machine && lib.is_64 == info.is_64).then(|| (path.to_path_buf(), lib))
+    }
+
+    /// Substitute `$ORIGIN` and `$LIB`; entries naming anything else
+    /// (`$PLATFORM`) are dropped.
+    fn expand(&self, dirs: &[String], origin: &Utf8Path, info: &DynamicInfo) -> Vec<Utf8PathBuf> {
+        let origin_dir = origin.parent().map_or("/", Utf8Path::as_str);
+        let lib = if info.is_64 { "lib64" } else { "lib" };
+        dirs.iter()
+            .map(|dir| {
+                dir.replace("${ORIGIN}", origin_dir)
+                    .replace("$ORIGIN", origin_dir)
+                    .replace("${LIB}", lib)
+                    .replace("$LIB", lib)
+            })
+            .filter(|dir| !dir.contains('$'))
+            .map(Utf8PathBuf::from)
+            .collect()
+    }
+}
+
+/// A found library is known by its path, a missing one by its name.
+fn dot_node(dep: &Dependency) -> String {
+    dep.path
+        .as_ref()
+        .map_or_else(|| dep.name.clone(), |p| p.to_string())
+}
+
+fn dot_id(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::dso_needing;
+    use crate::{Arch, SyntheticEntry};
+    use std::fs;
+
+    fn put(root: &Utf8Path, path: &str, data: &[u8]) {
+        let full = root.join(path.trim_start_matches('/'));
+        fs::create_dir_all(full.parent().unwrap()).unwrap();
+        fs::write(full, data).unwrap();
+    }
+
+    #[test]
+    fn resolves_through_runpath_cache_and_defaults() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        put(
+            root,
+            "/opt/app/bin/app",
+            &dso_needing(
+                &["liba.so.1", "libb.so.1", "libgone.so.1"],
+                Some("$ORIGIN/../lib"),
+            ),
+        );
+        put(
+            root,
+            "/opt/app/lib/liba.so.1",
+            &dso_needing(&["libb.so.1"], None),
+        );
+        put(root, "/srv/libb.so.1", &dso_needing(&["libc.so.6"], None));
+        put(root, "/usr/lib64/libc.so.6", &dso_needing(&[], None));
+
+        let cache = Cache::from_file_entries(&[], None, &[])
+            .unwrap()
+            .add_entries(&[SyntheticEntry::new(
+                "libb.so.1",
+                "/srv/libb.so.1",
+                Arch::X86_64,
+            )])
+            .unwrap();
+        let tree = Resolver::new(root, Some(&cache))
+            .resolve("/opt/app/bin/app")
+            .unwrap();
+
+        let liba = &tree.needed[0];
+        assert_eq!(
+            liba.path.as_deref(),
+            Some("/opt/app/bin/../lib/liba.so.1".into())
+        );
+        let libb = &liba.needed[0];
+        assert_eq!(libb.path.as_deref(), Some("/srv/libb.so.1".into()));
+        assert_eq!(
+            libb.needed[0].path.as_deref(),
+            Some("/usr/lib64/libc.so.6".into())
+        );
+        // Listed again without its subtree.
+        assert_eq!(tree.needed[1].path, libb.path);
+        assert!(tree.needed[1].needed.is_empty());
+        assert_eq!(tree.needed[2].path, None);
+        assert!(tree.has_missing());
+        assert!(!liba.has_missing());
+        assert_eq!(
+            tree.needed[2].to_json(),
+            r#"{"name":"libgone.so.1","path":null,"needed":[]}"#
+        );
+        let odd = Dependency {
+            name: "lib\"x\"\n.so".into(),
+            path: Some("/usr/lib/lib\tx.so".into()),
+            needed: vec![],
+        };
+        assert_eq!(
+            odd.to_json(),
+            r#"{"name":"lib\"x\"\n.so","path":"/usr/lib/lib\tx.so","needed":[]}"#
+        );
+
+        // A Debian loader does not look in /usr/lib64.
+        let tree = Resolver::new(root, Some(&cache))
+            .with_target(Target::Multiarch("x86_64-linux-gnu".into()))
+            .resolve("/opt/app/bin/app")
+            .unwrap();
+        assert_eq!(tree.needed[0].needed[0].needed[0].path, None);
+    }
+
+    #[test]
+    fn exports_dot_graph() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        put(
+            root,
+            "/usr/lib/liba.so.1",
+            &dso_needing(&["libc.so.6"], None),
+        );
+        put(
+            root,
+            "/usr/lib/libb.so.1",
+            &dso_needing(&["liba.so.1", "libc.so.6", "libgone.so.1"], None),
+        );
+        put(root, "/usr/lib/libc.so.6", &dso_needing(&[], None));
+        put(root, "/usr/lib/notes.txt.so.1", b"not elf");
+        let entries: Vec<SyntheticEntry> =
+            ["liba.so.1", "libb.so.1", "libc.so.6", "notes.txt.so.1"]
+                .iter()
+                .map(|&name| SyntheticEntry::new(name, format!("/usr/lib/{}", name), Arch::X86_64))
+                .collect();
+        let cache = Cache::from_file_entries(&[], None, &[])
+            .unwrap()
+            .add_entries(&entries)
+            .unwrap();
+        let resolver = Resolver::new(root, Some(&cache));
+
+        let whole = resolver.dot::<&str>(&[]).unwrap();
+        assert_eq!(
+            whole,
+            "digraph libraries {
+  \"/usr/lib/liba.so.1\" [label=\"liba.so.1\"];
+  \"/usr/lib/libb.so.1\" [label=\"libb.so.1\"];
+  \"/usr/lib/libc.so.6\" [label=\"libc.so.6\"];
+  \"libgone.so.1\" [label=\"libgone.so.1\", style=dashed];
+  \"/usr/lib/liba.so.1\" -> \"/usr/lib/libc.so.6\";
+  \"/usr/lib/libb.so.1\" -> \"/usr/lib/liba.so.1\";
+  \"/usr/lib/libb.so.1\" -> \"/usr/lib/libc.so.6\";
+  \"/usr/lib/libb.so.1\" -> \"libgone.so.1\";
+}
+"
+        );
+        let one = resolver.dot(&["/usr/lib/liba.so.1"]).unwrap();
+        assert_eq!(one.lines().filter(|l| l.contains("->")).count(), 1);
+    }
+
+    #[test]
+    fn runpath_is_not_inherited() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        put(root, "/bin/app", &dso_needing(&["liba.so.1"], Some("/a")));
+        put(
+            root,
+            "/a/liba.so.1",
+            &dso_needing(&["libb.so.1"], Some("/nowhere")),
+        );
+        put(root, "/a/libb.so.1", &dso_needing(&[], None));
+
+        let tree = Resolver::new(root, None).resolve("/bin/app").unwrap();
+        assert_eq!(tree.needed[0].needed[0].path, None);
+    }
+
+    #[test]
+    fn rpath_of_loaders_with_runpath_is_ignored() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        put(
+            root,
+            "/bin/app",
+            &elf::dso_rpath(&["liba.so.1"], "/rp", Some("/a")),
+        );
+        put(
+            root,
+            "/bin/old",
+            &elf::dso_rpath(&["liba.so.1"], "/rp:/a", None),
+        );
+        put(root, "/a/liba.so.1", &dso_needing(&["libb.so.1"], None));
+        put(root, "/rp/libb.so.1", &dso_needing(&[], None));
+        let resolver = Resolver::new(root, None);
+
+        let tree = resolver.resolve("/bin/app").unwrap();
+        assert_eq!(tree.needed[0].path.as_deref(), Some("/a/liba.so.1".into()));
+        assert_eq!(tree.needed[0].needed[0].path, None);
+        // Without a DT_RUNPATH, the loader's DT_RPATH is searched.
+        let tree = resolver.resolve("/bin/old").unwrap();
+        assert_eq!(
+            tree.needed[0].needed[0].path.as_deref(),
+            Some("/rp/libb.so.1".into())
+        );
+    }
+
+    #[test]
+    fn nodeflib_skips_cache_and_defaults() {
+        use goblin::elf::dynamic::{DF_1_NODEFLIB, DT_FLAGS_1};
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        let nodeflib = [(DT_FLAGS_1, DF_1_NODEFLIB)];
+        put(
+            root,
+            "/bin/app",
+            &elf::dso_tagged(None, &["libb.so.1", "liba.so.1"], Some("/a"), &nodeflib),
+        );
+        put(root, "/a/liba.so.1", &dso_needing(&["libc.so.6"], None));
+        put(root, "/usr/lib/libb.so.1", &dso_needing(&[], None));
+        put(root, "/usr/lib64/libc.so.6", &dso_needing(&[], None));
+        let cache = Cache::from_file_entries(&[], None, &[])
+            .unwrap()
+            .add_entries(&[SyntheticEntry::new(
+                "libb.so.1",
+                "/usr/lib/libb.so.1",
+                Arch::X86_64,
+            )])
+            .unwrap();
+
+        let tree = Resolver::new(root, Some(&cache))
+            .resolve("/bin/app")
+            .unwrap();
+        assert_eq!(tree.needed[0].path, None);
+        let liba = &tree.needed[1];
+        assert_eq!(liba.path.as_deref(), Some("/a/liba.so.1".into()));
+        // The flag is the loader's: liba's own dependencies are not affected.
+        assert_eq!(
+            liba.needed[0].path.as_deref(),
+            Some("/usr/lib64/libc.so.6".into())
+        );
+    }
+
+    #[test]
+    fn static_or_missing_objects_error() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8Path::from_path(tmp.path()).unwrap();
+        put(root, "/bin/script", b"#!/bin/sh\n");
+        let resolver = Resolver::new(root, None);
+        assert!(matches!(
+            resolver.resolve("/bin/script"),
+            Err(Error::NotDynamic(_))
+        ));
+        assert!(matches!(resolver.resolve("/bin/none"), Err(Error::Io(_))));
+    }
+}
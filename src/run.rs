@@ -0,0 +1,388 @@
+//! The whole `ldconfig` run as a function, for installers and init
+//! systems that would otherwise spawn the binary.
+//!
+//! ```no_run
+//! use ldconfig::{run, RunOptions};
+//!
+//! let summary = run(&RunOptions::builder().root("/sysroot").build())?;
+//! for warning in &summary.cache.report().warnings {
+//!     eprintln!("{}", warning);
+//! }
+//! # Ok::<(), ldconfig::Error>(())
+//! ```
+
+use crate::{
+    chroot_canon, error::Error, Backup, BuildPlan, Cache, CacheDiff, CacheFormat, Change, ElfMemo,
+    SearchPaths, Target, Warning,
+};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::path::PathBuf;
+use tracing::debug;
+
+/// What [`run`] does, as the `ldconfig` command line says it; every
+/// option defaults to what the command does without the flag.
+#[derive(Debug, Clone, bon::Builder)]
+pub struct RunOptions {
+    /// Root every path is resolved inside, like `-r` in emulation mode;
+    /// [`enter_root`](crate::enter_root) first to chroot instead
+    #[builder(into, default = Utf8PathBuf::from("/"))]
+    pub root: Utf8PathBuf,
+    /// Configuration file, `/etc/ld.so.conf` by default (`-f`)
+    #[builder(into)]
+    pub config_file: Option<Utf8PathBuf>,
+    /// Cache file, `/etc/ld.so.cache` by default (`-C`); with `-` the
+    /// cache is built but left to the caller to write
+    #[builder(into)]
+    pub cache_file: Option<Utf8PathBuf>,
+    /// Directories to scan on top of the configured ones
+    #[builder(default)]
+    pub dirs: Vec<Utf8PathBuf>,
+    /// Only scan `dirs`, without building a cache (`-n`)
+    #[builder(default)]
+    pub only_dirs: bool,
+    /// Don't write the cache, only update symlinks (`-N`)
+    #[builder(default)]
+    pub no_cache: bool,
+    /// Don't update symlinks (`-X`)
+    #[builder(default)]
+    pub no_links: bool,
+    /// On-disk layout (`--format`)
+    #[builder(default = CacheFormat::New)]
+    pub format: CacheFormat,
+    /// Oldest glibc whose ld.so must read the cache (`--target-glibc`)
+    #[builder(into, default = String::from("auto"))]
+    pub target_glibc: String,
+    /// Fail without writing anything if anything was warned about
+    #[builder(default)]
+    pub strict: bool,
+    /// Fail if a configured or given directory can't be scanned
+    #[builder(default)]
+    pub require_dirs: bool,
+    /// Skip, with a warning, larger files named like libraries
+    pub max_file_size: Option<u64>,
+    /// Keep the cache being replaced (`--backup`)
+    pub backup: Option<Backup>,
+}
+
+/// What [`run`] did.
+#[derive(Debug)]
+pub struct RunSummary {
+    /// The cache built; its report holds the warnings and the symlink
+    /// changes made.
+    pub cache: Cache,
+    /// Where the cache was written, inside the root; None when it was
+    /// not written.
+    pub cache_file: Option<Utf8PathBuf>,
+    /// Where the cache it replaced was saved, with a backup.
+    pub backup: Option<PathBuf>,
+}
+
+impl RunSummary {
+    /// The warnings logged, then the filesystem changes made, for
+    /// [`write_audit`](crate::write_audit).
+    pub fn changes(&self) -> Vec<Change<'_>> {
+        let report = self.cache.report();
+        let mut changes: Vec<Change> = report.warnings.iter().map(Change::Warned).collect();
+        changes.extend(report.links.iter().map(Change::Link));
+        if let Some(backup) = &self.backup {
+            changes.push(Change::BackupWritten { path: backup });
+        }
+        if let Some(path) = &self.cache_file {
+            changes.push(Change::CacheWritten {
+                path: path.as_std_path(),
+                bytes: self.cache.size(),
+            });
+        }
+        changes
+    }
+}
+
+/// Load the configuration, scan, update symlinks and write the cache, as
+/// the `ldconfig` command does without `-p`. When a cache file is written,
+/// it and the symlinks are changed together once the build is done, and
+/// neither is if the build fails.
+pub fn run(options: &RunOptions) -> Result<RunSummary, Error> {
+    run_with_memo(options, None)
+}
+
+/// What [`run_batch`] did in one root.
+#[derive(Debug)]
+pub struct BatchRun {
+    pub root: Utf8PathBuf,
+    pub result: Result<RunSummary, Error>,
+}
+
+/// [`run`] in each of `roots` in turn, with `options` otherwise, as a
+/// build farm rebuilding the caches of many chroots would. Files found
+/// unchanged in several roots, hardlinked or bind-mounted, are parsed
+/// once. A root failing does not stop the others.
+pub fn run_batch(options: &RunOptions, roots: &[Utf8PathBuf]) -> Vec<BatchRun> {
+    let memo = ElfMemo::new();
+    let runs = roots
+        .iter()
+        .map(|root| {
+            let options = RunOptions {
+                root: root.clone(),
+                ..options.clone()
+            };
+            BatchRun {
+                root: root.clone(),
+                result: run_with_memo(&options, Some(&memo)),
+            }
+        })
+        .collect();
+    debug!(
+        "Inspected {} files, {} reused across roots",
+        memo.len(),
+        memo.hits()
+    );
+    runs
+}
+
+/// [`run`] in two steps, for operators reviewing the changes first:
+/// everything is built without touching the disk, and the returned
+/// [`PlannedRun`] tells what would change before it is applied.
+pub fn plan_run(options: &RunOptions) -> Result<PlannedRun, Error> {
+    let built = build(options, None, true)?;
+    let plan = match &built.cache_file {
+        Some(real) => {
+            let existing = Cache::from_file(real).ok();
+            built.cache.plan(existing.as_ref())
+        }
+        None => BuildPlan {
+            diff: CacheDiff::default(),
+            ..built.cache.plan(None)
+        },
+    };
+    Ok(PlannedRun { plan, built })
+}
+
+/// A run built by [`plan_run`] but not applied yet: nothing on disk has
+/// changed.
+#[derive(Debug)]
+pub struct PlannedRun {
+    plan: BuildPlan,
+    built: Built,
+}
+
+impl PlannedRun {
+    /// The symlink changes to make and the cache entries against the
+    /// cache file there now; the diff is empty when no cache file is
+    /// written.
+    pub fn plan(&self) -> &BuildPlan {
+        &self.plan
+    }
+
+    /// Make the changes, all or nothing, as [`run`] would have; links
+    /// found changed since the plan fail it untouched.
+    pub fn apply(self) -> Result<RunSummary, Error> {
+        self.built.apply()
+    }
+
+    /// The cache built; its report holds the planned symlink changes.
+    pub(crate) fn cache(&self) -> &Cache {
+        &self.built.cache
+    }
+
+    /// Where the cache would be written, inside the root.
+    pub(crate) fn cache_file(&self) -> Option<&Utf8Path> {
+        self.built.cache_file.as_deref()
+    }
+
+    /// What could not be read of the configuration.
+    pub(crate) fn config_warnings(&self) -> &[Warning] {
+        &self.built.config_warnings
+    }
+}
+
+/// A built cache and where it goes.
+#[derive(Debug)]
+struct Built {
+    cache: Cache,
+    cache_file: Option<Utf8PathBuf>,
+    root: Utf8PathBuf,
+    backup: Option<Backup>,
+    /// Whether the symlink changes are only planned.
+    dry_run: bool,
+    config_warnings: Vec<Warning>,
+}
+
+impl Built {
+    fn apply(self) -> Result<RunSummary, Error> {
+        let mut backup = None;
+        match &self.cache_file {
+            Some(real) => {
+                backup = self.cache.apply(real, &self.root, self.backup)?;
+                if let Some(saved) = &backup {
+                    debug!("Saved the previous cache as {}", saved.display());
+                }
+                debug!("Wrote {} bytes to {}", self.cache.size(), real);
+            }
+            None if self.dry_run => self.cache.apply_links(&self.root)?,
+            None => {}
+        }
+        Ok(RunSummary {
+            cache: self.cache,
+            cache_file: self.cache_file,
+            backup,
+        })
+    }
+}
+
+fn run_with_memo(options: &RunOptions, memo: Option<&ElfMemo>) -> Result<RunSummary, Error> {
+    build(options, memo, false)?.apply()
+}
+
+/// Load the configuration and scan; symlinks are left alone when a cache
+/// file will be written or `plan` is set, to be changed when applied.
+fn build(options: &RunOptions, memo: Option<&ElfMemo>, plan: bool) -> Result<Built, Error> {
+    let root = options.root.as_path();
+    let build_cache = !(options.no_cache || options.only_dirs);
+    if build_cache {
+        if let Some(dir) = options.dirs.iter().find(|d| !d.as_str().starts_with('/')) {
+            return Err(Error::RelativeDir(dir.clone()));
+        }
+    }
+
+    let mut search_paths = SearchPaths::new(options.dirs.clone());
+    if !options.only_dirs {
+        let config_path = options
+            .config_file
+            .clone()
+            .unwrap_or_else(|| Target::Glibc.config_path(None));
+        let prefix = (root != "/").then_some(root);
+        search_paths.append(SearchPaths::from_file(config_path, prefix)?);
+    }
+    debug!("Directories to scan: {:?}", &*search_paths);
+
+    let cache_path = options
+        .cache_file
+        .clone()
+        .or_else(|| Target::Glibc.cache_path(None));
+    let cache_file = match cache_path {
+        Some(cache_path) if build_cache && cache_path != "-" => {
+            let real = cache_file_under_root(root, &cache_path)
+                .ok_or(Error::CacheDirectory(cache_path))?;
+            Some(real)
+        }
+        _ => None,
+    };
+
+    let dry_run = plan || cache_file.is_some();
+    let cache = Cache::builder()
+        .prefix(root)
+        .update_symlinks(!options.no_links)
+        .dry_run(dry_run)
+        .strict(options.strict)
+        .require_dirs(options.require_dirs)
+        .maybe_max_file_size(options.max_file_size)
+        .format(options.format)
+        .target_glibc(&options.target_glibc)
+        .maybe_elf_memo(memo)
+        .build(&search_paths)?;
+    Ok(Built {
+        cache,
+        cache_file,
+        root: root.to_owned(),
+        backup: options.backup,
+        dry_run,
+        config_warnings: search_paths.warnings().to_vec(),
+    })
+}
+
+/// Resolve the cache file's directory inside the root and append the
+/// file name, like glibc's main(); symlinks cannot escape the root and
+/// the temp-file rename stays confined.
+fn cache_file_under_root(root: &Utf8Path, path: &Utf8Path) -> Option<Utf8PathBuf> {
+    let parent = path.parent().unwrap_or(Utf8Path::new("/"));
+    let dir = chroot_canon(root, parent)?;
+    Some(dir.join(path.file_name()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn runs_like_the_command() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::create_dir_all(root.join("opt/lib")).unwrap();
+        fs::write(root.join("etc/ld.so.conf"), "/opt/lib\n").unwrap();
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        fs::write(root.join("opt/lib/libz.so.1.3"), dso).unwrap();
+
+        let relative = RunOptions::builder()
+            .root(&root)
+            .dirs(vec!["lib".into()])
+            .build();
+        assert!(matches!(run(&relative), Err(Error::RelativeDir(_))));
+
+        let summary = run(&RunOptions::builder().root(&root).build()).unwrap();
+        assert_eq!(summary.cache_file, Some(root.join("etc/ld.so.cache")));
+        let written = Cache::from_file(root.join("etc/ld.so.cache")).unwrap();
+        assert_eq!(written.as_bytes(), summary.cache.as_bytes());
+        assert_eq!(written.entries().next().unwrap().path, "/opt/lib/libz.so.1");
+        assert!(root.join("opt/lib/libz.so.1").is_symlink());
+        assert_eq!(summary.changes().len(), 2);
+    }
+
+    #[test]
+    fn planned_runs_change_nothing_until_applied() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::create_dir_all(root.join("opt/lib")).unwrap();
+        fs::write(root.join("etc/ld.so.conf"), "/opt/lib\n").unwrap();
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        fs::write(root.join("opt/lib/libz.so.1.3"), dso).unwrap();
+
+        let planned = plan_run(&RunOptions::builder().root(&root).build()).unwrap();
+        assert_eq!(planned.plan().links.len(), 1);
+        assert_eq!(planned.plan().diff.added.len(), 1);
+        assert!(!root.join("etc/ld.so.cache").exists());
+        assert!(!root.join("opt/lib/libz.so.1").is_symlink());
+
+        let summary = planned.apply().unwrap();
+        assert_eq!(summary.cache_file, Some(root.join("etc/ld.so.cache")));
+        assert!(root.join("etc/ld.so.cache").exists());
+        assert!(root.join("opt/lib/libz.so.1").is_symlink());
+
+        fs::remove_file(root.join("opt/lib/libz.so.1")).unwrap();
+        let links_only = RunOptions::builder().root(&root).no_cache(true).build();
+        let planned = plan_run(&links_only).unwrap();
+        assert!(planned.plan().diff.is_empty());
+        assert!(!root.join("opt/lib/libz.so.1").is_symlink());
+        planned.apply().unwrap();
+        assert!(root.join("opt/lib/libz.so.1").is_symlink());
+    }
+
+    #[test]
+    fn batches_share_parsed_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        let roots: Vec<Utf8PathBuf> = ["a", "b", "missing"].map(|r| base.join(r)).into();
+        for root in &roots[..2] {
+            fs::create_dir_all(root.join("usr/lib")).unwrap();
+            fs::create_dir_all(root.join("etc")).unwrap();
+        }
+        fs::write(roots[0].join("usr/lib/libz.so.1.3"), dso).unwrap();
+        fs::hard_link(
+            roots[0].join("usr/lib/libz.so.1.3"),
+            roots[1].join("usr/lib/libz.so.1.3"),
+        )
+        .unwrap();
+
+        let runs = run_batch(&RunOptions::builder().no_links(true).build(), &roots);
+        assert_eq!(runs.len(), 3);
+        for run in &runs[..2] {
+            let summary = run.result.as_ref().unwrap();
+            assert_eq!(summary.cache.entries().count(), 1);
+        }
+        assert_eq!(runs[2].root, roots[2]);
+        assert!(runs[2].result.is_err());
+    }
+}
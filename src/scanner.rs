@@ -1,13 +1,20 @@
 //! Directory scanning, mirroring glibc's search_dir and directory setup.
 
+use crate::cache_format::{Arch, FileStat};
 use crate::chroot::chroot_canon;
-use crate::elf;
+use crate::elf::{self, ElfMemo};
+use crate::mounts::{mount_of, read_mountinfo, Mount};
+use crate::report::{BuildReport, LinkAction, WarningKind};
+use crate::symlinks;
 use camino::{Utf8Path, Utf8PathBuf};
+use goblin::elf::header::ET_REL;
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
-use std::fs;
-use std::os::unix::fs::MetadataExt;
-use tracing::{debug, warn};
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
+use tracing::field::Empty;
+use tracing::{debug, instrument, Span};
 
 /// A directory to scan: the configured path (used as cache entry text)
 /// plus its on-disk location under the -r prefix.
@@ -19,6 +26,115 @@ pub(crate) struct ScanDir {
     pub hwcaps: Option<String>,
 }
 
+/// Backup and in-flight upgrade files from package managers and editors,
+/// skipped by default on top of glibc's own temporary-file rules.
+pub const DEFAULT_SKIP_SUFFIXES: &[&str] = &[
+    ".dpkg-old",
+    ".dpkg-dist",
+    ".dpkg-bak",
+    ".rpmnew",
+    ".rpmsave",
+    ".rpmorig",
+    ".ucf-dist",
+    ".ucf-old",
+    ".pacnew",
+    ".pacsave",
+    ".swp",
+    "~",
+];
+
+/// Pseudo filesystems never scanned by default: a stray `/proc` in the
+/// configuration would otherwise walk every process.
+pub const DEFAULT_DENIED_DIRS: &[&str] = &["/proc", "/sys", "/dev", "/run"];
+
+/// Decides from a scanned directory and a file name whether the file is
+/// inspected as a possible library; see [`is_dso_name`] for glibc's rule.
+pub type DsoNameFilter<'a> = &'a dyn Fn(&Utf8Path, &str) -> bool;
+
+/// Decides from a symlink's name and the soname of the library it points
+/// to whether the link is cached under its own name, when the two differ;
+/// see [`is_dev_symlink`] for glibc's rule.
+pub type SymlinkPolicy<'a> = &'a dyn Fn(&str, &str) -> bool;
+
+/// A library found by a scan, as handed to a
+/// [`LibraryFilter`]: everything the cache entry would hold, plus where
+/// the file is on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfLibrary {
+    /// The cache entry path: the soname link for regular directories, the
+    /// file itself in glibc-hwcaps subdirectories.
+    pub path: String,
+    /// The file on disk, under the -r root.
+    pub file: Utf8PathBuf,
+    /// DT_SONAME, or the file name without one.
+    pub soname: String,
+    pub arch: Arch,
+    /// x86 ISA level, 0 if unmarked.
+    pub isa_level: u32,
+    /// glibc-hwcaps subdirectory name, if found in one.
+    pub hwcaps: Option<String>,
+    /// File size in bytes.
+    pub size: u64,
+}
+
+/// Decides whether a scanned library goes into the cache.
+pub type LibraryFilter<'a> = &'a dyn Fn(&ElfLibrary) -> bool;
+
+/// Options applying to every directory scan.
+#[derive(Clone)]
+pub(crate) struct ScanOptions<'a> {
+    /// The -r root.
+    pub prefix: Utf8PathBuf,
+    /// Remove dangling *.so.* symlinks, like glibc when updating links.
+    pub remove_stale_links: bool,
+    /// Record stale link removals in the report instead of making them.
+    pub dry_run: bool,
+    /// Skip libraries whose NT_GNU_ABI_TAG (osversion encoding) is newer.
+    pub max_osversion: Option<u32>,
+    /// File name suffixes never considered for caching.
+    pub skip_suffixes: Vec<String>,
+    /// Record a SHA-256 of every library inspected.
+    pub hash_contents: bool,
+    /// Replaces [`is_dso_name`] as the file name filter.
+    pub dso_names: Option<DsoNameFilter<'a>>,
+    /// Inspect every file whatever its name; programs are still skipped.
+    pub all_shared_objects: bool,
+    /// Fill in [`DirLib::aliases`].
+    pub collect_aliases: bool,
+    /// Warn about static archives and relocatable objects named like
+    /// libraries.
+    pub misplaced_objects: bool,
+    /// Skip files larger than this many bytes, with a warning.
+    pub max_file_size: Option<u64>,
+    /// Replaces [`is_dev_symlink`] for links not named as their soname.
+    pub symlink_policy: Option<SymlinkPolicy<'a>>,
+    /// Files inspected by earlier scans, reused when unchanged.
+    pub elf_memo: Option<&'a ElfMemo>,
+}
+
+impl Default for ScanOptions<'_> {
+    fn default() -> Self {
+        Self {
+            prefix: Utf8PathBuf::from("/"),
+            remove_stale_links: false,
+            dry_run: false,
+            max_osversion: None,
+            skip_suffixes: DEFAULT_SKIP_SUFFIXES
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            hash_contents: false,
+            dso_names: None,
+            all_shared_objects: false,
+            collect_aliases: false,
+            misplaced_objects: false,
+            max_file_size: None,
+            symlink_policy: None,
+            elf_memo: None,
+        }
+    }
+}
+
 /// One library chosen for a directory: `name` is the file name on disk,
 /// `soname` the cache key.
 #[derive(Debug, Clone)]
@@ -28,19 +144,37 @@ pub(crate) struct DirLib {
     pub flags: u32,
     pub isa_level: u32,
     pub is_link: bool,
+    pub sha256: Option<[u8; 32]>,
+    pub stat: Option<FileStat>,
+    /// (dev, ino) of the library file, when collecting aliases.
+    pub inode: Option<(u64, u64)>,
+    /// Every name in the directory for that same file, sorted; only
+    /// filled in when collecting aliases.
+    pub aliases: Vec<String>,
 }
 
-/// Matches glibc's _dl_is_dso() from elf/dl-is_dso.h.
-pub(crate) fn is_dso(name: &str) -> bool {
+/// Whether glibc's ldconfig considers a file name a library: `lib*.so*`,
+/// `ld-*.so*`, `ld.so.*` or `ld64.so.*` (_dl_is_dso, elf/dl-is_dso.h).
+pub fn is_dso_name(name: &str) -> bool {
     ((name.starts_with("lib") || name.starts_with("ld-")) && name.contains(".so"))
         || name.starts_with("ld.so.")
         || name.starts_with("ld64.so.")
 }
 
+/// Whether glibc keeps a symlink named `name` as a link to a library
+/// whose soname differs: only the development link form, `libfoo.so` to
+/// `libfoo.so.1`, is; anything else is cached under the soname, as if it
+/// were the file itself (search_dir).
+pub fn is_dev_symlink(name: &str, soname: &str) -> bool {
+    name.ends_with(".so") && soname.starts_with(name)
+}
+
 /// Temporary files from prelink, RPM, and dpkg; glibc's
-/// skip_dso_based_on_name.
+/// skip_dso_based_on_name. Also Emacs lock links (".#name"), which are
+/// symlinks and would otherwise pass in glibc-hwcaps directories.
 fn is_temp_dso(name: &str) -> bool {
     let b = name.as_bytes();
+    name.starts_with(".#") ||
     // ".#prelink#" suffix or ".#prelink#.XXXXXX" (6-char random suffix).
     b.ends_with(b".#prelink#")
         || (b.len() >= 17 && &b[b.len() - 17..b.len() - 6] == b".#prelink#.")
@@ -49,6 +183,34 @@ fn is_temp_dso(name: &str) -> bool {
         || name.ends_with(".dpkg-tmp")
 }
 
+/// Why a regular file named like a library never gets loaded, when it
+/// is a static archive or a relocatable object: usually a sign of broken
+/// packaging.
+fn misplaced_object(path: &Utf8Path) -> Option<&'static str> {
+    let mut head = [0u8; 18];
+    let mut file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .ok()?;
+    file.read_exact(&mut head).ok()?;
+    if head.starts_with(b"!<arch>\n") || head.starts_with(b"!<thin>\n") {
+        return Some("a static archive");
+    }
+    let e_type = match head[5] {
+        1 => u16::from_le_bytes([head[16], head[17]]),
+        2 => u16::from_be_bytes([head[16], head[17]]),
+        _ => return None,
+    };
+    (head.starts_with(b"\x7fELF") && e_type == ET_REL).then_some("a relocatable object")
+}
+
+/// Fifos, sockets and devices: opening some of them blocks or has side
+/// effects, so they are never inspected.
+fn is_special(ft: fs::FileType) -> bool {
+    ft.is_fifo() || ft.is_socket() || ft.is_block_device() || ft.is_char_device()
+}
+
 fn resolve(prefix: &Utf8Path, path: &Utf8Path) -> Option<Utf8PathBuf> {
     if prefix == "/" {
         Some(path.to_path_buf())
@@ -57,12 +219,44 @@ fn resolve(prefix: &Utf8Path, path: &Utf8Path) -> Option<Utf8PathBuf> {
     }
 }
 
+/// Whether the configured directory `dir` can be scanned: it exists
+/// inside `prefix`, is a directory and can be listed.
+pub(crate) fn check_dir(prefix: &Utf8Path, dir: &Utf8Path) -> io::Result<()> {
+    let real = resolve(prefix, dir).ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+    if !fs::metadata(&real)?.is_dir() {
+        return Err(ErrorKind::NotADirectory.into());
+    }
+    fs::read_dir(&real)?;
+    Ok(())
+}
+
+/// Which path of a scanned directory its cache entries record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathPolicy {
+    /// The path as configured, through any symlinks, like glibc.
+    #[default]
+    Logical,
+    /// The path with every symlink resolved, inside the root.
+    Physical,
+}
+
 /// Build the scan list: strip trailing slashes, drop nonexistent
 /// directories, deduplicate by (dev, ino) keeping the first configured
 /// path text, and queue glibc-hwcaps subdirectories after their parent.
-pub(crate) fn collect_dirs(dirs: &[Utf8PathBuf], prefix: &Utf8Path) -> Vec<ScanDir> {
+/// Directories resolving to or below one of `denied` (inside the root)
+/// are skipped with a warning. Under [`PathPolicy::Physical`] the
+/// directories are recorded by their resolved path.
+#[instrument(level = "debug", skip_all, fields(dirs = dirs.len()))]
+pub(crate) fn collect_dirs(
+    dirs: &[Utf8PathBuf],
+    prefix: &Utf8Path,
+    denied: &[&str],
+    policy: PathPolicy,
+    report: &mut BuildReport,
+) -> Vec<ScanDir> {
     let mut seen: HashSet<(u64, u64)> = HashSet::new();
     let mut out = Vec::new();
+    let mounts = read_mountinfo();
 
     for dir in dirs {
         let trimmed = dir.as_str().trim_end_matches('/');
@@ -74,60 +268,143 @@ pub(crate) fn collect_dirs(dirs: &[Utf8PathBuf], prefix: &Utf8Path) -> Vec<ScanD
             debug!("Can't stat {}", logical);
             continue;
         };
-        let Ok(md) = fs::metadata(&real) else {
-            debug!("Can't stat {}", logical);
-            continue;
+        let md = match fs::metadata(&real) {
+            Ok(md) => md,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                debug!("Can't stat {}", logical);
+                continue;
+            }
+            Err(e) => {
+                report.warn(
+                    WarningKind::DirectoryUnreadable,
+                    format!("Can't stat {}: {}", logical, e),
+                );
+                continue;
+            }
         };
         if !md.is_dir() {
             continue;
         }
+        // Judged by where the directory really is, so a symlink into /run
+        // is caught and a path through one leading elsewhere is not.
+        let inside = if prefix == "/" {
+            fs::canonicalize(&real)
+                .ok()
+                .and_then(|p| Utf8PathBuf::try_from(p).ok())
+        } else {
+            real.strip_prefix(prefix)
+                .ok()
+                .map(|rest| Utf8Path::new("/").join(rest))
+        };
+        if let Some(deny) = inside.as_ref().and_then(|p| {
+            denied
+                .iter()
+                .find(|d| p.starts_with(d.trim_end_matches('/')))
+        }) {
+            report.warn(
+                WarningKind::PseudoFilesystem,
+                format!("Skipping {}: {} is a pseudo filesystem", logical, deny),
+            );
+            continue;
+        }
         if !seen.insert((md.dev(), md.ino())) {
             debug!("Path `{}' given more than once", logical);
             continue;
         }
+        // A symlink from one mount into an overlay layer or a bind mount
+        // may lead somewhere else, or nowhere, outside the build's mount
+        // namespace.
+        if let Some(physical) = inside.as_ref().filter(|p| **p != logical) {
+            let host = |p: &Utf8Path| match p.strip_prefix("/") {
+                Ok(rest) if prefix != "/" => prefix.join(rest),
+                _ => p.to_path_buf(),
+            };
+            let target = mount_of(&mounts, &host(physical));
+            if target != mount_of(&mounts, &host(&logical)) {
+                if let Some(kind) = target.and_then(Mount::layering) {
+                    let recorded = match policy {
+                        PathPolicy::Logical => &logical,
+                        PathPolicy::Physical => physical,
+                    };
+                    report.warn(
+                        WarningKind::MountMismatch,
+                        format!(
+                            "{} resolves to {} on {} mount; the cache records {}",
+                            logical, physical, kind, recorded
+                        ),
+                    );
+                }
+            }
+        }
+        let logical = match (policy, inside) {
+            (PathPolicy::Physical, Some(physical)) => physical,
+            _ => logical,
+        };
         out.push(ScanDir {
             path: logical.clone(),
-            real: real.clone(),
+            real,
             hwcaps: None,
         });
+        out.extend(glibc_hwcaps_dirs(prefix, &logical, &mut seen));
+    }
+    out
+}
 
-        // glibc-hwcaps subdirectories (add_glibc_hwcaps_subdirectories):
-        // every directory under <dir>/glibc-hwcaps, no name whitelist.
-        let hw = real.join("glibc-hwcaps");
-        let Ok(rd) = fs::read_dir(&hw) else { continue };
-        let mut subs: Vec<String> = Vec::new();
-        for entry in rd.flatten() {
-            let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
-                continue;
-            };
-            // Names with ':' cannot be looked up by the dynamic loader.
-            if name.starts_with('.') || name.contains(':') {
-                continue;
-            }
-            let Ok(md) = fs::metadata(entry.path()) else {
-                continue;
-            };
-            if !md.is_dir() || !seen.insert((md.dev(), md.ino())) {
-                continue;
-            }
-            subs.push(name);
+/// The glibc-hwcaps subdirectories of the library directory `dir`
+/// (add_glibc_hwcaps_subdirectories): every directory under
+/// `<dir>/glibc-hwcaps`, no name whitelist, sorted by name. Resolved
+/// inside the prefix like the directory itself: an absolute glibc-hwcaps
+/// link in a staging root must not reach the host's directories.
+/// Directories whose (dev, ino) is already in `seen` are skipped.
+pub(crate) fn glibc_hwcaps_dirs(
+    prefix: &Utf8Path,
+    dir: &Utf8Path,
+    seen: &mut HashSet<(u64, u64)>,
+) -> Vec<ScanDir> {
+    let Some(hw) = resolve(prefix, &dir.join("glibc-hwcaps")) else {
+        return Vec::new();
+    };
+    let Ok(rd) = fs::read_dir(&hw) else {
+        return Vec::new();
+    };
+    let mut subs: Vec<ScanDir> = Vec::new();
+    for entry in rd.flatten() {
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        // Names with ':' cannot be looked up by the dynamic loader.
+        if name.starts_with('.') || name.contains(':') {
+            continue;
         }
-        subs.sort();
-        for name in subs {
-            out.push(ScanDir {
-                path: logical.join("glibc-hwcaps").join(&name),
-                real: hw.join(&name),
-                hwcaps: Some(name),
-            });
+        let path = dir.join("glibc-hwcaps").join(&name);
+        let Some(real) = resolve(prefix, &path) else {
+            continue;
+        };
+        let Ok(md) = fs::metadata(&real) else {
+            continue;
+        };
+        if !md.is_dir() || !seen.insert((md.dev(), md.ino())) {
+            continue;
         }
+        subs.push(ScanDir {
+            path,
+            real,
+            hwcaps: Some(name),
+        });
     }
-    out
+    subs.sort_by(|a, b| a.hwcaps.cmp(&b.hwcaps));
+    subs
 }
 
 /// glibc's per-soname resolution inside one directory: prefer a real file
 /// over a symlink, otherwise the higher name per _dl_cache_libcmp. The
 /// first entry's flags are kept (glibc quirk), with a warning on mismatch.
-fn merge_candidate(dlibs: &mut HashMap<String, DirLib>, cand: DirLib, dir: &Utf8Path) {
+fn merge_candidate(
+    dlibs: &mut HashMap<String, DirLib>,
+    cand: DirLib,
+    dir: &Utf8Path,
+    report: &mut BuildReport,
+) {
     use crate::cache_format::dl_cache_libcmp;
 
     match dlibs.get_mut(&cand.soname) {
@@ -140,42 +417,107 @@ fn merge_candidate(dlibs: &mut HashMap<String, DirLib>, cand: DirLib, dir: &Utf8
                     && dl_cache_libcmp(&existing.name, &cand.name) == Ordering::Less)
             {
                 if existing.flags != cand.flags {
-                    warn!(
+                    report.warn(
+                        WarningKind::SonameTypeMismatch,
+                        format!(
                         "libraries {} and {} in directory {} have same soname but different type.",
                         existing.name, cand.name, dir
+                    ),
                     );
                 }
                 existing.name = cand.name;
+                existing.inode = cand.inode;
                 existing.is_link = cand.is_link;
                 existing.isa_level = cand.isa_level;
+                existing.sha256 = cand.sha256;
+                existing.stat = cand.stat;
             }
         }
     }
 }
 
-/// Scan one directory, returning the winning library per soname.
-/// `remove_stale_links` removes dangling *.so.* symlinks like glibc does
-/// when link updating is enabled.
-pub(crate) fn scan_dir(sd: &ScanDir, prefix: &Utf8Path, remove_stale_links: bool) -> Vec<DirLib> {
-    let Ok(rd) = fs::read_dir(&sd.real) else {
-        debug!("Can't open directory {}", sd.path);
-        return Vec::new();
+/// Scan one directory, returning the winning library per soname. IO
+/// errors on the directory or single entries become warnings; scanning
+/// carries on like glibc's search_dir.
+#[instrument(level = "debug", skip_all, fields(dir = %sd.path, files = Empty, libs = Empty))]
+pub(crate) fn scan_dir(sd: &ScanDir, opts: &ScanOptions, report: &mut BuildReport) -> Vec<DirLib> {
+    let prefix = opts.prefix.as_path();
+    let rd = match fs::read_dir(&sd.real) {
+        Ok(rd) => rd,
+        Err(e) => {
+            report.warn(
+                WarningKind::DirectoryUnreadable,
+                format!("Can't open directory {}: {}", sd.path, e),
+            );
+            return Vec::new();
+        }
     };
 
     let mut dlibs: HashMap<String, DirLib> = HashMap::new();
-    for entry in rd.flatten() {
+    let mut names: HashMap<(u64, u64), Vec<String>> = HashMap::new();
+    let mut files = 0usize;
+    for entry in rd {
+        files += 1;
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                report.warn(
+                    WarningKind::DirectoryUnreadable,
+                    format!("Can't read directory {}: {}", sd.path, e),
+                );
+                continue;
+            }
+        };
         let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
             continue;
         };
-        let Ok(ft) = entry.file_type() else { continue };
+        let ft = match entry.file_type() {
+            Ok(ft) => ft,
+            Err(e) => {
+                report.warn(
+                    WarningKind::FileUnreadable,
+                    format!("Can't stat {}/{}: {}", sd.path, name, e),
+                );
+                continue;
+            }
+        };
         let is_link = ft.is_symlink();
 
+        let library_like = name.starts_with("lib")
+            && (name.contains(".so") || name.ends_with(".a") || name.ends_with(".o"));
+        if opts.misplaced_objects && ft.is_file() && library_like {
+            if let Some(kind) = misplaced_object(&sd.real.join(&name)) {
+                report.warn(
+                    WarningKind::MisplacedObject,
+                    format!("{}/{} is {}, not a shared library", sd.path, name, kind),
+                );
+                continue;
+            }
+        }
+
         // In glibc-hwcaps directories the DSO name filter only applies to
         // regular files (search_dir).
-        if !is_dso(&name) && (!is_link || sd.hwcaps.is_none()) {
+        let named = match opts.dso_names {
+            Some(filter) => filter(&sd.path, &name),
+            None => is_dso_name(&name),
+        };
+        if !named && !opts.all_shared_objects && (!is_link || sd.hwcaps.is_none()) {
             continue;
         }
-        if is_temp_dso(&name) {
+        if is_temp_dso(&name)
+            || opts
+                .skip_suffixes
+                .iter()
+                .any(|s| name.ends_with(s.as_str()))
+        {
+            debug!(
+                directory = %sd.path,
+                library = %name,
+                action = "skip",
+                "{}/{}: temporary or backup file skipped",
+                sd.path,
+                name
+            );
             continue;
         }
 
@@ -190,36 +532,128 @@ pub(crate) fn scan_dir(sd: &ScanDir, prefix: &Utf8Path, remove_stale_links: bool
             };
             match fs::metadata(&target) {
                 Ok(md) if md.is_file() => {}
-                Ok(_) => continue,
+                Ok(md) => {
+                    if is_special(md.file_type()) {
+                        report.warn(
+                            WarningKind::NotRegularFile,
+                            format!("{}/{} does not point to a regular file", sd.path, name),
+                        );
+                    }
+                    continue;
+                }
                 Err(_) => {
                     // Remove stale symlinks.
-                    if remove_stale_links && name.contains(".so.") {
-                        let _ = fs::remove_file(&full);
+                    if opts.remove_stale_links && name.contains(".so.") {
+                        debug!(
+                            directory = %sd.path,
+                            library = %name,
+                            action = "unlink",
+                            "{}/{}: removing dangling link",
+                            sd.path,
+                            name
+                        );
+                        let action = LinkAction::RemoveStale {
+                            dir: sd.path.to_string(),
+                            link: name.clone(),
+                            target: symlinks::read_target(&full),
+                        };
+                        report.warn(
+                            WarningKind::DanglingSymlink,
+                            format!(
+                                "{}/{} is a dangling symlink to {}",
+                                sd.path,
+                                name,
+                                symlinks::read_target(&full)
+                            ),
+                        );
+                        if opts.dry_run || fs::remove_file(&full).is_ok() {
+                            report.links.push(action);
+                        }
                     }
                     continue;
                 }
             }
             inspect_path = target;
         } else if !ft.is_file() {
+            if is_special(ft) {
+                report.warn(
+                    WarningKind::NotRegularFile,
+                    format!("{}/{} is not a regular file", sd.path, name),
+                );
+            }
             continue;
         }
 
-        let Some(info) = elf::inspect(inspect_path.as_std_path()) else {
-            continue;
+        let info = match elf::inspect(
+            inspect_path.as_std_path(),
+            opts.hash_contents,
+            opts.max_file_size,
+            opts.elf_memo,
+        ) {
+            Ok(Some(info)) => info,
+            Ok(None) => continue,
+            // Vanished since the directory was read.
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) if e.kind() == ErrorKind::FileTooLarge => {
+                report.warn(
+                    WarningKind::FileTooLarge,
+                    format!("{}/{} is {}, skipped", sd.path, name, e),
+                );
+                continue;
+            }
+            Err(e) => {
+                report.warn(
+                    WarningKind::FileUnreadable,
+                    format!("Cannot open file {}/{}: {}", sd.path, name, e),
+                );
+                continue;
+            }
         };
+        if !named && opts.all_shared_objects && info.has_interp && info.soname.is_none() {
+            debug!(
+                directory = %sd.path,
+                library = %name,
+                action = "skip",
+                "{}/{}: position-independent executable skipped",
+                sd.path,
+                name
+            );
+            continue;
+        }
+        if opts.max_osversion.is_some_and(|max| info.osversion > max) {
+            debug!(
+                directory = %sd.path,
+                library = %name,
+                action = "skip",
+                "{}/{}: requires a newer kernel ({:#010x})",
+                sd.path,
+                name,
+                info.osversion
+            );
+            continue;
+        }
         let mut soname = info.soname.unwrap_or_else(|| name.clone());
         let mut is_link = is_link;
         if is_link && name != soname {
-            // Only the dev-symlink form (libfoo.so being a prefix of the
-            // soname) keeps link status; anything else is treated as a
-            // normal file (search_dir comment on link hygiene).
-            if !(name.ends_with(".so") && soname.starts_with(name.as_str())) {
+            let keep = match opts.symlink_policy {
+                Some(policy) => policy(&name, &soname),
+                None => is_dev_symlink(&name, &soname),
+            };
+            if !keep {
                 is_link = false;
             }
         }
         if is_link {
             soname = name.clone();
         }
+        let inode = opts
+            .collect_aliases
+            .then(|| fs::metadata(&inspect_path).ok())
+            .flatten()
+            .map(|md| (md.dev(), md.ino()));
+        if let Some(inode) = inode {
+            names.entry(inode).or_default().push(name.clone());
+        }
 
         merge_candidate(
             &mut dlibs,
@@ -229,13 +663,27 @@ pub(crate) fn scan_dir(sd: &ScanDir, prefix: &Utf8Path, remove_stale_links: bool
                 flags: info.flags,
                 isa_level: info.isa_level,
                 is_link,
+                sha256: info.sha256,
+                stat: info.stat,
+                inode,
+                aliases: Vec::new(),
             },
             &sd.path,
+            report,
         );
     }
 
     let mut libs: Vec<DirLib> = dlibs.into_values().collect();
     libs.sort_by(|a, b| a.soname.cmp(&b.soname));
+    for lib in &mut libs {
+        if let Some(aliases) = lib.inode.and_then(|inode| names.get(&inode)) {
+            lib.aliases = aliases.clone();
+            lib.aliases.sort();
+        }
+    }
+    Span::current()
+        .record("files", files)
+        .record("libs", libs.len());
     libs
 }
 
@@ -245,20 +693,20 @@ mod tests {
 
     #[test]
     fn is_dso_standard_libs() {
-        assert!(is_dso("libfoo.so"));
-        assert!(is_dso("libfoo.so.1"));
-        assert!(is_dso("libfoo.so.1.2.3"));
-        assert!(is_dso("ld-linux-x86-64.so.2"));
-        assert!(is_dso("ld.so.1"));
-        assert!(is_dso("ld64.so.2"));
+        assert!(is_dso_name("libfoo.so"));
+        assert!(is_dso_name("libfoo.so.1"));
+        assert!(is_dso_name("libfoo.so.1.2.3"));
+        assert!(is_dso_name("ld-linux-x86-64.so.2"));
+        assert!(is_dso_name("ld.so.1"));
+        assert!(is_dso_name("ld64.so.2"));
     }
 
     #[test]
     fn is_dso_rejects_non_libs() {
-        assert!(!is_dso("foo.txt"));
-        assert!(!is_dso("libfoo.a"));
-        assert!(!is_dso("README.md"));
-        assert!(!is_dso("foo.so")); // no lib/ld prefix
+        assert!(!is_dso_name("foo.txt"));
+        assert!(!is_dso_name("libfoo.a"));
+        assert!(!is_dso_name("README.md"));
+        assert!(!is_dso_name("foo.so")); // no lib/ld prefix
     }
 
     #[test]
@@ -267,6 +715,7 @@ mod tests {
         assert!(is_temp_dso("libfoo.so.1.#prelink#.ab12cd"));
         assert!(is_temp_dso("libfoo.so.1;5f3a"));
         assert!(is_temp_dso("libfoo.so.1.dpkg-new"));
+        assert!(is_temp_dso(".#libbar.so"));
         assert!(!is_temp_dso("libfoo.so.1"));
         // The prelink check is suffix-anchored, like glibc's.
         assert!(!is_temp_dso("libp.#prelink#.so.1"));
@@ -279,21 +728,230 @@ mod tests {
             flags: 0x0303,
             isa_level: 0,
             is_link,
+            sha256: None,
+            stat: None,
+            inode: None,
+            aliases: Vec::new(),
+        }
+    }
+
+    fn mkfifo(path: &Utf8Path) {
+        let c = std::ffi::CString::new(path.as_str()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c.as_ptr(), 0o644) }, 0);
+    }
+
+    #[test]
+    fn backup_suffixes_are_configurable() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        // Contents do not matter: a skipped name is never opened, an
+        // inspected one is rejected as non-ELF without a warning.
+        fs::write(dir.join("libbaz.so.rpmsave"), b"x").unwrap();
+        let sd = ScanDir {
+            path: dir.clone(),
+            real: dir.clone(),
+            hwcaps: None,
+        };
+        let mut report = BuildReport::default();
+        let mut opts = ScanOptions::default();
+        fs::set_permissions(
+            dir.join("libbaz.so.rpmsave"),
+            std::os::unix::fs::PermissionsExt::from_mode(0o000),
+        )
+        .unwrap();
+        scan_dir(&sd, &opts, &mut report);
+        assert!(report.warnings.is_empty());
+
+        // Without the default list the file is inspected; unreadable to
+        // non-root users, which shows up as a warning.
+        if unsafe { libc::geteuid() } != 0 {
+            opts.skip_suffixes.clear();
+            scan_dir(&sd, &opts, &mut report);
+            assert_eq!(report.warnings.len(), 1);
+        }
+    }
+
+    #[test]
+    fn fifos_named_like_libraries_are_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        mkfifo(&dir.join("libfifo.so.1"));
+        std::os::unix::fs::symlink("libfifo.so.1", dir.join("libfifo.so")).unwrap();
+
+        let sd = ScanDir {
+            path: dir.clone(),
+            real: dir.clone(),
+            hwcaps: None,
+        };
+        let opts = ScanOptions::default();
+        // Would block forever if the fifo were opened.
+        let mut report = BuildReport::default();
+        assert!(scan_dir(&sd, &opts, &mut report).is_empty());
+        assert_eq!(report.warnings.len(), 2);
+    }
+
+    #[test]
+    fn misplaced_archives_and_objects_are_reported() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::write(dir.join("libfoo.a"), b"!<arch>\nlibfoo.o/        ").unwrap();
+        let mut object = elf::dso_with(None, &[], None);
+        object[16..18].copy_from_slice(&ET_REL.to_ne_bytes());
+        fs::write(dir.join("libbar.so.1"), &object).unwrap();
+        fs::write(dir.join("libbaz.o"), &object).unwrap();
+        fs::write(dir.join("notes.a"), b"!<arch>\n").unwrap();
+        fs::write(dir.join("libtext.a"), b"not an archive at all").unwrap();
+        let sd = ScanDir {
+            path: dir.clone(),
+            real: dir.clone(),
+            hwcaps: None,
+        };
+
+        let mut report = BuildReport::default();
+        scan_dir(&sd, &ScanOptions::default(), &mut report);
+        assert!(report.warnings.is_empty());
+
+        let opts = ScanOptions {
+            misplaced_objects: true,
+            ..ScanOptions::default()
+        };
+        scan_dir(&sd, &opts, &mut report);
+        let mut warnings: Vec<String> = report.warnings.iter().map(|w| w.to_string()).collect();
+        warnings.sort();
+        assert_eq!(
+            warnings,
+            [
+                format!(
+                    "{}/libbar.so.1 is a relocatable object, not a shared library",
+                    dir
+                ),
+                format!(
+                    "{}/libbaz.o is a relocatable object, not a shared library",
+                    dir
+                ),
+                format!("{}/libfoo.a is a static archive, not a shared library", dir),
+            ]
+        );
+    }
+
+    #[test]
+    fn unreadable_entries_are_warnings() {
+        // Permission checks do not apply to root.
+        if unsafe { libc::geteuid() } == 0 {
+            return;
         }
+        use std::os::unix::fs::PermissionsExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::write(dir.join("libsecret.so.1"), b"x").unwrap();
+        fs::set_permissions(
+            dir.join("libsecret.so.1"),
+            fs::Permissions::from_mode(0o000),
+        )
+        .unwrap();
+        let locked = dir.join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+
+        let opts = ScanOptions::default();
+        let mut report = BuildReport::default();
+        for path in [&dir, &locked] {
+            let sd = ScanDir {
+                path: path.clone(),
+                real: path.clone(),
+                hwcaps: None,
+            };
+            assert!(scan_dir(&sd, &opts, &mut report).is_empty());
+        }
+        assert_eq!(report.warnings.len(), 2);
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn prefix_absolute_links_stay_inside_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::create_dir_all(root.join("usr/lib/hw/x86-64-v3")).unwrap();
+        // Absolute links, as a DESTDIR install would leave them; the host
+        // has neither /usr/lib/hw nor a matching /lib64.
+        std::os::unix::fs::symlink("/usr/lib", root.join("lib64")).unwrap();
+        std::os::unix::fs::symlink("/usr/lib/hw", root.join("usr/lib/glibc-hwcaps")).unwrap();
+
+        let mut report = BuildReport::default();
+        let dirs = collect_dirs(
+            &[Utf8PathBuf::from("/lib64")],
+            &root,
+            &[],
+            PathPolicy::Logical,
+            &mut report,
+        );
+        assert!(report.warnings.is_empty());
+        assert_eq!(dirs.len(), 2);
+        assert_eq!(dirs[0].path, "/lib64");
+        assert_eq!(dirs[0].real, root.join("usr/lib"));
+        assert_eq!(dirs[1].path, "/lib64/glibc-hwcaps/x86-64-v3");
+        assert_eq!(dirs[1].real, root.join("usr/lib/hw/x86-64-v3"));
+        assert_eq!(dirs[1].hwcaps.as_deref(), Some("x86-64-v3"));
+    }
+
+    #[test]
+    fn pseudo_filesystems_are_denied() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        fs::create_dir_all(root.join("proc/self")).unwrap();
+        fs::create_dir_all(root.join("usr/lib")).unwrap();
+        std::os::unix::fs::symlink("/proc/self", root.join("usr/lib/sneaky")).unwrap();
+        let dirs = [
+            Utf8PathBuf::from("/proc/self"),
+            Utf8PathBuf::from("/usr/lib/sneaky"),
+            Utf8PathBuf::from("/usr/lib"),
+        ];
+
+        let mut report = BuildReport::default();
+        let scanned = collect_dirs(
+            &dirs,
+            &root,
+            DEFAULT_DENIED_DIRS,
+            PathPolicy::Logical,
+            &mut report,
+        );
+        assert_eq!(scanned.len(), 1);
+        assert_eq!(scanned[0].path, "/usr/lib");
+        assert_eq!(report.warnings.len(), 2);
+        assert!(report.warnings[1]
+            .to_string()
+            .contains("/usr/lib/sneaky: /proc is a pseudo filesystem"));
+
+        let mut report = BuildReport::default();
+        assert_eq!(
+            collect_dirs(&dirs, &root, &[], PathPolicy::Logical, &mut report).len(),
+            2
+        );
     }
 
     #[test]
     fn file_beats_link_for_same_soname() {
         let dir = Utf8Path::new("/usr/lib");
         let mut m = HashMap::new();
-        merge_candidate(&mut m, lib("libfoo.so.1", "libfoo.so.1", true), dir);
-        merge_candidate(&mut m, lib("libfoo.so.1.2.3", "libfoo.so.1", false), dir);
+        let mut r = BuildReport::default();
+        merge_candidate(&mut m, lib("libfoo.so.1", "libfoo.so.1", true), dir, &mut r);
+        merge_candidate(
+            &mut m,
+            lib("libfoo.so.1.2.3", "libfoo.so.1", false),
+            dir,
+            &mut r,
+        );
         let winner = &m["libfoo.so.1"];
         assert_eq!(winner.name, "libfoo.so.1.2.3");
         assert!(!winner.is_link);
 
         // A link never displaces a file.
-        merge_candidate(&mut m, lib("libfoo.so.1.9", "libfoo.so.1", true), dir);
+        merge_candidate(
+            &mut m,
+            lib("libfoo.so.1.9", "libfoo.so.1", true),
+            dir,
+            &mut r,
+        );
         assert_eq!(m["libfoo.so.1"].name, "libfoo.so.1.2.3");
     }
 
@@ -301,10 +959,54 @@ mod tests {
     fn higher_version_wins_between_files() {
         let dir = Utf8Path::new("/usr/lib");
         let mut m = HashMap::new();
-        merge_candidate(&mut m, lib("libfoo.so.1.2", "libfoo.so.1", false), dir);
-        merge_candidate(&mut m, lib("libfoo.so.1.10", "libfoo.so.1", false), dir);
+        let mut r = BuildReport::default();
+        merge_candidate(
+            &mut m,
+            lib("libfoo.so.1.2", "libfoo.so.1", false),
+            dir,
+            &mut r,
+        );
+        merge_candidate(
+            &mut m,
+            lib("libfoo.so.1.10", "libfoo.so.1", false),
+            dir,
+            &mut r,
+        );
         assert_eq!(m["libfoo.so.1"].name, "libfoo.so.1.10");
-        merge_candidate(&mut m, lib("libfoo.so.1.9", "libfoo.so.1", false), dir);
+        merge_candidate(
+            &mut m,
+            lib("libfoo.so.1.9", "libfoo.so.1", false),
+            dir,
+            &mut r,
+        );
         assert_eq!(m["libfoo.so.1"].name, "libfoo.so.1.10");
     }
+
+    #[test]
+    fn winner_keeps_its_own_hash_and_stat() {
+        use sha2::{Digest, Sha256};
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let old = elf::dso_with(Some("libfoo.so.1"), &[], None);
+        let new = elf::dso_with(Some("libfoo.so.1"), &["libc.so.6"], None);
+        fs::write(dir.join("libfoo.so.1.2"), &old).unwrap();
+        fs::write(dir.join("libfoo.so.1.10"), &new).unwrap();
+        let sd = ScanDir {
+            path: dir.clone(),
+            real: dir.clone(),
+            hwcaps: None,
+        };
+        let opts = ScanOptions {
+            hash_contents: true,
+            ..ScanOptions::default()
+        };
+
+        let mut report = BuildReport::default();
+        let libs = scan_dir(&sd, &opts, &mut report);
+        assert_eq!(libs.len(), 1);
+        assert_eq!(libs[0].name, "libfoo.so.1.10");
+        let expected: [u8; 32] = Sha256::digest(&new).into();
+        assert_eq!(libs[0].sha256, Some(expected));
+        assert_eq!(libs[0].stat.map(|s| s.size), Some(new.len() as u64));
+    }
 }
@@ -0,0 +1,672 @@
+//! Bounded-memory serialization for very large roots: entries are
+//! spilled to sorted runs in temporary files as they are scanned, merged
+//! a bounded number of runs at a time, then merged straight into the
+//! cache layout, written in place into a temporary file mapped at its
+//! largest possible size, next to the cache file when it is known so
+//! that it can be renamed over it: entries, then the string table right
+//! behind them, the header written once the sizes are known. The per-entry
+//! extension sections go through temporary files of their own. Strings
+//! are shared through a bounded map of the most recently used ones, so
+//! the result is byte for byte what [`build_cache`] writes as long as a
+//! string recurs before that many others are used.
+//!
+//! [`build_cache`]: crate::cache_format::build_cache
+
+use crate::cache_format::{
+    compare, entry_bytes, extension_directory, header_bytes, FileEntry, FileStat, RawExtension,
+    ENTRY_SIZE, FILE_STAT_SIZE, HEADER_SIZE, TAG_CONTENT_SHA256, TAG_FILE_STAT, TAG_GENERATOR,
+    TAG_GLIBC_HWCAPS,
+};
+use crate::Shadowed;
+use memmap2::{Mmap, MmapMut};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::NamedTempFile;
+use tracing::debug;
+
+/// Runs merged at once, which bounds the files held open.
+const FAN_IN: usize = 16;
+
+/// Where a queued entry comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Source {
+    /// Scanned, checked for shadowing.
+    Scanned,
+    /// Scanned and recorded under its canonical path: an entry repeating
+    /// the soname and path of an earlier one is dropped.
+    Canonical,
+    /// Synthetic.
+    Synthetic,
+}
+
+/// (scan order, source, entry)
+type Record = (u64, Source, FileEntry);
+
+/// Entries on their way to the cache, at most `limit` of them in memory.
+pub(crate) struct EntrySpill {
+    limit: usize,
+    buffer: Vec<Record>,
+    /// Sorted runs and how many merges went into each.
+    runs: Vec<(File, u32)>,
+    count: u64,
+    hwcaps_names: BTreeSet<String>,
+    any_sha256: bool,
+    any_stat: bool,
+    /// The string table size were no string shared.
+    string_bytes: u64,
+}
+
+fn write_str(w: &mut impl Write, s: &str) -> io::Result<()> {
+    w.write_all(&(s.len() as u32).to_ne_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut b = [0u8; N];
+    r.read_exact(&mut b)?;
+    Ok(b)
+}
+
+fn read_str(r: &mut impl Read) -> io::Result<String> {
+    let len = u32::from_ne_bytes(read_array(r)?) as usize;
+    let mut b = vec![0u8; len];
+    r.read_exact(&mut b)?;
+    String::from_utf8(b).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_record(w: &mut impl Write, seq: u64, source: Source, e: &FileEntry) -> io::Result<()> {
+    w.write_all(&seq.to_ne_bytes())?;
+    w.write_all(&[source as u8])?;
+    write_str(w, &e.soname)?;
+    write_str(w, &e.path)?;
+    w.write_all(&e.flags.to_ne_bytes())?;
+    w.write_all(&e.isa_level.to_ne_bytes())?;
+    w.write_all(&e.hwcap.to_ne_bytes())?;
+    w.write_all(&e.osversion.to_ne_bytes())?;
+    match &e.hwcaps {
+        Some(name) => {
+            w.write_all(&[1])?;
+            write_str(w, name)?;
+        }
+        None => w.write_all(&[0])?,
+    }
+    match &e.sha256 {
+        Some(digest) => {
+            w.write_all(&[1])?;
+            w.write_all(digest)?;
+        }
+        None => w.write_all(&[0])?,
+    }
+    match e.stat {
+        Some(stat) => {
+            w.write_all(&[1])?;
+            w.write_all(&stat.to_bytes())?;
+        }
+        None => w.write_all(&[0])?,
+    }
+    Ok(())
+}
+
+/// The next record of a run, None at its end.
+fn read_record(r: &mut impl Read) -> io::Result<Option<Record>> {
+    let mut seq = [0u8; 8];
+    match r.read_exact(&mut seq) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let source = match read_array(r)? {
+        [0] => Source::Scanned,
+        [1] => Source::Canonical,
+        _ => Source::Synthetic,
+    };
+    let soname = read_str(r)?;
+    let path = read_str(r)?;
+    let flags = u32::from_ne_bytes(read_array(r)?);
+    let isa_level = u32::from_ne_bytes(read_array(r)?);
+    let hwcap = u64::from_ne_bytes(read_array(r)?);
+    let osversion = u32::from_ne_bytes(read_array(r)?);
+    let [has_hwcaps] = read_array(r)?;
+    let hwcaps = if has_hwcaps != 0 {
+        Some(read_str(r)?)
+    } else {
+        None
+    };
+    let [has_sha256] = read_array(r)?;
+    let sha256 = if has_sha256 != 0 {
+        Some(read_array::<32>(r)?)
+    } else {
+        None
+    };
+    let [has_stat] = read_array(r)?;
+    let stat = if has_stat != 0 {
+        FileStat::from_bytes(&read_array::<FILE_STAT_SIZE>(r)?)
+    } else {
+        None
+    };
+    let entry = FileEntry {
+        soname,
+        path,
+        flags,
+        isa_level,
+        hwcaps,
+        hwcap,
+        osversion,
+        sha256,
+        stat,
+    };
+    Ok(Some((u64::from_ne_bytes(seq), source, entry)))
+}
+
+/// Cache order; equal entries keep the order they were pushed in.
+fn order(a: &Record, b: &Record) -> Ordering {
+    compare(&a.2, &b.2).then(a.0.cmp(&b.0))
+}
+
+/// The next record of the run at the index, ordered so that
+/// [`BinaryHeap`] pops the first in cache order.
+struct Head(Record, usize);
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        order(&other.0, &self.0)
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+/// Sorted runs read back as one, in cache order.
+struct Merge {
+    runs: Vec<BufReader<File>>,
+    heads: BinaryHeap<Head>,
+}
+
+impl Merge {
+    fn new(runs: impl IntoIterator<Item = File>) -> io::Result<Self> {
+        let mut merge = Self {
+            runs: Vec::new(),
+            heads: BinaryHeap::new(),
+        };
+        for run in runs {
+            let mut reader = BufReader::new(run);
+            if let Some(head) = read_record(&mut reader)? {
+                merge.heads.push(Head(head, merge.runs.len()));
+            }
+            merge.runs.push(reader);
+        }
+        Ok(merge)
+    }
+
+    fn next(&mut self) -> io::Result<Option<Record>> {
+        let Some(Head(record, i)) = self.heads.pop() else {
+            return Ok(None);
+        };
+        if let Some(head) = read_record(&mut self.runs[i])? {
+            self.heads.push(Head(head, i));
+        }
+        Ok(Some(record))
+    }
+
+    /// Merge into a single run.
+    fn into_run(mut self) -> io::Result<File> {
+        let mut w = BufWriter::new(tempfile::tempfile()?);
+        while let Some((seq, source, e)) = self.next()? {
+            write_record(&mut w, seq, source, &e)?;
+        }
+        rewound(w)
+    }
+}
+
+/// Writes strings into the mapping past the entries, sharing those among
+/// the `capacity` most recently used: equal sonames sort next to each
+/// other and an entry's aliases near it, so this interns them as
+/// [`build_cache`](crate::cache_format::build_cache) does.
+struct StringTable<'a> {
+    out: Cursor<&'a mut [u8]>,
+    base: u64,
+    len: u64,
+    capacity: usize,
+    /// Offset and last use of each string held.
+    offsets: HashMap<String, (u32, u64)>,
+    /// The same strings by last use, oldest first.
+    uses: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl StringTable<'_> {
+    fn add(&mut self, s: &str) -> io::Result<u32> {
+        self.clock += 1;
+        if let Some((off, used)) = self.offsets.get_mut(s) {
+            let s = self.uses.remove(used).unwrap();
+            *used = self.clock;
+            self.uses.insert(self.clock, s);
+            return Ok(*off);
+        }
+        let off = (self.base + self.len) as u32;
+        self.out.write_all(s.as_bytes())?;
+        self.out.write_all(&[0])?;
+        self.len += s.len() as u64 + 1;
+        if self.offsets.len() == self.capacity {
+            if let Some((_, oldest)) = self.uses.pop_first() {
+                self.offsets.remove(&oldest);
+            }
+        }
+        self.offsets.insert(s.to_owned(), (off, self.clock));
+        self.uses.insert(self.clock, s.to_owned());
+        Ok(off)
+    }
+}
+
+/// A temporary file written through a buffer, read back from the start.
+fn rewound(w: BufWriter<File>) -> io::Result<File> {
+    let mut file = w.into_inner().map_err(io::IntoInnerError::into_error)?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+enum SectionData<'a> {
+    Bytes(&'a [u8]),
+    File(File, u64),
+}
+
+impl SectionData<'_> {
+    fn len(&self) -> u64 {
+        match self {
+            SectionData::Bytes(b) => b.len() as u64,
+            SectionData::File(_, len) => *len,
+        }
+    }
+}
+
+impl EntrySpill {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: limit.max(1),
+            buffer: Vec::new(),
+            runs: Vec::new(),
+            count: 0,
+            hwcaps_names: BTreeSet::new(),
+            any_sha256: false,
+            any_stat: false,
+            string_bytes: 0,
+        }
+    }
+
+    /// Queue an entry.
+    pub fn push(&mut self, entry: FileEntry, source: Source) -> io::Result<()> {
+        if let Some(name) = &entry.hwcaps {
+            if !self.hwcaps_names.contains(name) {
+                self.hwcaps_names.insert(name.clone());
+            }
+        }
+        self.any_sha256 |= entry.sha256.is_some();
+        self.any_stat |= entry.stat.is_some();
+        self.string_bytes += (entry.soname.len() + entry.path.len() + 2) as u64;
+        self.buffer.push((self.count, source, entry));
+        self.count += 1;
+        if self.buffer.len() >= self.limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Entries queued so far, including those the merge will drop.
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.buffer.sort_by(order);
+        let mut w = BufWriter::new(tempfile::tempfile()?);
+        for (seq, source, e) in self.buffer.drain(..) {
+            write_record(&mut w, seq, source, &e)?;
+        }
+        self.runs.push((rewound(w)?, 0));
+        debug!(
+            "Spilled run {} ({} entries so far)",
+            self.runs.len(),
+            self.count
+        );
+        // Runs of the same generation are merged once there are enough,
+        // so every entry is rewritten once per generation.
+        while self.runs.len() >= FAN_IN {
+            let level = self.runs[self.runs.len() - FAN_IN].1;
+            if self.runs[self.runs.len() - FAN_IN..]
+                .iter()
+                .any(|(_, l)| *l != level)
+            {
+                break;
+            }
+            self.merge_last(FAN_IN, level + 1)?;
+        }
+        Ok(())
+    }
+
+    /// Merge the last `n` runs into one of generation `level`.
+    fn merge_last(&mut self, n: usize, level: u32) -> io::Result<()> {
+        let at = self.runs.len() - n;
+        let merged = Merge::new(self.runs.drain(at..).map(|(run, _)| run))?.into_run()?;
+        self.runs.push((merged, level));
+        Ok(())
+    }
+
+    /// Merge the runs into cache bytes, in the layout of
+    /// [`build_cache`](crate::cache_format::build_cache), mapped from a
+    /// temporary file in `dir` (the system's temporary directory if None)
+    /// rather than held in memory, returned with the mapping to be renamed
+    /// into place once complete. Scanned entries hidden
+    /// by an earlier one with the same soname, ABI and hwcaps subdirectory
+    /// are reported in cache order; `written` sees every entry the cache
+    /// gets, in cache order.
+    pub fn write_cache(
+        mut self,
+        generator: Option<&str>,
+        extensions: &[RawExtension],
+        shadowed: &mut Vec<Shadowed>,
+        mut written: impl FnMut(&FileEntry),
+        dir: Option<&Path>,
+    ) -> io::Result<(Mmap, NamedTempFile)> {
+        self.spill()?;
+        while self.runs.len() > FAN_IN {
+            let level = self.runs.iter().map(|(_, l)| *l).max().unwrap_or(0);
+            self.merge_last(FAN_IN, level)?;
+        }
+        let hwcaps_names: Vec<String> = self.hwcaps_names.into_iter().collect();
+        // At most; entries dropped by the merge shrink it below.
+        let count = self.count as usize;
+        let string_table_offset = HEADER_SIZE + count * ENTRY_SIZE;
+
+        // Every section padded as much as alignment ever pads it.
+        let section_lens = [
+            generator.map(str::len),
+            Some(hwcaps_names.len() * 4),
+            self.any_sha256.then_some(count * 32),
+            self.any_stat.then_some(count * FILE_STAT_SIZE),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(extensions.iter().map(|ext| ext.data.len()));
+        let sections_bound: u64 = section_lens.map(|len| 16 + 3 + len as u64).sum();
+        let names_bound: u64 = hwcaps_names.iter().map(|n| n.len() as u64 + 1).sum();
+        let bound =
+            string_table_offset as u64 + self.string_bytes + names_bound + 3 + 8 + sections_bound;
+        let file = match dir {
+            Some(dir) => NamedTempFile::new_in(dir)?,
+            None => NamedTempFile::new()?,
+        };
+        file.as_file().set_len(bound)?;
+        // Safety: a temporary file created for this, under a random name.
+        let mut map = unsafe { MmapMut::map_mut(file.as_file())? };
+        let (head, tail) = map.split_at_mut(string_table_offset);
+
+        let mut out = Cursor::new(head);
+        out.set_position(HEADER_SIZE as u64);
+        let mut strings = StringTable {
+            out: Cursor::new(tail),
+            base: string_table_offset as u64,
+            len: 0,
+            capacity: 2 * self.limit,
+            offsets: HashMap::new(),
+            uses: BTreeMap::new(),
+            clock: 0,
+        };
+        let mut sha256 = BufWriter::new(tempfile::tempfile()?);
+        let mut stats = BufWriter::new(tempfile::tempfile()?);
+
+        let mut merge = Merge::new(self.runs.into_iter().map(|(run, _)| run))?;
+        let mut nlibs = 0;
+        // (soname, flags, hwcaps, path) of the first scanned entry of a key.
+        let mut first: Option<(String, u32, Option<String>, String)> = None;
+        // Paths of the canonical entries of the current soname.
+        let mut canonical: (String, HashSet<String>) = Default::default();
+        while let Some((_, source, e)) = merge.next()? {
+            if source == Source::Canonical {
+                if canonical.0 != e.soname {
+                    canonical = (e.soname.clone(), HashSet::new());
+                }
+                if !canonical.1.insert(e.path.clone()) {
+                    continue;
+                }
+            }
+            if source != Source::Synthetic {
+                match &first {
+                    Some((soname, flags, hwcaps, used))
+                        if *soname == e.soname && *flags == e.flags && *hwcaps == e.hwcaps =>
+                    {
+                        debug!(library = %e.path, action = "shadow", "{} shadowed by {}", e.path, used);
+                        shadowed.push(Shadowed {
+                            soname: e.soname.clone(),
+                            used: used.clone(),
+                            shadowed: e.path.clone(),
+                        });
+                    }
+                    _ => {
+                        first = Some((e.soname.clone(), e.flags, e.hwcaps.clone(), e.path.clone()))
+                    }
+                }
+            }
+
+            let key = strings.add(&e.soname)?;
+            let value = strings.add(&e.path)?;
+            out.write_all(&entry_bytes(&e, key, value, &hwcaps_names))?;
+            if self.any_sha256 {
+                sha256.write_all(&e.sha256.unwrap_or([0; 32]))?;
+            }
+            if self.any_stat {
+                stats.write_all(&e.stat.map_or([0; FILE_STAT_SIZE], FileStat::to_bytes))?;
+            }
+            written(&e);
+            nlibs += 1;
+        }
+
+        let hwcaps_offsets = hwcaps_names
+            .iter()
+            .map(|n| strings.add(n))
+            .collect::<io::Result<Vec<u32>>>()?;
+        let len_strings = strings.len;
+
+        // Entries were dropped: move the strings up behind the last one
+        // and the entries' offsets into them with it.
+        let strings_at = HEADER_SIZE + nlibs * ENTRY_SIZE;
+        let shift = (string_table_offset - strings_at) as u32;
+        let hwcaps_offsets: Vec<u32> = hwcaps_offsets.iter().map(|o| o - shift).collect();
+        if shift != 0 {
+            let table = string_table_offset..string_table_offset + len_strings as usize;
+            map.copy_within(table.clone(), strings_at);
+            map[strings_at + len_strings as usize..table.end].fill(0);
+            for entry in map[HEADER_SIZE..strings_at].chunks_exact_mut(ENTRY_SIZE) {
+                for field in entry[4..12].chunks_exact_mut(4) {
+                    let offset = u32::from_ne_bytes(field.try_into().unwrap()) - shift;
+                    field.copy_from_slice(&offset.to_ne_bytes());
+                }
+            }
+        }
+
+        let hwcaps_data: Vec<u8> = hwcaps_offsets
+            .iter()
+            .flat_map(|o| o.to_ne_bytes())
+            .collect();
+        let mut sections: Vec<(u32, u32, SectionData)> = Vec::new();
+        if let Some(generator) = generator {
+            sections.push((TAG_GENERATOR, 0, SectionData::Bytes(generator.as_bytes())));
+        }
+        if !hwcaps_data.is_empty() {
+            sections.push((TAG_GLIBC_HWCAPS, 0, SectionData::Bytes(&hwcaps_data)));
+        }
+        if self.any_sha256 {
+            let len = (nlibs * 32) as u64;
+            sections.push((
+                TAG_CONTENT_SHA256,
+                0,
+                SectionData::File(rewound(sha256)?, len),
+            ));
+        }
+        if self.any_stat {
+            let len = (nlibs * FILE_STAT_SIZE) as u64;
+            sections.push((TAG_FILE_STAT, 0, SectionData::File(rewound(stats)?, len)));
+        }
+        for ext in extensions {
+            sections.push((ext.tag, ext.flags, SectionData::Bytes(&ext.data)));
+        }
+
+        // The rest follows the strings, 4-byte aligned; the mapping is
+        // still zero there.
+        let mut pos = (strings_at as u64 + len_strings).next_multiple_of(4);
+        let extension_offset = if sections.is_empty() { 0 } else { pos as u32 };
+        if !sections.is_empty() {
+            let lens: Vec<(u32, u32, u32)> = sections
+                .iter()
+                .map(|(tag, flags, data)| (*tag, *flags, data.len() as u32))
+                .collect();
+            let (directory, placement) = extension_directory(extension_offset, &lens);
+            let mut tail = Cursor::new(&mut map[..]);
+            tail.set_position(pos);
+            tail.write_all(&directory)?;
+            for (i, offset) in placement {
+                tail.set_position(u64::from(offset));
+                let data = &mut sections[i].2;
+                pos = u64::from(offset) + data.len();
+                match data {
+                    SectionData::Bytes(b) => tail.write_all(b)?,
+                    SectionData::File(file, _) => {
+                        io::copy(file, &mut tail)?;
+                    }
+                }
+            }
+        }
+
+        map[..HEADER_SIZE].copy_from_slice(&header_bytes(
+            nlibs as u32,
+            len_strings as u32,
+            extension_offset,
+        ));
+
+        drop(map);
+        file.as_file().set_len(pos)?;
+        debug!("Cache of {} bytes written in place", pos);
+        // Safety: as above; nothing writes to it any more.
+        let map = unsafe { Mmap::map(file.as_file())? };
+        Ok((map, file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_format::{build_cache, Provenance};
+
+    fn entry(soname: &str, path: &str, flags: u32) -> FileEntry {
+        FileEntry {
+            soname: soname.into(),
+            path: path.into(),
+            flags,
+            isa_level: 0,
+            hwcaps: None,
+            hwcap: 0,
+            osversion: 0,
+            sha256: None,
+            stat: None,
+        }
+    }
+
+    #[test]
+    fn merged_runs_match_build_cache() {
+        let mut entries = Vec::new();
+        for i in 0..40 {
+            let mut e = entry(
+                &format!("libn{}.so.{}", i % 7, i % 3),
+                &format!("/usr/lib{}/libn{}.so.{}", i % 4, i % 7, i % 3),
+                if i % 5 == 0 { 0x0003 } else { 0x0303 },
+            );
+            if i % 6 == 0 {
+                e.hwcaps = Some(
+                    if i % 12 == 0 {
+                        "x86-64-v3"
+                    } else {
+                        "x86-64-v2"
+                    }
+                    .into(),
+                );
+                e.isa_level = 3;
+            }
+            if i % 4 == 0 {
+                e.sha256 = Some([i as u8; 32]);
+                e.stat = Some(FileStat {
+                    size: 100 + i,
+                    mtime: 1_700_000_000,
+                    mtime_nsec: 5,
+                });
+            }
+            entries.push(e);
+        }
+        let provenance = Provenance {
+            tool_version: Some("test".into()),
+            ..Provenance::default()
+        };
+        let extensions = [provenance.to_extension()];
+        let expected = build_cache(&entries, Some("gen"), &extensions);
+
+        for limit in [1, 7, 100] {
+            let mut spill = EntrySpill::new(limit);
+            for e in &entries {
+                spill.push(e.clone(), Source::Scanned).unwrap();
+            }
+            assert_eq!(spill.len(), 40);
+            let mut shadowed = Vec::new();
+            let data = spill
+                .write_cache(Some("gen"), &extensions, &mut shadowed, |_| {}, None)
+                .unwrap()
+                .0;
+            assert!(data[..] == expected[..], "limit {}", limit);
+            assert!(!shadowed.is_empty());
+        }
+
+        let empty = EntrySpill::new(4)
+            .write_cache(None, &[], &mut Vec::new(), |_| {}, None)
+            .unwrap()
+            .0;
+        assert_eq!(empty[..], build_cache(&[], None, &[])[..]);
+    }
+
+    #[test]
+    fn paths_are_shared_across_sonames() {
+        // Every name of one file, as alias entries record them.
+        let mut entries: Vec<FileEntry> = ["libfoo.so", "libfoo.so.1", "libfoo.so.1.2"]
+            .into_iter()
+            .map(|soname| entry(soname, "/usr/lib/libfoo.so.1.2", 0x0303))
+            .collect();
+        for i in 0..20 {
+            let soname = format!("libbar{}.so.1", i);
+            entries.push(entry(&soname, &format!("/usr/lib/{}", soname), 0x0303));
+        }
+        let expected = build_cache(&entries, None, &[]);
+
+        let mut spill = EntrySpill::new(2);
+        for e in &entries {
+            spill.push(e.clone(), Source::Canonical).unwrap();
+        }
+        let data = spill
+            .write_cache(None, &[], &mut Vec::new(), |_| {}, None)
+            .unwrap()
+            .0;
+        assert!(data[..] == expected[..]);
+    }
+}
@@ -0,0 +1,169 @@
+//! Summary figures for a cache, for `ldconfig --stats` and monitoring.
+
+use crate::cache_format::{self, Header, OLD_ENTRY_SIZE, OLD_HEADER_SIZE};
+use crate::Cache;
+use camino::Utf8Path;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Counts describing a cache; every list is sorted largest first, then
+/// by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: usize,
+    /// Size of the string table holding sonames and paths.
+    pub string_table_bytes: usize,
+    /// Entries per ABI description, e.g. ("libc6,x86-64", 480).
+    pub per_arch: Vec<(String, usize)>,
+    /// Entries per glibc-hwcaps subdirectory, or legacy hwcap names
+    /// joined with `/`; baseline entries are not counted.
+    pub hwcap_variants: Vec<(String, usize)>,
+    /// Entries per directory.
+    pub directories: Vec<(String, usize)>,
+    /// Sonames with more than one entry for the same ABI and hwcaps
+    /// subdirectory, and how many; the loader only uses the first.
+    pub duplicate_sonames: Vec<(String, usize)>,
+}
+
+fn ranked<K: Into<String>>(counts: HashMap<K, usize>) -> Vec<(String, usize)> {
+    let mut ranked: Vec<(String, usize)> = counts.into_iter().map(|(k, n)| (k.into(), n)).collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ranked
+}
+
+impl Cache {
+    /// Entry counts by architecture, hwcaps variant and directory, the
+    /// string table size and duplicated sonames.
+    pub fn stats(&self) -> CacheStats {
+        let mut per_arch: HashMap<String, usize> = HashMap::new();
+        let mut hwcap_variants: HashMap<String, usize> = HashMap::new();
+        let mut directories: HashMap<String, usize> = HashMap::new();
+        let mut sonames: HashMap<(String, u32, Option<String>), usize> = HashMap::new();
+        for entry in self.entries() {
+            *per_arch.entry(entry.arch.clone()).or_default() += 1;
+            let variant = match &entry.hwcaps {
+                Some(name) => Some(name.clone()),
+                None if entry.hwcap != 0 => {
+                    let names = entry.legacy_hwcaps();
+                    Some(if names.is_empty() {
+                        format!("{:#x}", entry.hwcap)
+                    } else {
+                        names.join("/")
+                    })
+                }
+                None => None,
+            };
+            if let Some(variant) = variant {
+                *hwcap_variants.entry(variant).or_default() += 1;
+            }
+            let dir = Utf8Path::new(&entry.path)
+                .parent()
+                .map_or("", Utf8Path::as_str);
+            *directories.entry(dir.to_owned()).or_default() += 1;
+            *sonames
+                .entry((entry.soname, entry.flags, entry.hwcaps))
+                .or_default() += 1;
+        }
+
+        let mut duplicates: HashMap<String, usize> = HashMap::new();
+        for ((soname, _, _), n) in sonames {
+            if n > 1 {
+                *duplicates.entry(soname).or_default() += n;
+            }
+        }
+
+        CacheStats {
+            entries: self.info().num_entries,
+            string_table_bytes: self.string_table_bytes(),
+            per_arch: ranked(per_arch),
+            hwcap_variants: ranked(hwcap_variants),
+            directories: ranked(directories),
+            duplicate_sonames: ranked(duplicates),
+        }
+    }
+
+    /// The new-format string table, or the old one for an old-only cache.
+    fn string_table_bytes(&self) -> usize {
+        match cache_format::new_format_offset(&self.data) {
+            Some(offset) => {
+                Header::parse(&self.data[offset..]).map_or(0, |header| header.len_strings as usize)
+            }
+            None => self
+                .data
+                .len()
+                .saturating_sub(OLD_HEADER_SIZE + self.info().num_entries * OLD_ENTRY_SIZE),
+        }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    /// The figures as `ldconfig --stats` prints them, with the five
+    /// largest directories.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Entries: {}", self.entries)?;
+        writeln!(f, "String table: {} bytes", self.string_table_bytes)?;
+        let sections = [
+            ("Architectures", &self.per_arch[..]),
+            ("hwcaps variants", &self.hwcap_variants[..]),
+            (
+                "Largest directories",
+                &self.directories[..self.directories.len().min(5)],
+            ),
+            ("Duplicate sonames", &self.duplicate_sonames[..]),
+        ];
+        for (title, counts) in sections {
+            if counts.is_empty() {
+                continue;
+            }
+            writeln!(f, "{}:", title)?;
+            for (name, n) in counts {
+                writeln!(f, "  {}: {}", name, n)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Arch, CacheFormat, SyntheticEntry};
+
+    #[test]
+    fn counts_entries_by_kind() {
+        let mut hw = SyntheticEntry::new(
+            "libm.so.6",
+            "/usr/lib/glibc-hwcaps/x86-64-v3/libm.so.6",
+            Arch::X86_64,
+        );
+        hw.hwcaps = Some("x86-64-v3".into());
+        let cache = Cache::from_file_entries(&[], None, &[])
+            .unwrap()
+            .add_entries(&[
+                SyntheticEntry::new("libz.so.1", "/usr/lib/libz.so.1", Arch::X86_64),
+                SyntheticEntry::new("libz.so.1", "/opt/lib/libz.so.1", Arch::X86_64),
+                SyntheticEntry::new("libm.so.6", "/usr/lib/libm.so.6", Arch::X86_64),
+                SyntheticEntry::new("libz.so.1", "/usr/lib32/libz.so.1", Arch::Libc6),
+                hw,
+            ])
+            .unwrap();
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 5);
+        assert_eq!(
+            stats.per_arch,
+            [("libc6,x86-64".to_owned(), 4), ("libc6".to_owned(), 1)]
+        );
+        assert_eq!(stats.hwcap_variants, [("x86-64-v3".to_owned(), 1)]);
+        assert_eq!(stats.directories[0], ("/usr/lib".to_owned(), 2));
+        assert_eq!(stats.duplicate_sonames, [("libz.so.1".to_owned(), 2)]);
+        assert_eq!(
+            stats.string_table_bytes,
+            cache_format::string_table_len(cache.as_bytes())
+        );
+        let old = cache.with_format(CacheFormat::Old).unwrap();
+        assert!(old.stats().string_table_bytes > 0);
+        assert!(stats
+            .to_string()
+            .contains("Duplicate sonames:\n  libz.so.1: 2\n"));
+    }
+}
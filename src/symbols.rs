@@ -0,0 +1,294 @@
+//! The symbols shared objects export, to find which cached library
+//! provides a function.
+//!
+//! ```no_run
+//! use ldconfig::Cache;
+//!
+//! let cache = Cache::from_file("/etc/ld.so.cache")?;
+//! for entry in cache.entries_exporting("SSL_CTX_new", "/") {
+//!     println!("{}", entry.path);
+//! }
+//! # Ok::<(), ldconfig::Error>(())
+//! ```
+
+use crate::{chroot_canon, elf, error::Error, Cache, CacheEntry};
+use camino::Utf8Path;
+use std::fmt;
+use tracing::debug;
+
+/// What kind of definition a symbol is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    Function,
+    /// A GNU indirect function, resolved by the loader.
+    IndirectFunction,
+    /// Data, including common symbols.
+    Object,
+    /// Thread-local data.
+    Tls,
+    Other,
+}
+
+/// A symbol defined in a shared object's dynamic symbol table and
+/// visible to other objects.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExportedSymbol {
+    pub name: String,
+    /// The version it is defined with ("GLIBC_2.14"); None for
+    /// unversioned objects, or when the version sections were stripped.
+    pub version: Option<String>,
+    /// Whether it is the version new links bind to (`@@` rather than `@`).
+    pub default_version: bool,
+    pub kind: SymbolKind,
+}
+
+impl fmt::Display for ExportedSymbol {
+    /// `name@@version`, `name@version` or just `name`, like `nm -D`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) if self.default_version => write!(f, "{}@@{}", self.name, version),
+            Some(version) => write!(f, "{}@{}", self.name, version),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+/// The symbols the shared object at `path` exports, in symbol table
+/// order; a name appears once per version it is defined with.
+pub fn exported_symbols(path: impl AsRef<Utf8Path>) -> Result<Vec<ExportedSymbol>, Error> {
+    let path = path.as_ref();
+    elf::read_exports(path.as_std_path())?.ok_or_else(|| Error::NotDynamic(path.into()))
+}
+
+impl Cache {
+    /// Entries whose library exports `symbol`, in cache order. Paths are
+    /// resolved inside `prefix`; entries that cannot be read are skipped.
+    pub fn entries_exporting(&self, symbol: &str, prefix: impl AsRef<Utf8Path>) -> Vec<CacheEntry> {
+        let prefix = prefix.as_ref();
+        self.entries()
+            .filter(|entry| {
+                let Some(real) = chroot_canon(prefix, Utf8Path::new(&entry.path)) else {
+                    return false;
+                };
+                match exported_symbols(real) {
+                    Ok(symbols) => symbols.iter().any(|s| s.name == symbol),
+                    Err(e) => {
+                        debug!("{}: {}", entry.path, e);
+                        false
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Arch, SyntheticEntry};
+
+    /// An x86-64 `libfoo.so.1` defining versions LIBFOO_1 and LIBFOO_2,
+    /// with a DT_HASH sized dynamic symbol table and the .gnu.version and
+    /// .gnu.version_d sections goblin reads versions from. Symbols are
+    /// (name, STT, STB, STV, st_shndx, versym).
+    fn versioned_dso(symbols: &[(&str, u8, u8, u8, u32, u16)]) -> Vec<u8> {
+        use goblin::elf::dynamic::{
+            DT_HASH, DT_NULL, DT_SONAME, DT_STRSZ, DT_STRTAB, DT_SYMENT, DT_SYMTAB, DT_VERDEF,
+            DT_VERDEFNUM, DT_VERSYM,
+        };
+        use goblin::elf::header::{EM_X86_64, ET_DYN};
+        use goblin::elf::program_header::{PT_DYNAMIC, PT_LOAD};
+        use goblin::elf::section_header::{SHT_GNU_VERDEF, SHT_GNU_VERSYM};
+        use goblin::elf::symver::VER_FLG_BASE;
+
+        let mut strtab = vec![0u8];
+        let mut intern = |s: &str| {
+            let at = strtab.len() as u32;
+            strtab.extend_from_slice(s.as_bytes());
+            strtab.push(0);
+            at
+        };
+        let defs = ["libfoo.so.1", "LIBFOO_1", "LIBFOO_2"].map(&mut intern);
+        let names: Vec<u32> = symbols.iter().map(|s| intern(s.0)).collect();
+
+        let nsyms = symbols.len() + 1;
+        let dyn_off = 64 + 2 * 56;
+        let dyn_count = 10;
+        let str_off = dyn_off + dyn_count * 16;
+        let sym_off = (str_off + strtab.len()).next_multiple_of(8);
+        let hash_off = sym_off + nsyms * 24;
+        let versym_off = hash_off + (2 + 1 + nsyms) * 4;
+        let verdef_off = (versym_off + nsyms * 2).next_multiple_of(4);
+        let verdef_len = defs.len() * 28;
+        let sh_off = (verdef_off + verdef_len).next_multiple_of(8);
+        let total = (sh_off + 3 * 64) as u64;
+
+        let mut d = Vec::new();
+        d.extend_from_slice(b"\x7fELF");
+        d.extend_from_slice(&[2, if cfg!(target_endian = "little") { 1 } else { 2 }, 1]);
+        d.resize(16, 0);
+        d.extend_from_slice(&ET_DYN.to_ne_bytes());
+        d.extend_from_slice(&EM_X86_64.to_ne_bytes());
+        d.extend_from_slice(&1u32.to_ne_bytes());
+        for word in [0u64, 64, sh_off as u64] {
+            d.extend_from_slice(&word.to_ne_bytes());
+        }
+        d.extend_from_slice(&0u32.to_ne_bytes());
+        for half in [64u16, 56, 2, 64, 3, 0] {
+            d.extend_from_slice(&half.to_ne_bytes());
+        }
+        d.extend_from_slice(&PT_LOAD.to_ne_bytes());
+        d.extend_from_slice(&5u32.to_ne_bytes());
+        for word in [0u64, 0, 0, total, total, 0x1000] {
+            d.extend_from_slice(&word.to_ne_bytes());
+        }
+        let dyn_len = (dyn_count * 16) as u64;
+        d.extend_from_slice(&PT_DYNAMIC.to_ne_bytes());
+        d.extend_from_slice(&6u32.to_ne_bytes());
+        for word in [
+            dyn_off as u64,
+            dyn_off as u64,
+            dyn_off as u64,
+            dyn_len,
+            dyn_len,
+            8,
+        ] {
+            d.extend_from_slice(&word.to_ne_bytes());
+        }
+        for (tag, val) in [
+            (DT_SONAME, defs[0] as usize),
+            (DT_HASH, hash_off),
+            (DT_SYMTAB, sym_off),
+            (DT_SYMENT, 24),
+            (DT_STRTAB, str_off),
+            (DT_STRSZ, strtab.len()),
+            (DT_VERSYM, versym_off),
+            (DT_VERDEF, verdef_off),
+            (DT_VERDEFNUM, defs.len()),
+            (DT_NULL, 0),
+        ] {
+            d.extend_from_slice(&tag.to_ne_bytes());
+            d.extend_from_slice(&(val as u64).to_ne_bytes());
+        }
+        d.extend_from_slice(&strtab);
+        d.resize(sym_off + 24, 0);
+        for (&(_, stt, stb, stv, shndx, _), &name) in symbols.iter().zip(&names) {
+            d.extend_from_slice(&name.to_ne_bytes());
+            d.extend_from_slice(&[(stb << 4) | stt, stv]);
+            d.extend_from_slice(&(shndx as u16).to_ne_bytes());
+            d.extend_from_slice(&[0; 16]);
+        }
+        // One bucket chaining every symbol, which is all goblin needs to
+        // size the table.
+        for word in [1, nsyms as u32, 0] {
+            d.extend_from_slice(&word.to_ne_bytes());
+        }
+        d.resize(versym_off, 0);
+        d.extend_from_slice(&0u16.to_ne_bytes());
+        for &(.., versym) in symbols {
+            d.extend_from_slice(&versym.to_ne_bytes());
+        }
+        d.resize(verdef_off, 0);
+        for (i, &name) in defs.iter().enumerate() {
+            let flags = if i == 0 { VER_FLG_BASE } else { 0 };
+            for half in [1, flags, i as u16 + 1, 1] {
+                d.extend_from_slice(&half.to_ne_bytes());
+            }
+            let next = if i + 1 < defs.len() { 28u32 } else { 0 };
+            for word in [0, 20, next, name, 0] {
+                d.extend_from_slice(&word.to_ne_bytes());
+            }
+        }
+        d.resize(sh_off + 64, 0);
+        for (kind, offset, size, info) in [
+            (SHT_GNU_VERSYM, versym_off, nsyms * 2, 0),
+            (SHT_GNU_VERDEF, verdef_off, verdef_len, defs.len() as u32),
+        ] {
+            d.extend_from_slice(&0u32.to_ne_bytes());
+            d.extend_from_slice(&kind.to_ne_bytes());
+            for word in [0u64, offset as u64, offset as u64, size as u64] {
+                d.extend_from_slice(&word.to_ne_bytes());
+            }
+            d.extend_from_slice(&0u32.to_ne_bytes());
+            d.extend_from_slice(&info.to_ne_bytes());
+            d.extend_from_slice(&[0; 16]);
+        }
+        d
+    }
+
+    #[test]
+    fn lists_versioned_exports() {
+        use goblin::elf::section_header::{SHN_ABS, SHN_UNDEF};
+        use goblin::elf::sym::{
+            STB_GLOBAL, STB_LOCAL, STB_WEAK, STT_FUNC, STT_GNU_IFUNC, STT_OBJECT, STT_TLS,
+            STV_DEFAULT, STV_HIDDEN,
+        };
+        const HIDDEN: u16 = 0x8000;
+        const TEXT: u32 = 7;
+        let dso = versioned_dso(&[
+            // The version definition itself, absolute, as ld writes it.
+            ("LIBFOO_2", STT_OBJECT, STB_GLOBAL, STV_DEFAULT, SHN_ABS, 3),
+            ("foo", STT_FUNC, STB_GLOBAL, STV_DEFAULT, TEXT, 2 | HIDDEN),
+            ("foo", STT_FUNC, STB_GLOBAL, STV_DEFAULT, TEXT, 3),
+            ("foo_data", STT_OBJECT, STB_GLOBAL, STV_DEFAULT, TEXT, 3),
+            ("foo_tls", STT_TLS, STB_WEAK, STV_DEFAULT, TEXT, 2),
+            (
+                "foo_resolve",
+                STT_GNU_IFUNC,
+                STB_GLOBAL,
+                STV_DEFAULT,
+                TEXT,
+                3,
+            ),
+            ("foo_plain", STT_FUNC, STB_GLOBAL, STV_DEFAULT, TEXT, 1),
+            ("puts", STT_FUNC, STB_GLOBAL, STV_DEFAULT, SHN_UNDEF, 0),
+            ("foo_internal", STT_FUNC, STB_GLOBAL, STV_HIDDEN, TEXT, 1),
+            ("foo_local", STT_FUNC, STB_LOCAL, STV_DEFAULT, TEXT, 1),
+        ]);
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let path = dir.join("libfoo.so.1");
+        std::fs::write(&path, &dso).unwrap();
+
+        let symbols = exported_symbols(&path).unwrap();
+        let listed: Vec<(String, SymbolKind)> =
+            symbols.iter().map(|s| (s.to_string(), s.kind)).collect();
+        assert_eq!(
+            listed,
+            [
+                ("foo@LIBFOO_1".to_owned(), SymbolKind::Function),
+                ("foo@@LIBFOO_2".to_owned(), SymbolKind::Function),
+                ("foo_data@@LIBFOO_2".to_owned(), SymbolKind::Object),
+                ("foo_tls@@LIBFOO_1".to_owned(), SymbolKind::Tls),
+                (
+                    "foo_resolve@@LIBFOO_2".to_owned(),
+                    SymbolKind::IndirectFunction
+                ),
+                ("foo_plain".to_owned(), SymbolKind::Function),
+            ]
+        );
+        assert_eq!(symbols[0].version.as_deref(), Some("LIBFOO_1"));
+        assert!(!symbols[0].default_version);
+        assert_eq!(symbols[5].version, None);
+
+        let cache = Cache::from_file_entries(&[], None, &[])
+            .unwrap()
+            .add_entries(&[
+                SyntheticEntry::new("libfoo.so.1", path.as_str(), Arch::X86_64),
+                SyntheticEntry::new("libgone.so.1", "/nowhere/libgone.so.1", Arch::X86_64),
+            ])
+            .unwrap();
+        let found = cache.entries_exporting("foo_data", "/");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, path);
+        assert!(cache.entries_exporting("puts", "/").is_empty());
+    }
+
+    #[test]
+    fn rejects_non_elf() {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(tmp.path(), "not ELF").unwrap();
+        let path = Utf8Path::from_path(tmp.path()).unwrap();
+        assert!(matches!(exported_symbols(path), Err(Error::NotDynamic(_))));
+    }
+}
@@ -1,11 +1,12 @@
 //! Symlink management, mirroring glibc's create_links.
 
 use crate::chroot::chroot_canon;
+use crate::report::{BuildReport, LinkAction, WarningKind};
 use camino::Utf8Path;
 use std::fs;
 use std::io;
 use std::os::unix::fs::MetadataExt;
-use tracing::{debug, warn};
+use tracing::{debug, instrument};
 
 /// stat() that resolves symlinks inside the -r root (glibc chroot_stat).
 fn chroot_stat(prefix: &Utf8Path, real: &Utf8Path, logical: &Utf8Path) -> io::Result<fs::Metadata> {
@@ -21,17 +22,26 @@ fn chroot_stat(prefix: &Utf8Path, real: &Utf8Path, logical: &Utf8Path) -> io::Re
     fs::metadata(canon)
 }
 
+/// Where the symlink at `link` points, as written; empty if unreadable.
+pub(crate) fn read_target(link: &Utf8Path) -> String {
+    fs::read_link(link).map_or_else(|_| String::new(), |t| t.to_string_lossy().into_owned())
+}
+
 /// Create or update the `soname` -> `libname` symlink in one directory.
-/// Never removes anything that is not a symlink.
+/// Never removes anything that is not a symlink. A dry run only records
+/// the action it would take. Returns whether a link was (to be) made.
+#[instrument(level = "trace", skip_all, fields(dir = %dir, soname = %soname))]
 pub(crate) fn create_link(
     prefix: &Utf8Path,
     real_dir: &Utf8Path,
     dir: &Utf8Path,
     libname: &str,
     soname: &str,
-) {
+    dry_run: bool,
+    report: &mut BuildReport,
+) -> bool {
     if libname == soname {
-        return;
+        return false;
     }
     let link = real_dir.join(soname);
     let target = real_dir.join(libname);
@@ -40,17 +50,23 @@ pub(crate) fn create_link(
     match chroot_stat(prefix, &link, &dir.join(soname)) {
         Ok(st_so) => {
             let Ok(st_lib) = chroot_stat(prefix, &target, &dir.join(libname)) else {
-                warn!("Can't stat {}/{}", dir, libname);
-                return;
+                report.warn(
+                    WarningKind::FileUnreadable,
+                    format!("Can't stat {}/{}", dir, libname),
+                );
+                return false;
             };
             if st_so.dev() == st_lib.dev() && st_so.ino() == st_lib.ino() {
-                return; // link is already correct
+                return false; // link is already correct
             }
             match fs::symlink_metadata(&link) {
                 Ok(md) if md.file_type().is_symlink() => {}
                 _ => {
-                    warn!("{}/{} is not a symbolic link", dir, soname);
-                    return;
+                    report.warn(
+                        WarningKind::NotSymlink,
+                        format!("{}/{} is not a symbolic link", dir, soname),
+                    );
+                    return false;
                 }
             }
         }
@@ -61,15 +77,54 @@ pub(crate) fn create_link(
         }
     }
 
+    let action = if do_remove {
+        LinkAction::Replace {
+            dir: dir.to_string(),
+            link: soname.to_owned(),
+            target: libname.to_owned(),
+            previous: read_target(&link),
+        }
+    } else {
+        LinkAction::Create {
+            dir: dir.to_string(),
+            link: soname.to_owned(),
+            target: libname.to_owned(),
+        }
+    };
+    if dry_run {
+        report.links.push(action);
+        return true;
+    }
+
     if do_remove {
         if let Err(e) = fs::remove_file(&link) {
-            warn!("Can't unlink {}/{}: {}", dir, soname, e);
-            return;
+            report.warn(
+                WarningKind::LinkFailed,
+                format!("Can't unlink {}/{}: {}", dir, soname, e),
+            );
+            return false;
         }
     }
     match std::os::unix::fs::symlink(libname, &link) {
-        Ok(()) => debug!("{} -> {} (changed)", soname, libname),
-        Err(e) => warn!("Can't link {}/{} to {}: {}", dir, soname, libname, e),
+        Ok(()) => {
+            report.links.push(action);
+            debug!(
+                directory = %dir,
+                library = libname,
+                action = "link",
+                "{} -> {} (changed)",
+                soname,
+                libname
+            );
+            true
+        }
+        Err(e) => {
+            report.warn(
+                WarningKind::LinkFailed,
+                format!("Can't link {}/{} to {}: {}", dir, soname, libname, e),
+            );
+            false
+        }
     }
 }
 
@@ -95,14 +150,56 @@ mod tests {
     #[test]
     fn creates_missing_link() {
         let (_tmp, dir) = setup();
+        let mut report = BuildReport::default();
         create_link(
             Utf8Path::new("/"),
             &dir,
             &dir,
             "libfoo.so.1.2.3",
             "libfoo.so.1",
+            false,
+            &mut report,
         );
         assert_eq!(link_target(&dir, "libfoo.so.1").unwrap(), "libfoo.so.1.2.3");
+        assert_eq!(report.links.len(), 1);
+
+        // Already correct: nothing to do.
+        create_link(
+            Utf8Path::new("/"),
+            &dir,
+            &dir,
+            "libfoo.so.1.2.3",
+            "libfoo.so.1",
+            false,
+            &mut report,
+        );
+        assert_eq!(report.links.len(), 1);
+    }
+
+    #[test]
+    fn dry_run_only_plans() {
+        let (_tmp, dir) = setup();
+        symlink("libgone.so.9", dir.join("libfoo.so.1")).unwrap();
+        let mut report = BuildReport::default();
+        create_link(
+            Utf8Path::new("/"),
+            &dir,
+            &dir,
+            "libfoo.so.1.2.3",
+            "libfoo.so.1",
+            true,
+            &mut report,
+        );
+        assert_eq!(link_target(&dir, "libfoo.so.1").unwrap(), "libgone.so.9");
+        assert_eq!(
+            report.links,
+            [LinkAction::Replace {
+                dir: dir.to_string(),
+                link: "libfoo.so.1".into(),
+                target: "libfoo.so.1.2.3".into(),
+                previous: "libgone.so.9".into(),
+            }]
+        );
     }
 
     #[test]
@@ -116,6 +213,8 @@ mod tests {
             &dir,
             "libfoo.so.1.2.3",
             "libfoo.so.1",
+            false,
+            &mut BuildReport::default(),
         );
         assert_eq!(link_target(&dir, "libfoo.so.1").unwrap(), "libfoo.so.1.2.3");
     }
@@ -130,6 +229,8 @@ mod tests {
             &dir,
             "libfoo.so.1.2.3",
             "libfoo.so.1",
+            false,
+            &mut BuildReport::default(),
         );
         assert_eq!(link_target(&dir, "libfoo.so.1").unwrap(), "libfoo.so.1.2.3");
     }
@@ -138,13 +239,17 @@ mod tests {
     fn never_removes_regular_file() {
         let (_tmp, dir) = setup();
         fs::write(dir.join("libfoo.so.1"), b"real file").unwrap();
+        let mut report = BuildReport::default();
         create_link(
             Utf8Path::new("/"),
             &dir,
             &dir,
             "libfoo.so.1.2.3",
             "libfoo.so.1",
+            false,
+            &mut report,
         );
+        assert_eq!(report.warnings.len(), 1);
         let md = fs::symlink_metadata(dir.join("libfoo.so.1")).unwrap();
         assert!(md.file_type().is_file());
         assert_eq!(fs::read(dir.join("libfoo.so.1")).unwrap(), b"real file");
@@ -161,6 +266,8 @@ mod tests {
             &dir,
             "libfoo.so.1.2.3",
             "libfoo.so.1",
+            false,
+            &mut BuildReport::default(),
         );
         let after = fs::symlink_metadata(dir.join("libfoo.so.1")).unwrap().ino();
         assert_eq!(before, after);
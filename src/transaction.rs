@@ -0,0 +1,307 @@
+//! Applying a planned build all at once: the symlink changes and the
+//! cache are staged under temporary names, then renamed into place, and
+//! undone if anything fails on the way.
+//!
+//! ```no_run
+//! use ldconfig::{Cache, SearchPaths};
+//!
+//! let paths = SearchPaths::from_file("/etc/ld.so.conf", None)?;
+//! let planned = Cache::builder().dry_run(true).build(&paths)?;
+//! planned.apply("/etc/ld.so.cache", "/", None)?;
+//! # Ok::<(), ldconfig::Error>(())
+//! ```
+
+use crate::atomic_write;
+use crate::{chroot_canon, error::Error, Backup, Cache, LinkAction};
+use camino::{Utf8Path, Utf8PathBuf};
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use tracing::{debug, debug_span, warn};
+
+enum StepKind {
+    Create,
+    Replace { previous: String },
+    Remove,
+}
+
+/// One symlink change.
+struct Step {
+    /// The link, in its directory inside the root.
+    link: Utf8PathBuf,
+    /// The new link while staged, or where a removed one is moved aside.
+    temp: Utf8PathBuf,
+    kind: StepKind,
+}
+
+/// Symlink changes staged under temporary names. Dropped before
+/// [`Transaction::finish`], it undoes the committed ones, newest first,
+/// and removes what is still staged.
+#[derive(Default)]
+struct Transaction {
+    steps: Vec<Step>,
+    committed: usize,
+    done: bool,
+}
+
+impl Transaction {
+    /// Stage `action`, checking that its link is still as planned: never
+    /// anything but a symlink is replaced or removed, and a replaced one
+    /// must still point where it did.
+    fn stage(&mut self, prefix: &Utf8Path, action: &LinkAction) -> Result<(), Error> {
+        let (LinkAction::Create { dir, link, .. }
+        | LinkAction::Replace { dir, link, .. }
+        | LinkAction::RemoveStale { dir, link, .. }) = action;
+        let changed = || Error::ChangedSincePlan(format!("{}/{}", dir, link));
+        let real_dir = chroot_canon(prefix, Utf8Path::new(dir)).ok_or_else(changed)?;
+        let path = real_dir.join(link);
+        let is_link = match fs::symlink_metadata(&path) {
+            Ok(md) => md.file_type().is_symlink(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e.into()),
+        };
+        let exists = is_link || path.exists();
+        let (kind, target) = match action {
+            LinkAction::Create { target, .. } if !exists => (StepKind::Create, Some(target)),
+            LinkAction::Replace {
+                target, previous, ..
+            } if is_link && fs::read_link(&path).is_ok_and(|t| t == Path::new(previous)) => (
+                StepKind::Replace {
+                    previous: previous.clone(),
+                },
+                Some(target),
+            ),
+            LinkAction::RemoveStale { .. } if is_link => (StepKind::Remove, None),
+            _ => return Err(changed()),
+        };
+        let temp = match target {
+            Some(target) => {
+                let temp = real_dir.join(format!(".{}.ldconfig-new", link));
+                let _ = fs::remove_file(&temp);
+                symlink(target, &temp)?;
+                temp
+            }
+            None => real_dir.join(format!(".{}.ldconfig-old", link)),
+        };
+        self.steps.push(Step {
+            link: path,
+            temp,
+            kind,
+        });
+        Ok(())
+    }
+
+    /// Rename every staged change into place, in order.
+    fn commit(&mut self) -> io::Result<()> {
+        for step in &self.steps {
+            match step.kind {
+                StepKind::Create | StepKind::Replace { .. } => fs::rename(&step.temp, &step.link)?,
+                StepKind::Remove => fs::rename(&step.link, &step.temp)?,
+            }
+            self.committed += 1;
+        }
+        Ok(())
+    }
+
+    /// Keep the changes, dropping the links moved aside.
+    fn finish(mut self) {
+        for step in &self.steps {
+            if let StepKind::Remove = step.kind {
+                let _ = fs::remove_file(&step.temp);
+            }
+        }
+        self.done = true;
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let (committed, staged) = self.steps.split_at(self.committed);
+        for step in staged {
+            if let StepKind::Create | StepKind::Replace { .. } = step.kind {
+                let _ = fs::remove_file(&step.temp);
+            }
+        }
+        for step in committed.iter().rev() {
+            let undone = match &step.kind {
+                StepKind::Create => fs::remove_file(&step.link),
+                StepKind::Replace { previous } => {
+                    symlink(previous, &step.temp).and_then(|()| fs::rename(&step.temp, &step.link))
+                }
+                StepKind::Remove => fs::rename(&step.temp, &step.link),
+            };
+            if let Err(e) = undone {
+                warn!("Can't restore {}: {}", step.link, e);
+            }
+        }
+    }
+}
+
+impl Cache {
+    /// Make the symlink changes a `dry_run(true)` build planned, inside
+    /// `prefix`, and write the cache to `path`, all or nothing: links and
+    /// cache are staged under temporary names first, then renamed into
+    /// place, and what was already renamed is undone if anything fails.
+    /// Links found changed since the build fail it untouched. With a
+    /// `backup`, the cache being replaced is saved first, as
+    /// [`Cache::write_to_file_with_backup`] does, and where is returned.
+    pub fn apply(
+        &self,
+        path: impl AsRef<Path>,
+        prefix: impl AsRef<Utf8Path>,
+        backup: Option<Backup>,
+    ) -> Result<Option<PathBuf>, Error> {
+        let path = path.as_ref();
+        let prefix = prefix.as_ref();
+        let links = &self.report().links;
+        let _span = debug_span!("apply", path = %path.display(), links = links.len()).entered();
+
+        let mut transaction = Transaction::default();
+        for action in links {
+            transaction.stage(prefix, action)?;
+        }
+        // A spilled build's file next to `path` is renamed as it is.
+        let cache = if self.data.staged_for(path) {
+            None
+        } else {
+            Some(atomic_write::stage(path, self.as_bytes())?)
+        };
+        let saved = match backup {
+            Some(backup) => self.save_backup(path, backup)?,
+            None => None,
+        };
+        transaction.commit()?;
+        match cache {
+            Some(cache) => drop(cache.persist(path).map_err(io::Error::from)?),
+            None => self.data.persist(path)?,
+        }
+        transaction.finish();
+        debug!("Applied {} link changes", links.len());
+        Ok(saved)
+    }
+
+    /// [`Cache::apply`] without a cache to write: only the symlink
+    /// changes, all or nothing.
+    pub(crate) fn apply_links(&self, prefix: &Utf8Path) -> Result<(), Error> {
+        let links = &self.report().links;
+        let _span = debug_span!("apply", links = links.len()).entered();
+        let mut transaction = Transaction::default();
+        for action in links {
+            transaction.stage(prefix, action)?;
+        }
+        transaction.commit()?;
+        transaction.finish();
+        debug!("Applied {} link changes", links.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SearchPaths;
+
+    #[test]
+    fn applies_everything_or_nothing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let lib = root.join("lib");
+        fs::create_dir(&lib).unwrap();
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        fs::write(lib.join("libz.so.1.3"), dso).unwrap();
+        symlink("libgone.so.1.0", lib.join("libgone.so.1")).unwrap();
+        let paths = SearchPaths::new(vec![lib.clone()]);
+        let planned = Cache::builder().dry_run(true).build(&paths).unwrap();
+        assert_eq!(planned.report().links.len(), 2);
+
+        // The cache cannot replace a directory: the links are put back.
+        let blocked = root.join("blocked.cache");
+        fs::create_dir(&blocked).unwrap();
+        assert!(planned.apply(&blocked, "/", None).is_err());
+        assert!(!lib.join("libz.so.1").is_symlink());
+        assert!(lib.join("libgone.so.1").is_symlink());
+        assert_eq!(fs::read_dir(&lib).unwrap().count(), 2);
+
+        let cache = root.join("ld.so.cache");
+        planned.apply(&cache, "/", None).unwrap();
+        assert_eq!(fs::read(&cache).unwrap(), planned.as_bytes());
+        assert_eq!(
+            fs::read_link(lib.join("libz.so.1")).unwrap(),
+            Path::new("libz.so.1.3")
+        );
+        assert!(!lib.join("libgone.so.1").is_symlink());
+        assert_eq!(fs::read_dir(&lib).unwrap().count(), 2);
+
+        // Planned against links that are gone now.
+        assert!(matches!(
+            planned.apply(&cache, "/", None),
+            Err(Error::ChangedSincePlan(_))
+        ));
+    }
+
+    #[test]
+    fn spilled_caches_are_renamed_into_place() {
+        use std::os::unix::fs::MetadataExt;
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let lib = root.join("lib");
+        fs::create_dir(&lib).unwrap();
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        fs::write(lib.join("libz.so.1.3"), dso).unwrap();
+        let etc = root.join("etc");
+        fs::create_dir(&etc).unwrap();
+        let cache = etc.join("ld.so.cache");
+        let planned = Cache::builder()
+            .dry_run(true)
+            .spill_entries(1)
+            .cache_file(&cache)
+            .build(&SearchPaths::new(vec![lib.clone()]))
+            .unwrap();
+        let staged = fs::read_dir(&etc).unwrap().next().unwrap().unwrap();
+        let inode = staged.metadata().unwrap().ino();
+
+        planned.apply(&cache, "/", None).unwrap();
+        assert_eq!(fs::read_dir(&etc).unwrap().count(), 1);
+        assert_eq!(fs::metadata(&cache).unwrap().ino(), inode);
+        assert_eq!(fs::read(&cache).unwrap(), planned.as_bytes());
+        assert!(lib.join("libz.so.1").is_symlink());
+    }
+
+    #[test]
+    fn retargeted_links_fail_the_apply() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = Utf8PathBuf::try_from(tmp.path().to_path_buf()).unwrap();
+        let lib = root.join("lib");
+        fs::create_dir(&lib).unwrap();
+        let dso = crate::elf::dso_with(Some("libz.so.1"), &[], None);
+        fs::write(lib.join("libz.so.1.2"), &dso).unwrap();
+        fs::write(lib.join("libz.so.1.3"), dso).unwrap();
+        symlink("libz.so.1.2", lib.join("libz.so.1")).unwrap();
+        let planned = Cache::builder()
+            .dry_run(true)
+            .build(&SearchPaths::new(vec![lib.clone()]))
+            .unwrap();
+        assert!(matches!(
+            planned.report().links[..],
+            [LinkAction::Replace { .. }]
+        ));
+
+        // Pointed elsewhere between the plan and the apply.
+        fs::remove_file(lib.join("libz.so.1")).unwrap();
+        symlink("libz.so.1.9", lib.join("libz.so.1")).unwrap();
+        let cache = root.join("ld.so.cache");
+        assert!(matches!(
+            planned.apply(&cache, "/", None),
+            Err(Error::ChangedSincePlan(_))
+        ));
+        assert_eq!(
+            fs::read_link(lib.join("libz.so.1")).unwrap(),
+            Path::new("libz.so.1.9")
+        );
+        assert!(!cache.exists());
+    }
+}